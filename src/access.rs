@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::{self, CacheKey}, stats, Result, Storage};
+
+/// Reads kept in each cache's log, oldest are dropped first
+const MAX_ENTRIES: usize = 200;
+
+/// Name of the log below [crate::cache::Cache::location]
+pub(crate) const LOG_NAME: &str = "access-log";
+
+/// One line of a cache's access-log, appended by each download
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccessRecord {
+    pub time: DateTime<Utc>,
+    /// Bytes restored, excluding files skipped as unchanged
+    pub bytes: u64,
+}
+
+pub(crate) fn log_location(cache_name: &str) -> String {
+    format!("cache/{}/{}", cache_name, LOG_NAME)
+}
+
+async fn try_record_download(storage: &Storage, cache_name: &str, record: &AccessRecord) -> Result<()> {
+    let location = log_location(cache_name);
+    let existing = stats::read_snapshot(storage, &location).await?;
+    let content = stats::append_line(&existing, record, MAX_ENTRIES);
+    storage.put_file(&mut std::io::Cursor::new(content), &location).await?;
+    Ok(())
+}
+
+/// Best-effort append to the cache's access-log - failures are logged, never returned
+pub(crate) async fn record_download(storage: &Storage, cache_name: &str, bytes: u64) {
+    let record = AccessRecord { time: Utc::now(), bytes };
+    if let Err(e) = try_record_download(storage, cache_name, &record).await {
+        log::info!("Unable to record access to '{}': {:#}", cache_name, e);
+    }
+}
+
+/// Reads of one cache within a window
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ReadActivity {
+    pub name: String,
+    pub last_read: Option<DateTime<Utc>>,
+    pub reads: usize,
+    pub bytes: u64,
+}
+
+/// Aggregate a cache's log over reads since
+fn summarise(name: &str, records: &[AccessRecord], since: DateTime<Utc>) -> ReadActivity {
+    let mut activity = ReadActivity {
+        name: name.to_owned(),
+        last_read: records.iter().map(|r| r.time).max(),
+        ..Default::default()
+    };
+    for r in records.iter().filter(|r| r.time >= since) {
+        activity.reads += 1;
+        activity.bytes += r.bytes;
+    }
+    activity
+}
+
+/// Read activity of every cache with an access-log, busiest first
+pub async fn report(storage: &Storage, since: DateTime<Utc>) -> Result<Vec<ReadActivity>> {
+    let mut found = Vec::new();
+    for o in storage.list_objects("cache/").await? {
+        let Some((name, CacheKey::Meta(LOG_NAME))) = cache::parse_key(&o.key) else {
+            continue;
+        };
+        let records = stats::parse_lines(&stats::read_snapshot(storage, &o.key).await?);
+        found.push(summarise(name, &records, since));
+    }
+    found.sort_by(|a, b| b.reads.cmp(&a.reads).then_with(|| a.name.cmp(&b.name)));
+    Ok(found)
+}
+
+/// Caches read at least min_reads times since
+pub async fn hot_caches(storage: &Storage, since: DateTime<Utc>, min_reads: usize) -> Result<Vec<String>> {
+    Ok(report(storage, since).await?.into_iter()
+       .filter(|a| a.reads >= min_reads)
+       .map(|a| a.name)
+       .collect())
+}
+
+pub fn print_table(activity: &[ReadActivity]) {
+    let len = activity.iter().map(|a| a.name.len()).max().unwrap_or(0).max(30);
+    println!("{:<len$} {:>25} {:>8} {:>14}", "cache", "last read", "reads", "restored");
+    for a in activity {
+        println!("{:<len$} {:>25} {:>8} {:>14}", a.name,
+                 a.last_read.map_or("-".into(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string()),
+                 a.reads, a.bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn record(t: &str, bytes: u64) -> AccessRecord {
+        AccessRecord { time: time(t), bytes }
+    }
+
+    #[test]
+    fn log_is_truncated_on_write() {
+        let mut content = String::new();
+        for i in 0..(MAX_ENTRIES as u64 + 5) {
+            content = stats::append_line(content.as_bytes(), &record("2025-01-01T00:00:00Z", i), MAX_ENTRIES);
+        }
+        let records: Vec<AccessRecord> = stats::parse_lines(content.as_bytes());
+        assert_eq!(records.len(), MAX_ENTRIES);
+        assert_eq!(records[0].bytes, 5);
+        assert_eq!(records.last().unwrap().bytes, MAX_ENTRIES as u64 + 4);
+    }
+
+    #[test]
+    fn reads_within_window() {
+        let records = vec![
+            record("2025-01-01T00:00:00Z", 100),
+            record("2025-01-15T00:00:00Z", 10),
+            record("2025-01-20T00:00:00Z", 20),
+        ];
+        let a = summarise("one", &records, time("2025-01-10T00:00:00Z"));
+        assert_eq!(a, ReadActivity { name: "one".into(), last_read: Some(time("2025-01-20T00:00:00Z")), reads: 2, bytes: 30 });
+
+        let none = summarise("two", &records[..1], time("2025-01-10T00:00:00Z"));
+        assert_eq!(none.reads, 0);
+        assert_eq!(none.last_read, Some(time("2025-01-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn log_key_is_reserved() {
+        assert_eq!(cache::parse_key(&log_location("c")), Some(("c", CacheKey::Meta(LOG_NAME))));
+    }
+}
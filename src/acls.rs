@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// POSIX ACLs of a file or directory, in the short text form, eg
+/// "user::rwx,user:1000:r-x,group::r-x,mask::r-x,other::---"
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acls {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access: Option<String>,
+    /// Inherited by new entries, directories only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+}
+
+impl Acls {
+    pub fn is_empty(&self) -> bool {
+        self.access.is_none() && self.default.is_none()
+    }
+}
+
+#[cfg(target_os = "linux")]
+const ACCESS: &str = "system.posix_acl_access";
+#[cfg(target_os = "linux")]
+const DEFAULT: &str = "system.posix_acl_default";
+#[cfg(target_os = "linux")]
+const EOPNOTSUPP: i32 = 95;
+
+/// Linux's xattr representation: a version, then (tag, perm, id) entries
+const VERSION: u32 = 2;
+const UNDEFINED_ID: u32 = u32::MAX;
+const TAGS: [(u16, &str, bool); 6] = [
+    (0x01, "user", false),
+    (0x02, "user", true),
+    (0x04, "group", false),
+    (0x08, "group", true),
+    (0x10, "mask", false),
+    (0x20, "other", false),
+];
+
+fn invalid(what: impl std::fmt::Display) -> anyhow::Error {
+    Error::InvalidAcl(what.to_string()).into()
+}
+
+/// Text form of an ACL xattr value
+pub fn decode(bytes: &[u8]) -> Result<String> {
+    let word = |b: &[u8]| u32::from_le_bytes(b.try_into().expect("4 bytes"));
+    if bytes.len() < 4 || (bytes.len() - 4) % 8 != 0 || word(&bytes[..4]) != VERSION {
+        return Err(invalid(format!("{} byte value", bytes.len())));
+    }
+
+    let mut entries = Vec::new();
+    for e in bytes[4..].chunks_exact(8) {
+        let tag = u16::from_le_bytes([e[0], e[1]]);
+        let perm = u16::from_le_bytes([e[2], e[3]]);
+        let id = word(&e[4..]);
+        let (_, name, qualified) = TAGS.iter().find(|(t, _, _)| *t == tag)
+            .ok_or_else(|| invalid(format!("tag {:#x}", tag)))?;
+        let qualifier = if *qualified { id.to_string() } else { String::new() };
+        let perms: String = [(4, 'r'), (2, 'w'), (1, 'x')].iter()
+            .map(|(bit, c)| if perm & bit != 0 { *c } else { '-' }).collect();
+        entries.push(format!("{}:{}:{}", name, qualifier, perms));
+    }
+    Ok(entries.join(","))
+}
+
+/// ACL xattr value for the text form [decode] produces
+pub fn encode(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = VERSION.to_le_bytes().to_vec();
+    for entry in text.split(',') {
+        let [name, qualifier, perms] = entry.split(':').collect::<Vec<_>>()[..] else {
+            return Err(invalid(entry));
+        };
+        let qualified = !qualifier.is_empty();
+        let (tag, _, _) = TAGS.iter().find(|(_, n, q)| *n == name && *q == qualified)
+            .ok_or_else(|| invalid(entry))?;
+        let id = if qualified { qualifier.parse().map_err(|_| invalid(entry))? } else { UNDEFINED_ID };
+        if perms.len() != 3 {
+            return Err(invalid(entry));
+        }
+        let mut perm = 0u16;
+        for (c, (bit, want)) in perms.chars().zip([(4, 'r'), (2, 'w'), (1, 'x')]) {
+            match c {
+                '-' => {},
+                c if c == want => perm |= bit,
+                _ => return Err(invalid(entry)),
+            }
+        }
+        bytes.extend(tag.to_le_bytes());
+        bytes.extend(perm.to_le_bytes());
+        bytes.extend(id.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+#[cfg(target_os = "linux")]
+fn read(path: &Path, name: &str) -> Result<Option<String>> {
+    match xattr::get(path, name) {
+        Ok(Some(value)) => Ok(Some(decode(&value)?)),
+        Ok(None) => Ok(None),
+        // filesystem without ACL support holds none
+        Err(e) if e.raw_os_error() == Some(EOPNOTSUPP) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Extended ACLs of path, None if it only has those implied by its mode
+#[cfg(target_os = "linux")]
+pub fn capture(path: &Path, is_dir: bool) -> Result<Option<Acls>> {
+    let acls = Acls {
+        access: read(path, ACCESS)?,
+        default: if is_dir { read(path, DEFAULT)? } else { None },
+    };
+    Ok((!acls.is_empty()).then_some(acls))
+}
+
+/// Apply acls to path, after permissions as chmod rewrites the mask.
+/// Failures, eg a filesystem without ACLs, are warned about.
+#[cfg(target_os = "linux")]
+pub fn restore(path: &Path, acls: &Acls) {
+    for (name, text) in [(ACCESS, &acls.access), (DEFAULT, &acls.default)] {
+        let Some(text) = text else {
+            continue;
+        };
+        if let Err(e) = encode(text).and_then(|value| Ok(xattr::set(path, name, &value)?)) {
+            log::warn!("Failed to set ACL {} on {}: {}", text, path.display(), e);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+static UNSUPPORTED: std::sync::Once = std::sync::Once::new();
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() {
+    UNSUPPORTED.call_once(|| log::warn!("POSIX ACLs are only supported on Linux, skipping them"));
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn capture(_path: &Path, _is_dir: bool) -> Result<Option<Acls>> {
+    unsupported();
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restore(_path: &Path, _acls: &Acls) {
+    unsupported();
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const TEXT: &str = "user::rwx,user:1000:r-x,group::r-x,group:50:rw-,mask::rwx,other::---";
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode(TEXT).unwrap();
+        assert_eq!(bytes.len(), 4 + 6 * 8);
+        assert_eq!(&bytes[..4], &[2, 0, 0, 0]);
+        // user:1000:r-x
+        assert_eq!(&bytes[12..20], &[0x02, 0, 5, 0, 0xe8, 0x03, 0, 0]);
+        assert_eq!(decode(&bytes).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn bad_text() {
+        for text in ["", "user::rwx,", "user:bob:rwx", "mask:1:rwx", "other::rwxx", "other::xwr", "owner::rwx", "user:rwx"] {
+            assert!(encode(text).is_err(), "accepted {:?}", text);
+        }
+    }
+
+    #[test]
+    fn bad_bytes() {
+        let good = encode(TEXT).unwrap();
+        assert!(decode(&good[..good.len() - 1]).is_err());
+        assert!(decode(&[1, 0, 0, 0]).is_err());
+        let mut bad_tag = good.clone();
+        bad_tag[4] = 0x40;
+        assert!(decode(&bad_tag).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn filesystem_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let acls = Acls {
+            access: Some("user::rwx,group::r-x,group:0:rwx,mask::rwx,other::r-x".into()),
+            default: Some("user::rwx,group::rwx,other::r-x".into()),
+        };
+        if xattr::set(dir.path(), ACCESS, &encode(acls.access.as_ref().unwrap()).unwrap()).is_err() {
+            eprintln!("filesystem doesn't support ACLs, skipping");
+            return;
+        }
+        restore(dir.path(), &acls);
+        assert_eq!(capture(dir.path(), true).unwrap(), Some(acls));
+    }
+}
@@ -2,23 +2,29 @@
 // (C) Copyright 2025 Greg Whiteley
 
 use anyhow::Context;
+use serde::Serialize;
 use async_std::{fs, path::PathBuf};
+use std::sync::Arc;
+use path_slash::PathExt as _;
+use tokio::io::AsyncWriteExt as _;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 
-use crate::{Result, cache::{self, Cache}, Storage};
+use crate::{Result, cache::{self, Cache, FileHasher, HashAlgorithm}, compression::CompressionCodec, content_type, display::{key_display, local_display}, fsync::{Fsync, FsyncPolicy}, hashes::HashManifest, index::DedupIndex, layout, limits::{self, UploadLimits}, links::{self, AbsoluteSymlinks, LinkKind, Symlinks}, local_state::{self, LocalState}, object::ObjectKey, paths::{GlobSet, PathRules}, plan::{Destination, PlannedFile, UploadPlan}, progress::{Counting, ProgressSink}, ranged, acls, resume::UploadState, sentinel, skip::{DownloadSkipReason, SkipReason, Skips}, stats, times::{self, PreserveTimes}, unicode::{self, Normalization}, xattrs, Storage};
 
 #[derive(Debug)]
 struct Meta {
     path: PathBuf,
     file: Option<std::fs::Metadata>,
     hash: Option<[u8;32]>,
+    /// A large file left to be hashed as it's uploaded
+    hash_on_upload: bool,
     link_target: Option<PathBuf>,
 }
 
 impl Meta {
     fn new(path: PathBuf) -> Meta {
-        Meta { path, file: None, hash: None, link_target: None }
+        Meta { path, file: None, hash: None, hash_on_upload: false, link_target: None }
     }
 
     async fn resolve(&mut self) -> Result<()> {
@@ -26,15 +32,8 @@ impl Meta {
         Ok(())
     }
 
-    fn object_path(&self) -> Option<PathBuf> {
-        self.hash.map(|ref x| {
-            let mut path = PathBuf::new();
-            path.push(faster_hex::hex_string(&x[0..4]));
-            path.push(faster_hex::hex_string(&x[4..8]));
-            path.push(faster_hex::hex_string(&x[8..12]));
-            path.push(faster_hex::hex_string(&x[12..]));
-            path
-        })
+    fn object_key(&self) -> Option<ObjectKey> {
+        self.hash.as_ref().map(ObjectKey::from_digest)
     }
 
     fn cacheable_link(&self) -> Option<PathBuf> {
@@ -42,7 +41,7 @@ impl Meta {
     }
 
     fn is_cacheable_file(&self) -> bool {
-        self.hash.is_some() && self.file.is_some()
+        (self.hash.is_some() || self.hash_on_upload) && self.file.is_some()
     }
 
     #[cfg(unix)]
@@ -58,8 +57,9 @@ impl Meta {
     }
 }
 
-async fn meta_for(path: PathBuf) -> Result<Meta> {
-    log::debug!("Fetching metadata for {:?}", &path);
+/// Metadata and link target, but no hash
+async fn resolve_meta(path: PathBuf) -> Result<Meta> {
+    log::debug!("Fetching metadata for {}", local_display(&path));
 
     let mut m = Meta::new(path);
     m.resolve().await?;
@@ -67,317 +67,3080 @@ async fn meta_for(path: PathBuf) -> Result<Meta> {
     if m.file.as_ref().is_some_and(std::fs::Metadata::is_symlink) {
         m.link_target = Some(fs::read_link(m.path.as_path()).await?);
     }
+    Ok(m)
+}
+
+/// Metadata and hash, except for files larger than hash_above, which are
+/// left for upload to hash unless the manifest has them.  Manifests hold
+/// sha256, so are only consulted for that.
+async fn meta_for(path: PathBuf, hashes: Option<Arc<HashManifest>>, hash_above: Option<u64>,
+                  hasher: FileHasher) -> Result<Meta> {
+    let hashes = hashes.filter(|_| hasher.algorithm.is_sha256());
+    let mut m = resolve_meta(path).await?;
+
     if m.file.as_ref().is_some_and(std::fs::Metadata::is_file) {
-        m.hash = Some(cache::read_hash(m.path.as_path(), &m.file.as_ref().map(std::fs::Metadata::len)).await?);
+        let len = m.file.as_ref().map(std::fs::Metadata::len);
+        let provided = hashes.as_ref().and_then(|h| h.get(m.path.as_ref()).map(|x| (h, x)));
+        if provided.is_none() && hash_above.is_some_and(|above| len.is_some_and(|len| len > above)) {
+            // not read until upload, so find out now if it can't be
+            drop(fs::File::open(m.path.as_path()).await?);
+            m.hash_on_upload = true;
+            return Ok(m);
+        }
+        m.hash = Some(match provided {
+            Some((manifest, expected)) => {
+                if manifest.sample() {
+                    let actual = cache::read_hash(m.path.as_path(), &len).await?;
+                    if actual != expected {
+                        return Err(crate::Error::HashManifestMismatch {
+                            path: m.path.to_string_lossy().into(),
+                            expected: faster_hex::hex_string(&expected),
+                            actual: faster_hex::hex_string(&actual),
+                        }.into());
+                    }
+                }
+                expected
+            },
+            None => hasher.hash(m.path.as_path(), &len).await?,
+        });
     }
     Ok(m)
 }
 
 #[cfg(unix)]
-fn create_symlink(target: String, path: PathBuf) -> Result<()> {
-    log::debug!("Creating symlink {} -> {}", &path.display(), &target);
-    std::os::unix::fs::symlink(target, path)?;
-    Ok(())
+fn create_symlink(target: &str, path: &PathBuf, _kind: Option<LinkKind>) -> std::io::Result<()> {
+    log::debug!("Creating symlink {} -> {}", local_display(path), target);
+    std::os::unix::fs::symlink(target, path)
 }
 
-#[cfg(not(unix))]
-fn create_symlink(target: String, path: PathBuf) -> Result<()> {
-    log::error!("Unable to create symlink {} -> {} on Windows", &path.display(), &target);
+/// Needs Developer Mode or the privilege to create symlinks, except links
+/// to directories, which fall back to junctions.  A junction holds the
+/// absolute path of its target, so doesn't follow the tree if it's moved.
+/// Entries without the kind are guessed from what's restored so far.
+#[cfg(windows)]
+fn create_symlink(target: &str, path: &PathBuf, kind: Option<LinkKind>) -> std::io::Result<()> {
+    log::debug!("Creating symlink {} -> {}", local_display(path), target);
+    let path = std::path::Path::new(path.as_os_str());
+    let resolved = path.parent().map_or_else(|| target.into(), |dir| dir.join(target));
+    let kind = kind.unwrap_or(if resolved.is_dir() { LinkKind::Dir } else { LinkKind::File });
+    match kind {
+        LinkKind::File => std::os::windows::fs::symlink_file(target, path),
+        LinkKind::Dir => std::os::windows::fs::symlink_dir(target, path).or_else(|e| {
+            let resolved = links::absolute(&resolved, &std::env::current_dir()?);
+            log::debug!("Falling back to a junction to {} for {}: {}", local_display(&resolved), local_display(path), e);
+            junction::create(&resolved, path).map_err(|_| e)
+        }),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_target: &str, _path: &PathBuf, _kind: Option<LinkKind>) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Copy a file, or a directory and everything in it, following symlinks
+fn copy_tree(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    if !from.is_dir() {
+        std::fs::copy(from, to)?;
+        return Ok(());
+    }
+    for entry in walkdir::WalkDir::new(from).follow_links(true) {
+        let entry = entry?;
+        let dest = to.join(entry.path().strip_prefix(from).expect("walked below from"));
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&dest)?;
+        } else {
+            std::fs::copy(entry.path(), &dest)?;
+        }
+    }
     Ok(())
 }
 
+/// Deal with symlinks download couldn't create, given as entry path and
+/// target, once everything else is in place
+fn unmade_links(outpath: &std::path::Path, unmade: Vec<(String, String)>, policy: Symlinks,
+                skipped: &mut Skips<DownloadSkipReason>) -> Result<()> {
+    let list = |links: &[(String, String)]| -> String {
+        links.iter().map(|(path, target)| format!("{} -> {}", path, target)).collect::<Vec<_>>().join(", ")
+    };
+    match policy {
+        Symlinks::Native | Symlinks::Error => Err(crate::Error::SymlinksNotRestored(list(&unmade)).into()),
+        Symlinks::Skip => {
+            for (path, target) in unmade {
+                log::warn!("Left out symlink {} -> {}", path, target);
+                skipped.add(DownloadSkipReason::Symlink, path);
+            }
+            Ok(())
+        },
+        Symlinks::Copy => {
+            let mut pending = unmade;
+            // a target may be another link, copied in an earlier round
+            while !pending.is_empty() {
+                let before = pending.len();
+                let mut left = Vec::new();
+                for (path, target) in pending {
+                    let source = links::resolve_in_entry(&path, &target)
+                        .filter(|source| !path.starts_with(&format!("{}/", source)))
+                        .ok_or_else(|| crate::Error::SymlinksNotRestored(
+                            format!("{} -> {} can't be copied from within the restored files", path, target)))?;
+                    let (from, to) = (outpath.join(cache::File::path_of(&source)), outpath.join(cache::File::path_of(&path)));
+                    if std::fs::symlink_metadata(&from).is_err() {
+                        left.push((path, target));
+                        continue;
+                    }
+                    copy_tree(&from, &to).with_context(|| format!("Failed to copy {} in place of a symlink", source))?;
+                    log::info!("Copied {} to {} in place of a symlink", source, path);
+                }
+                if left.len() == before {
+                    return Err(crate::Error::SymlinksNotRestored(format!("targets not found: {}", list(&left))).into());
+                }
+                pending = left;
+            }
+            Ok(())
+        },
+    }
+}
+
 #[cfg(unix)]
-fn set_permisions(path: &async_std::path::Path, mode: u32) {
+fn set_permisions(path: &async_std::path::Path, mode: u32, strict: bool) -> Result<()> {
     if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)) {
-        log::warn!("Failed to set permissions on {}: {}", path.to_str().unwrap(), e.kind());
+        if strict {
+            return Err(crate::Error::SetPermissions(path.to_string_lossy().into(), e).into());
+        }
+        log::warn!("Failed to set permissions on {}: {}", local_display(path), e.kind());
     }
+    Ok(())
 }
 
 #[cfg(not(unix))]
-fn set_permisions(_path: &async_std::path::Path, _mode: u32) {
+fn set_permisions(_path: &async_std::path::Path, _mode: u32, _strict: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Re-hash a restored file, checking it holds the object it came from
+pub(crate) async fn verify_download(path: &async_std::path::Path, size: u64, expected: &ObjectKey,
+                                    algorithm: HashAlgorithm) -> Result<()> {
+    let actual = ObjectKey::from_digest(&cache::read_hash_with(path, &Some(size), algorithm).await?);
+    if actual != *expected {
+        return Err(crate::Error::IntegrityError {
+            path: local_display(path), expected: expected.to_string(), actual: actual.to_string(),
+        }.into());
+    }
+    Ok(())
+}
+
+/// Re-hash a restored file, checking it has the content its entry recorded
+async fn verify_checksum(path: &async_std::path::Path, size: u64, expected: &str) -> Result<()> {
+    let actual = faster_hex::hex_string(&cache::read_hash(path, &Some(size)).await?);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(crate::Error::ChecksumMismatch {
+            path: local_display(path), expected: expected.to_owned(), actual,
+        }.into());
+    }
+    Ok(())
+}
+
+/// How restored files are checked against their entry
+#[derive(Debug, Clone, Copy)]
+struct Checks {
+    /// What the entry's objects are keyed by
+    algorithm: HashAlgorithm,
+    /// Re-hash deduplicated files without a recorded sha256
+    verify: bool,
 }
 
-async fn download_file(storage: Storage, file: cache::File, cache_name: String, base: PathBuf) -> Result<()> {
+async fn download_file(storage: Storage, file: cache::File, cache_name: String, base: PathBuf, fsync: Fsync,
+                       checks: Checks, budget: ranged::Budget) -> Result<()> {
     let mut path = base;
     path.push(file.path());
 
     if let Some(p) = path.parent() {
         if p != path && ! p.is_dir().await {
-            log::info!("creating directory {:?} for {:?}", &p, &path);
+            log::info!("creating directory {} for {}", local_display(p), local_display(&path));
             std::fs::create_dir_all(p)?;
         }
     }
 
-    if fs::symlink_metadata(&path).await.is_ok_and(|x| x.is_symlink()) {
-        // erase symlink instead of writing through it
-        fs::remove_file(&path).await.context(format!("Removing existing symlink at {}", &path.display()))?;
+    if fs::symlink_metadata(&path).await.is_ok_and(|x| x.is_symlink() || (x.is_file() && file.link_target.is_some())) {
+        // erase symlink instead of writing through it, or a file where one goes
+        fs::remove_file(&path).await.context(format!("Removing existing file at {}", local_display(&path)))?;
     }
 
-    if let Some(target) = file.link_target {
-        create_symlink(target, path)?;
+    if let Some(target) = file.link_target.as_deref() {
+        if let Err(source) = create_symlink(target, &path, file.link_kind) {
+            return Err(crate::Error::SymlinkFailed { path: file.path_str().to_owned(), target: target.to_owned(), source }.into());
+        }
+        fsync.parent_of(path.as_ref())?;
         return Ok(())
     }
 
-    let mut f = tokio::fs::File::create(&path).await?;
-
     let p = file.storage_path(cache_name.as_str());
     let object_path = p.to_str().expect("Invalid storage_path -> string");
-    log::debug!("Downloading {:?} from {}", path, object_path);
-    storage.get_file(&mut f, object_path).await?;
+    log::debug!("Downloading {} from {}", local_display(&path), key_display(object_path));
+    let ranged_object = file.object_key()?.filter(|_| file.compression.is_none() && file.size >= budget.threshold);
+    let f = if let Some(object) = ranged_object.as_ref() {
+        ranged::download(&storage, object_path, object, file.size, path.as_ref(), checks.algorithm, &budget).await?;
+        tokio::fs::OpenOptions::new().write(true).open(&path).await?
+    } else {
+        let _connection = budget.acquire().await?;
+        let mut f = tokio::fs::File::create(&path).await?;
+        if let Some(codec) = file.compression {
+            let mut decoder = codec.decoder(f);
+            storage.get_file(&mut Counting::new(&mut decoder, budget.progress.clone()), object_path).await?;
+            decoder.shutdown().await.with_context(|| format!("Failed to decompress {}", key_display(object_path)))?;
+            f = decoder.into_inner();
+        } else {
+            storage.get_file(&mut Counting::new(&mut f, budget.progress.clone()), object_path).await?;
+        }
+        f
+    };
+
+    // before permissions, which may make the file read-only
+    let f = f.into_std().await;
+    times::restore(&f, path.as_ref(), &file.times());
+    if let Some(attrs) = file.xattrs.as_ref() {
+        xattrs::restore(path.as_ref(), attrs);
+    }
+    fsync.file(&f, path.as_ref())?;
+    drop(f);
+
+    // ranged downloads are checked before they're moved into place
+    let checked = ranged_object.is_some();
+    if let Some(expected) = file.sha256.as_deref().filter(|_| !checked) {
+        verify_checksum(&path, file.size, expected).await?;
+    } else if let Some(expected) = file.object_key()?.filter(|_| checks.verify && !checked) {
+        verify_download(&path, file.size, &expected, checks.algorithm).await?;
+    }
 
     if let Some(mode) = file.mode {
-        set_permisions(path.as_path(), mode);
+        set_permisions(path.as_path(), mode, storage.strictness().permissions)?;
     }
+    if let Some(a) = file.acls.as_ref() {
+        acls::restore(path.as_ref(), a);
+    }
+    fsync.parent_of(path.as_ref())?;
     Ok(())
 }
 
-async fn upload_file(storage: Storage, file: cache::File, cache_name: String, dry_run: bool) -> Result<()> {
-    let mut f = tokio::fs::File::open(&file.path_str()).await?;
+/// Tag on each object upload puts naming the cache it was uploaded for, so
+/// lifecycle rules and queries can select by it
+pub const ORIGIN_TAG: &str = "s3cache-origin";
+
+/// Where upload records the objects it puts, and reports its progress
+#[derive(Debug, Clone)]
+struct Tracking {
+    index: Option<Arc<DedupIndex>>,
+    state: Option<Arc<UploadState>>,
+    progress: Arc<dyn ProgressSink>,
+}
 
+/// Upload a planned file, returning its entry.  Files left unhashed by the
+/// plan are hashed first, so their content is read twice in quick succession
+/// rather than once while planning and again much later.
+async fn upload_file(storage: Storage, planned: PlannedFile, cache_name: String, dry_run: bool,
+                     tracking: Tracking, hasher: FileHasher) -> Result<cache::File> {
+    let Tracking { index, state, progress } = tracking;
+    let local = planned.local_path();
+    let unhashed = planned.is_unhashed();
+    let PlannedFile { entry: mut file, content_type, .. } = planned;
+    if unhashed {
+        let hash = hasher.hash(async_std::path::Path::new(local.as_os_str()), &Some(file.size)).await
+            .with_context(|| format!("Failed to hash {}", local_display(&local)))?;
+        file.object = Some(ObjectKey::from_digest(&hash).as_str().to_owned());
+        file.sha256 = hasher.algorithm.is_sha256().then(|| faster_hex::hex_string(&hash));
+    }
     let p = file.storage_path(cache_name.as_str());
     let path = p.to_str().expect("Invalid storage_path -> string");
-    log::info!("Inserting {}", file.path_str());
-    if ! dry_run {
-        storage.put_file_unless_exists(&mut f, path).await?;
+    let index = index.filter(|_| file.object.is_some());
+    let state = state.filter(|_| file.object.is_some());
+
+    if state.as_ref().is_some_and(|s| s.is_confirmed(path)) {
+        log::info!("File {} confirmed by an earlier run, not checking", key_display(path));
+        return Ok(file);
+    }
+    if index.as_ref().is_some_and(|i| i.is_fresh(path, chrono::Utc::now())) {
+        log::info!("File {} known to exist, not checking", key_display(path));
+        return Ok(file);
     }
 
-    Ok(())
+    let mut f = tokio::fs::File::open(&local).await?;
+    log::info!("Inserting {}", local_display(&local));
+    if dry_run {
+        return Ok(file);
+    }
+
+    // files stored with the cache belong to this entry, so always replace
+    // whatever an earlier upload of the same name left there
+    let owned = file.object.is_none();
+    // look first rather than compress content that's already there
+    let checked = !owned && (index.is_some() || file.compression.is_some());
+    let existing = if checked { storage.head(path).await? } else { None };
+    match existing {
+        Some(existing) => {
+            log::info!("File {} exists, not putting", key_display(path));
+            if let (Some(index), Some(modified)) = (index.as_ref(), existing.last_modified) {
+                index.record(path, modified);
+            }
+        },
+        None => {
+            let mut compressed = match file.compression {
+                Some(codec) => Some(codec.compress(&mut f).await
+                                    .with_context(|| format!("Failed to compress {}", local_display(&local)))?),
+                None => None,
+            };
+            let reader = match compressed.as_mut() {
+                Some(c) => &mut c.file,
+                None => &mut f,
+            };
+            let reader = &mut Counting::new(reader, progress);
+            let tags = [(ORIGIN_TAG, cache_name.as_str())];
+            if checked || owned {
+                storage.put_file_as(reader, path, &content_type, &tags).await?;
+            } else {
+                storage.put_file_unless_exists(reader, path, &content_type, &tags).await?;
+            }
+            if let Some(index) = index.as_ref() {
+                index.record(path, chrono::Utc::now());
+            }
+        },
+    }
+    if let Some(state) = state {
+        state.confirm(path);
+    }
+
+    Ok(file)
 }
 
-enum UploadWork {
-    Meta(Result<Meta>),
-    Upload(Result<()>),
+pub async fn expire(storage: Storage, options: &crate::expire::ExpireOptions) -> Result<crate::expire::ExpireReport> {
+    layout::check(&storage, false).await?;
+    crate::expire::expire(&storage, options).await
 }
 
-async fn work_meta_for(path: PathBuf) -> UploadWork {
-    UploadWork::Meta(meta_for(path).await)
+pub async fn purge_orphans(storage: Storage, options: &crate::expire::PurgeOptions) -> Result<crate::expire::PurgeReport> {
+    layout::check(&storage, false).await?;
+    crate::expire::purge_orphans(&storage, options).await
 }
 
-async fn work_upload(storage: Storage, file: cache::File, cache_name: String, dry_run: bool) -> UploadWork {
-    UploadWork::Upload(upload_file(storage, file, cache_name, dry_run).await)
+pub async fn maintain(storage: Storage, options: &crate::maintain::MaintainOptions,
+                      on_cycle: impl FnMut(&crate::maintain::CycleReport)) -> Result<crate::maintain::CycleReport> {
+    layout::check(&storage, false).await?;
+    crate::maintain::maintain(&storage, options, on_cycle).await
 }
 
-pub async fn expire(storage: Storage, age_days: u32) -> Result<()> {
-    let now = chrono::Utc::now();
-    let expiry_time = now.checked_sub_days(
-        chrono::Days::new(age_days as u64))
-        .ok_or(crate::Error::ExpiryAgeConversionError(age_days))?;
+/// Tuning for [upload]
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    /// Upload all files in directories
+    pub recurse: bool,
+    /// Don't actually do the upload
+    pub dry_run: bool,
+    /// Files at or below this size are stored with the cache, not deduplicated
+    pub threshold: usize,
+    /// Maximum number of parallel network connections
+    pub max_in_flight: u32,
+    /// Trust these digests instead of hashing the listed files
+    pub hashes: Option<Arc<HashManifest>>,
+    /// File timestamps to record in the entry
+    pub preserve_times: PreserveTimes,
+    /// Skip checking for objects recently known to exist
+    pub index: Option<Arc<DedupIndex>>,
+    /// Store cache-local files with a content type from their extension
+    pub detect_content_type: bool,
+    /// Handling of symlinks to absolute paths
+    pub absolute_symlinks: AbsoluteSymlinks,
+    /// Record confirmed objects here, and skip those an interrupted run confirmed
+    pub state: Option<Arc<UploadState>>,
+    /// Record capabilities and user extended attributes
+    pub xattrs: bool,
+    /// Record POSIX ACLs of files and directories
+    pub acls: bool,
+    /// Unicode form to record entry paths in
+    pub unicode_normalize: Normalization,
+    /// Compress file content before storing it
+    pub compression: Option<CompressionCodec>,
+    /// Caps on the files and bytes cached
+    pub limits: UploadLimits,
+    /// Paths not to upload, matched against the path as given or walked
+    pub exclude: GlobSet,
+    /// Free-form label recorded in the entry, eg a git SHA
+    pub label: Option<String>,
+    /// What deduplicated objects are keyed by
+    pub hash_algorithm: HashAlgorithm,
+    /// Chunks of large files hashed at once by sha256-merkle
+    pub hash_jobs: usize,
+    /// Skip files this process can't read rather than failing, and carry
+    /// on past files that fail to upload, failing with them all at the
+    /// end without writing the entry
+    pub keep_going: bool,
+    /// Told of each file and byte uploaded
+    pub progress: Arc<dyn ProgressSink>,
+    /// Directory the paths are relative to, instead of the current one,
+    /// left out of the paths recorded
+    pub base: Option<std::path::PathBuf>,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            recurse: false,
+            dry_run: false,
+            threshold: 25*1024*1024,
+            max_in_flight: 3,
+            hashes: None,
+            preserve_times: PreserveTimes::None,
+            index: None,
+            detect_content_type: true,
+            absolute_symlinks: AbsoluteSymlinks::Keep,
+            state: None,
+            xattrs: false,
+            acls: false,
+            unicode_normalize: Normalization::Off,
+            compression: None,
+            limits: UploadLimits::default(),
+            exclude: GlobSet::default(),
+            label: None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            hash_jobs: cache::default_hash_jobs(),
+            keep_going: false,
+            progress: crate::progress::noop(),
+            base: None,
+        }
+    }
+}
+
+/// Objects may be shared by files of any name, so only cache-local files
+/// get a detected type
+fn content_type_for(file: &cache::File, detect: bool) -> &'static str {
+    if let Some(codec) = file.compression {
+        codec.content_type()
+    } else if detect && file.object.is_none() {
+        content_type::detect(file.path_str())
+    } else {
+        content_type::OCTET_STREAM
+    }
+}
+
+// small files should be uploaded under cache and not deduped for deletion
+// pragmatism
+fn route_object(meta: &Meta, size: u64, cache_threshold: usize) -> Option<ObjectKey> {
+    if size > cache_threshold.try_into().expect("usize should if in u64") {
+        meta.object_key()
+    } else {
+        None
+    }
+}
 
-    storage.recursive_expire("objects/", expiry_time).await?;
+/// Refuse files whose key, eg via "..", would overwrite cache metadata
+fn check_not_reserved(file: &cache::File, cache_name: &str) -> Result<()> {
+    let key = file.storage_path(cache_name);
+    let key = key.to_str().expect("Invalid storage_path -> string");
+    if cache::is_reserved_key(key) {
+        return Err(crate::Error::ReservedKey { path: file.path_str().into(), key: key.into() }.into());
+    }
     Ok(())
 }
 
-pub async fn upload(storage: Storage,
-                    cache_name: &str, paths: &[std::path::PathBuf],
-                    recurse: bool, dry_run: bool,
-                    cache_threshold: usize,
-                    max_in_flight: u32) -> Result<()> {
+/// The paths upload considers: those given, or everything below them
+fn walk(paths: &[std::path::PathBuf], recurse: bool) -> Vec<PathBuf> {
+    walk_all(paths, recurse).into_iter().filter(|path| !is_internal(path)).collect()
+}
 
-    let mut path_set = tokio::task::JoinSet::<UploadWork>::new();
+/// Our own local state and sentinel files, never uploaded or compared
+fn is_internal(path: &async_std::path::Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        name == std::ffi::OsStr::new(local_state::FILE_NAME) || name == std::ffi::OsStr::new(sentinel::FILE_NAME)
+    })
+}
 
+fn walk_all(paths: &[std::path::PathBuf], recurse: bool) -> Vec<PathBuf> {
     if recurse {
-        for path in paths {
-            for entry in walkdir::WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
-                path_set.spawn(work_meta_for(entry.path().into()));
-            }
-        }
+        paths.iter()
+            .flat_map(|path| walkdir::WalkDir::new(path).into_iter().filter_map(|e| e.ok()))
+            .map(|entry| entry.path().into())
+            .collect()
     } else {
-        for path in paths {
-            path_set.spawn(work_meta_for(path.into()));
-        }
+        paths.iter().map(|path| path.into()).collect()
     }
+}
 
-    let mut cache_entry = cache::Cache::default();
-    let mut delayed = std::collections::VecDeque::new();
-    let mut net_in_flight = 0;
+/// Paths walked for upload, recording why any others were left out
+fn upload_paths(paths: &[std::path::PathBuf], recurse: bool, exclude: &GlobSet,
+                skipped: &mut Skips<SkipReason>) -> Vec<PathBuf> {
+    walk_all(paths, recurse).into_iter()
+        .filter(|path| {
+            let reason = if is_internal(path) {
+                Some(SkipReason::Internal)
+            } else if path.to_str().is_none() {
+                Some(SkipReason::NonUtf8)
+            } else {
+                let slashed = slash(path);
+                exclude.matches(slashed.strip_prefix("./").unwrap_or(&slashed)).then_some(SkipReason::Excluded)
+            };
+            if let Some(reason) = reason {
+                skipped.add(reason, path.to_string_lossy());
+            }
+            reason.is_none()
+        })
+        .collect()
+}
 
-    log::debug!("Dispatching upload processing jobs...");
-    while let Some(work) = path_set.join_next().await {
-        // JoinError
-        let work = work.with_context(|| "Failure waiting on upload work")?;
+/// Directories no planned file or symlink lies within, which download
+/// wouldn't otherwise create, parents before children
+fn empty_dirs(files: &[PlannedFile], dirs: Vec<cache::Dir>) -> Vec<cache::Dir> {
+    let parents: std::collections::BTreeSet<&std::path::Path> = files.iter()
+        .flat_map(|f| std::path::Path::new(f.entry.path_str()).ancestors().skip(1))
+        .collect();
+    let mut dirs: Vec<cache::Dir> = dirs.into_iter().filter(|d| !parents.contains(std::path::Path::new(&d.path))).collect();
+    dirs.sort_by(|a, b| a.path.cmp(&b.path));
+    dirs
+}
 
-        match work {
-            UploadWork::Meta(meta) => {
-                let meta = meta.with_context(|| "Failed to load metadata")?;
+/// The io error if e is the process being refused access (EACCES or EPERM),
+/// otherwise e
+fn permission_denied(e: anyhow::Error) -> std::result::Result<std::io::Error, anyhow::Error> {
+    match e.downcast::<std::io::Error>() {
+        Ok(io) if io.kind() == std::io::ErrorKind::PermissionDenied => Ok(io),
+        Ok(io) => Err(io.into()),
+        Err(e) => Err(e),
+    }
+}
 
-                log::debug!("{:?}\tmeta={:?} size={:?} path={:?}",
-                            meta.path.to_str(), meta, meta.file.as_ref().map_or(0, |x| { x.len() }),
-                            meta.object_path());
+/// Why a path that's been looked at can't be uploaded, if it can't
+fn skip_reason(meta: &Meta) -> Option<SkipReason> {
+    if meta.link_target.as_ref().is_some_and(|target| target.to_str().is_none()) {
+        return Some(SkipReason::NonUtf8);
+    }
+    let special = meta.link_target.is_none() && meta.file.as_ref().is_some_and(|m| !m.is_file() && !m.is_dir());
+    special.then_some(SkipReason::SpecialFile)
+}
 
-                if let Some(link) = meta.cacheable_link() {
+/// Where meta is recorded in the entry: below [UploadOptions::base] if given
+fn entry_path<'a>(meta: &'a Meta, options: &UploadOptions) -> &'a async_std::path::Path {
+    let relative = options.base.as_deref().and_then(|base| std::path::Path::new(meta.path.as_os_str()).strip_prefix(base).ok());
+    relative.map_or(meta.path.as_path(), |p| async_std::path::Path::new(p.as_os_str()))
+}
 
-                    let path = meta.path.to_str().expect("bad paths should be handled by is_cacheable");
+/// Normalise the path file records, returning where it is on disk if that
+/// differs
+fn record_path(file: &mut cache::File, meta: &Meta, options: &UploadOptions) -> Option<String> {
+    let normalized = file.normalize_path(options.unicode_normalize);
+    match options.base {
+        Some(_) => Some(slash(meta.path.as_path())),
+        None => normalized,
+    }
+}
 
-                    let file = cache::File::new_async(
-                        meta.path.as_path(),
-                        None,
-                        link.as_os_str().len() as u64,
-                        None,
-                        Some(link.to_str().expect("symlink text should be normal string").into()),
-                    );
+fn plan_file(meta: &Meta, cache_name: &str, options: &UploadOptions) -> Result<Option<PlannedFile>> {
+    let local_mtime = meta.file.as_ref().and_then(|m| m.modified().ok()).map(chrono::DateTime::from);
+
+    if let Some(link) = meta.cacheable_link() {
+
+        let mut file = cache::File::new_async(
+            entry_path(meta, options),
+            None,
+            link.as_os_str().len() as u64,
+            None,
+            Some(link.to_str().expect("symlink text should be normal string").into()),
+        );
+        let local_path = record_path(&mut file, meta, options);
+        file.link_target = file.link_target.map(|t| options.unicode_normalize.apply(&t).into_owned());
+        file.link_kind = LinkKind::of(std::path::Path::new(meta.path.as_os_str()));
+
+        log::info!("{} symlink to {}", local_display(&meta.path), local_display(&link));
+        return Ok(Some(PlannedFile {
+            destination: Destination::Link, key: None, size: file.size, exists: None, local_mtime,
+            content_type: content_type::OCTET_STREAM.into(), local_target: None, local_path, entry: file,
+        }));
+    }
 
-                    cache_entry.files.push(file);
+    if !meta.is_cacheable_file() {
+        log::info!("{} will not be uploaded", local_display(&meta.path));
+        return Ok(None);
+    }
 
-                    log::info!("{} symlink to {}", path, link.to_str().unwrap());
-                    continue;
-                }
+    let size = meta.file.as_ref().map_or(0, std::fs::Metadata::len);
+    let mode = meta.get_mode();
+
+    let object = route_object(meta, size, options.threshold);
+
+    let mut file = cache::File::new_async(
+        entry_path(meta, options),
+        object,
+        size,
+        mode,
+        None,
+    ).with_times(meta.file.as_ref().map_or_else(
+        times::FileTimes::default, |m| times::capture(m, options.preserve_times)));
+    file.sha256 = meta.hash.as_ref().filter(|_| options.hash_algorithm.is_sha256()).map(|h| faster_hex::hex_string(h));
+    file.compression = options.compression;
+    let local_path = record_path(&mut file, meta, options);
+    // unhashed files go to objects/, which is never reserved
+    if !meta.hash_on_upload {
+        check_not_reserved(&file, cache_name)?;
+    }
+    if options.xattrs {
+        file.xattrs = xattrs::capture(meta.path.as_ref())
+            .inspect_err(|e| log::warn!("Unable to read extended attributes of {}: {}", local_display(&meta.path), e))
+            .ok().filter(|a| !a.is_empty());
+    }
+    if options.acls {
+        file.acls = capture_acls(meta);
+    }
 
-                if !meta.is_cacheable_file() {
-                    log::info!("{} will not be uploaded", meta.path.to_str().unwrap());
-                    continue;
-                }
+    let destination = if file.object.is_some() || meta.hash_on_upload { Destination::Object } else { Destination::Cache };
+    let key = (!meta.hash_on_upload)
+        .then(|| file.storage_path(cache_name).to_str().expect("Invalid storage_path -> string").to_owned());
+    let content_type = content_type_for(&file, options.detect_content_type && !meta.hash_on_upload).into();
+    Ok(Some(PlannedFile {
+        destination, key, size, exists: None, local_mtime, content_type, local_target: None, local_path,
+        entry: file,
+    }))
+}
 
-                let size = meta.file.as_ref().map_or(0, std::fs::Metadata::len);
-                let mode = meta.get_mode();
+fn capture_acls(meta: &Meta) -> Option<acls::Acls> {
+    let is_dir = meta.file.as_ref().is_some_and(std::fs::Metadata::is_dir);
+    acls::capture(meta.path.as_ref(), is_dir)
+        .inspect_err(|e| log::warn!("Unable to read ACLs of {}: {}", local_display(&meta.path), e))
+        .ok().flatten()
+}
 
-                // small files should be uploaded under cache and not deduped for deletion
-                // pragmatism
-                let object = if size > cache_threshold.try_into().expect("usize should if in u64") {
-                    meta.object_path().clone()
-                } else {
-                    None
-                };
+/// Apply policy to a symlink with an absolute target, returning it as
+/// "link -> target" if it's rejected
+fn absolute_link(meta: &mut Meta, policy: AbsoluteSymlinks, roots: &[std::path::PathBuf],
+                 cwd: &std::path::Path) -> Option<String> {
+    let target = meta.link_target.as_ref().filter(|t| t.is_absolute())?;
+    let (link, target) = (std::path::Path::new(meta.path.as_os_str()), std::path::Path::new(target.as_os_str()));
+    match policy {
+        AbsoluteSymlinks::Keep => None,
+        AbsoluteSymlinks::Reject => Some(format!("{} -> {}", local_display(link), local_display(target))),
+        AbsoluteSymlinks::Rewrite => {
+            match links::rewrite(link, target, roots, cwd) {
+                Some(relative) => {
+                    log::info!("{} symlink to {} rewritten to {}", local_display(link), local_display(target), local_display(&relative));
+                    meta.link_target = Some(relative.into());
+                },
+                None => log::warn!("{} symlink to {} is outside the uploaded paths, keeping it absolute",
+                                   local_display(link), local_display(target)),
+            }
+            None
+        },
+    }
+}
 
-                let file = cache::File::new_async(
-                    meta.path.as_path(),
-                    object,
-                    size,
-                    mode,
-                    None,
-                );
+/// Look up which planned objects already exist, trusting the index where fresh
+async fn check_existing(storage: &Storage, plan: &mut UploadPlan, options: &UploadOptions) -> Result<()> {
+    let now = chrono::Utc::now();
+    let mut set = tokio::task::JoinSet::<Result<(usize, bool)>>::new();
+    let mut found = Vec::new();
+
+    for (i, f) in plan.files.iter().enumerate() {
+        let Some(key) = f.key.clone().filter(|_| f.destination == Destination::Object) else {
+            continue;
+        };
+        if options.index.as_ref().is_some_and(|index| index.is_fresh(&key, now)) {
+            found.push((i, true));
+            continue;
+        }
+        while set.len() >= options.max_in_flight as usize {
+            if let Some(work) = set.join_next().await {
+                found.push(work.with_context(|| "Failure waiting on existence checks")??);
+            }
+        }
+        let storage = storage.clone();
+        set.spawn(async move { Ok((i, storage.head(&key).await?.is_some())) });
+    }
+    while let Some(work) = set.join_next().await {
+        found.push(work.with_context(|| "Failure waiting on existence checks")??);
+    }
 
-                cache_entry.files.push(file.clone());
+    for (i, exists) in found {
+        plan.files[i].exists = Some(exists);
+    }
+    Ok(())
+}
 
-                if net_in_flight >= max_in_flight {
-                    delayed.push_back(work_upload(storage.clone(), file, cache_name.to_owned(), dry_run));
-                } else {
-                    net_in_flight += 1;
-                    path_set.spawn(work_upload(storage.clone(), file, cache_name.to_owned(), dry_run));
-                }
+/// Refuse a plan where normalisation made two local paths one entry path
+fn check_collisions(plan: &UploadPlan) -> Result<()> {
+    let collisions = unicode::collisions(plan.files.iter().map(|f| {
+        (f.entry.path_str(), f.local_path.as_deref().unwrap_or(f.entry.path_str()))
+    }));
+    if collisions.is_empty() {
+        return Ok(());
+    }
+    let details: Vec<String> = collisions.iter()
+        .map(|(entry, locals)| format!("'{}' from {}", entry, locals.join(", ")))
+        .collect();
+    Err(crate::Error::UnicodeCollision(details.join("; ")).into())
+}
+
+/// Work out what upload would do without uploading anything.  With
+/// check_remote, objects are looked up so the plan shows what's new.
+pub async fn plan_upload(storage: &Storage, cache_name: &str, paths: &[std::path::PathBuf],
+                         options: &UploadOptions, check_remote: bool) -> Result<UploadPlan> {
+    plan_files(storage, cache_name, paths, options, check_remote, false).await
+}
+
+/// Plan an upload.  With hash_on_upload, files large enough to be
+/// deduplicated are hashed by the task uploading them rather than here, so
+/// the upload reads them again while they're still cached.
+async fn plan_files(storage: &Storage, cache_name: &str, paths: &[std::path::PathBuf], options: &UploadOptions,
+                    check_remote: bool, hash_on_upload: bool) -> Result<UploadPlan> {
+    if let Some(hashes) = options.hashes.as_ref() {
+        log::info!("Using {} pre-computed hashes", hashes.len());
+    }
+
+    let mut plan = UploadPlan {
+        cache: cache_name.to_owned(), files: Vec::new(), dirs: Vec::new(), dir_acls: Default::default(),
+        normalization: options.unicode_normalize, skipped: Skips::default(), label: options.label.clone(),
+        hash_algorithm: options.hash_algorithm,
+    };
+    let mut path_set = tokio::task::JoinSet::new();
+    let hash_above = hash_on_upload.then_some(options.threshold as u64);
+    let hasher = FileHasher::new(options.hash_algorithm, options.hash_jobs);
+    let paths: Vec<std::path::PathBuf> = match &options.base {
+        Some(base) => paths.iter().map(|p| base.join(p)).collect(),
+        None => paths.to_vec(),
+    };
+    let paths = paths.as_slice();
+    for path in upload_paths(paths, options.recurse, &options.exclude, &mut plan.skipped) {
+        let meta = meta_for(path.clone(), options.hashes.clone(), hash_above, hasher.clone());
+        path_set.spawn(async move { (path, meta.await) });
+    }
+
+    let cwd = std::env::current_dir()?;
+    let mut rejected = Vec::new();
+    while let Some(meta) = path_set.join_next().await {
+        // JoinError
+        let (path, meta) = meta.with_context(|| "Failure waiting on upload work")?;
+        let mut meta = match meta.map_err(permission_denied) {
+            Err(Ok(_)) if options.keep_going => {
+                plan.skipped.add(SkipReason::PermissionDenied, path.to_string_lossy());
+                continue;
             },
+            Err(Ok(source)) => return Err(crate::Error::PermissionDenied { path: local_display(&path), source }.into()),
+            Err(Err(e)) => return Err(e.context("Failed to load metadata")),
+            Ok(meta) => meta,
+        };
+        if let Some(reason) = skip_reason(&meta) {
+            plan.skipped.add(reason, meta.path.to_string_lossy());
+            continue;
+        }
+        let local_target = meta.link_target.clone();
+        if let Some(offender) = absolute_link(&mut meta, options.absolute_symlinks, paths, &cwd) {
+            rejected.push(offender);
+            continue;
+        }
 
-            UploadWork::Upload(result) => {
-                result.with_context(|| "Failed to upload file")?;
-                assert!(net_in_flight > 0);
-                net_in_flight -= 1;
-                while !delayed.is_empty() && net_in_flight < max_in_flight {
-                    net_in_flight += 1;
-                    path_set.spawn(delayed.pop_front().unwrap());
+        log::debug!("{}\tmeta={:?} size={:?} object={:?}",
+                    local_display(&meta.path), meta, meta.file.as_ref().map_or(0, |x| { x.len() }),
+                    meta.object_key());
+
+        if meta.file.as_ref().is_some_and(std::fs::Metadata::is_dir) {
+            let path = options.unicode_normalize.apply(&slash(entry_path(&meta, options))).into_owned();
+            if options.acls {
+                if let Some(a) = capture_acls(&meta) {
+                    plan.dir_acls.insert(path.clone(), a);
                 }
+            }
+            if options.recurse {
+                plan.dirs.push(cache::Dir { path, mode: meta.get_mode() });
+            }
+        }
+
+        if let Some(mut planned) = plan_file(&meta, cache_name, options)? {
+            // rewritten or normalised
+            let local_target = local_target.and_then(|t| t.to_str().map(String::from));
+            if local_target != planned.entry.link_target {
+                planned.local_target = local_target;
+            }
+            plan.files.push(planned);
+        }
+    }
+    if !rejected.is_empty() {
+        rejected.sort();
+        return Err(crate::Error::AbsoluteSymlinks(rejected.join(", ")).into());
+    }
+    if !options.unicode_normalize.is_off() {
+        check_collisions(&plan)?;
+    }
+    limits::enforce(&mut plan, &options.limits)?;
+    plan.dirs = empty_dirs(&plan.files, std::mem::take(&mut plan.dirs));
+    if !plan.skipped.is_empty() {
+        log::warn!("Skipped {} paths: {}", plan.skipped.len(), plan.skipped.summary());
+    }
+
+    if check_remote {
+        check_existing(storage, &mut plan, options).await?;
+    }
+    Ok(plan)
+}
+
+/// Carry out a plan: upload its content, then push its entry.  Every file is
+/// checked against the plan first, so nothing is uploaded from a stale one.
+pub async fn execute_plan(storage: Storage, plan: &UploadPlan, options: &UploadOptions) -> Result<stats::UploadRecord> {
+    let UploadOptions { dry_run, max_in_flight, .. } = *options;
+    let cache_name = plan.cache.as_str();
+
+    for f in &plan.files {
+        f.check()?;
+    }
+    layout::check(&storage, !dry_run).await?;
+
+    let mut set = tokio::task::JoinSet::new();
+    // only entries upload changed, as objects hashed on upload are only now
+    // known: the rest are written straight from the plan
+    let mut changed = std::collections::BTreeMap::new();
+    let mut failures = Vec::new();
+    let mut record_change = |work: std::result::Result<(usize, Result<cache::File>), tokio::task::JoinError>| -> Result<()> {
+        let (i, result) = work.with_context(|| "Failure waiting on upload work")?;
+        match result {
+            Ok(file) if file != plan.files[i].entry => {
+                changed.insert(i, file);
+            },
+            Ok(_) => {},
+            Err(e) if options.keep_going => {
+                failures.push(crate::FileFailure { path: plan.files[i].entry.path_str().to_owned(), error: format!("{:#}", e) });
             },
+            Err(e) => return Err(e.context("Failed to upload file")),
         }
+        Ok(())
+    };
+    let hasher = FileHasher::new(plan.hash_algorithm, options.hash_jobs);
+    for (i, f) in plan.files.iter().enumerate().filter(|(_, f)| f.key.is_some() || f.is_unhashed()) {
+        while set.len() >= max_in_flight as usize {
+            if let Some(work) = set.join_next().await {
+                record_change(work)?;
+            }
+        }
+        let tracking = Tracking { index: options.index.clone(), state: options.state.clone(), progress: options.progress.clone() };
+        let upload = upload_file(storage.clone(), f.clone(), cache_name.to_owned(), dry_run, tracking, hasher.clone());
+        let progress = options.progress.clone();
+        let path = f.entry.path_str().to_owned();
+        progress.on_file_start(&path, f.size);
+        set.spawn(async move {
+            let result = upload.await;
+            progress.on_file_done(&path);
+            (i, result)
+        });
     }
-    assert!(delayed.is_empty());
+    while let Some(work) = set.join_next().await {
+        record_change(work)?;
+    }
+    // an entry would send downloads after objects that aren't there
+    failed(cache_name, "upload", failures)?;
 
     let path = Cache::entry_location(cache_name);
-    let count = cache_entry.files.len();
-    log::debug!("Pushing cache entry with {} files to {:?}", count, path);
+    let count = plan.files.len();
+    log::debug!("Pushing cache entry with {} files to {}", count, key_display(&path));
+    let record = stats::UploadRecord {
+        time: chrono::Utc::now(),
+        cache: cache_name.to_owned(),
+        files: count,
+        bytes: plan.entry_files(&changed).map(|f| f.size).sum(),
+        deduped_bytes: plan.entry_files(&changed).filter(|f| f.object.is_some()).map(|f| f.size).sum(),
+        dropped: plan.skipped.paths(SkipReason::OverLimit).to_vec(),
+        skipped: plan.skipped.counts(),
+    };
     if dry_run {
-        log::warn!("Simulate Pushing cache entry with {} files to '{}' at {:?}", count, cache_name, path);
+        log::warn!("Simulate Pushing cache entry with {} files to '{}' at {}", count, cache_name, key_display(&path));
     } else {
-        storage.put_file(&mut std::io::Cursor::new(cache_entry.into_string()), path.to_str().unwrap()).await?;
+        let mut entry = Vec::new();
+        plan.write_entry(&changed, options.label.clone(), &mut entry)?;
+        storage.put_file(&mut std::io::Cursor::new(entry), path.to_str().unwrap()).await?;
         log::warn!("Pushed {} files to '{}'", count, cache_name);
+        if let Some(state) = options.state.as_ref() {
+            state.finish();
+        }
+        stats::record_upload(&storage, record.clone()).await;
     }
 
-    Ok(())
+    Ok(record)
 }
 
-async fn read_cache_info(storage: &Storage, cache_name: &str) -> Result<Cache> {
+pub async fn upload(storage: Storage,
+                    cache_name: &str, paths: &[std::path::PathBuf],
+                    options: &UploadOptions) -> Result<stats::UploadRecord> {
+    let plan = plan_files(&storage, cache_name, paths, options, false, true).await?;
+    execute_plan(storage, &plan, options).await
+}
+
+pub(crate) async fn read_cache_info(storage: &Storage, cache_name: &str) -> Result<Cache> {
+    layout::check(storage, false).await?;
     let path = Cache::entry_location(cache_name);
 
-    let mut vec = Vec::<u8>::new();
-    storage.get_file(&mut vec, path.to_str().unwrap()).await?;
-    let c = cache::decode(&vec)?;
+    let vec = cache::read_entry(storage, cache_name, path.to_str().unwrap()).await?;
+    let (c, version) = cache::decode(cache_name, &vec)?;
+    log::debug!("Entry of '{}' is version {}", cache_name, version.number());
     Ok(c)
 }
 
-pub async fn list(storage: Storage, cache_name: Option<&str>) -> Result<()> {
-    if let Some(cache_name) = cache_name {
-        let c = read_cache_info(&storage, cache_name).await?;
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<crate::Error>().is_some_and(crate::Error::is_not_found)
+}
 
-        let largest = c.files.iter().max_by(|x, y| x.path_str().len().cmp(&y.path_str().len()));
-        if let Some(longest) = largest {
-            let len = longest.path_str().len().max(30);
-            for f in c.files {
-                println!("{path:<0$} {size:>10}", len, path=f.path_str(), size=f.size);
-            }
+/// Of caches and when their entries were written, the newest named with prefix
+fn newest_matching<'a>(caches: &'a [(String, Option<chrono::DateTime<chrono::Utc>>)], prefix: &str) -> Option<&'a str> {
+    caches.iter()
+        .filter(|(name, _)| name.starts_with(prefix))
+        .max_by(|(a, a_time), (b, b_time)| a_time.cmp(b_time).then(b.cmp(a)))
+        .map(|(name, _)| name.as_str())
+}
+
+/// Read cache_name, or when it's missing, the newest cache starting with
+/// each fallback prefix in turn.  Returns the name read, and its entry, or
+/// [crate::Error::CacheNotFound] if none are found.
+pub(crate) async fn resolve_cache(storage: &Storage, cache_name: &str, fallbacks: &[String]) -> Result<(String, Cache)> {
+    match read_cache_info(storage, cache_name).await {
+        Ok(c) => return Ok((cache_name.to_owned(), c)),
+        Err(e) if !is_not_found(&e) => return Err(e),
+        Err(e) => log::info!("Cache '{}' not found: {:#}", cache_name, e),
+    }
+    if fallbacks.is_empty() {
+        return Err(crate::Error::CacheNotFound(cache_name.to_owned()).into());
+    }
+
+    let mut caches = Vec::new();
+    for name in storage.list_dirs("cache/").await? {
+        if !fallbacks.iter().any(|prefix| name.starts_with(prefix.as_str())) {
+            continue;
         }
-    } else {
-        for c in storage.list_dirs("cache/").await? {
-            println!("{}", c);
+        let entry = Cache::entry_location(&name);
+        if let Some(info) = storage.head(entry.to_str().expect("entry location is utf8")).await? {
+            caches.push((name, info.last_modified));
         }
     }
-    Ok(())
+    for prefix in fallbacks {
+        let Some(name) = newest_matching(&caches, prefix) else {
+            log::info!("No cache matches fallback '{}'", prefix);
+            continue;
+        };
+        match read_cache_info(storage, name).await {
+            Ok(c) => {
+                log::warn!("Cache '{}' not found, using '{}' matching fallback '{}'", cache_name, name, prefix);
+                return Ok((name.to_owned(), c));
+            },
+            // removed since listing
+            Err(e) if is_not_found(&e) => log::info!("Fallback '{}' disappeared: {}", name, e),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(crate::Error::CacheNotFound(cache_name.to_owned()).into())
 }
 
-enum DownloadWork {
-    Download(Result<()>)
+/// Caches whose entries are read at once for totals
+const LIST_MAX_IN_FLIGHT: usize = 8;
+
+/// A cache as listed with totals
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CacheSummary {
+    pub name: String,
+    pub file_count: usize,
+    pub total_size: u64,
+    /// When the entry was last written
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// When the cache was first uploaded, as recorded in its entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+    /// Version of s3-cache that wrote the entry, from V2
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub writer_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
 }
 
-async fn work_download(storage: Storage, file: cache::File, cache_name: String, base: PathBuf) -> DownloadWork {
-    DownloadWork::Download(download_file(storage, file, cache_name, base).await)
+/// A file of a cache as listed
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ListedFile {
+    pub path: String,
+    pub object: Option<String>,
+    pub size: u64,
+    pub mode: Option<u32>,
+    pub link_target: Option<String>,
 }
 
-pub async fn download(storage: Storage, cache_name: &str, outpath: std::path::PathBuf, max_in_flight: u32) -> Result<()> {
-    let c = read_cache_info(&storage, cache_name).await?;
-    if ! c.files.is_empty() && !outpath.is_dir() {
-        std::fs::create_dir_all(&outpath).context(format!("Failed to create {:?}", &outpath))?;
+impl From<cache::File> for ListedFile {
+    fn from(f: cache::File) -> Self {
+        ListedFile { path: f.path_str().to_owned(), object: f.object, size: f.size, mode: f.mode, link_target: f.link_target }
     }
+}
 
-    let mut download_set = tokio::task::JoinSet::<DownloadWork>::new();
-
-    let handle = |work: std::result::Result<DownloadWork, tokio::task::JoinError>| -> Result<()> {
-        // JoinError
-        let work = work.with_context(|| "Failure waiting on download jobs")?;
+/// What list found, rendered as text or serialised
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Listing {
+    Names(Vec<String>),
+    Caches(Vec<CacheSummary>),
+    Files(Vec<ListedFile>),
+}
 
-        match work {
-            DownloadWork::Download(result) => {
-                result.with_context(|| "Failed to download file")?;
-            }
+impl Listing {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        match self {
+            Listing::Names(names) => {
+                for name in names {
+                    out.push_str(&format!("{}\n", name));
+                }
+            },
+            Listing::Caches(caches) => {
+                let len = caches.iter().map(|c| c.name.len()).max().unwrap_or(0).max(30);
+                let time = |t: Option<chrono::DateTime<chrono::Utc>>| t.map_or("-".into(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+                for c in caches {
+                    out.push_str(&format!("{:<len$} {:>8} {:>14} {:>20} {:>20} {:>8} {}\n", c.name, c.file_count, c.total_size,
+                                          time(c.last_modified), time(c.created),
+                                          c.writer_version.as_deref().unwrap_or("-"), c.label.as_deref().unwrap_or("-")));
+                }
+            },
+            Listing::Files(files) => {
+                let len = files.iter().map(|f| f.path.len()).max().unwrap_or(0).max(30);
+                for f in files {
+                    out.push_str(&format!("{path:<0$} {size:>10}\n", len, path=f.path, size=f.size));
+                }
+                let bytes: u64 = files.iter().map(|f| f.size).sum();
+                let deduplicated = files.iter().filter(|f| f.object.is_some()).count();
+                out.push_str(&format!("{} files, {} bytes, {} deduplicated\n", files.len(), bytes, deduplicated));
+            },
         }
-        Ok(())
+        out
+    }
+}
+
+/// Read a cache's entry for its totals, None if it can't be read
+async fn summarise(storage: Storage, name: String) -> Result<Option<CacheSummary>> {
+    let entry = Cache::entry_location(&name);
+    let Some(info) = storage.head(entry.to_str().expect("entry location is utf8")).await? else {
+        return Ok(None);
+    };
+    let c = match read_cache_info(&storage, &name).await {
+        Ok(c) => c,
+        Err(e) if e.downcast_ref::<crate::Error>().is_some_and(|e| e.is_auth() || e.is_retryable()) => return Err(e),
+        Err(e) => {
+            log::warn!("Unable to read '{}': {:#}", name, e);
+            return Ok(None);
+        },
     };
+    Ok(Some(CacheSummary {
+        name,
+        file_count: c.files.len(),
+        total_size: c.size(),
+        last_modified: info.last_modified,
+        created: c.created_at,
+        writer_version: c.writer_version,
+        label: c.label,
+    }))
+}
 
-    let mut count = 0;
-    let total = c.files.len();
+/// A cache as listed a page at a time
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ListedCache {
+    pub name: String,
+    /// When the entry was last written, if it was looked up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+}
 
-    for f in c.files {
-        while download_set.len() >= max_in_flight as usize {
-            if count == 0 {
-                log::debug!("Dispatching download jobs...");
-            }
-            if let Some(work) = download_set.join_next().await {
-                count += 1;
-                handle(work)?;
-            } else {
-                log::warn!("Unexpected termination of downloads after {} expecting {}", count, total);
-                break;
+/// One page of caches, and the name to start the next after
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CachePage {
+    pub caches: Vec<ListedCache>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+/// Up to limit directories below prefix, fetching more pages when a server
+/// returns short ones, and the key to continue after if there may be more
+async fn gather_dirs<F, Fut>(fetch: F, mut start_after: Option<String>, limit: usize) -> Result<(Vec<String>, Option<String>)>
+where F: Fn(Option<String>, usize) -> Fut,
+      Fut: std::future::Future<Output = std::result::Result<(Vec<String>, Option<String>), crate::Error>>
+{
+    let mut dirs = Vec::new();
+    while dirs.len() < limit {
+        let (page, next) = fetch(start_after.take(), limit - dirs.len()).await?;
+        dirs.extend(page);
+        start_after = next;
+        if start_after.is_none() {
+            break;
+        }
+    }
+    Ok((dirs, start_after))
+}
+
+/// Up to limit caches whose names start with prefix, in listing order after
+/// the cache start_after, eg the next of an earlier page.  Only caches on
+/// the page are looked at, and their entries only with modified.
+pub async fn list_caches_page(storage: &Storage, start_after: Option<&str>, limit: usize, prefix: Option<&str>,
+                              modified: bool) -> Result<CachePage> {
+    let path = format!("cache/{}", prefix.unwrap_or(""));
+    let start = start_after.map(|name| crate::s3::key_after_dir(&format!("cache/{}/", name)));
+    let fetch = |start: Option<String>, limit| {
+        let path = path.as_str();
+        async move { storage.list_dirs_page(path, start.as_deref(), limit).await }
+    };
+    let (dirs, more) = gather_dirs(fetch, start, limit.max(1)).await?;
+    let mut caches: Vec<ListedCache> = dirs.iter()
+        .filter_map(|d| d.strip_prefix("cache/")?.strip_suffix('/'))
+        .map(|name| ListedCache { name: name.to_owned(), last_modified: None })
+        .collect();
+    if modified {
+        let mut set = tokio::task::JoinSet::new();
+        let mut found = Vec::new();
+        for (i, c) in caches.iter().enumerate() {
+            while set.len() >= LIST_MAX_IN_FLIGHT {
+                if let Some(work) = set.join_next().await {
+                    found.push(work.with_context(|| "Failure waiting on list work")??);
+                }
             }
+            let (storage, entry) = (storage.clone(), Cache::entry_location(&c.name));
+            set.spawn(async move {
+                let info = storage.head(entry.to_str().expect("entry location is utf8")).await?;
+                Ok::<_, crate::Error>((i, info.and_then(|info| info.last_modified)))
+            });
+        }
+        while let Some(work) = set.join_next().await {
+            found.push(work.with_context(|| "Failure waiting on list work")??);
+        }
+        for (i, last_modified) in found {
+            caches[i].last_modified = last_modified;
         }
-        download_set.spawn(work_download(storage.clone(), f.clone(), cache_name.to_owned(), outpath.clone().into()));
     }
+    let next = more.and_then(|_| caches.last()).map(|c| c.name.clone());
+    Ok(CachePage { caches, next })
+}
 
-    if count == 0 {
-        log::debug!("Dispatching download jobs...");
+/// The files of cache_name, or without one, the caches, with totals read
+/// from each entry if asked for
+pub async fn list(storage: Storage, cache_name: Option<&str>, totals: bool) -> Result<Listing> {
+    if let Some(cache_name) = cache_name {
+        let c = read_cache_info(&storage, cache_name).await?;
+        return Ok(Listing::Files(c.files.into_iter().map(ListedFile::from).collect()));
     }
-    while let Some(work) = download_set.join_next().await {
-        count += 1;
-        handle(work)?;
+    let names = storage.list_dirs("cache/").await?;
+    if !totals {
+        return Ok(Listing::Names(names));
     }
 
-    log::warn!("Downloaded {} files from '{}'", count, cache_name);
+    let mut caches = Vec::new();
+    let mut set = tokio::task::JoinSet::new();
+    for name in names {
+        while set.len() >= LIST_MAX_IN_FLIGHT {
+            if let Some(work) = set.join_next().await {
+                caches.extend(work.with_context(|| "Failure waiting on list work")??);
+            }
+        }
+        set.spawn(summarise(storage.clone(), name));
+    }
+    while let Some(work) = set.join_next().await {
+        caches.extend(work.with_context(|| "Failure waiting on list work")??);
+    }
+    caches.sort_by(|a: &CacheSummary, b| a.name.cmp(&b.name));
+    Ok(Listing::Caches(caches))
+}
 
-    Ok(())
+enum DownloadWork {
+    /// The file's path, and whether it was restored
+    Download(String, Result<()>),
+    /// Already in place with the right content, so not fetched
+    Unchanged(String),
 }
 
-pub async fn delete(storage: Storage, cache_name: &str) -> Result<()> {
-    if let Err(e) = read_cache_info(&storage, cache_name).await {
-        log::warn!("Cache {} not found:{}", cache_name, e);
+/// Whether path already holds file: a symlink to the same target, or a
+/// regular file of the same size and, where the entry records it, the same
+/// sha256.  Without a hash a recorded modification time must match too.
+async fn in_place(path: &async_std::path::Path, file: &cache::File, algorithm: HashAlgorithm) -> Result<bool> {
+    let Ok(meta) = fs::symlink_metadata(path).await else {
+        return Ok(false);
+    };
+    if let Some(target) = file.link_target.as_deref() {
+        return Ok(meta.is_symlink() && fs::read_link(path).await?.to_str() == Some(target));
+    }
+    if !meta.is_file() || meta.len() != file.size {
+        return Ok(false);
+    }
+    if let Some(expected) = file.sha256.as_deref() {
+        let actual = faster_hex::hex_string(&cache::read_hash(path, &Some(file.size)).await?);
+        return Ok(actual.eq_ignore_ascii_case(expected));
     }
+    if let Ok(Some(expected)) = file.object_key() {
+        return Ok(ObjectKey::from_digest(&cache::read_hash_with(path, &Some(file.size), algorithm).await?) == expected);
+    }
+    Ok(file.mtime.is_none_or(|recorded| meta.modified().ok().map(chrono::DateTime::<chrono::Utc>::from) == Some(recorded)))
+}
 
-    let mut path = Cache::entry_location(cache_name);
-    path.pop();
-    storage.recursive_delete_p(path.as_ref()).await?;
-    log::warn!("Deleted '{}'", cache_name);
-    Ok(())
+/// Download file unless it's already in place, then only fixing its
+/// permissions
+async fn work_if_changed(storage: Storage, file: cache::File, base: PathBuf, algorithm: HashAlgorithm,
+                         download: impl std::future::Future<Output = DownloadWork>) -> DownloadWork {
+    let path = base.join(file.path());
+    match in_place(&path, &file, algorithm).await {
+        Ok(true) => {
+            if let (Some(mode), None) = (file.mode, file.link_target.as_ref()) {
+                if let Err(e) = set_permisions(path.as_path(), mode, storage.strictness().permissions) {
+                    return DownloadWork::Download(file.path_str().to_owned(), Err(e));
+                }
+            }
+            log::debug!("{} already in place", local_display(&path));
+            DownloadWork::Unchanged(file.path_str().to_owned())
+        },
+        Ok(false) => download.await,
+        Err(e) => DownloadWork::Download(file.path_str().to_owned(),
+                                         Err(e.context(format!("Failed to compare {}", local_display(&path))))),
+    }
+}
+
+async fn work_download(storage: Storage, file: cache::File, cache_name: String, base: PathBuf, fsync: Fsync,
+                       checks: Checks, budget: ranged::Budget) -> DownloadWork {
+    let progress = budget.progress.clone();
+    let path = file.path_str().to_owned();
+    progress.on_file_start(&path, file.size);
+    let result = download_file(storage, file, cache_name, base, fsync, checks, budget).await;
+    progress.on_file_done(&path);
+    DownloadWork::Download(path, result)
+}
+
+/// Split off files whose object an earlier file also restores, returning
+/// the files to fetch and, with the first of each, those to copy from it
+fn share_objects(files: Vec<cache::File>) -> (Vec<cache::File>, Vec<(cache::File, Vec<cache::File>)>) {
+    let mut first = std::collections::HashMap::new();
+    let mut fetch = Vec::new();
+    let mut shared: Vec<(cache::File, Vec<cache::File>)> = Vec::new();
+    for f in files {
+        let key = f.object_storage_key().filter(|_| f.link_target.is_none());
+        match key.as_ref().and_then(|key| first.get(key)) {
+            Some(&i) => shared[i].1.push(f),
+            None => {
+                if let Some(key) = key {
+                    first.insert(key, shared.len());
+                    shared.push((f.clone(), Vec::new()));
+                }
+                fetch.push(f);
+            },
+        }
+    }
+    shared.retain(|(_, copies)| !copies.is_empty());
+    (fetch, shared)
+}
+
+/// Restore file from from, a file already restored with the same object:
+/// hard linked when asked and nothing recorded about them differs, else
+/// copied, then given file's own times, attributes and mode
+async fn restore_copy(storage: &Storage, from: &cache::File, file: &cache::File, base: &PathBuf, fsync: &Fsync,
+                      hardlink: bool) -> Result<()> {
+    let (source, path) = (base.join(from.path()), base.join(file.path()));
+    if let Some(p) = path.parent() {
+        if ! p.is_dir().await {
+            std::fs::create_dir_all(p)?;
+        }
+    }
+    if fs::symlink_metadata(&path).await.is_ok() {
+        fs::remove_file(&path).await.context(format!("Removing existing file at {}", local_display(&path)))?;
+    }
+
+    let alike = file.mode == from.mode && file.mtime == from.mtime && file.btime == from.btime
+        && file.xattrs == from.xattrs && file.acls == from.acls;
+    if hardlink && alike {
+        match std::fs::hard_link(&source, &path) {
+            Ok(()) => {
+                log::debug!("Linked {} to {}", local_display(&path), local_display(&source));
+                fsync.parent_of(path.as_ref())?;
+                return Ok(());
+            },
+            // eg a filesystem without hard links
+            Err(e) => log::debug!("Unable to link {} to {}, copying: {}", local_display(&path), local_display(&source), e),
+        }
+    }
+
+    log::debug!("Copying {} from {}", local_display(&path), local_display(&source));
+    let mut f = tokio::fs::File::create(&path).await?;
+    tokio::io::copy(&mut tokio::fs::File::open(&source).await?, &mut f).await
+        .with_context(|| format!("Failed to copy {} to {}", local_display(&source), local_display(&path)))?;
+    let f = f.into_std().await;
+    times::restore(&f, path.as_ref(), &file.times());
+    if let Some(attrs) = file.xattrs.as_ref() {
+        xattrs::restore(path.as_ref(), attrs);
+    }
+    fsync.file(&f, path.as_ref())?;
+    drop(f);
+    if let Some(mode) = file.mode {
+        set_permisions(path.as_path(), mode, storage.strictness().permissions)?;
+    }
+    if let Some(a) = file.acls.as_ref() {
+        acls::restore(path.as_ref(), a);
+    }
+    fsync.parent_of(path.as_ref())?;
+    Ok(())
+}
+
+/// Tuning for [download]
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Maximum number of parallel network connections
+    pub max_in_flight: u32,
+    /// Only restore files recorded as modified after this
+    pub newer_than: Option<chrono::DateTime<chrono::Utc>>,
+    /// How hard to push restored files to disk
+    pub fsync: FsyncPolicy,
+    /// List larger caches first to find missing or damaged files up front
+    pub preflight: bool,
+    /// Restore what's there, skipping files the preflight found damaged and
+    /// carrying on past files that fail to download, failing with them all
+    /// at the end
+    pub keep_going: bool,
+    /// Restore recorded extended attributes
+    pub xattrs: bool,
+    /// Restore recorded POSIX ACLs
+    pub acls: bool,
+    /// Skip deduplicated files already holding the right content, tracked
+    /// in a state file in the outpath
+    pub local_state: bool,
+    /// Re-hash deduplicated files without a recorded sha256 once written,
+    /// failing on a mismatch.  Files with one are always checked.
+    pub verify: bool,
+    /// Name prefixes tried in order when the cache asked for is missing,
+    /// restoring the newest cache matching the first that finds one
+    pub fallbacks: Vec<String>,
+    /// Write sha256sum checksums of the restored files to SHA256SUMS in the
+    /// outpath
+    pub write_checksums: bool,
+    /// Size from which uncompressed objects are fetched in parallel ranges,
+    /// over connections not in use for other files
+    pub ranged_threshold: u64,
+    /// Only restore files whose entry path matches
+    pub filter: Option<GlobSet>,
+    /// Log what would be restored without writing anything
+    pub dry_run: bool,
+    /// What to do with symlinks that can't be created
+    pub symlinks: Symlinks,
+    /// Skip files already in the outpath with the right content, fetching
+    /// only those missing or different
+    pub if_changed: bool,
+    /// Told of each file and byte fetched
+    pub progress: Arc<dyn ProgressSink>,
+    /// Restore files sharing an object as hard links to one another where
+    /// the filesystem allows, rather than copies
+    pub hardlink: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            max_in_flight: 3,
+            newer_than: None,
+            fsync: FsyncPolicy::None,
+            preflight: true,
+            keep_going: false,
+            xattrs: false,
+            acls: false,
+            local_state: false,
+            verify: false,
+            fallbacks: Vec::new(),
+            write_checksums: false,
+            ranged_threshold: ranged::DEFAULT_THRESHOLD,
+            filter: None,
+            dry_run: false,
+            symlinks: Symlinks::Native,
+            if_changed: false,
+            progress: crate::progress::noop(),
+            hardlink: false,
+        }
+    }
+}
+
+/// Files modified after since, plus symlinks to anything kept.  Files
+/// without a recorded time are kept as they can't be ruled out.
+fn newer_than(cache_name: &str, files: Vec<cache::File>, since: chrono::DateTime<chrono::Utc>,
+              skipped: &mut Skips<DownloadSkipReason>) -> Result<Vec<cache::File>> {
+    if !files.iter().any(|f| f.mtime.is_some()) {
+        return Err(crate::Error::NoRecordedTimes(cache_name.to_owned()).into());
+    }
+
+    let mut keep: Vec<bool> = files.iter()
+        .map(|f| f.link_target.is_none() && f.mtime.is_none_or(|t| t > since))
+        .collect();
+    let links: Vec<Option<String>> = files.iter().map(|f| f.link_target.as_ref()
+        .filter(|target| !target.starts_with('/'))
+        .map(|target| {
+            let parent = f.path_str().rsplit_once('/').map_or("", |(p, _)| p);
+            cache::resolve_dots(&format!("{}/{}", parent, target)).join("/")
+        })).collect();
+
+    // Repeat so links to links follow their eventual target
+    loop {
+        let kept: std::collections::HashSet<&str> = files.iter().zip(&keep)
+            .filter(|(_, k)| **k)
+            .map(|(f, _)| f.path_str()).collect();
+        let more: Vec<usize> = links.iter().enumerate()
+            .filter(|(i, target)| !keep[*i] && target.as_deref().is_some_and(|t| kept.contains(t)))
+            .map(|(i, _)| i).collect();
+        if more.is_empty() {
+            break;
+        }
+        for i in more {
+            keep[i] = true;
+        }
+    }
+
+    Ok(files.into_iter().zip(keep)
+       .filter_map(|(f, k)| {
+           if !k {
+               skipped.add(DownloadSkipReason::NotNewer, f.path_str());
+           }
+           k.then_some(f)
+       })
+       .collect())
+}
+
+/// Files matching filter, recording the rest as not included
+fn included(files: Vec<cache::File>, filter: &GlobSet, skipped: &mut Skips<DownloadSkipReason>) -> Vec<cache::File> {
+    files.into_iter()
+        .filter(|f| {
+            let included = f.matches_glob(filter);
+            if !included {
+                skipped.add(DownloadSkipReason::NotIncluded, f.path_str());
+            }
+            included
+        })
+        .collect()
+}
+
+/// Create the entry's directories below outpath, with their recorded modes
+/// set children first so a read-only parent doesn't stop them
+fn create_dirs(outpath: &std::path::Path, dirs: &[cache::Dir], strict: bool) -> Result<()> {
+    for d in dirs {
+        let path = outpath.join(d.path());
+        std::fs::create_dir_all(&path).with_context(|| format!("Failed to create {}", local_display(&path)))?;
+    }
+    for d in dirs.iter().rev() {
+        if let Some(mode) = d.mode {
+            set_permisions(async_std::path::Path::new(outpath.join(d.path()).as_os_str()), mode, strict)?;
+        }
+    }
+    Ok(())
+}
+
+/// Caches with at least this many cache-local files are checked up front
+const PREFLIGHT_MIN_FILES: usize = 10;
+
+/// Entry files whose cache-local object is missing or the wrong size in a
+/// listing of the cache, described for the user
+fn preflight(cache_name: &str, files: &[cache::File], listed: &[crate::s3::ObjectInfo]) -> Vec<(usize, String)> {
+    let sizes: std::collections::HashMap<&str, u64> = listed.iter().map(|o| (o.key.as_str(), o.size)).collect();
+    files.iter().enumerate()
+        .filter(|(_, f)| f.object.is_none() && f.link_target.is_none())
+        .filter_map(|(i, f)| {
+            let key = f.storage_path(cache_name);
+            match sizes.get(key.to_str()?) {
+                None => Some((i, format!("{}: missing", f.path_str()))),
+                Some(&size) if size != f.size => Some((i, format!("{}: size {} expected {}", f.path_str(), size, f.size))),
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+/// Check the cache-local files in one listing rather than discovering
+/// problems one failed GET at a time
+async fn check_cache_files(storage: &Storage, cache_name: &str, c: &mut Cache, keep_going: bool,
+                           skipped: &mut Skips<DownloadSkipReason>) -> Result<()> {
+    let local = c.files.iter().filter(|f| f.object.is_none() && f.link_target.is_none()).count();
+    if local < PREFLIGHT_MIN_FILES {
+        return Ok(());
+    }
+    let location = format!("{}/files/", Cache::location(cache_name).to_str().expect("Invalid location -> string"));
+    let listed = storage.list_objects(&location).await?;
+    let problems = preflight(cache_name, &c.files, &listed);
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let details: Vec<&str> = problems.iter().map(|(_, d)| d.as_str()).collect();
+    if !keep_going {
+        return Err(crate::Error::CacheDamaged {
+            cache: cache_name.to_owned(), count: problems.len(), details: details.join(", "),
+        }.into());
+    }
+    log::warn!("Skipping {} damaged files in '{}':\n  {}", problems.len(), cache_name, details.join("\n  "));
+    let skip: std::collections::HashSet<usize> = problems.into_iter().map(|(i, _)| i).collect();
+    let files = std::mem::take(&mut c.files);
+    for (i, f) in files.into_iter().enumerate() {
+        if skip.contains(&i) {
+            skipped.add(DownloadSkipReason::Damaged, f.path_str());
+        } else {
+            c.files.push(f);
+        }
+    }
+    Ok(())
+}
+
+/// Drop deduplicated files already restored with the right content, just
+/// fixing their permissions
+async fn skip_unchanged(state: &LocalState, c: &mut Cache, outpath: &std::path::Path, strict: bool,
+                        skipped: &mut Skips<DownloadSkipReason>) -> Result<()> {
+    let mut kept = Vec::with_capacity(c.files.len());
+    let mut count = 0;
+    for f in std::mem::take(&mut c.files) {
+        let unchanged = match f.object_key() {
+            Ok(Some(object)) if f.link_target.is_none() => state.has_content(f.path_str(), f.size, &object, c.hash_algorithm).await?,
+            _ => false,
+        };
+        if !unchanged {
+            kept.push(f);
+            continue;
+        }
+        count += 1;
+        skipped.add(DownloadSkipReason::Unchanged, f.path_str());
+        if let Some(mode) = f.mode {
+            let path = PathBuf::from(outpath.join(f.path()));
+            set_permisions(path.as_path(), mode, strict)?;
+        }
+    }
+    log::warn!("Skipping {} files already restored", count);
+    c.files = kept;
+    Ok(())
+}
+
+/// What a download restored
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DownloadReport {
+    pub cache: String,
+    /// The name asked for, when cache is a fallback restored in its place
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_for: Option<String>,
+    /// Files restored, excluding those skipped as unchanged
+    pub files: usize,
+    pub bytes: u64,
+    /// With write_checksums, sha256sum lines for everything restored, left
+    /// to the caller to write once files are in place
+    #[serde(skip)]
+    pub(crate) sums: Option<String>,
+    /// Files in the entry not restored, counted by why
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub skipped: std::collections::BTreeMap<DownloadSkipReason, usize>,
+}
+
+pub async fn download(storage: Storage, cache_name: &str, outpath: std::path::PathBuf, options: &DownloadOptions) -> Result<DownloadReport> {
+    let (name, c) = resolve_cache(&storage, cache_name, &options.fallbacks).await?;
+    let mut report = download_entry(storage, &name, c, outpath.clone(), options).await?;
+    report.fallback_for = (name != cache_name).then(|| cache_name.to_owned());
+    if let Some(sums) = &report.sums {
+        crate::checksums::write(&outpath, sums)?;
+    }
+    Ok(report)
+}
+
+/// Restore an entry already read
+pub(crate) async fn download_entry(storage: Storage, cache_name: &str, mut c: Cache, outpath: std::path::PathBuf,
+                                   options: &DownloadOptions) -> Result<DownloadReport> {
+    c.check_paths().with_context(|| format!("Refusing to restore '{}'", cache_name))?;
+    let max_in_flight = options.max_in_flight;
+    let fsync = Fsync::new(options.fsync);
+    let mut skipped = Skips::default();
+    if let Some(since) = options.newer_than {
+        let total = c.files.len();
+        c.files = newer_than(cache_name, c.files, since, &mut skipped)?;
+        // directories record no times, so none are newer
+        c.dirs.clear();
+        log::info!("Restoring {} of {} files newer than {}", c.files.len(), total, since.to_rfc3339());
+    }
+    if let Some(filter) = &options.filter {
+        let total = c.files.len();
+        c.files = included(c.files, filter, &mut skipped);
+        c.dirs.retain(|d| filter.matches(&d.path));
+        log::info!("Restoring {} of {} files matching --include", c.files.len(), total);
+    }
+    if options.preflight {
+        check_cache_files(&storage, cache_name, &mut c, options.keep_going, &mut skipped).await?;
+    }
+    if options.dry_run {
+        for d in &c.dirs {
+            log::warn!("Simulate creating directory {}", d.path);
+        }
+        for f in &c.files {
+            let existing = std::fs::symlink_metadata(outpath.join(f.path())).is_ok();
+            log::warn!("Simulate restoring {} ({}){}", f.path_str(), match (&f.link_target, &f.object) {
+                (Some(target), _) => format!("symlink to {}", target),
+                (None, Some(_)) => format!("{} bytes, deduplicated", f.size),
+                (None, None) => format!("{} bytes, with the cache", f.size),
+            }, if existing { ", overwriting the existing file" } else { "" });
+        }
+        return Ok(DownloadReport {
+            cache: cache_name.to_owned(), fallback_for: None, files: c.files.len(),
+            bytes: c.files.iter().map(|f| f.size).sum(), sums: None, skipped: skipped.counts(),
+        });
+    }
+    // before unchanged files are skipped, as they're restored all the same
+    let sums = if options.write_checksums {
+        Some(crate::checksums::sums(&storage, cache_name, &c.files, c.hash_algorithm, options.max_in_flight).await?)
+    } else {
+        None
+    };
+    if !options.xattrs {
+        for f in c.files.iter_mut() {
+            f.xattrs = None;
+        }
+    }
+    if !options.acls {
+        for f in c.files.iter_mut() {
+            f.acls = None;
+        }
+    }
+    let dir_acls = if options.acls { std::mem::take(&mut c.dir_acls) } else { Default::default() };
+    let mut state = options.local_state.then(|| LocalState::load(&outpath));
+    let objects: Vec<(String, ObjectKey)> = c.files.iter()
+        .filter(|f| f.link_target.is_none())
+        .filter_map(|f| Some((f.path_str().to_owned(), f.object_key().ok()??)))
+        .collect();
+    if let Some(state) = state.as_ref() {
+        skip_unchanged(state, &mut c, &outpath, storage.strictness().permissions, &mut skipped).await?;
+    }
+    if ! c.files.is_empty() && !outpath.is_dir() {
+        std::fs::create_dir_all(&outpath).context(format!("Failed to create {}", local_display(&outpath)))?;
+    }
+    create_dirs(&outpath, &c.dirs, storage.strictness().permissions)?;
+
+    let mut download_set = tokio::task::JoinSet::<DownloadWork>::new();
+    let budget = ranged::Budget::new(max_in_flight, options.ranged_threshold).with_progress(options.progress.clone());
+    let checks = Checks { algorithm: c.hash_algorithm, verify: options.verify };
+
+    let mut unmade = Vec::new();
+    let mut unchanged = 0;
+    let mut failures = Vec::new();
+    let mut handle = |work: std::result::Result<DownloadWork, tokio::task::JoinError>| -> Result<()> {
+        // JoinError
+        let work = work.with_context(|| "Failure waiting on download jobs")?;
+
+        match work {
+            DownloadWork::Download(path, Err(e)) => {
+                if let Some(crate::Error::SymlinkFailed { path, target, source }) = e.downcast_ref::<crate::Error>()
+                    .filter(|_| options.symlinks != Symlinks::Native) {
+                    log::info!("Unable to create symlink {} -> {}: {}", path, target, source);
+                    unmade.push((path.clone(), target.clone()));
+                } else if options.keep_going {
+                    failures.push(crate::FileFailure { path, error: format!("{:#}", e) });
+                } else {
+                    return Err(e.context("Failed to download file"));
+                }
+            },
+            DownloadWork::Download(_, Ok(())) => {},
+            DownloadWork::Unchanged(path) => {
+                unchanged += 1;
+                skipped.add(DownloadSkipReason::Unchanged, path);
+            },
+        }
+        Ok(())
+    };
+
+    let mut count = 0;
+    let total = c.files.len();
+    let bytes = c.files.iter().map(|f| f.size).sum();
+    let (fetch, shared) = share_objects(c.files);
+
+    for f in fetch {
+        while download_set.len() >= max_in_flight as usize {
+            if count == 0 {
+                log::debug!("Dispatching download jobs...");
+            }
+            if let Some(work) = download_set.join_next().await {
+                count += 1;
+                handle(work)?;
+            } else {
+                log::warn!("Unexpected termination of downloads after {} expecting {}", count, total);
+                break;
+            }
+        }
+        let work = work_download(storage.clone(), f.clone(), cache_name.to_owned(), outpath.clone().into(), fsync.clone(),
+                                 checks, budget.clone());
+        if options.if_changed {
+            download_set.spawn(work_if_changed(storage.clone(), f, outpath.clone().into(), checks.algorithm, work));
+        } else {
+            download_set.spawn(work);
+        }
+    }
+
+    if count == 0 {
+        log::debug!("Dispatching download jobs...");
+    }
+    while let Some(work) = download_set.join_next().await {
+        count += 1;
+        handle(work)?;
+    }
+
+    // once the object each shares is in place
+    let unavailable: std::collections::HashSet<String> = failures.iter().map(|f| f.path.clone()).collect();
+    let base: PathBuf = outpath.clone().into();
+    for (from, copies) in shared {
+        for f in copies {
+            count += 1;
+            let path = f.path_str().to_owned();
+            let result = if unavailable.contains(from.path_str()) {
+                Err(anyhow::anyhow!("Not restored as {}, sharing its content, failed", from.path_str()))
+            } else {
+                // failing to look at what's there is a failure of this file like any other
+                let same = if options.if_changed { in_place(&base.join(f.path()), &f, checks.algorithm).await } else { Ok(false) };
+                match same {
+                    Ok(true) => {
+                        unchanged += 1;
+                        skipped.add(DownloadSkipReason::Unchanged, path);
+                        continue;
+                    },
+                    Ok(false) => restore_copy(&storage, &from, &f, &base, &fsync, options.hardlink).await,
+                    Err(e) => Err(e),
+                }
+            };
+            match result {
+                Ok(()) => {},
+                Err(e) if options.keep_going => failures.push(crate::FileFailure { path, error: format!("{:#}", e) }),
+                Err(e) => return Err(e.context(format!("Failed to restore {}", path))),
+            }
+        }
+    }
+    failed(cache_name, "download", failures)?;
+    count -= unchanged;
+    if !unmade.is_empty() {
+        let links = unmade.len();
+        unmade_links(&outpath, unmade, options.symlinks, &mut skipped)?;
+        if options.symlinks == Symlinks::Skip {
+            count -= links;
+        }
+    }
+
+    // once their content is in place, as default ACLs would be inherited
+    for (dir, a) in &dir_acls {
+        let path = outpath.join(cache::File::path_of(dir));
+        if path.is_dir() {
+            acls::restore(&path, a);
+        }
+    }
+
+    if skipped.is_empty() {
+        log::warn!("Downloaded {} files from '{}'", count, cache_name);
+    } else {
+        log::warn!("Downloaded {} files from '{}', skipping {}", count, cache_name, skipped.summary());
+    }
+    crate::access::record_download(&storage, cache_name, bytes).await;
+    if fsync.policy() != FsyncPolicy::None {
+        log::warn!("fsync ({:?}) took {:.3}s", fsync.policy(), fsync.spent().as_secs_f64());
+    }
+    if let Some(state) = state.as_mut() {
+        for (path, object) in &objects {
+            state.record(path, object);
+        }
+        state.save().with_context(|| format!("Failed to save {}", local_state::FILE_NAME))?;
+    }
+
+    Ok(DownloadReport { cache: cache_name.to_owned(), fallback_for: None, files: count, bytes, sums,
+                        skipped: skipped.counts() })
+}
+
+/// Symlinks [open_file] follows from one file of a cache to another
+const MAX_LINK_DEPTH: usize = 8;
+
+/// The file at path in an entry, following symlinks to its other files
+fn locate<'a>(c: &'a Cache, cache_name: &str, path: &str) -> Result<&'a cache::File> {
+    let unreadable = |reason: String| crate::Error::UnreadableLink { cache: cache_name.to_owned(), path: path.to_owned(), reason };
+    let rules = c.path_rules();
+    let mut at = path.trim_start_matches("./").to_owned();
+    for _ in 0..=MAX_LINK_DEPTH {
+        let f = c.files.iter().find(|f| rules.same(f.path_str(), &at))
+            .ok_or_else(|| crate::Error::FileNotFound { cache: cache_name.to_owned(), path: at.clone() })?;
+        let Some(target) = f.link_target.as_deref() else {
+            return Ok(f);
+        };
+        at = links::resolve_in_entry(f.path_str(), target)
+            .ok_or_else(|| unreadable(format!("{} links to {}, outside the cache", f.path_str(), target)))?;
+    }
+    Err(unreadable(format!("more than {} symlinks deep", MAX_LINK_DEPTH)).into())
+}
+
+/// Read the file at path in a cache as it's fetched, without writing it
+/// anywhere.  Symlinks are followed to other files of the cache.
+pub async fn open_file(storage: Storage, cache_name: &str, path: &str)
+                       -> Result<Box<dyn tokio::io::AsyncRead + Send + Unpin>> {
+    let (_, c) = resolve_cache(&storage, cache_name, &[]).await?;
+    let f = locate(&c, cache_name, path)?;
+    let p = f.storage_path(cache_name);
+    let stream = storage.get_stream(p.to_str().expect("Invalid storage_path -> string"))?;
+    Ok(match f.compression {
+        Some(codec) => Box::new(codec.reader(tokio::io::BufReader::new(stream))),
+        None => Box::new(stream),
+    })
+}
+
+/// The content of the file at path in a cache, as [open_file] reads it
+pub async fn read_file_to_vec(storage: Storage, cache_name: &str, path: &str) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    tokio::io::AsyncReadExt::read_to_end(&mut open_file(storage, cache_name, path).await?, &mut content).await
+        .with_context(|| format!("Failed to read '{}' of cache '{}'", path, cache_name))?;
+    Ok(content)
+}
+
+/// Log each of failures, then fail with them all if there are any
+fn failed(cache_name: &str, operation: &'static str, failures: Vec<crate::FileFailure>) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    log::warn!("Failed to {} {} files of '{}':\n  {}", operation, failures.len(), cache_name,
+               failures.iter().map(|f| format!("{}: {}", f.path, f.error)).collect::<Vec<_>>().join("\n  "));
+    Err(crate::Error::FilesFailed { operation, cache: cache_name.to_owned(), failures }.into())
+}
+
+/// Prefix holding everything of cache_name.  The trailing '/' keeps
+/// listings from taking in other caches whose names start the same.
+fn cache_dir(cache_name: &str) -> String {
+    format!("{}/", key_display(&Cache::location(cache_name)))
+}
+
+/// Delete cache_name, or with dry_run log each key that would go, in key
+/// order, and their total
+pub async fn delete(storage: Storage, cache_name: &str, dry_run: bool) -> Result<()> {
+    if let Err(e) = read_cache_info(&storage, cache_name).await {
+        // a damaged entry shouldn't stop the delete, but being unable to look should
+        if e.downcast_ref::<crate::Error>().is_some_and(|e| e.is_auth() || e.is_retryable()) {
+            return Err(e);
+        }
+        if storage.strictness().missing_cache {
+            return Err(e.context(crate::Error::CacheNotFound(cache_name.to_owned())));
+        }
+        log::warn!("Cache {} not found:{}", cache_name, e);
+    }
+
+    if dry_run {
+        let mut objects = storage.list_objects(&cache_dir(cache_name)).await?;
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        for o in &objects {
+            log::warn!("Simulate deleting {} ({} bytes)", key_display(&o.key), o.size);
+        }
+        log::warn!("Simulate deleting {} keys, {} bytes, of '{}'", objects.len(), objects.iter().map(|o| o.size).sum::<u64>(), cache_name);
+        return Ok(());
+    }
+    storage.recursive_delete(&cache_dir(cache_name)).await?;
+    log::warn!("Deleted '{}'", cache_name);
+    Ok(())
+}
+
+/// Delete every cache whose name matches template, resolved now.  More
+/// than one is only deleted with many, so a loose pattern can't remove
+/// caches by surprise.  Returns the names matched.
+pub async fn delete_matching(storage: Storage, template: &str, dry_run: bool, many: bool) -> Result<Vec<String>> {
+    let pattern = crate::names::resolve(template)?;
+    let matched = crate::names::matching(&pattern, &storage.list_dirs("cache/").await?)?;
+    log::warn!("'{}' resolves to '{}', matching {} caches{}{}", template, pattern, matched.len(),
+               if matched.is_empty() { "" } else { ": " }, matched.join(", "));
+    if matched.is_empty() && storage.strictness().missing_cache {
+        return Err(crate::Error::CacheNotFound(pattern).into());
+    }
+    if matched.len() > 1 && !many && !dry_run {
+        return Err(crate::Error::AmbiguousMatch { pattern, count: matched.len() }.into());
+    }
+    for name in &matched {
+        delete(storage.clone(), name, dry_run).await?;
+    }
+    Ok(matched)
+}
+
+/// Copy cache src_name to dst_name.  Deduplicated objects are shared, so
+/// only files stored with the cache are copied, server-side, before the entry.
+pub async fn copy(storage: Storage, src_name: &str, dst_name: &str, overwrite: bool) -> Result<()> {
+    let c = match read_cache_info(&storage, src_name).await {
+        Err(e) if is_not_found(&e) => return Err(e.context(crate::Error::CacheNotFound(src_name.to_owned()))),
+        c => c?,
+    };
+    let path = Cache::entry_location(dst_name);
+    let path = path.to_str().expect("entry location is utf8");
+    if src_name == dst_name || (!overwrite && storage.head(path).await?.is_some()) {
+        return Err(crate::Error::CacheAlreadyExists(dst_name.to_owned()).into());
+    }
+
+    let local: Vec<_> = c.files.iter().filter(|f| f.object.is_none() && f.link_target.is_none()).collect();
+    for f in &local {
+        let (from, to) = (f.storage_path(src_name), f.storage_path(dst_name));
+        storage.copy(from.to_str().expect("storage path is utf8"), to.to_str().expect("storage path is utf8")).await
+            .with_context(|| format!("Failed to copy {}", key_display(&from)))?;
+    }
+    let count = c.files.len();
+    storage.put_file(&mut std::io::Cursor::new(c.into_string()), path).await?;
+    log::warn!("Copied '{}' to '{}': {} files, {} stored with the cache", src_name, dst_name, count, local.len());
+    Ok(())
+}
+
+/// Move cache old_name to new_name.  The new entry is written before the
+/// old cache is removed, so a failure part way leaves it readable.
+pub async fn rename(storage: Storage, old_name: &str, new_name: &str, overwrite: bool) -> Result<()> {
+    copy(storage.clone(), old_name, new_name, overwrite).await?;
+    storage.recursive_delete(&cache_dir(old_name)).await?;
+    log::warn!("Renamed '{}' to '{}'", old_name, new_name);
+    Ok(())
+}
+
+/// Write cache_name to dest as a tar of its files, with its entry as
+/// [crate::archive::META], so it can be restored or imported without the
+/// bucket it came from
+pub async fn export(storage: Storage, cache_name: &str, dest: &std::path::Path) -> Result<()> {
+    layout::check(&storage, false).await?;
+    let key = Cache::entry_location(cache_name);
+    let meta = match cache::read_entry(&storage, cache_name, key.to_str().expect("entry location is utf8")).await {
+        Err(e) if e.is_not_found() => return Err(crate::Error::CacheNotFound(cache_name.to_owned()).into()),
+        meta => meta?,
+    };
+    let (c, _) = cache::decode(cache_name, &meta)?;
+    let paths = c.files.iter().map(|f| f.path()).chain(c.dirs.iter().map(|d| d.path()));
+    if let Some(path) = paths.into_iter().find(|p| p.has_root()) {
+        anyhow::bail!("Cache '{}' holds absolute path {}, which can't be exported", cache_name, local_display(&path));
+    }
+
+    let staging = crate::archive::Staging::new("export")?;
+    let report = download_entry(storage, cache_name, c, staging.path().to_owned(), &DownloadOptions::default()).await?;
+    let (dir, dest_path) = (staging.path().to_owned(), dest.to_owned());
+    tokio::task::spawn_blocking(move || crate::archive::pack(&dir, &meta, &dest_path)).await
+        .with_context(|| "Failure waiting on export")??;
+    log::warn!("Exported '{}' to {}: {} files, {} bytes", cache_name, local_display(dest), report.files, report.bytes);
+    Ok(())
+}
+
+/// Upload the files of an [export] as cache_name, as upload would from a
+/// directory holding them
+pub async fn import(storage: Storage, archive: &std::path::Path, cache_name: &str) -> Result<stats::UploadRecord> {
+    let staging = crate::archive::Staging::new("import")?;
+    let (archive_path, dir) = (archive.to_owned(), staging.path().to_owned());
+    let meta = tokio::task::spawn_blocking(move || crate::archive::unpack(&archive_path, &dir)).await
+        .with_context(|| "Failure waiting on import")??;
+    let (c, _) = cache::decode(cache_name, &meta)?;
+    // the names the export was uploaded as, so the entry records the same paths
+    let top: std::collections::BTreeSet<std::path::PathBuf> = c.files.iter().map(|f| f.path()).chain(c.dirs.iter().map(|d| d.path()))
+        .filter_map(|p| p.components().next().map(|first| first.as_os_str().into()))
+        .collect();
+    let options = UploadOptions {
+        recurse: true, hash_algorithm: c.hash_algorithm, base: Some(staging.path().to_owned()), ..Default::default()
+    };
+    let record = upload(storage, cache_name, &top.into_iter().collect::<Vec<_>>(), &options).await?;
+    log::warn!("Imported {} as '{}': {} files, {} bytes", local_display(archive), cache_name, record.files, record.bytes);
+    Ok(record)
+}
+
+pub async fn report(storage: Storage, since: std::time::Duration, access: bool, json: bool) -> Result<()> {
+    let since = chrono::Utc::now() - chrono::Duration::from_std(since)
+        .context("Report window out of range")?;
+    if access {
+        let activity = crate::access::report(&storage, since).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&activity)?);
+        } else {
+            crate::access::print_table(&activity);
+        }
+        return Ok(());
+    }
+    let report = stats::report(&storage, since).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print_table();
+    }
+    Ok(())
+}
+
+pub async fn namespaces(storage: Storage, max_in_flight: u32, json: bool) -> Result<()> {
+    let namespaces = crate::namespaces::namespaces(&storage, max_in_flight).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&namespaces)?);
+    } else {
+        crate::namespaces::print_table(&namespaces);
+    }
+    Ok(())
+}
+
+pub async fn migrate(storage: Storage, prefix: &str, options: &crate::migrate::MigrateOptions) -> Result<crate::migrate::Progress> {
+    layout::check(&storage, false).await?;
+    crate::migrate::migrate(storage, prefix, options).await
+}
+
+/// Describe the bucket and its layout marker
+pub async fn info(storage: Storage) -> Result<()> {
+    println!("bucket         {}", storage.bucket_name());
+    println!("endpoint       {}", storage.endpoint());
+    match storage.layout().await? {
+        Some(found) => {
+            found.print();
+            if let Some(reason) = found.incompatibility() {
+                println!("INCOMPATIBLE   {}", reason);
+            }
+        },
+        None => println!("layout         legacy, no {} marker", layout::MARKER),
+    }
+    match crate::clock::measure(&storage).await? {
+        Some(skew) => println!("clock skew     {} (server ahead of local)", crate::clock::format_skew(skew)),
+        None => println!("clock skew     unknown"),
+    }
+    Ok(())
+}
+
+pub async fn selftest(storage: Storage, options: &crate::selftest::SelftestOptions) -> Result<()> {
+    crate::selftest::selftest(storage, options).await
+}
+
+/// Local tree compared with an existing cache entry, by entry path
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct StatusReport {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+impl StatusReport {
+    pub fn in_sync(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    pub fn print(&self, verbose: bool) {
+        for (label, paths) in [("added", &self.added), ("removed", &self.removed),
+                               ("modified", &self.modified), ("unchanged", &self.unchanged)] {
+            println!("{:<10} {:>8}", label, paths.len());
+            if verbose && label != "unchanged" {
+                for p in paths {
+                    println!("  {}", p);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Added,
+    Modified,
+    Unchanged,
+    /// Not something upload would store, eg a directory
+    Ignored,
+}
+
+fn slash(path: &async_std::path::Path) -> String {
+    std::path::Path::new(path.as_os_str()).to_slash().expect("path->slash").into_owned()
+}
+
+/// Compare a regular file with its entry, hashing only when size, mode and
+/// any recorded mtime can't settle it.  Content of cache-local files isn't
+/// hashed in the entry, so without an mtime those are compared by size.
+async fn compare_file(meta: &Meta, entry: &cache::File, threshold: usize, algorithm: HashAlgorithm) -> Result<Change> {
+    let Some(local) = meta.file.as_ref().filter(|m| m.is_file()) else {
+        return Ok(Change::Modified);
+    };
+    let deduplicated = local.len() > threshold as u64;
+    if entry.link_target.is_some() || local.len() != entry.size || deduplicated != entry.object.is_some() {
+        return Ok(Change::Modified);
+    }
+    if let (Some(mode), Some(recorded)) = (meta.get_mode(), entry.mode) {
+        if mode != recorded {
+            return Ok(Change::Modified);
+        }
+    }
+    if let Some(recorded) = entry.mtime {
+        if local.modified().ok().map(chrono::DateTime::<chrono::Utc>::from) == Some(recorded) {
+            return Ok(Change::Unchanged);
+        }
+        if entry.object.is_none() {
+            return Ok(Change::Modified);
+        }
+    }
+    match entry.object_key() {
+        Ok(Some(object)) => {
+            let hash = cache::read_hash_with(meta.path.as_path(), &Some(local.len()), algorithm).await?;
+            Ok(if ObjectKey::from_digest(&hash) == object { Change::Unchanged } else { Change::Modified })
+        },
+        Ok(None) => Ok(Change::Unchanged),
+        // can't be what upload would record now
+        Err(_) => Ok(Change::Modified),
+    }
+}
+
+async fn path_status(path: PathBuf, key: String, entry: Option<cache::File>, threshold: usize,
+                     algorithm: HashAlgorithm) -> Result<(String, Change)> {
+    let meta = resolve_meta(path).await?;
+    let storable = meta.file.as_ref().is_some_and(|m| m.is_file() || m.is_symlink());
+    let change = match (entry, meta.cacheable_link()) {
+        (None, _) if !storable => Change::Ignored,
+        (None, _) => Change::Added,
+        (Some(entry), Some(link)) if entry.link_target.as_deref() == link.to_str() => Change::Unchanged,
+        (Some(_), Some(_)) => Change::Modified,
+        (Some(entry), None) => compare_file(&meta, &entry, threshold, algorithm).await?,
+    };
+    Ok((key, change))
+}
+
+/// Compare paths, walked as upload would, against the files of an entry
+async fn compare_tree(files: Vec<cache::File>, rules: PathRules, paths: &[std::path::PathBuf],
+                      recurse: bool, threshold: usize, algorithm: HashAlgorithm) -> Result<StatusReport> {
+    let mut entry: std::collections::HashMap<String, cache::File> =
+        files.into_iter().map(|f| (rules.key(f.path_str()).into_owned(), f)).collect();
+    let mut seen = std::collections::HashSet::new();
+    let mut set = tokio::task::JoinSet::new();
+    for path in walk(paths, recurse) {
+        let key = rules.local_key(std::path::Path::new(path.as_os_str()));
+        if seen.insert(key.clone()) {
+            let recorded = entry.remove(&key);
+            // reported as recorded, where it was
+            let shown = recorded.as_ref().map_or_else(|| key.clone(), |f| f.path_str().to_owned());
+            set.spawn(path_status(path, shown, recorded, threshold, algorithm));
+        }
+    }
+
+    let mut report = StatusReport::default();
+    while let Some(work) = set.join_next().await {
+        let (key, change) = work.with_context(|| "Failure waiting on status work")??;
+        match change {
+            Change::Added => report.added.push(key),
+            Change::Modified => report.modified.push(key),
+            Change::Unchanged => report.unchanged.push(key),
+            Change::Ignored => {},
+        }
+    }
+    report.removed = entry.into_values().map(|f| f.path_str().to_owned()).collect();
+    for list in [&mut report.added, &mut report.removed, &mut report.modified, &mut report.unchanged] {
+        list.sort();
+    }
+    Ok(report)
+}
+
+/// Compare local paths against an existing cache without transferring content
+pub async fn status(storage: Storage, cache_name: &str, paths: &[std::path::PathBuf],
+                    recurse: bool, threshold: usize) -> Result<StatusReport> {
+    let c = read_cache_info(&storage, cache_name).await?;
+    let rules = c.path_rules();
+    compare_tree(c.files, rules, paths, recurse, threshold, c.hash_algorithm).await
+}
+
+/// Summary of a cache entry's files and how they're stored
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CacheStat {
+    pub file_count: usize,
+    /// Size of the files as restored
+    pub total_logical_bytes: u64,
+    /// Distinct deduplicated objects, counting files sharing content once
+    pub deduped_object_count: usize,
+    /// Files stored with the cache rather than deduplicated
+    pub inline_file_count: usize,
+    pub symlink_count: usize,
+}
+
+impl CacheStat {
+    fn of(files: &[cache::File]) -> CacheStat {
+        let objects: std::collections::HashSet<String> = files.iter().filter_map(cache::File::object_storage_key).collect();
+        CacheStat {
+            file_count: files.len(),
+            total_logical_bytes: files.iter().filter(|f| f.link_target.is_none()).map(|f| f.size).sum(),
+            deduped_object_count: objects.len(),
+            inline_file_count: files.iter().filter(|f| f.object.is_none() && f.link_target.is_none()).count(),
+            symlink_count: files.iter().filter(|f| f.link_target.is_some()).count(),
+        }
+    }
+
+    pub fn print_table(&self) {
+        println!("files:          {:>10}", self.file_count);
+        println!("logical bytes:  {:>10}", self.total_logical_bytes);
+        println!("dedup objects:  {:>10}", self.deduped_object_count);
+        println!("inline files:   {:>10}", self.inline_file_count);
+        println!("symlinks:       {:>10}", self.symlink_count);
+    }
+}
+
+/// Count a cache's files by how they're stored
+pub async fn stat(storage: Storage, cache_name: &str) -> Result<CacheStat> {
+    let c = read_cache_info(&storage, cache_name).await?;
+    Ok(CacheStat::of(&c.files))
+}
+
+/// Is there an entry for cache_name, checked without reading it
+pub async fn exists(storage: Storage, cache_name: &str) -> Result<bool> {
+    let entry = Cache::entry_location(cache_name);
+    Ok(storage.exists(entry.to_str().expect("entry location is utf8")).await?)
+}
+
+/// Whether a cache exists, and with --verbose what's in it
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExistsReport {
+    pub cache: String,
+    pub exists: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stat: Option<CacheStat>,
+}
+
+impl ExistsReport {
+    pub fn print(&self) {
+        match (&self.stat, self.exists) {
+            (Some(stat), true) => println!("{}: {} files, {} bytes", self.cache, stat.file_count, stat.total_logical_bytes),
+            (None, true) => println!("{}", self.cache),
+            (_, false) => println!("{}: not found", self.cache),
+        }
+    }
+}
+
+/// Deduplicated objects of a cache entry checked against the bucket
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Entry paths whose object is missing
+    pub missing: Vec<String>,
+    /// Files whose object was found
+    pub ok_count: usize,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    pub fn print(&self) {
+        for p in &self.missing {
+            println!("missing {}", p);
+        }
+        println!("{} of {} objects present", self.ok_count, self.ok_count + self.missing.len());
+    }
+}
+
+/// Sort the entry's deduplicated files by whether their object was found
+fn verify_report(files: &[cache::File], found: &std::collections::HashSet<String>) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    for (f, key) in files.iter().filter_map(|f| Some((f, f.object_storage_key()?))) {
+        if found.contains(&key) {
+            report.ok_count += 1;
+        } else {
+            report.missing.push(f.path_str().to_owned());
+        }
+    }
+    report.missing.sort();
+    report
+}
+
+/// Check every object the cache refers to still exists, without downloading
+pub async fn verify(storage: Storage, cache_name: &str, max_in_flight: u32) -> Result<VerifyReport> {
+    let c = read_cache_info(&storage, cache_name).await?;
+    // objects shared by several files are only looked up once
+    let keys: std::collections::BTreeSet<String> = c.files.iter()
+        .filter_map(cache::File::object_storage_key)
+        .collect();
+
+    let mut found = std::collections::HashSet::new();
+    let mut set = tokio::task::JoinSet::new();
+    let mut handle = |r: std::result::Result<Result<(String, bool)>, tokio::task::JoinError>| -> Result<()> {
+        let (key, exists) = r.with_context(|| "Failure waiting on verify work")??;
+        if exists {
+            found.insert(key);
+        }
+        Ok(())
+    };
+    for key in keys {
+        while set.len() >= max_in_flight as usize {
+            if let Some(r) = set.join_next().await {
+                handle(r)?;
+            }
+        }
+        let storage = storage.clone();
+        set.spawn(async move {
+            let exists = storage.head(&key).await?.is_some();
+            Ok((key, exists))
+        });
+    }
+    while let Some(r) = set.join_next().await {
+        handle(r)?;
+    }
+
+    let report = verify_report(&c.files, &found);
+    log::info!("{} of {} files in '{}' have their object", report.ok_count, report.ok_count + report.missing.len(), cache_name);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const WRONG: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn fixture() -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "hello world\n").unwrap();
+        (dir, file)
+    }
+
+    #[test]
+    fn fallback_picks_newest_match() {
+        let at = |s: &str| Some(s.parse::<chrono::DateTime<chrono::Utc>>().unwrap());
+        let caches = vec![
+            ("deps-aaa".to_owned(), at("2025-06-01T00:00:00Z")),
+            ("deps-bbb".to_owned(), at("2025-06-03T00:00:00Z")),
+            ("deps-ccc".to_owned(), None),
+            ("build-ddd".to_owned(), at("2025-06-09T00:00:00Z")),
+        ];
+        assert_eq!(newest_matching(&caches, "deps-"), Some("deps-bbb"));
+        assert_eq!(newest_matching(&caches, ""), Some("build-ddd"));
+        assert_eq!(newest_matching(&caches, "deps-c"), Some("deps-ccc"));
+        assert_eq!(newest_matching(&caches, "tools-"), None);
+    }
+
+    fn manifest(file: &std::path::Path, sample: u8) -> Option<Arc<HashManifest>> {
+        let text = format!("{}  {}\n", WRONG, file.display());
+        Some(Arc::new(HashManifest::parse(&text).unwrap().with_verify_sample(sample)))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permission_failure_strictness() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = PathBuf::from(dir.path().join("missing"));
+        assert!(set_permisions(&missing, 0o644, false).is_ok());
+        let err = set_permisions(&missing, 0o644, true).unwrap_err();
+        assert!(matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::SetPermissions(..))));
+    }
+
+    #[tokio::test]
+    async fn manifest_hash_is_trusted() {
+        let (_dir, file) = fixture();
+        let meta = meta_for(file.clone().into(), manifest(&file, 0), None, FileHasher::new(HashAlgorithm::Sha256, 1)).await.unwrap();
+        assert_eq!(faster_hex::hex_string(&meta.hash.unwrap()), WRONG);
+    }
+
+    #[tokio::test]
+    async fn unlisted_file_is_hashed() {
+        let (dir, file) = fixture();
+        let other = dir.path().join("b.txt");
+        std::fs::write(&other, "hello world\n").unwrap();
+        let meta = meta_for(other.into(), manifest(&file, 0), None, FileHasher::new(HashAlgorithm::Sha256, 1)).await.unwrap();
+        assert_ne!(faster_hex::hex_string(&meta.hash.unwrap()), WRONG);
+    }
+
+    #[tokio::test]
+    async fn manifest_sample_catches_wrong_hash() {
+        let (_dir, file) = fixture();
+        let err = meta_for(file.clone().into(), manifest(&file, 100), None, FileHasher::new(HashAlgorithm::Sha256, 1)).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::HashManifestMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn downloads_are_verified() {
+        use sha2::Digest;
+        let (_dir, file) = fixture();
+        let path = async_std::path::PathBuf::from(file);
+        let right = ObjectKey::from_digest(&sha2::Sha256::digest(b"hello world\n").into());
+        verify_download(&path, 12, &right, HashAlgorithm::Sha256).await.unwrap();
+
+        let mut wrong = [0u8; 32];
+        faster_hex::hex_decode(WRONG.as_bytes(), &mut wrong).unwrap();
+        let err = verify_download(&path, 12, &ObjectKey::from_digest(&wrong), HashAlgorithm::Sha256).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::IntegrityError { .. })));
+    }
+
+    #[tokio::test]
+    async fn recorded_checksums_are_checked() {
+        let (_dir, file) = fixture();
+        let path = async_std::path::PathBuf::from(file);
+        verify_checksum(&path, 12, "A948904F2F0F479B8F8197694B30184B0D2ED1C1CD2A1EC0FB85D299A192A447").await.unwrap();
+        let err = verify_checksum(&path, 12, WRONG).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn reserved_keys_are_refused() {
+        let file = |p: &str| cache::File::new_async(async_std::path::Path::new(p), None, 1, None, None);
+        assert!(check_not_reserved(&file("entry"), "c").is_ok());
+        assert!(check_not_reserved(&file("dir/pinned"), "c").is_ok());
+        let err = check_not_reserved(&file("../entry"), "c").unwrap_err();
+        assert!(matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::ReservedKey { .. })));
+    }
+
+    fn entry_file(path: &str, mtime: Option<&str>, link_target: Option<&str>) -> cache::File {
+        let mut f = cache::File::new_async(async_std::path::Path::new(path), None, 1, None, link_target.map(String::from));
+        f.mtime = mtime.map(|t| chrono::DateTime::parse_from_rfc3339(t).unwrap().to_utc());
+        f
+    }
+
+    fn kept(files: Vec<cache::File>, since: &str) -> Vec<String> {
+        let since = chrono::DateTime::parse_from_rfc3339(since).unwrap().to_utc();
+        newer_than("c", files, since, &mut Skips::default()).unwrap().iter().map(|f| f.path_str().to_owned()).collect()
+    }
+
+    #[test]
+    fn newer_than_filters_by_mtime() {
+        let files = vec![
+            entry_file("old.txt", Some("2025-01-01T00:00:00Z"), None),
+            entry_file("dir/new.txt", Some("2025-03-01T00:00:00Z"), None),
+            entry_file("unknown.txt", None, None),
+        ];
+        assert_eq!(kept(files, "2025-02-01T00:00:00Z"), vec!["dir/new.txt", "unknown.txt"]);
+    }
+
+    #[test]
+    fn download_skips_record_why() {
+        let files = vec![
+            entry_file("old.txt", Some("2025-01-01T00:00:00Z"), None),
+            entry_file("dir/new.h", Some("2025-03-01T00:00:00Z"), None),
+            entry_file("dir/new.c", Some("2025-03-01T00:00:00Z"), None),
+        ];
+        let mut skipped = Skips::default();
+        let since = chrono::DateTime::parse_from_rfc3339("2025-02-01T00:00:00Z").unwrap().to_utc();
+        let files = newer_than("c", files, since, &mut skipped).unwrap();
+        let filter = GlobSet::new(["**/*.h"], PathRules::default()).unwrap();
+        let files = included(files, &filter, &mut skipped);
+        assert_eq!(files.iter().map(cache::File::path_str).collect::<Vec<_>>(), vec!["dir/new.h"]);
+        assert_eq!(skipped.paths(DownloadSkipReason::NotNewer), ["old.txt"]);
+        assert_eq!(skipped.paths(DownloadSkipReason::NotIncluded), ["dir/new.c"]);
+        assert_eq!(skipped.summary(), "1 not newer, 1 not included");
+    }
+
+    #[test]
+    fn newer_than_keeps_links_to_restored_files() {
+        let files = vec![
+            entry_file("old.txt", Some("2025-01-01T00:00:00Z"), None),
+            entry_file("dir/new.txt", Some("2025-03-01T00:00:00Z"), None),
+            entry_file("new.link", None, Some("dir/new.txt")),
+            entry_file("dir/up.link", None, Some("../dir/./new.txt")),
+            entry_file("chain.link", None, Some("new.link")),
+            entry_file("old.link", None, Some("old.txt")),
+            entry_file("outside.link", None, Some("/etc/hosts")),
+        ];
+        assert_eq!(kept(files, "2025-02-01T00:00:00Z"),
+                   vec!["dir/new.txt", "new.link", "dir/up.link", "chain.link"]);
+    }
+
+    #[test]
+    fn newer_than_needs_recorded_times() {
+        let err = newer_than("c", vec![entry_file("a", None, None)], chrono::Utc::now(), &mut Skips::default()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::NoRecordedTimes(_))));
+    }
+
+    #[test]
+    fn preflight_reports_missing_and_wrong_size() {
+        let files: Vec<cache::File> = (0..10)
+            .map(|i| cache::File::new_async(async_std::path::Path::new(&format!("f{}", i)), None, i, None, None))
+            .collect();
+        let listed: Vec<crate::s3::ObjectInfo> = files.iter()
+            .filter(|f| f.path_str() != "f3" && f.path_str() != "f7")
+            .map(|f| crate::s3::ObjectInfo {
+                key: f.storage_path("c").to_str().unwrap().into(),
+                size: if f.path_str() == "f5" { 99 } else { f.size },
+                last_modified: None,
+            })
+            .collect();
+
+        let problems = preflight("c", &files, &listed);
+        assert_eq!(problems, vec![
+            (3, "f3: missing".to_owned()),
+            (5, "f5: size 99 expected 5".to_owned()),
+            (7, "f7: missing".to_owned()),
+        ]);
+        assert!(preflight("c", &files[..3], &listed).is_empty());
+    }
+
+    #[test]
+    fn verify_finds_missing_objects() {
+        let object = |p: &str, b: u8| cache::File::new_async(async_std::path::Path::new(p),
+                                                            Some(ObjectKey::from_digest(&[b; 32])), 1, None, None);
+        let files = vec![
+            object("b", 0xbb), object("a", 0xaa), object("shared", 0xaa),
+            cache::File::new_async(async_std::path::Path::new("local"), None, 1, None, None),
+            cache::File::new_async(async_std::path::Path::new("l"), None, 1, None, Some("a".into())),
+        ];
+        let found = std::collections::HashSet::from([ObjectKey::from_digest(&[0xaa; 32]).storage_key()]);
+        assert_eq!(verify_report(&files, &found), VerifyReport { missing: vec!["b".into()], ok_count: 2 });
+        assert!(verify_report(&files[1..], &found).is_ok());
+    }
+
+    #[test]
+    fn content_types() {
+        let local = |p: &str| cache::File::new_async(async_std::path::Path::new(p), None, 1, None, None);
+        assert_eq!(content_type_for(&local("report/index.html"), true), "text/html");
+        assert_eq!(content_type_for(&local("shot.png"), true), "image/png");
+        assert_eq!(content_type_for(&local("out.bin"), true), content_type::OCTET_STREAM);
+        assert_eq!(content_type_for(&local("report/index.html"), false), content_type::OCTET_STREAM);
+
+        let object = cache::File::new_async(async_std::path::Path::new("big.html"),
+                                            Some(ObjectKey::from_digest(&[0xaa; 32])), 1, None, None);
+        assert_eq!(content_type_for(&object, true), content_type::OCTET_STREAM);
+    }
+
+    #[test]
+    fn preflight_ignores_objects_and_links() {
+        let object = cache::File::new_async(async_std::path::Path::new("big"),
+                                            Some(ObjectKey::from_digest(&[0xaa; 32])), 1, None, None);
+        let link = cache::File::new_async(async_std::path::Path::new("l"), None, 1, None, Some("big".into()));
+        assert!(preflight("c", &[object, link], &[]).is_empty());
+    }
+
+    /// Entry as upload would record it for path
+    async fn recorded(path: &std::path::Path, threshold: usize, preserve: PreserveTimes) -> cache::File {
+        let meta = meta_for(path.into(), None, None, FileHasher::new(HashAlgorithm::Sha256, 1)).await.unwrap();
+        let size = meta.file.as_ref().unwrap().len();
+        cache::File::new_async(meta.path.as_path(), route_object(&meta, size, threshold), size, meta.get_mode(), None)
+            .with_times(times::capture(meta.file.as_ref().unwrap(), preserve))
+    }
+
+    #[tokio::test]
+    async fn status_of_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, content: &str| {
+            let p = dir.path().join(name);
+            std::fs::write(&p, content).unwrap();
+            p
+        };
+        let (same, small, big, gone) = (file("same", "same"), file("small", "small"), file("big", "big content"), file("gone", "x"));
+        let mut files = Vec::new();
+        for p in [&same, &small, &big, &gone] {
+            files.push(recorded(p, 5, PreserveTimes::None).await);
+        }
+        std::fs::remove_file(&gone).unwrap();
+        std::fs::write(&small, "SMALL").unwrap(); // same size, cache-local: can't tell
+        std::fs::write(&big, "BIG CONTENT").unwrap(); // same size, but hash differs
+        let added = file("added", "new");
+
+        let r = compare_tree(files, PathRules::default(), &[dir.path().into()], true, 5, HashAlgorithm::Sha256).await.unwrap();
+        let key = |p: &std::path::Path| slash(async_std::path::Path::new(p.as_os_str()));
+        assert_eq!(r.added, vec![key(&added)]);
+        assert_eq!(r.removed, vec![key(&gone)]);
+        assert_eq!(r.modified, vec![key(&big)]);
+        assert_eq!(r.unchanged, vec![key(&same), key(&small)]);
+        assert!(!r.in_sync());
+    }
+
+    /// A "/" delimited listing as S3 pages it: keys and directories both
+    /// count towards max, and pages come back short of it
+    fn fake_dirs_page(keys: &[String], prefix: &str, start_after: Option<&str>, max: usize) -> (Vec<String>, Option<String>) {
+        let mut units: Vec<(String, bool)> = Vec::new();
+        for key in keys.iter().filter(|k| k.starts_with(prefix) && start_after.is_none_or(|s| k.as_str() > s)) {
+            let unit = match key[prefix.len()..].find('/') {
+                Some(i) => (key[..prefix.len() + i + 1].to_owned(), true),
+                None => (key.clone(), false),
+            };
+            if units.last() != Some(&unit) {
+                units.push(unit);
+            }
+        }
+        let more = units.len() > max.min(7);
+        units.truncate(max.min(7));
+        let next = more.then(|| units.last().map(|(k, dir)| if *dir { crate::s3::key_after_dir(k) } else { k.clone() })).flatten();
+        (units.into_iter().filter(|(_, dir)| *dir).map(|(k, _)| k).collect(), next)
+    }
+
+    #[tokio::test]
+    async fn cache_pages_have_no_gaps_or_repeats() {
+        // '-' sorts before '/', so "c0001-x/" lists before "c0001/"
+        let mut names: Vec<String> = (0..2500).map(|i| format!("c{:04}", i)).collect();
+        names.extend(["c0001-x", "c00010", "c1"].map(String::from));
+        let mut keys: Vec<String> = names.iter()
+            .flat_map(|n| [format!("cache/{}/entry", n), format!("cache/{}/files/a", n)])
+            .chain(["cache/stray".to_owned()])
+            .collect();
+        keys.sort();
+        let mut expected: Vec<&String> = names.iter().collect();
+        expected.sort_by_key(|n| format!("{}/", n));
+
+        for (limit, prefix) in [(1, ""), (7, ""), (100, ""), (2500, ""), (5000, ""), (13, "c1")] {
+            let path = format!("cache/{}", prefix);
+            let mut listed = Vec::new();
+            let mut start: Option<String> = None;
+            loop {
+                let from = start.as_deref().map(|name| crate::s3::key_after_dir(&format!("cache/{}/", name)));
+                let fetch = |after: Option<String>, max| {
+                    let page = fake_dirs_page(&keys, &path, after.as_deref(), max);
+                    async move { Ok::<_, crate::Error>(page) }
+                };
+                let (dirs, more) = gather_dirs(fetch, from, limit).await.unwrap();
+                assert!(dirs.len() <= limit);
+                let page: Vec<String> = dirs.iter().map(|d| d["cache/".len()..d.len() - 1].to_owned()).collect();
+                start = more.and(page.last().cloned());
+                listed.extend(page);
+                if start.is_none() {
+                    break;
+                }
+            }
+            let wanted: Vec<&String> = expected.iter().copied().filter(|n| n.starts_with(prefix)).collect();
+            assert_eq!(listed.iter().collect::<Vec<_>>(), wanted, "limit {} prefix {:?}", limit, prefix);
+        }
+    }
+
+    #[test]
+    fn listings_render() {
+        let file = |path: &str, object: Option<&str>, size| ListedFile {
+            path: path.into(), object: object.map(Into::into), size, mode: None, link_target: None,
+        };
+        let files = Listing::Files(vec![file("a", Some("aa/bb"), 100), file("b", None, 5)]);
+        let text = files.render();
+        assert!(text.starts_with(&format!("{:<30} {:>10}\n", "a", 100)));
+        assert!(text.ends_with("2 files, 105 bytes, 1 deduplicated\n"));
+        assert_eq!(serde_json::to_value(&files).unwrap()[0]["object"], "aa/bb");
+
+        assert_eq!(Listing::Names(vec!["x".into(), "y".into()]).render(), "x\ny\n");
+        let caches = Listing::Caches(vec![CacheSummary { name: "x".into(), file_count: 2, total_size: 105, last_modified: None,
+                                                         created: None, writer_version: None, label: None }]);
+        assert_eq!(serde_json::to_string(&caches).unwrap(),
+                   r#"[{"name":"x","file_count":2,"total_size":105,"last_modified":null}]"#);
+        assert!(caches.render().trim_end().ends_with('-'));
+        let labelled = Listing::Caches(vec![CacheSummary { name: "x".into(), file_count: 2, total_size: 105, last_modified: None,
+                                                           created: Some("2025-01-02T03:04:05Z".parse().unwrap()),
+                                                           writer_version: Some("1.2.3".into()), label: Some("abc123".into()) }]);
+        assert!(labelled.render().trim_end().ends_with("2025-01-02 03:04:05    1.2.3 abc123"));
+        assert_eq!(serde_json::to_value(&labelled).unwrap()[0]["label"], "abc123");
+    }
+
+    #[test]
+    fn stat_partitions_files() {
+        let object = |b: u8| Some(ObjectKey::from_digest(&[b; 32]));
+        let path = |p: &str| async_std::path::PathBuf::from(p);
+        let files = vec![
+            cache::File::new_async(&path("a"), object(1), 100, None, None),
+            cache::File::new_async(&path("copy-of-a"), object(1), 100, None, None),
+            cache::File::new_async(&path("b"), object(2), 50, None, None),
+            cache::File::new_async(&path("small"), None, 7, None, None),
+            cache::File::new_async(&path("link"), None, 5, None, Some("a".into())),
+        ];
+        assert_eq!(CacheStat::of(&files), CacheStat {
+            file_count: 5, total_logical_bytes: 257, deduped_object_count: 2, inline_file_count: 1, symlink_count: 1,
+        });
+        assert_eq!(CacheStat::of(&[]), CacheStat::default());
+    }
+
+    #[test]
+    fn excluded_paths_are_not_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for path in ["src/main.c", "src/main.o", "target/debug/.fingerprint/x/hash", "target/debug/tool"] {
+            std::fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            std::fs::write(root.join(path), "x").unwrap();
+        }
+        let files = |exclude: &[&str], paths: &[std::path::PathBuf], recurse| -> Vec<String> {
+            let exclude = GlobSet::new(exclude.iter().copied(), PathRules::default()).unwrap();
+            let mut files: Vec<String> = upload_paths(paths, recurse, &exclude, &mut Skips::default()).iter()
+                .filter(|p| std::path::Path::new(p.as_os_str()).is_file())
+                .map(|p| slash(p).strip_prefix(&format!("{}/", slash(async_std::path::Path::new(root.as_os_str())))).unwrap().to_owned())
+                .collect();
+            files.sort();
+            files
+        };
+        let all = files(&[], &[root.into()], true);
+        assert_eq!(all, vec!["src/main.c", "src/main.o", "target/debug/.fingerprint/x/hash", "target/debug/tool"]);
+        assert_eq!(files(&["**/*.o", "**/target/debug/.fingerprint/**"], &[root.into()], true),
+                   vec!["src/main.c", "target/debug/tool"]);
+        // named files are filtered too
+        assert_eq!(files(&["**/*.o"], &[root.join("src/main.o"), root.join("src/main.c")], false), vec!["src/main.c"]);
+    }
+
+    #[test]
+    fn walk_skips_state_and_sentinel() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a", local_state::FILE_NAME, sentinel::FILE_NAME] {
+            std::fs::write(dir.path().join(name), "x").unwrap();
+        }
+        let files = |walked: Vec<PathBuf>| -> Vec<String> {
+            walked.iter()
+                .filter(|p| std::path::Path::new(p.as_os_str()).is_file())
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(files(walk(&[dir.path().into()], true)), vec!["a"]);
+        assert!(files(walk(&[dir.path().join(sentinel::FILE_NAME)], false)).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn upload_skips_record_why() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let bad = std::ffi::OsStr::from_bytes(b"bad\xff");
+        for name in ["a", "a.o", local_state::FILE_NAME] {
+            std::fs::write(root.join(name), "x").unwrap();
+        }
+        std::fs::write(root.join(bad), "x").unwrap();
+        std::os::unix::fs::symlink(bad, root.join("link")).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(root.join("sock")).unwrap();
+
+        let mut skipped = Skips::default();
+        let exclude = GlobSet::new(["**/*.o"], PathRules::default()).unwrap();
+        let mut kept = Vec::new();
+        for path in upload_paths(&[root.into()], true, &exclude, &mut skipped) {
+            let meta = resolve_meta(path).await.unwrap();
+            match skip_reason(&meta) {
+                Some(reason) => skipped.add(reason, meta.path.to_string_lossy()),
+                None if meta.file.as_ref().is_some_and(std::fs::Metadata::is_file) =>
+                    kept.push(meta.path.file_name().unwrap().to_string_lossy().into_owned()),
+                None => {},
+            }
+        }
+        let name = |reason| -> Vec<String> {
+            skipped.paths(reason).iter().map(|p| p.rsplit('/').next().unwrap().to_owned()).collect()
+        };
+        assert_eq!(kept, vec!["a"]);
+        assert_eq!(name(SkipReason::Internal), vec![local_state::FILE_NAME]);
+        assert_eq!(name(SkipReason::Excluded), vec!["a.o"]);
+        assert_eq!(name(SkipReason::SpecialFile), vec!["sock"]);
+        let mut non_utf8 = name(SkipReason::NonUtf8);
+        non_utf8.sort();
+        assert_eq!(non_utf8, vec!["bad\u{fffd}", "link"]);
+        assert_eq!(skipped.len(), 5);
+    }
+
+    #[test]
+    fn unmade_links_follow_the_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("lib")).unwrap();
+        std::fs::create_dir_all(dir.path().join("share/data")).unwrap();
+        std::fs::write(dir.path().join("lib/libfoo.so.1"), "foo").unwrap();
+        std::fs::write(dir.path().join("share/data/x"), "x").unwrap();
+        let unmade = || vec![
+            // needs the next one copied first
+            ("alias".to_owned(), "lib/libfoo.so".to_owned()),
+            ("lib/libfoo.so".to_owned(), "libfoo.so.1".to_owned()),
+            ("data".to_owned(), "share/data".to_owned()),
+        ];
+
+        let mut skipped = Skips::default();
+        assert!(unmade_links(dir.path(), unmade(), Symlinks::Error, &mut skipped).is_err());
+        unmade_links(dir.path(), unmade(), Symlinks::Skip, &mut skipped).unwrap();
+        assert_eq!(skipped.paths(DownloadSkipReason::Symlink), ["alias", "lib/libfoo.so", "data"]);
+
+        let mut skipped = Skips::default();
+        unmade_links(dir.path(), unmade(), Symlinks::Copy, &mut skipped).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(std::fs::read_to_string(dir.path().join("alias")).unwrap(), "foo");
+        assert_eq!(std::fs::read_to_string(dir.path().join("data/x")).unwrap(), "x");
+
+        let outside = vec![("lib/up".to_owned(), "../../etc/passwd".to_owned())];
+        assert!(unmade_links(dir.path(), outside, Symlinks::Copy, &mut skipped).is_err());
+        let dangling = vec![("lib/gone".to_owned(), "missing".to_owned())];
+        assert!(unmade_links(dir.path(), dangling, Symlinks::Copy, &mut skipped).is_err());
+        let inside = vec![("share/data/loop".to_owned(), "..".to_owned())];
+        assert!(unmade_links(dir.path(), inside, Symlinks::Copy, &mut skipped).is_err());
+    }
+
+    #[tokio::test]
+    async fn large_files_are_hashed_on_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (big, small) = (dir.path().join("big.txt"), dir.path().join("small.txt"));
+        std::fs::write(&big, "x".repeat(100)).unwrap();
+        std::fs::write(&small, "x").unwrap();
+        let options = UploadOptions { threshold: 10, ..Default::default() };
+
+        let meta = meta_for(big.clone().into(), None, Some(10), FileHasher::new(HashAlgorithm::Sha256, 1)).await.unwrap();
+        assert!(meta.hash.is_none() && meta.hash_on_upload);
+        let planned = plan_file(&meta, "c", &options).unwrap().unwrap();
+        assert!(planned.is_unhashed());
+        assert_eq!(planned.content_type, content_type::OCTET_STREAM, "objects aren't typed by name");
+
+        let meta = meta_for(small.into(), None, Some(10), FileHasher::new(HashAlgorithm::Sha256, 1)).await.unwrap();
+        assert!(meta.hash.is_some() && !meta.hash_on_upload);
+        let planned = plan_file(&meta, "c", &options).unwrap().unwrap();
+        assert_eq!(planned.destination, Destination::Cache);
+        assert!(!planned.is_unhashed());
+
+        let meta = meta_for(big.into(), None, None, FileHasher::new(HashAlgorithm::Sha256, 1)).await.unwrap();
+        let planned = plan_file(&meta, "c", &options).unwrap().unwrap();
+        assert!(planned.key.unwrap().starts_with("objects/"), "plans hash everything");
+    }
+
+    #[tokio::test]
+    async fn status_of_normalised_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cafe\u{301}.txt");
+        std::fs::write(&path, "content").unwrap();
+        let mut entry = recorded(&path, 0, PreserveTimes::None).await;
+        assert!(entry.normalize_path(Normalization::Nfc).is_some());
+        let composed = entry.path_str().to_owned();
+        assert!(composed.ends_with("caf\u{e9}.txt"));
+
+        let r = compare_tree(vec![entry.clone()], Normalization::Nfc.into(), &[dir.path().into()], true, 0, HashAlgorithm::Sha256).await.unwrap();
+        assert_eq!(r.unchanged, vec![composed]);
+        assert!(r.in_sync());
+
+        // without the recorded form the local name looks new
+        let r = compare_tree(vec![entry], PathRules::default(), &[dir.path().into()], true, 0, HashAlgorithm::Sha256).await.unwrap();
+        assert_eq!(r.added.len(), 1);
+        assert_eq!(r.removed.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn status_uses_recorded_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, "before").unwrap();
+        let mut entry = recorded(&path, 0, PreserveTimes::Mtime).await;
+        let meta = resolve_meta(path.clone().into()).await.unwrap();
+
+        // matching mtime is trusted without hashing
+        entry.object = Some("not/the/real/hash".into());
+        assert_eq!(compare_file(&meta, &entry, 0, HashAlgorithm::Sha256).await.unwrap(), Change::Unchanged);
+
+        // different mtime falls back to the hash
+        entry.mtime = entry.mtime.map(|t| t - chrono::Duration::seconds(10));
+        assert_eq!(compare_file(&meta, &entry, 0, HashAlgorithm::Sha256).await.unwrap(), Change::Modified);
+        let mut fresh = recorded(&path, 0, PreserveTimes::None).await;
+        fresh.mtime = entry.mtime;
+        assert_eq!(compare_file(&meta, &fresh, 0, HashAlgorithm::Sha256).await.unwrap(), Change::Unchanged);
+    }
+
+    #[tokio::test]
+    async fn files_in_place_are_not_fetched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, "content").unwrap();
+        let entry = recorded(&path, 0, PreserveTimes::None).await;
+        let local = async_std::path::PathBuf::from(path.clone());
+        assert!(in_place(&local, &entry, HashAlgorithm::Sha256).await.unwrap());
+
+        // same size, different content
+        std::fs::write(&path, "CONTENT").unwrap();
+        assert!(!in_place(&local, &entry, HashAlgorithm::Sha256).await.unwrap());
+
+        let link = cache::File { link_target: Some("b".into()), ..entry.clone() };
+        assert!(!in_place(&local, &link, HashAlgorithm::Sha256).await.unwrap(), "a file where a symlink goes");
+        std::fs::remove_file(&path).unwrap();
+        assert!(!in_place(&local, &entry, HashAlgorithm::Sha256).await.unwrap());
+        std::os::unix::fs::symlink("b", &path).unwrap();
+        assert!(in_place(&local, &link, HashAlgorithm::Sha256).await.unwrap());
+        assert!(!in_place(&local, &cache::File { link_target: Some("c".into()), ..entry.clone() }, HashAlgorithm::Sha256).await.unwrap());
+        assert!(!in_place(&local, &entry, HashAlgorithm::Sha256).await.unwrap(), "a symlink where a file goes");
+    }
+
+    #[tokio::test]
+    async fn unreadable_files_fail_or_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ok"), "content").unwrap();
+        let secret = dir.path().join("secret");
+        std::fs::write(&secret, "content").unwrap();
+        std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if std::fs::File::open(&secret).is_ok() {
+            // root reads it anyway
+            return;
+        }
+        let credentials = || Ok::<_, crate::Error>(::s3::creds::Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap());
+        let storage = Storage::builder("bucket").endpoint("http://127.0.0.1:9").credentials(Arc::new(credentials))
+            .offline(true).connect().await.unwrap();
+        let paths = [dir.path().to_owned()];
+
+        let options = UploadOptions { recurse: true, ..Default::default() };
+        let err = plan_upload(&storage, "c", &paths, &options, false).await.unwrap_err();
+        match err.downcast_ref::<crate::Error>() {
+            Some(crate::Error::PermissionDenied { path, .. }) => assert!(path.ends_with("secret"), "{}", path),
+            _ => panic!("expected permission denied, got {:?}", err),
+        }
+
+        let options = UploadOptions { recurse: true, keep_going: true, ..Default::default() };
+        let plan = plan_upload(&storage, "c", &paths, &options, false).await.unwrap();
+        assert_eq!(plan.skipped.paths(SkipReason::PermissionDenied), [secret.to_string_lossy().into_owned()]);
+        assert!(plan.files.iter().any(|f| f.entry.path_str().ends_with("ok")));
+    }
+
+    #[tokio::test]
+    async fn paths_below_a_base_are_recorded_relative_to_it() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("tree/empty")).unwrap();
+        std::fs::write(dir.path().join("tree/a"), "content").unwrap();
+        let credentials = || Ok::<_, crate::Error>(::s3::creds::Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap());
+        let storage = Storage::builder("bucket").endpoint("http://127.0.0.1:9").credentials(Arc::new(credentials))
+            .offline(true).connect().await.unwrap();
+
+        let options = UploadOptions { recurse: true, base: Some(dir.path().to_owned()), ..Default::default() };
+        let plan = plan_upload(&storage, "c", &[std::path::PathBuf::from("tree")], &options, false).await.unwrap();
+        let [file] = plan.files.as_slice() else { panic!("expected one file, got {:?}", plan.files) };
+        assert_eq!(file.entry.path_str(), "tree/a");
+        assert_eq!(file.key.as_deref(), Some("cache/c/files/tree/a"));
+        assert_eq!(file.local_path(), dir.path().join("tree/a"));
+        assert_eq!(plan.dirs.iter().map(|d| d.path.as_str()).collect::<Vec<_>>(), ["tree/empty"]);
+    }
+
+    #[tokio::test]
+    async fn empty_directories_are_recorded_and_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("tree");
+        for d in ["empty", "nested/deeper", "linkonly", "full"] {
+            std::fs::create_dir_all(root.join(d)).unwrap();
+        }
+        std::os::unix::fs::symlink("../full/a", root.join("linkonly/link")).unwrap();
+        std::fs::write(root.join("full/a"), "content").unwrap();
+        std::fs::set_permissions(root.join("empty"), std::fs::Permissions::from_mode(0o750)).unwrap();
+        let credentials = || Ok::<_, crate::Error>(::s3::creds::Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap());
+        let storage = Storage::builder("bucket").endpoint("http://127.0.0.1:9").credentials(Arc::new(credentials))
+            .offline(true).connect().await.unwrap();
+
+        let options = UploadOptions { recurse: true, ..Default::default() };
+        let plan = plan_upload(&storage, "c", &[root.clone()], &options, false).await.unwrap();
+        let prefix = format!("{}/", slash(async_std::path::Path::new(root.as_os_str())));
+        let relative: Vec<cache::Dir> = plan.dirs.iter()
+            .map(|d| cache::Dir { path: d.path.strip_prefix(&prefix).unwrap().to_owned(), mode: d.mode })
+            .collect();
+        assert_eq!(relative.iter().map(|d| d.path.as_str()).collect::<Vec<_>>(), ["empty", "nested", "nested/deeper"]);
+        assert_eq!(relative[0].mode.map(|m| m & 0o777), Some(0o750));
+
+        // only the symlink, as the file's object isn't in the bucket
+        let link = cache::File::new_async(async_std::path::Path::new("linkonly/link"), None, 8, None, Some("../full/a".into()));
+        let c = Cache { files: vec![link], dirs: relative, ..Default::default() };
+        let restored = dir.path().join("out");
+        download_entry(storage, "c", c, restored.clone(), &DownloadOptions::default()).await.unwrap();
+        assert!(restored.join("empty").is_dir());
+        assert_eq!(std::fs::metadata(restored.join("empty")).unwrap().permissions().mode() & 0o777, 0o750);
+        assert!(restored.join("nested/deeper").is_dir());
+        assert_eq!(std::fs::read_link(restored.join("linkonly/link")).unwrap(), std::path::Path::new("../full/a"));
+        assert!(!restored.join("full").exists());
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_are_collected_with_keep_going() {
+        let dir = tempfile::tempdir().unwrap();
+        let credentials = || Ok::<_, crate::Error>(::s3::creds::Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap());
+        let storage = Storage::builder("bucket").endpoint("http://127.0.0.1:9").credentials(Arc::new(credentials))
+            .offline(true).connect().await.unwrap();
+        // offline, so each file fails to fetch, while the symlink is made
+        let cache = || Cache { files: vec![
+            cache::File::new_async(async_std::path::Path::new("a"), None, 1, None, None),
+            cache::File::new_async(async_std::path::Path::new("b"), None, 1, None, None),
+            cache::File::new_async(async_std::path::Path::new("link"), None, 1, None, Some("a".into())),
+        ], ..Default::default() };
+
+        let err = download_entry(storage.clone(), "c", cache(), dir.path().join("first"), &DownloadOptions::default())
+            .await.unwrap_err();
+        assert!(!matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::FilesFailed { .. })), "{:?}", err);
+
+        let options = DownloadOptions { keep_going: true, ..Default::default() };
+        let err = download_entry(storage, "c", cache(), dir.path().join("all"), &options).await.unwrap_err();
+        match err.downcast_ref::<crate::Error>() {
+            Some(crate::Error::FilesFailed { operation: "download", cache, failures }) => {
+                assert_eq!(cache, "c");
+                assert_eq!(failures.iter().map(|f| f.path.as_str()).collect::<std::collections::BTreeSet<_>>(),
+                           ["a", "b"].into());
+                assert!(failures[0].error.contains("Offline"), "{}", failures[0].error);
+            },
+            _ => panic!("expected failed files, got {:?}", err),
+        }
+        assert!(std::fs::symlink_metadata(dir.path().join("all/link")).is_ok());
+    }
+
+    #[test]
+    fn shared_objects_are_fetched_once() {
+        let object = |p: &str, b: u8| cache::File::new_async(async_std::path::Path::new(p),
+                                                              Some(ObjectKey::from_digest(&[b; 32])), 1, None, None);
+        let files = vec![object("a", 1), object("b", 2), object("copy-of-a", 1), object("again-a", 1),
+                         cache::File::new_async(async_std::path::Path::new("link"), Some(ObjectKey::from_digest(&[1; 32])),
+                                                1, None, Some("a".into()))];
+        let (fetch, shared) = share_objects(files);
+        assert_eq!(fetch.iter().map(cache::File::path_str).collect::<Vec<_>>(), ["a", "b", "link"]);
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].0.path_str(), "a");
+        assert_eq!(shared[0].1.iter().map(cache::File::path_str).collect::<Vec<_>>(), ["copy-of-a", "again-a"]);
+    }
+
+    #[tokio::test]
+    async fn shared_objects_are_requested_once() {
+        #[derive(Default)]
+        struct Started(std::sync::Mutex<Vec<String>>);
+        impl ProgressSink for Started {
+            fn on_file_start(&self, path: &str, _size: u64) {
+                self.0.lock().unwrap().push(path.to_owned());
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let credentials = || Ok::<_, crate::Error>(::s3::creds::Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap());
+        let storage = Storage::builder("bucket").endpoint("http://127.0.0.1:9").credentials(Arc::new(credentials))
+            .offline(true).connect().await.unwrap();
+        let object = Some(ObjectKey::from_digest(&[1; 32]));
+        let cache = Cache { files: vec![
+            cache::File::new_async(async_std::path::Path::new("a"), object.clone(), 1, None, None),
+            cache::File::new_async(async_std::path::Path::new("b"), object, 1, None, None),
+        ], ..Default::default() };
+
+        let started = Arc::new(Started::default());
+        let options = DownloadOptions { keep_going: true, progress: started.clone(), ..Default::default() };
+        let err = download_entry(storage, "c", cache, dir.path().to_owned(), &options).await.unwrap_err();
+        assert_eq!(*started.0.lock().unwrap(), ["a"]);
+        match err.downcast_ref::<crate::Error>() {
+            Some(crate::Error::FilesFailed { failures, .. }) => {
+                assert_eq!(failures.iter().map(|f| f.path.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+                assert!(failures[1].error.contains("sharing its content"), "{}", failures[1].error);
+            },
+            _ => panic!("expected failed files, got {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_objects_are_copied_or_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let credentials = || Ok::<_, crate::Error>(::s3::creds::Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap());
+        let storage = Storage::builder("bucket").endpoint("http://127.0.0.1:9").credentials(Arc::new(credentials))
+            .offline(true).connect().await.unwrap();
+        let base: PathBuf = dir.path().to_owned().into();
+        let object = Some(ObjectKey::from_digest(&[1; 32]));
+        let entry = |p: &str, mode| cache::File::new_async(async_std::path::Path::new(p), object.clone(), 4, mode, None);
+        let (from, copy, link) = (entry("a", Some(0o644)), entry("sub/copy", Some(0o600)), entry("link", Some(0o644)));
+        std::fs::write(dir.path().join("a"), "data").unwrap();
+        std::fs::write(dir.path().join("link"), "old").unwrap();
+        let fsync = Fsync::new(FsyncPolicy::None);
+
+        restore_copy(&storage, &from, &copy, &base, &fsync, true).await.unwrap();
+        restore_copy(&storage, &from, &link, &base, &fsync, true).await.unwrap();
+        for p in ["sub/copy", "link"] {
+            assert_eq!(std::fs::read_to_string(dir.path().join(p)).unwrap(), "data");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let meta = |p: &str| std::fs::metadata(dir.path().join(p)).unwrap();
+            // a differing mode can't be shared, so that's a copy
+            assert_eq!(meta("sub/copy").mode() & 0o777, 0o600);
+            assert_ne!(meta("sub/copy").ino(), meta("a").ino());
+            assert_eq!(meta("link").ino(), meta("a").ino());
+        }
+    }
+
+    #[test]
+    fn files_are_located_through_links() {
+        let file = |p: &str, object: Option<u8>, link: Option<&str>| cache::File::new_async(
+            async_std::path::Path::new(p), object.map(|b| ObjectKey::from_digest(&[b; 32])), 1, None, link.map(Into::into));
+        let c = Cache { files: vec![
+            file("index.json", Some(1), None),
+            file("inline.txt", None, None),
+            file("dir/latest.json", None, Some("../index.json")),
+            file("latest", None, Some("dir/latest.json")),
+            file("loop-a", None, Some("loop-b")),
+            file("loop-b", None, Some("loop-a")),
+            file("dir/outside", None, Some("../../etc/passwd")),
+            file("absolute", None, Some("/etc/passwd")),
+            file("dangling", None, Some("gone")),
+        ], ..Default::default() };
+        let key = |p: &str| locate(&c, "c", p).unwrap().storage_path("c").to_str().unwrap().to_owned();
+
+        assert_eq!(key("index.json"), c.files[0].object_storage_key().unwrap());
+        assert_eq!(key("./inline.txt"), "cache/c/files/inline.txt");
+        assert_eq!(key("dir/latest.json"), key("index.json"));
+        assert_eq!(key("latest"), key("index.json"));
+
+        let error = |p: &str| locate(&c, "c", p).unwrap_err().downcast::<crate::Error>().unwrap();
+        assert!(matches!(error("missing"), crate::Error::FileNotFound { path, .. } if path == "missing"));
+        assert!(matches!(error("dangling"), crate::Error::FileNotFound { path, .. } if path == "gone"));
+        assert!(matches!(error("dir"), crate::Error::FileNotFound { .. }));
+        for p in ["loop-a", "dir/outside", "absolute"] {
+            assert!(matches!(error(p), crate::Error::UnreadableLink { path, .. } if path == p), "{}", p);
+        }
+    }
+
+    #[tokio::test]
+    async fn unsafe_entries_restore_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let credentials = || Ok::<_, crate::Error>(::s3::creds::Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap());
+        let storage = Storage::builder("bucket").endpoint("http://127.0.0.1:9").credentials(Arc::new(credentials))
+            .offline(true).connect().await.unwrap();
+        let cache = Cache { files: vec![
+            cache::File::new_async(async_std::path::Path::new("link"), None, 1, None, Some("a".into())),
+            cache::File::new_async(async_std::path::Path::new("sub/../../escaped"), None, 1, None, None),
+        ], dirs: vec![cache::Dir { path: "empty".into(), mode: None }], ..Default::default() };
+
+        let out = dir.path().join("out");
+        let err = download_entry(storage, "c", cache, out.clone(), &DownloadOptions::default()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::UnsafePath(path)) if path == "sub/../../escaped"),
+                "{:?}", err);
+        assert!(!out.exists());
+    }
+
+    #[tokio::test]
+    async fn manifest_hash_routes_by_threshold() {
+        let (_dir, file) = fixture();
+        let meta = meta_for(file.clone().into(), manifest(&file, 0), None, FileHasher::new(HashAlgorithm::Sha256, 1)).await.unwrap();
+        let object = route_object(&meta, 12, 0).unwrap();
+        assert!(object.starts_with("e3b0c442"));
+        assert!(route_object(&meta, 12, 12).is_none());
+    }
 }
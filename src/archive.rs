@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::{display::local_display, Result};
+
+/// Name at the root of an export holding the cache entry, as stored
+pub const META: &str = ".s3cache_meta.json";
+
+/// A directory under the system temp directory, removed with everything
+/// in it when dropped
+#[derive(Debug)]
+pub(crate) struct Staging(PathBuf);
+
+impl Staging {
+    pub fn new(purpose: &str) -> Result<Staging> {
+        let path = std::env::temp_dir().join(format!("s3-cache-{}-{}", purpose, uuid::Uuid::new_v4()));
+        std::fs::create_dir(&path).with_context(|| format!("Failed to create {}", local_display(&path)))?;
+        Ok(Staging(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            log::warn!("Unable to remove {}: {}", local_display(&self.0), e);
+        }
+    }
+}
+
+/// Write meta as [META], then everything under dir, as a tar at dest.
+/// Symlinks are stored as links, and modes and times kept.
+pub(crate) fn pack(dir: &Path, meta: &[u8], dest: &Path) -> Result<()> {
+    let out = std::fs::File::create(dest).with_context(|| format!("Failed to create {}", local_display(dest)))?;
+    let mut tar = tar::Builder::new(std::io::BufWriter::new(out));
+    tar.follow_symlinks(false);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(meta.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+    tar.append_data(&mut header, META, meta)?;
+    tar.append_dir_all(".", dir)?;
+    tar.into_inner()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// Unpack the tar at archive into dir, returning [META]'s content and
+/// leaving only the files beside it
+pub(crate) fn unpack(archive: &Path, dir: &Path) -> Result<Vec<u8>> {
+    let f = std::fs::File::open(archive).with_context(|| format!("Failed to open {}", local_display(archive)))?;
+    let mut tar = tar::Archive::new(std::io::BufReader::new(f));
+    tar.set_preserve_permissions(true);
+    tar.unpack(dir).with_context(|| format!("Failed to unpack {}", local_display(archive)))?;
+    let meta = dir.join(META);
+    let content = std::fs::read(&meta)
+        .with_context(|| format!("{} is not an export: it has no {}", local_display(archive), META))?;
+    std::fs::remove_file(&meta)?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn packed_trees_unpack_the_same() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(src.path().join("dir/empty")).unwrap();
+        std::fs::write(src.path().join("dir/text.txt"), "some text").unwrap();
+        std::fs::write(src.path().join("top"), "top").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("text.txt", src.path().join("dir/link")).unwrap();
+
+        let out = tempfile::tempdir().unwrap();
+        let archive = out.path().join("export.tar");
+        pack(src.path(), b"{\"entry\": true}", &archive).unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        assert_eq!(unpack(&archive, dest.path()).unwrap(), b"{\"entry\": true}");
+        assert!(!dest.path().join(META).exists());
+        assert_eq!(std::fs::read_to_string(dest.path().join("dir/text.txt")).unwrap(), "some text");
+        assert_eq!(std::fs::read_to_string(dest.path().join("top")).unwrap(), "top");
+        assert!(dest.path().join("dir/empty").is_dir());
+        #[cfg(unix)]
+        assert_eq!(std::fs::read_link(dest.path().join("dir/link")).unwrap(), Path::new("text.txt"));
+    }
+
+    #[test]
+    fn archives_need_meta() {
+        let src = tempfile::tempdir().unwrap();
+        let archive = src.path().join("plain.tar");
+        let mut tar = tar::Builder::new(std::fs::File::create(&archive).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "file", &b"x"[..]).unwrap();
+        tar.into_inner().unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let e = unpack(&archive, dest.path()).unwrap_err();
+        assert!(format!("{:#}", e).contains("is not an export"), "{:#}", e);
+    }
+
+    #[test]
+    fn staging_is_removed() {
+        let staging = Staging::new("test").unwrap();
+        let path = staging.path().to_owned();
+        std::fs::write(path.join("file"), "x").unwrap();
+        drop(staging);
+        assert!(!path.exists());
+    }
+}
@@ -2,10 +2,22 @@
 // (C) Copyright 2025 Greg Whiteley
 
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
 
-use super::Result;
+use super::{Error, Result, Storage};
+use crate::compression::CompressionCodec;
+use crate::links::LinkKind;
+use crate::merkle::{self, MerkleHasher};
+use crate::object::{self, ObjectKey};
+use crate::paths::{GlobSet, PathRules};
+use crate::times::FileTimes;
+use crate::unicode::Normalization;
+use chrono::{DateTime, Utc};
 use sha2::{Sha256, Digest};
 use tokio::io::AsyncReadExt;
+use tokio::sync::Semaphore;
 use path_slash::PathExt as _;
 use path_slash::PathBufExt as _;
 
@@ -13,14 +25,261 @@ use serde::{Deserialize, Serialize};
 
 /// Wrap cache entries in version number we so we can be incompatible later
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub(crate) enum CacheVersions {
+pub(crate) enum CacheVersions<F = Vec<File>> {
     #[serde(rename = "v1")]
     V1(Cache),
+    #[serde(rename = "v2")]
+    V2(CacheV2<F>),
+}
+
+/// Formats decode understands, so newer ones can be told from corruption
+const KNOWN_VERSIONS: [&str; 2] = ["v1", "v2"];
+
+/// Which of [CacheVersions] an entry was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SchemaVersion {
+    V1,
+    V2,
+}
+
+impl SchemaVersion {
+    /// As recorded in [CacheV2::schema_version]
+    pub fn number(self) -> u32 {
+        match self {
+            SchemaVersion::V1 => 1,
+            SchemaVersion::V2 => 2,
+        }
+    }
+}
+
+/// V2 entries written before schema_version was recorded are still V2
+fn schema_v2() -> u32 {
+    SchemaVersion::V2.number()
+}
+
+/// How content is hashed to key deduplicated objects.  All give 32 bytes,
+/// split into the same [ObjectKey] layout.  Written as "sha256", "blake3"
+/// or "sha256-merkle-<chunk bytes>".
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// Several times faster, but objects aren't shared with sha256 caches
+    Blake3,
+    /// sha256 of chunks of this many bytes, hashed in parallel, then of
+    /// their digests.  See [crate::merkle].
+    Sha256Merkle(u64),
+}
+
+impl HashAlgorithm {
+    pub fn is_sha256(&self) -> bool {
+        *self == HashAlgorithm::Sha256
+    }
+
+    pub(crate) fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgorithm::Sha256Merkle(chunk) => Hasher::Merkle(Box::new(MerkleHasher::new(chunk))),
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => f.write_str("sha256"),
+            HashAlgorithm::Blake3 => f.write_str("blake3"),
+            HashAlgorithm::Sha256Merkle(chunk) => write!(f, "sha256-merkle-{}", chunk),
+        }
+    }
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+
+    /// "sha256-merkle" alone takes the default chunk size
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256-merkle" => Ok(HashAlgorithm::Sha256Merkle(merkle::DEFAULT_CHUNK)),
+            _ => match s.strip_prefix("sha256-merkle-").map(str::parse::<u64>) {
+                Some(Ok(chunk)) if chunk >= merkle::MIN_CHUNK => Ok(HashAlgorithm::Sha256Merkle(chunk)),
+                Some(Ok(_)) => Err(format!("chunks of '{}' must be at least {} bytes", s, merkle::MIN_CHUNK)),
+                _ => Err(format!("unknown hash algorithm '{}', expected sha256, blake3 or sha256-merkle[-<chunk bytes>]", s)),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for HashAlgorithm {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<HashAlgorithm> for String {
+    fn from(algorithm: HashAlgorithm) -> String {
+        algorithm.to_string()
+    }
+}
+
+/// Incremental hash in any algorithm
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Merkle(Box<MerkleHasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => { h.update(data); },
+            Hasher::Merkle(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            Hasher::Sha256(h) => h.finalize().into(),
+            Hasher::Blake3(h) => *h.finalize().as_bytes(),
+            Hasher::Merkle(h) => h.finalize(),
+        }
+    }
+}
+
+/// Hashes files for upload, sharing a limit on the chunks hashed at once
+/// between every file
+#[derive(Debug, Clone)]
+pub(crate) struct FileHasher {
+    pub algorithm: HashAlgorithm,
+    jobs: Arc<Semaphore>,
+}
+
+impl FileHasher {
+    pub fn new(algorithm: HashAlgorithm, jobs: usize) -> FileHasher {
+        FileHasher { algorithm, jobs: Arc::new(Semaphore::new(jobs.max(1))) }
+    }
+
+    pub async fn hash(&self, path: &async_std::path::Path, len: &Option<u64>) -> Result<[u8;32]> {
+        match self.algorithm {
+            HashAlgorithm::Sha256Merkle(chunk) => merkle::hash_file(std::path::Path::new(path.as_os_str()), chunk, &self.jobs).await,
+            algorithm => read_hash_stream(path, len, algorithm).await,
+        }
+    }
+}
+
+/// Chunks hashed at once by default: one per CPU
+pub fn default_hash_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+}
+
+/// An entry as V2 writes it, recording who wrote it and when.  Read into a
+/// [Cache], as V1 entries are.  Written from anything serialising as the
+/// list of files, so upload needn't gather them into one.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct CacheV2<F = Vec<File>> {
+    /// None when upgraded from a V1 entry that didn't record it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Utc>>,
+    /// Version of s3-cache that first wrote the entry, None as for created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub writer_version: Option<String>,
+    /// Free-form, as given to upload --label, eg a git SHA
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Total size of the files as restored
+    pub size: u64,
+    pub files: F,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dirs: Vec<Dir>,
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub dir_acls: std::collections::BTreeMap<String, crate::acls::Acls>,
+    #[serde(default, skip_serializing_if = "Normalization::is_off")]
+    pub normalization: Normalization,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_name: Option<String>,
+    #[serde(default, skip_serializing_if = "HashAlgorithm::is_sha256")]
+    pub hash_algorithm: HashAlgorithm,
+    /// Always 2, for tooling reading the entry without the wrapper
+    #[serde(default = "schema_v2")]
+    pub schema_version: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub(crate) struct Cache {
     pub files: Vec<File>,
+    /// Directories no file is restored into, so download creates them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dirs: Vec<Dir>,
+    /// POSIX ACLs of directories, when recorded with --acls
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub dir_acls: std::collections::BTreeMap<String, crate::acls::Acls>,
+    /// Unicode form paths were recorded in, with --unicode-normalize
+    #[serde(default, skip_serializing_if = "Normalization::is_off")]
+    pub normalization: Normalization,
+    /// When the entry was first uploaded, by the uploader's clock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// The name it was uploaded as, kept when copied or renamed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_name: Option<String>,
+    /// Version of s3-cache that wrote a V2 entry, None for V1 and what's
+    /// rewritten from one
+    #[serde(skip)]
+    pub writer_version: Option<String>,
+    /// Free-form label given to upload, V2 only
+    #[serde(skip)]
+    pub label: Option<String>,
+    /// What objects are keyed by, sha256 for V1
+    #[serde(skip)]
+    pub hash_algorithm: HashAlgorithm,
+}
+
+impl From<CacheV2> for Cache {
+    fn from(c: CacheV2) -> Cache {
+        Cache {
+            files: c.files,
+            dirs: c.dirs,
+            dir_acls: c.dir_acls,
+            normalization: c.normalization,
+            created_at: c.created,
+            origin_name: c.origin_name,
+            writer_version: c.writer_version,
+            label: c.label,
+            hash_algorithm: c.hash_algorithm,
+        }
+    }
+}
+
+impl From<Cache> for CacheV2 {
+    fn from(c: Cache) -> CacheV2 {
+        migrate_v1_to_v2(c)
+    }
+}
+
+/// The V2 entry for c, as written back after a V1 entry is read.  Keeps who
+/// wrote the entry and when, so rewriting an upgraded V1 entry doesn't make
+/// up either.
+pub(crate) fn migrate_v1_to_v2(c: Cache) -> CacheV2 {
+    CacheV2 {
+        created: c.created_at,
+        writer_version: c.writer_version,
+        label: c.label,
+        size: c.size(),
+        files: c.files,
+        dirs: c.dirs,
+        dir_acls: c.dir_acls,
+        normalization: c.normalization,
+        origin_name: c.origin_name,
+        hash_algorithm: c.hash_algorithm,
+        schema_version: schema_v2(),
+    }
 }
 
 impl Cache {
@@ -37,16 +296,243 @@ impl Cache {
         PathBuf::from(b.to_slash().expect("slash conversion").as_ref())
     }
 
+    /// How this entry's paths compare, as recorded
+    pub fn path_rules(&self) -> PathRules {
+        PathRules::from(self.normalization)
+    }
+
+    /// Total size of the files as restored
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    /// Always written as V2
     pub fn into_string(self) -> String {
-        let cache = CacheVersions::V1(self);
+        let cache: CacheVersions = CacheVersions::V2(self.into());
         serde_json::to_string(&cache).expect("Cache entries should be serialiseable")
     }
 }
 
-pub(crate) fn decode(v: &[u8]) -> Result<Cache> {
-    let x: CacheVersions = serde_json::from_str(std::str::from_utf8(v)?)?;
-    match x {
-        CacheVersions::V1(c) => Ok(c),
+/// Names directly under [Cache::location] that hold metadata - user files
+/// always live below `files/` so can never be stored at these
+pub(crate) const RESERVED: [&str; 4] = ["entry", "pinned", "last-access", crate::access::LOG_NAME];
+
+pub(crate) fn is_reserved(name: &str) -> bool {
+    RESERVED.contains(&name) || name.starts_with("entry.prev.")
+}
+
+/// A key below `cache/`, as seen by tooling listing the prefix
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum CacheKey<'a> {
+    /// Reserved metadata, eg the entry itself
+    Meta(&'a str),
+    /// A user file stored with the cache, relative to `files/`
+    File(&'a str),
+}
+
+/// Split a listed key into cache name and what it holds, None if it's neither
+pub(crate) fn parse_key(key: &str) -> Option<(&str, CacheKey<'_>)> {
+    let (name, rest) = key.strip_prefix("cache/")?.split_once('/')?;
+    if let Some(file) = rest.strip_prefix("files/") {
+        Some((name, CacheKey::File(file)))
+    } else if is_reserved(rest) {
+        Some((name, CacheKey::Meta(rest)))
+    } else {
+        None
+    }
+}
+
+/// Would key land on some cache's metadata once "." and ".." are resolved,
+/// as tooling that normalises keys will do
+pub(crate) fn is_reserved_key(key: &str) -> bool {
+    matches!(resolve_dots(key).as_slice(), ["cache", _, name] if is_reserved(name))
+}
+
+/// Lexically resolve "." and ".." in a slash separated path
+pub(crate) fn resolve_dots(path: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "." | "" => {},
+            ".." => { parts.pop(); },
+            p => parts.push(p),
+        }
+    }
+    parts
+}
+
+/// Largest entry read unless --max-entry-size says otherwise
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 256 << 20;
+
+/// Longest path or link target accepted in an entry, as Linux's PATH_MAX
+const MAX_PATH_LEN: usize = 4096;
+
+/// Most extended attributes one file may record
+const MAX_XATTRS: usize = 1024;
+
+/// Longest extended attribute name, as Linux's XATTR_NAME_MAX
+const MAX_XATTR_NAME_LEN: usize = 255;
+
+/// Start of a long string, for naming it in errors
+fn abbreviate(s: &str) -> String {
+    match s.char_indices().nth(40) {
+        Some((i, _)) => format!("{}...", &s[..i]),
+        None => s.to_owned(),
+    }
+}
+
+impl Cache {
+    /// Reject field values no honest upload would produce
+    fn check_limits(&self) -> std::result::Result<(), String> {
+        let paths = self.files.iter().map(|f| f.path.as_str())
+            .chain(self.dirs.iter().map(|d| d.path.as_str()))
+            .chain(self.dir_acls.keys().map(String::as_str));
+        let targets = self.files.iter().filter_map(|f| f.link_target.as_deref());
+        for (what, s) in paths.map(|p| ("path", p)).chain(targets.map(|t| ("link target", t))) {
+            if s.len() > MAX_PATH_LEN {
+                return Err(format!("{} '{}' is {} bytes, more than {}", what, abbreviate(s), s.len(), MAX_PATH_LEN));
+            }
+        }
+        for f in &self.files {
+            let Some(xattrs) = &f.xattrs else {
+                continue;
+            };
+            if xattrs.len() > MAX_XATTRS {
+                return Err(format!("'{}' has {} extended attributes, more than {}", abbreviate(&f.path), xattrs.len(), MAX_XATTRS));
+            }
+            if let Some(name) = xattrs.keys().find(|n| n.len() > MAX_XATTR_NAME_LEN) {
+                return Err(format!("'{}' has extended attribute '{}' longer than {} bytes", abbreviate(&f.path), abbreviate(name), MAX_XATTR_NAME_LEN));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether an entry path stays below the directory it's restored into:
+/// relative, without a .. component, and on no platform a drive or UNC path
+pub(crate) fn is_contained(path: &str) -> bool {
+    let drive = matches!(path.as_bytes(), [letter, b':', ..] if letter.is_ascii_alphabetic());
+    if path.starts_with(['/', '\\']) || drive || path.split(['/', '\\']).any(|part| part == "..") {
+        return false;
+    }
+    File::path_of(path).components().all(|c| matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir))
+}
+
+impl Cache {
+    /// Reject paths that would restore outside the output directory,
+    /// including those below a symlink of the entry, which may lead anywhere
+    pub(crate) fn check_paths(&self) -> std::result::Result<(), Error> {
+        let paths = || self.files.iter().map(|f| f.path.as_str())
+            .chain(self.dirs.iter().map(|d| d.path.as_str()))
+            .chain(self.dir_acls.keys().map(String::as_str));
+        if let Some(path) = paths().find(|p| !is_contained(p)) {
+            return Err(Error::UnsafePath(path.to_owned()));
+        }
+        let rules = self.path_rules();
+        let normal = |p: &str| rules.key(&p.split('/').filter(|part| !part.is_empty() && *part != ".")
+                                          .collect::<Vec<_>>().join("/")).into_owned();
+        let links: std::collections::HashSet<String> = self.files.iter()
+            .filter(|f| f.link_target.is_some())
+            .map(|f| normal(&f.path))
+            .collect();
+        for path in paths() {
+            let key = normal(path);
+            let mut parent = key.as_str();
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                if links.contains(dir) {
+                    return Err(Error::UnsafePath(format!("{} (below the symlink {})", path, dir)));
+                }
+                parent = dir;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the entry of cache_name, and which version it was written as.
+/// serde_json's recursion limit is left on, so hostile nesting fails rather
+/// than overflowing the stack.
+pub(crate) fn decode(cache_name: &str, v: &[u8]) -> Result<(Cache, SchemaVersion)> {
+    let corrupt = |reason: String| Error::CorruptEntry { cache: cache_name.to_owned(), reason };
+    let x: CacheVersions = serde_json::from_slice(v).map_err(|e| match newer_version(v) {
+        Some(version) => Error::UnsupportedEntryVersion { cache: cache_name.to_owned(), version },
+        None => corrupt(e.to_string()),
+    })?;
+    let (c, version) = match x {
+        CacheVersions::V1(c) => (c, SchemaVersion::V1),
+        CacheVersions::V2(c) => (c.into(), SchemaVersion::V2),
+    };
+    c.check_limits().map_err(corrupt)?;
+    Ok((c, version))
+}
+
+/// The version an entry that failed to decode is wrapped in, if it's one
+/// this release doesn't know rather than a damaged known one
+fn newer_version(v: &[u8]) -> Option<String> {
+    let wrapper: std::collections::BTreeMap<String, serde::de::IgnoredAny> = serde_json::from_slice(v).ok()?;
+    match wrapper.keys().collect::<Vec<_>>().as_slice() {
+        [version] if !KNOWN_VERSIONS.contains(&version.as_str()) => Some((*version).clone()),
+        _ => None,
+    }
+}
+
+/// Collects a download, failing once it passes a limit
+struct CappedWriter {
+    buf: Vec<u8>,
+    limit: u64,
+    exceeded: bool,
+}
+
+impl tokio::io::AsyncWrite for CappedWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if (this.buf.len() + buf.len()) as u64 > this.limit {
+            this.exceeded = true;
+            return Poll::Ready(Err(std::io::Error::other("entry too large")));
+        }
+        this.buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Download the entry of cache_name at key, refusing any larger than
+/// [Storage::max_entry_size] and warning as one approaches it
+pub(crate) async fn read_entry(storage: &Storage, cache_name: &str, key: &str) -> std::result::Result<Vec<u8>, Error> {
+    let limit = storage.max_entry_size();
+    let mut writer = CappedWriter { buf: Vec::new(), limit, exceeded: false };
+    let result = storage.get_file(&mut writer, key).await;
+    match result {
+        Err(_) if writer.exceeded => return Err(Error::CorruptEntry {
+            cache: cache_name.to_owned(),
+            reason: format!("larger than {} bytes (raise --max-entry-size to read it)", limit),
+        }),
+        result => result?,
+    }
+    if writer.buf.len() as u64 > limit / 4 {
+        log::warn!("Entry of cache '{}' is {} bytes, approaching the {} byte limit", cache_name, writer.buf.len(), limit);
+    }
+    Ok(writer.buf)
+}
+
+/// A directory as recorded in an entry
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct Dir {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+impl Dir {
+    pub fn path(&self) -> PathBuf {
+        File::path_of(&self.path)
     }
 }
 
@@ -57,6 +543,28 @@ pub(crate) struct File {
     pub size: u64,
     pub mode: Option<u32>,
     pub link_target: Option<String>,
+    /// Modification time, when recorded with --preserve-times
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<DateTime<Utc>>,
+    /// Creation (birth) time, when recorded and the platform reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub btime: Option<DateTime<Utc>>,
+    /// Extended attributes, when recorded with --xattrs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<crate::xattrs::Xattrs>,
+    /// POSIX ACLs, when recorded with --acls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acls: Option<crate::acls::Acls>,
+    /// Hex sha256 of a regular file's content; older entries lack it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// How the content is compressed in storage, when uploaded with --compress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionCodec>,
+    /// Whether a symlink's target was a file or directory; older entries
+    /// and dangling links lack it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_kind: Option<LinkKind>,
 }
 
 impl File {
@@ -66,60 +574,131 @@ impl File {
             object: object.map(|x| x.to_slash().expect("path->slash").to_string()),
             size,
             mode,
-            link_target
+            link_target,
+            mtime: None,
+            btime: None,
+            xattrs: None,
+            acls: None,
+            sha256: None,
+            compression: None,
+            link_kind: None,
         }
     }
 
+    pub fn with_times(mut self, times: FileTimes) -> File {
+        self.mtime = times.modified;
+        self.btime = times.created;
+        self
+    }
+
+    pub fn times(&self) -> FileTimes {
+        FileTimes { modified: self.mtime, created: self.btime }
+    }
+
     // Massage entry into slash format
-    pub fn new_async(path: &async_std::path::Path, object: Option<async_std::path::PathBuf>, size: u64, mode: Option<u32>, link_target: Option<String>) -> File {
+    pub fn new_async(path: &async_std::path::Path, object: Option<ObjectKey>, size: u64, mode: Option<u32>, link_target: Option<String>) -> File {
         Self::new(
             std::path::PathBuf::from(path.as_os_str()).as_path(),
-            object.map(|x| std::path::PathBuf::from(x.as_path())),
+            object.map(|x| std::path::PathBuf::from(x.as_str())),
             size,
             mode,
             link_target
         )
     }
 
+    /// The recorded object, if it's well formed
+    pub fn object_key(&self) -> Result<Option<ObjectKey>> {
+        Ok(self.object.as_deref().map(ObjectKey::parse).transpose()?)
+    }
+
+    /// Record the path in form, returning the original if that changed it
+    pub(crate) fn normalize_path(&mut self, form: Normalization) -> Option<String> {
+        let normalized = form.apply(&self.path).into_owned();
+        (normalized != self.path).then(|| std::mem::replace(&mut self.path, normalized))
+    }
+
     pub fn path_str(&self) -> &str {
         self.path.as_str()
     }
 
+    pub fn matches_glob(&self, globs: &GlobSet) -> bool {
+        globs.matches(&self.path)
+    }
+
+    pub fn cmp_path(&self, other: &File, rules: &PathRules) -> std::cmp::Ordering {
+        rules.compare(&self.path, &other.path)
+    }
+
+    /// Hex sha256 of the content when the entry says: recorded, or the key of
+    /// the object it's deduplicated as, which is only a sha256 in entries
+    /// keyed by it
+    pub fn known_sha256(&self) -> Option<String> {
+        if let Some(hex) = &self.sha256 {
+            return Some(hex.to_ascii_lowercase());
+        }
+        Some(self.object_key().ok()??.as_str().replace('/', ""))
+    }
+
+    /// Same link target, or same size and known hash.  Files whose hash isn't
+    /// known, cache-local ones in older entries, never compare the same.
+    pub fn same_content(&self, other: &File) -> bool {
+        if self.link_target.is_some() || other.link_target.is_some() {
+            return self.link_target == other.link_target;
+        }
+        self.size == other.size && self.known_sha256().is_some_and(|hash| Some(hash) == other.known_sha256())
+    }
+
     pub fn path(&self) -> PathBuf {
-        PathBuf::from_slash(self.path.as_str())
+        Self::path_of(self.path.as_str())
+    }
+
+    /// Local form of a path as recorded in an entry
+    pub fn path_of(path: &str) -> PathBuf {
+        PathBuf::from_slash(path)
+    }
+
+    /// Storage key of the deduplicated object, None if stored with the cache
+    pub fn object_storage_key(&self) -> Option<String> {
+        let object = object::storage_key(self.object.as_deref()?);
+        Some(format!("{}{}", object, self.compression.map_or("", |c| c.suffix())))
     }
 
     pub fn storage_path(&self, cache_name: &str) -> PathBuf {
-        let mut b = PathBuf::new();
-        if let Some(s) = self.object.as_ref() {
-            b.push("objects");
-            b.push(s);
-            b.push("bin");
-        } else {
-            b.push("cache");
-            b.push(cache_name);
-            b.push("files");
-            b.push(&self.path);
+        if let Some(key) = self.object_storage_key() {
+            return PathBuf::from(key);
         }
-        PathBuf::from(b.to_slash().expect("slash conversion").as_ref())
+        let mut b = PathBuf::new();
+        b.push("cache");
+        b.push(cache_name);
+        b.push("files");
+        b.push(&self.path);
+        let key = b.to_slash().expect("slash conversion");
+        PathBuf::from(format!("{}{}", key, self.compression.map_or("", |c| c.suffix())))
     }
 }
 
 pub(crate) async fn read_hash(path: &async_std::path::Path, len: &Option<u64>) -> Result<[u8;32]> {
+    read_hash_with(path, len, HashAlgorithm::Sha256).await
+}
+
+pub(crate) async fn read_hash_with(path: &async_std::path::Path, len: &Option<u64>, algorithm: HashAlgorithm) -> Result<[u8;32]> {
+    FileHasher::new(algorithm, default_hash_jobs()).hash(path, len).await
+}
+
+async fn read_hash_stream(path: &async_std::path::Path, len: &Option<u64>, algorithm: HashAlgorithm) -> Result<[u8;32]> {
 
     // allocate a buffer one page -> 1 meg
     let buf_size = len.unwrap_or(0).clamp(4096, 1024*1024);
     let mut buf = vec![0; buf_size.try_into().unwrap()];
-    let mut sha = Sha256::new();
+    let mut hasher = algorithm.hasher();
 
     let mut f = tokio::fs::File::open(path).await?;
     loop {
         let len = f.read(&mut buf).await?;
         if len == 0 { break; }
-        sha.update(&buf[..len]);
+        hasher.update(&buf[..len]);
     }
-    let result = sha.finalize();
-    Ok(result.into())
+    Ok(hasher.finalize())
 }
 
 #[cfg(test)]
@@ -136,8 +715,8 @@ mod test {
 
         // Round trip of version container
         let mut c = Cache::default();
-        c.files.push(File{ path: "foo.exe".into(), object: Some("aa/bb/cc/dddd".into()), size: 123456, mode: Some(0o100664), link_target: None });
-        c.files.push(File{ path: "libfoo.so".into(), object: None, size: 7, mode: None, link_target: Some("libfoo.so.1".into()) });
+        c.files.push(File{ path: "foo.exe".into(), object: Some("aa/bb/cc/dddd".into()), size: 123456, mode: Some(0o100664), link_target: None, mtime: None, btime: None, xattrs: None, acls: None, sha256: None, compression: None, link_kind: None });
+        c.files.push(File{ path: "libfoo.so".into(), object: None, size: 7, mode: None, link_target: Some("libfoo.so.1".into()), mtime: None, btime: None, xattrs: None, acls: None, sha256: None, compression: None, link_kind: Some(LinkKind::File) });
         let v = CacheVersions::V1(c);
         let x = serde_json::to_string(&v).unwrap();
         println!("json = {}", x);
@@ -146,13 +725,195 @@ mod test {
 "v1": {
   "files": [
     {"path":"foo.exe","object":"aa/bb/cc/dddd","size":123456,"mode":33204},
-    {"path":"libfoo.so","size":7,"link_target": "libfoo.so.1"}
+    {"path":"libfoo.so","size":7,"link_target": "libfoo.so.1", "link_kind": "file"}
   ]
 }
 }"#).unwrap();
 
         assert_eq!(inp, v);
         assert_eq!(serde_json::from_str::<CacheVersions>(&x).unwrap(), v);
+
+        // V2 round trips too, and V1 is read into the same Cache it upgrades to
+        let CacheVersions::V1(c) = v else { unreachable!() };
+        let files = c.files.clone();
+        let v2 = CacheVersions::V2(CacheV2 {
+            created: Some("2025-01-02T03:04:05Z".parse().unwrap()), writer_version: Some("0.3.1".into()), ..migrate_v1_to_v2(c)
+        });
+        let x = serde_json::to_string(&v2).unwrap();
+        let inp: CacheVersions = serde_json::from_str(r#" {
+"v2": {
+  "created": "2025-01-02T03:04:05Z",
+  "writer_version": "0.3.1",
+  "size": 123463,
+  "files": [
+    {"path":"foo.exe","object":"aa/bb/cc/dddd","size":123456,"mode":33204},
+    {"path":"libfoo.so","size":7,"link_target": "libfoo.so.1", "link_kind": "file"}
+  ],
+  "schema_version": 2
+}
+}"#).unwrap();
+        assert_eq!(inp, v2);
+        assert!(x.ends_with(r#""schema_version":2}}"#), "{}", x);
+        assert_eq!(serde_json::from_str::<CacheVersions>(&x).unwrap(), v2);
+        let CacheVersions::V2(read) = inp else { unreachable!() };
+        assert_eq!(Cache::from(read).files, files);
+
+        // V2 written before the schema version was recorded
+        let CacheVersions::V2(early) = serde_json::from_str::<CacheVersions>(r#"{"v2": {"size": 0, "files": []}}"#).unwrap()
+            else { unreachable!() };
+        assert_eq!(early.schema_version, 2);
+    }
+
+    #[test]
+    fn entry_versions() {
+        // V1 upgraded in memory, with nothing to say who wrote it
+        let (v1, version) = decode("c", br#"{"v1": {"files": [{"path": "a", "size": 3}], "created_at": "2025-01-02T03:04:05Z"}}"#).unwrap();
+        assert_eq!(version, SchemaVersion::V1);
+        assert_eq!(v1.created_at, Some("2025-01-02T03:04:05Z".parse().unwrap()));
+        assert_eq!((v1.writer_version.as_deref(), v1.label.as_deref()), (None, None));
+
+        // always written as V2, with the total size for tooling
+        let label = Cache { label: Some("abc123".into()), ..v1 };
+        let text = label.into_string();
+        assert!(text.starts_with(r#"{"v2":{"created":"2025-01-02T03:04:05Z","label":"abc123","#), "{}", text);
+        assert!(text.contains(r#""label":"abc123","size":3,"#), "{}", text);
+        let (v2, version) = decode("c", text.as_bytes()).unwrap();
+        assert_eq!(version, SchemaVersion::V2);
+        assert_eq!(v2.files.len(), 1);
+        assert_eq!(v2.created_at, Some("2025-01-02T03:04:05Z".parse().unwrap()));
+        assert_eq!(v2.writer_version, None);
+        assert_eq!(v2.label.as_deref(), Some("abc123"));
+        assert_eq!(decode("c", v2.into_string().as_bytes()).unwrap().0.label.as_deref(), Some("abc123"));
+
+        // sha256 isn't written, so the field only appears for blake3
+        assert!(!text.contains("hash_algorithm"), "{}", text);
+        let blake3 = Cache { hash_algorithm: HashAlgorithm::Blake3, ..Default::default() }.into_string();
+        assert!(blake3.contains(r#""hash_algorithm":"blake3""#), "{}", blake3);
+        assert_eq!(decode("c", blake3.as_bytes()).unwrap().0.hash_algorithm, HashAlgorithm::Blake3);
+
+        // V1 without a creation time doesn't get one made up when rewritten,
+        // and a V2 writer is kept
+        let (unstamped, _) = decode("c", Cache::default().into_string().as_bytes()).unwrap();
+        assert_eq!((unstamped.created_at, unstamped.writer_version), (None, None));
+        let old = Cache { writer_version: Some("0.3.1".into()), ..Default::default() }.into_string();
+        assert_eq!(decode("c", old.as_bytes()).unwrap().0.writer_version.as_deref(), Some("0.3.1"));
+
+        match decode("c", br#"{"v3": {"files": [], "chunks": []}}"#).unwrap_err().downcast::<Error>() {
+            Ok(Error::UnsupportedEntryVersion { cache, version }) => assert_eq!((cache.as_str(), version.as_str()), ("c", "v3")),
+            other => panic!("expected UnsupportedEntryVersion, got {:?}", other),
+        }
+        // a known version missing fields is damage, not the future
+        assert!(corrupt_reason(br#"{"v2": {"files": []}}"#).contains("missing field"));
+    }
+
+    #[tokio::test]
+    async fn hashes_by_algorithm() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, "content").unwrap();
+        let path = async_std::path::Path::new(path.as_os_str());
+        assert_eq!(read_hash(path, &None).await.unwrap(), <[u8; 32]>::from(Sha256::digest(b"content")));
+        assert_eq!(read_hash_with(path, &None, HashAlgorithm::Blake3).await.unwrap(), *blake3::hash(b"content").as_bytes());
+    }
+
+    #[test]
+    fn hash_algorithm_names() {
+        for name in ["sha256", "blake3", "sha256-merkle-1048576"] {
+            assert_eq!(name.parse::<HashAlgorithm>().unwrap().to_string(), name);
+        }
+        assert_eq!("sha256-merkle".parse(), Ok(HashAlgorithm::Sha256Merkle(merkle::DEFAULT_CHUNK)));
+        assert!("sha256-merkle-4096".parse::<HashAlgorithm>().is_err());
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+        let c = Cache { hash_algorithm: HashAlgorithm::Sha256Merkle(1 << 20), ..Default::default() }.into_string();
+        assert!(c.contains(r#""hash_algorithm":"sha256-merkle-1048576""#), "{}", c);
+        assert_eq!(decode("c", c.as_bytes()).unwrap().0.hash_algorithm, HashAlgorithm::Sha256Merkle(1 << 20));
+    }
+
+    #[test]
+    fn times_compat() {
+        let time = |s| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+
+        // entries from before times were recorded
+        let old: File = serde_json::from_str(r#"{"path":"a","size":1,"mode":33204}"#).unwrap();
+        assert_eq!(old.times(), FileTimes::default());
+        assert!(!serde_json::to_string(&old).unwrap().contains("time"), "absent times shouldn't be written");
+
+        let f: File = serde_json::from_str(
+            r#"{"path":"a","size":1,"mtime":"2020-01-02T03:04:05Z","btime":"2019-01-02T03:04:05.5Z"}"#).unwrap();
+        assert_eq!(f.mtime, Some(time("2020-01-02T03:04:05Z")));
+        assert_eq!(f.btime, Some(time("2019-01-02T03:04:05.5Z")));
+        assert_eq!(serde_json::from_str::<File>(&serde_json::to_string(&f).unwrap()).unwrap(), f);
+
+        // only mtime
+        let f = old.with_times(FileTimes { modified: f.mtime, created: None });
+        let x = serde_json::to_string(&f).unwrap();
+        assert!(x.contains("mtime") && !x.contains("btime"));
+    }
+
+    #[test]
+    fn sha256_compat() {
+        let hash = "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447";
+
+        // entries from before hashes were recorded
+        let old: File = serde_json::from_str(r#"{"path":"a","size":12,"mode":33204}"#).unwrap();
+        assert_eq!(old.sha256, None);
+        assert!(!serde_json::to_string(&old).unwrap().contains("sha256"), "absent hash shouldn't be written");
+
+        let f: File = serde_json::from_str(&format!(r#"{{"path":"a","size":12,"sha256":"{}"}}"#, hash)).unwrap();
+        assert_eq!(f.sha256.as_deref(), Some(hash));
+        assert_eq!(serde_json::from_str::<File>(&serde_json::to_string(&f).unwrap()).unwrap(), f);
+    }
+
+    #[test]
+    fn compression_compat() {
+        let old: File = serde_json::from_str(r#"{"path":"a","object":"aa/bb/cc/dd","size":12}"#).unwrap();
+        assert_eq!(old.compression, None);
+        assert!(!serde_json::to_string(&old).unwrap().contains("compression"), "absent codec shouldn't be written");
+
+        let f: File = serde_json::from_str(r#"{"path":"a","object":"aa/bb/cc/dd","size":12,"compression":{"zstd":{"level":3}}}"#).unwrap();
+        assert_eq!(f.compression, Some(CompressionCodec::Zstd { level: 3 }));
+        // kept apart from raw copies of the same content
+        assert_eq!(f.storage_path("c").to_str().unwrap(), "objects/aa/bb/cc/dd/bin.zst");
+        let local = File { object: None, ..f };
+        assert_eq!(local.storage_path("c").to_str().unwrap(), "cache/c/files/a.zst");
+    }
+
+    #[test]
+    fn reserved_names() {
+        for name in ["entry", "pinned", "last-access", "access-log", "entry.prev.1"] {
+            assert!(is_reserved(name), "{}", name);
+        }
+        assert!(!is_reserved("files"));
+        assert!(!is_reserved("entry2"));
+    }
+
+    #[test]
+    fn reserved_keys() {
+        assert!(is_reserved_key("cache/n/entry"));
+        assert!(is_reserved_key("cache/n/files/../entry"));
+        assert!(is_reserved_key("cache/n/files/./../pinned"));
+        assert!(is_reserved_key("cache/n/files/../../other/last-access"));
+        assert!(!is_reserved_key("cache/n/files/entry"));
+        assert!(!is_reserved_key("cache/n/files/dir/../entry.prev.1"));
+        assert!(!is_reserved_key("objects/aa/bb/cc/dd/bin"));
+    }
+
+    #[test]
+    fn user_file_named_entry_is_not_reserved() {
+        let f = File::new(PathBuf::from("entry").as_path(), None, 1, None, None);
+        let key = f.storage_path("mycache");
+        assert_eq!(key.to_str().unwrap(), "cache/mycache/files/entry");
+        assert!(!is_reserved_key(key.to_str().unwrap()));
+    }
+
+    #[test]
+    fn listing_keys() {
+        assert_eq!(parse_key("cache/n/entry"), Some(("n", CacheKey::Meta("entry"))));
+        assert_eq!(parse_key("cache/n/entry.prev.2"), Some(("n", CacheKey::Meta("entry.prev.2"))));
+        assert_eq!(parse_key("cache/n/files/entry"), Some(("n", CacheKey::File("entry"))));
+        assert_eq!(parse_key("cache/n/files/d/pinned"), Some(("n", CacheKey::File("d/pinned"))));
+        assert_eq!(parse_key("cache/n/other"), None);
+        assert_eq!(parse_key("objects/a/bin"), None);
     }
 
     // construct a path-like string from directory and file
@@ -216,4 +977,81 @@ mod test {
     fn path_compat_location() {
         assert_eq!(Cache::location("mycache").to_str().expect("valid string"), "cache/mycache");
     }
+
+    #[test]
+    fn escaping_paths_are_unsafe() {
+        for path in ["a", "a/b/c", "./a", "a/./b", "dir/..hidden", "a..b", "dir/file:stream"] {
+            assert!(is_contained(path), "{}", path);
+        }
+        for path in ["../a", "a/../../b", "a/b/../c", "..", "/etc/cron.d/x", "\\\\server\\share\\x", "\\a",
+                     "C:\\Windows\\x", "c:/x", "C:x", "a\\..\\..\\b"] {
+            assert!(!is_contained(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn entries_with_unsafe_paths_are_rejected() {
+        let file = |p: &str, link: Option<&str>| File::new_async(async_std::path::Path::new(p), None, 1, None, link.map(Into::into));
+        let entry = |files: Vec<File>, dirs: &[&str]| Cache {
+            files, dirs: dirs.iter().map(|d| Dir { path: d.to_string(), mode: None }).collect(), ..Default::default()
+        };
+        let unsafe_path = |c: Cache| match c.check_paths() {
+            Err(Error::UnsafePath(path)) => path,
+            other => panic!("expected UnsafePath, got {:?}", other),
+        };
+
+        // absolute links are kept, as long as nothing is restored through them
+        let ok = entry(vec![file("a", None), file("lib/x.so", Some("/usr/lib/x.so")), file("lib/y", None)], &["empty"]);
+        assert!(ok.check_paths().is_ok());
+
+        assert_eq!(unsafe_path(entry(vec![file("a", None), file("a/../../x", None)], &[])), "a/../../x");
+        assert_eq!(unsafe_path(entry(vec![file("/etc/cron.d/x", None)], &[])), "/etc/cron.d/x");
+        assert_eq!(unsafe_path(entry(vec![], &["C:\\Windows"])), "C:\\Windows");
+        assert_eq!(unsafe_path(entry(vec![file("d", Some("/etc")), file("d/cron.d/x", None)], &[])),
+                   "d/cron.d/x (below the symlink d)");
+        assert_eq!(unsafe_path(entry(vec![file("./d", Some("../..")), file("d/./x", None)], &[])),
+                   "d/./x (below the symlink d)");
+        assert_eq!(unsafe_path(entry(vec![file("d", Some("elsewhere"))], &["d/sub"])), "d/sub (below the symlink d)");
+    }
+
+    fn corrupt_reason(bytes: &[u8]) -> String {
+        match decode("hostile", bytes).unwrap_err().downcast::<Error>() {
+            Ok(Error::CorruptEntry { cache, reason }) if cache == "hostile" => reason,
+            other => panic!("expected CorruptEntry, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hostile_entries_fail() {
+        let wide = format!(r#"{{"v1": {{"files": [{}]}}}}"#, vec!["{}"; 1_000_000].join(","));
+        assert!(corrupt_reason(wide.as_bytes()).contains("missing field"));
+
+        let deep = format!(r#"{{"v1": {{"files": {}{}}}}}"#, "[".repeat(10_000), "]".repeat(10_000));
+        corrupt_reason(deep.as_bytes());
+        let deep = format!("{}{}", "{\"v1\": ".repeat(10_000), "}".repeat(10_000));
+        corrupt_reason(deep.as_bytes());
+
+        let long = format!(r#"{{"v1": {{"files": [{{"path": "{}", "size": 1}}]}}}}"#, "a".repeat(100 << 20));
+        assert!(corrupt_reason(long.as_bytes()).starts_with("path 'aaaa"));
+
+        let target = format!(r#"{{"v1": {{"files": [{{"path": "l", "size": 1, "link_target": "{}"}}]}}}}"#, "../".repeat(2000));
+        assert!(corrupt_reason(target.as_bytes()).starts_with("link target"));
+
+        let xattrs: Vec<String> = (0..2000).map(|i| format!(r#""user.{}": """#, i)).collect();
+        let labelled = format!(r#"{{"v1": {{"files": [{{"path": "x", "size": 1, "xattrs": {{{}}}}}]}}}}"#, xattrs.join(","));
+        assert!(corrupt_reason(labelled.as_bytes()).contains("extended attributes"));
+
+        assert!(corrupt_reason(b"\xff").contains("expected value"));
+        assert!(decode("fine", br#"{"v1": {"files": [{"path": "a", "size": 1}]}}"#).is_ok());
+    }
+
+    #[tokio::test]
+    async fn entry_reads_are_capped() {
+        use tokio::io::AsyncWriteExt;
+        let mut writer = CappedWriter { buf: Vec::new(), limit: 8, exceeded: false };
+        writer.write_all(b"12345").await.unwrap();
+        assert!(writer.write_all(b"6789").await.is_err());
+        assert!(writer.exceeded);
+        assert_eq!(writer.buf, b"12345");
+    }
 }
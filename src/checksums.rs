@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use anyhow::Context;
+use sha2::Digest;
+use tokio::io::AsyncWriteExt as _;
+
+use crate::{actions::read_cache_info, cache::{self, HashAlgorithm}, display::{key_display, local_display}, fsck::HashWriter, Result, Storage};
+
+/// Written in the outpath by download --write-checksums
+pub const SUMS_FILE: &str = "SHA256SUMS";
+
+/// A line of `sha256sum` output.  Like coreutils, paths holding a backslash
+/// or newline are escaped, flagged by a leading backslash.
+fn line(hex: &str, path: &str) -> String {
+    if path.contains(['\\', '\n']) {
+        format!("\\{}  {}\n", hex, path.replace('\\', "\\\\").replace('\n', "\\n"))
+    } else {
+        format!("{}  {}\n", hex, path)
+    }
+}
+
+/// Download a cache-local file to hash its raw content
+async fn fetch_digest(storage: Storage, cache_name: String, f: cache::File) -> Result<(String, String)> {
+    let p = f.storage_path(&cache_name);
+    let key = p.to_str().expect("Invalid storage_path -> string");
+    let hasher = if let Some(codec) = f.compression {
+        let mut decoder = codec.decoder(HashWriter::default());
+        storage.get_file(&mut decoder, key).await?;
+        decoder.shutdown().await.with_context(|| format!("Failed to decompress {}", key_display(key)))?;
+        decoder.into_inner()
+    } else {
+        let mut hasher = HashWriter::default();
+        storage.get_file(&mut hasher, key).await?;
+        hasher
+    };
+    Ok((f.path_str().to_owned(), faster_hex::hex_string(&hasher.0.finalize())))
+}
+
+/// `sha256sum` compatible lines for the regular files among files, sorted by
+/// path.  Files without a known hash are downloaded, max_in_flight at a time.
+/// Object keys only give the sha256 in entries keyed by it.
+pub(crate) async fn sums(storage: &Storage, cache_name: &str, files: &[cache::File], algorithm: HashAlgorithm,
+                         max_in_flight: u32) -> Result<String> {
+    let mut digests = Vec::new();
+    let mut set = tokio::task::JoinSet::new();
+    for f in files.iter().filter(|f| f.link_target.is_none()) {
+        let known = if algorithm.is_sha256() { f.known_sha256() } else { f.sha256.as_deref().map(str::to_ascii_lowercase) };
+        if let Some(hex) = known {
+            digests.push((f.path_str().to_owned(), hex));
+            continue;
+        }
+        while set.len() >= max_in_flight as usize {
+            if let Some(work) = set.join_next().await {
+                digests.push(work.with_context(|| "Failure waiting on hash jobs")??);
+            }
+        }
+        set.spawn(fetch_digest(storage.clone(), cache_name.to_owned(), f.clone()));
+    }
+    while let Some(work) = set.join_next().await {
+        digests.push(work.with_context(|| "Failure waiting on hash jobs")??);
+    }
+    digests.sort();
+    Ok(digests.iter().map(|(path, hex)| line(hex, path)).collect())
+}
+
+/// `sha256sum` compatible checksums of the files in a cache, as they'd be
+/// restored relative to the outpath
+pub async fn checksums(storage: Storage, cache_name: &str, max_in_flight: u32) -> Result<String> {
+    let c = read_cache_info(&storage, cache_name).await?;
+    sums(&storage, cache_name, &c.files, c.hash_algorithm, max_in_flight).await
+}
+
+/// Write sums to the [SUMS_FILE] in dir
+pub(crate) fn write(dir: &std::path::Path, sums: &str) -> Result<()> {
+    let path = dir.join(SUMS_FILE);
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", local_display(dir)))?;
+    std::fs::write(&path, sums).with_context(|| format!("Failed to write {}", local_display(&path)))?;
+    log::warn!("Wrote checksums of {} files to {}", sums.lines().count(), local_display(&path));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn lines_match_coreutils() {
+        let hex = "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447";
+        assert_eq!(line(hex, "dir/text.txt"), format!("{}  dir/text.txt\n", hex));
+        assert_eq!(line(hex, "a\\b\nc"), format!("\\{}  a\\\\b\\nc\n", hex));
+    }
+}
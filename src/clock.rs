@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::{layout, Result, Storage};
+
+/// Skew worth warning about; Date headers only have whole seconds anyway
+const WARN_SKEW: TimeDelta = TimeDelta::minutes(1);
+
+/// Parse an HTTP Date header, eg "Tue, 15 Nov 1994 08:12:31 GMT"
+fn parse_date(header: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(header).ok().map(|d| d.to_utc())
+}
+
+/// How far the server's clock is ahead of ours, taking the request to have
+/// been answered halfway between sent and received
+fn skew(server: DateTime<Utc>, sent: DateTime<Utc>, received: DateTime<Utc>) -> TimeDelta {
+    server - (sent + (received - sent) / 2)
+}
+
+/// Expiry cutoff of age before now by the server's clock, None if out of range
+pub(crate) fn cutoff(skew: TimeDelta, now: DateTime<Utc>, age: TimeDelta) -> Option<DateTime<Utc>> {
+    now.checked_add_signed(skew)?.checked_sub_signed(age)
+}
+
+/// How far the server's clock is ahead of ours, measured from the Date of
+/// reading the layout marker.  None if the bucket has no marker, or the
+/// service sent no usable Date.
+pub async fn measure(storage: &Storage) -> Result<Option<TimeDelta>> {
+    let sent = Utc::now();
+    let date = storage.server_date(layout::MARKER).await?;
+    let received = Utc::now();
+    let Some(server) = date.as_deref().and_then(parse_date) else {
+        log::info!("Unable to measure clock skew: no Date from reading {}", layout::MARKER);
+        return Ok(None);
+    };
+    let skew = skew(server, sent, received);
+    if skew.abs() > WARN_SKEW {
+        log::warn!("Server clock is {} {} local time",
+                   humantime::format_duration(skew.abs().to_std().expect("abs is positive")),
+                   if skew > TimeDelta::zero() { "ahead of" } else { "behind" });
+    }
+    Ok(Some(skew))
+}
+
+/// Signed skew for display, eg "+40m 3s"
+pub fn format_skew(skew: TimeDelta) -> String {
+    let sign = if skew < TimeDelta::zero() { '-' } else { '+' };
+    let whole = TimeDelta::seconds(skew.num_seconds().abs());
+    format!("{}{}", sign, humantime::format_duration(whole.to_std().expect("abs is positive")))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn dates_are_parsed() {
+        assert_eq!(parse_date("Tue, 15 Nov 1994 08:12:31 GMT"), Some(time("1994-11-15T08:12:31Z")));
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn skew_is_from_midpoint() {
+        let sent = time("2025-01-01T00:00:00Z");
+        let received = time("2025-01-01T00:00:02Z");
+        let server = parse_date("Wed, 01 Jan 2025 00:40:01 GMT").unwrap();
+        assert_eq!(skew(server, sent, received), TimeDelta::minutes(40));
+        let behind = parse_date("Tue, 31 Dec 2024 23:20:01 GMT").unwrap();
+        assert_eq!(skew(behind, sent, received), TimeDelta::minutes(-40));
+    }
+
+    #[test]
+    fn cutoff_follows_server_clock() {
+        let now = time("2025-01-01T00:00:00Z");
+        // a server 40 minutes behind wrote "new" objects 40 minutes in our past
+        assert_eq!(cutoff(TimeDelta::minutes(-40), now, TimeDelta::zero()), Some(time("2024-12-31T23:20:00Z")));
+        assert_eq!(cutoff(TimeDelta::zero(), now, TimeDelta::days(1)), Some(time("2024-12-31T00:00:00Z")));
+        assert_eq!(cutoff(TimeDelta::zero(), now, TimeDelta::MAX), None);
+    }
+
+    #[test]
+    fn skew_display() {
+        assert_eq!(format_skew(TimeDelta::seconds(2403)), "+40m 3s");
+        assert_eq!(format_skew(TimeDelta::milliseconds(-1500)), "-1s");
+        assert_eq!(format_skew(TimeDelta::zero()), "+0s");
+    }
+}
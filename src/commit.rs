@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{display::local_display, fsync::{OsSyncer, Syncer}, Result};
+
+/// Directory below the outpath an atomic download restores into first
+pub(crate) const STAGING_PREFIX: &str = ".s3-cache-staging.";
+
+/// In the staging directory, the moves into place, written whole before
+/// the first move: once it exists the commit is only ever finished
+const JOURNAL: &str = "journal.json";
+
+/// In the staging directory, the index of each move done, a line each
+const PROGRESS: &str = "progress";
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Dir,
+    File,
+}
+
+/// A file, symlink or directory to put in place.  from is relative to the
+/// staging directory, to relative to the outpath.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Move {
+    kind: Kind,
+    from: PathBuf,
+    to: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Journal {
+    version: u32,
+    moves: Vec<Move>,
+}
+
+/// The moves putting each staged directory, relative to staging, at its
+/// destination, relative to the outpath.  Ordered by destination, so
+/// directories come before what's in them and reruns move in the same order.
+pub(crate) fn plan(staging: &Path, staged: &[(PathBuf, PathBuf)]) -> Result<Vec<Move>> {
+    let mut moves = Vec::new();
+    for (dir, to) in staged {
+        let root = staging.join(dir);
+        // caches without files leave nothing staged
+        if !root.exists() {
+            continue;
+        }
+        for entry in walkdir::WalkDir::new(&root) {
+            let entry = entry?;
+            let relative = entry.path().strip_prefix(&root).expect("walk stays below its root");
+            let kind = if entry.file_type().is_dir() { Kind::Dir } else { Kind::File };
+            let m = Move { kind, from: dir.join(relative), to: to.join(relative) };
+            // the outpath itself is already there
+            if !m.to.as_os_str().is_empty() {
+                moves.push(m);
+            }
+        }
+    }
+    moves.sort_by(|a, b| a.to.cmp(&b.to));
+    // caches restored to the same place share directories
+    moves.dedup_by(|a, b| a.kind == Kind::Dir && b.kind == Kind::Dir && a.to == b.to);
+    Ok(moves)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Panic before the move of this index, as if the process were killed
+    static FAIL_BEFORE: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+/// Put one staged entry in place.  Safe to repeat: a file already moved
+/// is left alone, and directories are only created.
+fn apply(outpath: &Path, staging: &Path, m: &Move) -> std::io::Result<()> {
+    let (from, to) = (staging.join(&m.from), outpath.join(&m.to));
+    let existing = std::fs::symlink_metadata(&to).ok();
+    match m.kind {
+        Kind::Dir => {
+            match existing {
+                Some(meta) if meta.is_dir() => return Ok(()),
+                Some(_) => std::fs::remove_file(&to)?,
+                None => {},
+            }
+            std::fs::create_dir(&to)?;
+            // as restored, eg an empty directory's recorded mode
+            if let Ok(staged) = std::fs::metadata(&from) {
+                std::fs::set_permissions(&to, staged.permissions())?;
+            }
+            Ok(())
+        },
+        Kind::File => {
+            if std::fs::symlink_metadata(&from).is_err() && existing.is_some() {
+                return Ok(());
+            }
+            // rename only replaces files everywhere, and only on unix
+            match existing {
+                Some(meta) if meta.is_dir() => std::fs::remove_dir_all(&to)?,
+                Some(_) => std::fs::remove_file(&to)?,
+                None => {},
+            }
+            std::fs::rename(&from, &to)
+        },
+    }
+}
+
+/// Write the journal of moves, then make them.  An interruption from then
+/// on is finished by [recover].
+pub(crate) fn commit(outpath: &Path, staging: &Path, moves: Vec<Move>) -> Result<()> {
+    let journal = serde_json::to_vec(&Journal { version: VERSION, moves })?;
+    let partial = staging.join(format!("{}.partial", JOURNAL));
+    let mut f = std::fs::File::create(&partial)?;
+    f.write_all(&journal)?;
+    OsSyncer.sync_file(&f)?;
+    std::fs::rename(&partial, staging.join(JOURNAL))?;
+    OsSyncer.sync_dir(staging)?;
+    finish(outpath, staging)
+}
+
+/// Make the journal's moves not yet recorded as done, then remove staging
+fn finish(outpath: &Path, staging: &Path) -> Result<()> {
+    let text = std::fs::read(staging.join(JOURNAL))?;
+    let journal: Journal = serde_json::from_slice(&text)
+        .with_context(|| format!("Unreadable journal in {}", local_display(staging)))?;
+    if journal.version > VERSION {
+        anyhow::bail!("Journal in {} is version {}, newer than this release understands",
+                      local_display(staging), journal.version);
+    }
+    // a line cut short by the interruption doesn't count
+    let done = std::fs::read_to_string(staging.join(PROGRESS)).unwrap_or_default()
+        .lines().filter(|line| line.parse::<usize>().is_ok()).count();
+    let mut progress = std::fs::OpenOptions::new().create(true).append(true).open(staging.join(PROGRESS))?;
+    let mut dirs = BTreeSet::new();
+    for (i, m) in journal.moves.iter().enumerate().skip(done) {
+        #[cfg(test)]
+        if FAIL_BEFORE.get() == Some(i) {
+            panic!("injected failure before move {}", i);
+        }
+        apply(outpath, staging, m)
+            .with_context(|| format!("Failed to move {} into place", local_display(&outpath.join(&m.to))))?;
+        writeln!(progress, "{}", i)?;
+        dirs.insert(outpath.join(&m.to).parent().map(Path::to_owned).unwrap_or_default());
+    }
+    for dir in dirs.iter().filter(|d| !d.as_os_str().is_empty()) {
+        OsSyncer.sync_dir(dir).with_context(|| format!("Failed to fsync directory {}", local_display(dir)))?;
+    }
+    drop(progress);
+    std::fs::remove_dir_all(staging).with_context(|| format!("Failed to remove {}", local_display(staging)))?;
+    Ok(())
+}
+
+/// Staging directories left in outpath by atomic downloads that were
+/// interrupted, and whether each had started moving files into place
+pub(crate) fn leftovers(outpath: &Path) -> Result<Vec<(PathBuf, bool)>> {
+    let mut found = Vec::new();
+    let entries = match std::fs::read_dir(outpath) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(found),
+        entries => entries?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(STAGING_PREFIX) && entry.file_type()?.is_dir() {
+            found.push((entry.path(), entry.path().join(JOURNAL).exists()));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Finish commits interrupted once their journal was written, and discard
+/// staging left before then.  Returns how many were finished and
+/// discarded.
+pub fn recover(outpath: &Path) -> Result<(usize, usize)> {
+    let (mut finished, mut discarded) = (0, 0);
+    for (staging, journaled) in leftovers(outpath)? {
+        if journaled {
+            log::warn!("Finishing interrupted commit from {}", local_display(&staging));
+            finish(outpath, &staging)?;
+            finished += 1;
+        } else {
+            log::warn!("Discarding {}, interrupted before its commit", local_display(&staging));
+            std::fs::remove_dir_all(&staging).with_context(|| format!("Failed to remove {}", local_display(&staging)))?;
+            discarded += 1;
+        }
+    }
+    Ok((finished, discarded))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    /// An outpath with an existing tree, and a staging directory restoring
+    /// over it
+    fn fixture() -> (tempfile::TempDir, PathBuf, Vec<Move>) {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path();
+        std::fs::create_dir_all(out.join("sub")).unwrap();
+        std::fs::write(out.join("sub/a"), "old").unwrap();
+        std::fs::write(out.join("keep"), "k").unwrap();
+        std::fs::write(out.join("was-file"), "file").unwrap();
+        std::fs::create_dir_all(out.join("was-dir/inner")).unwrap();
+
+        let staging = out.join(format!("{}test", STAGING_PREFIX));
+        let staged = staging.join("0");
+        std::fs::create_dir_all(staged.join("sub/new")).unwrap();
+        std::fs::create_dir_all(staged.join("was-file")).unwrap();
+        std::fs::create_dir_all(staged.join("empty")).unwrap();
+        for (path, content) in [("sub/a", "restored"), ("sub/new/b", "b"), ("was-file/c", "c"), ("was-dir", "now a file")] {
+            std::fs::write(staged.join(path), content).unwrap();
+        }
+        for i in 0..20 {
+            std::fs::write(staged.join(format!("sub/many{:02}", i)), i.to_string()).unwrap();
+        }
+        let moves = plan(&staging, &[(PathBuf::from("0"), PathBuf::new())]).unwrap();
+        (dir, staging, moves)
+    }
+
+    fn check(out: &Path) {
+        assert_eq!(std::fs::read_to_string(out.join("sub/a")).unwrap(), "restored");
+        assert_eq!(std::fs::read_to_string(out.join("sub/new/b")).unwrap(), "b");
+        assert_eq!(std::fs::read_to_string(out.join("keep")).unwrap(), "k");
+        assert_eq!(std::fs::read_to_string(out.join("was-file/c")).unwrap(), "c");
+        assert_eq!(std::fs::read_to_string(out.join("was-dir")).unwrap(), "now a file");
+        assert_eq!(std::fs::read_to_string(out.join("sub/many19")).unwrap(), "19");
+        assert!(out.join("empty").is_dir());
+        assert!(leftovers(out).unwrap().is_empty());
+    }
+
+    #[test]
+    fn directories_move_before_their_contents() {
+        let (_dir, _staging, moves) = fixture();
+        let to: Vec<&Path> = moves.iter().map(|m| m.to.as_path()).collect();
+        for (i, m) in moves.iter().enumerate() {
+            if let Some(parent) = m.to.parent().filter(|p| !p.as_os_str().is_empty()) {
+                assert!(to[..i].contains(&parent), "{:?} before its directory", m.to);
+            }
+        }
+    }
+
+    #[test]
+    fn commits_merge_into_place() {
+        let (dir, staging, moves) = fixture();
+        commit(dir.path(), &staging, moves).unwrap();
+        check(dir.path());
+    }
+
+    #[test]
+    fn interrupted_commits_are_recovered() {
+        let count = fixture().2.len();
+        for fail in [0, 1, count / 2, count - 1] {
+            let (dir, staging, moves) = fixture();
+            FAIL_BEFORE.set(Some(fail));
+            let interrupted = std::panic::catch_unwind(|| commit(dir.path(), &staging, moves));
+            FAIL_BEFORE.set(None);
+            assert!(interrupted.is_err(), "commit should be interrupted before move {}", fail);
+            assert_eq!(leftovers(dir.path()).unwrap(), [(staging.clone(), true)]);
+
+            assert_eq!(recover(dir.path()).unwrap(), (1, 0));
+            check(dir.path());
+        }
+    }
+
+    #[test]
+    fn a_move_repeated_by_recovery_is_harmless() {
+        let (dir, staging, moves) = fixture();
+        FAIL_BEFORE.set(Some(3));
+        assert!(std::panic::catch_unwind(|| commit(dir.path(), &staging, moves.clone())).is_err());
+        FAIL_BEFORE.set(None);
+        // as if killed after a move but before recording it
+        let progress = staging.join(PROGRESS);
+        let lines = std::fs::read_to_string(&progress).unwrap();
+        std::fs::write(&progress, lines.lines().take(2).map(|l| format!("{}\n", l)).collect::<String>()).unwrap();
+        recover(dir.path()).unwrap();
+        check(dir.path());
+    }
+
+    #[test]
+    fn staging_without_a_journal_is_discarded() {
+        let (dir, staging, _moves) = fixture();
+        assert_eq!(recover(dir.path()).unwrap(), (0, 1));
+        assert!(!staging.exists());
+        assert_eq!(std::fs::read_to_string(dir.path().join("sub/a")).unwrap(), "old");
+    }
+}
@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::PathBuf;
+
+use async_compression::tokio::write::{ZstdDecoder, ZstdEncoder};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::Result;
+
+/// Appended to the storage key of zstd compressed content
+pub(crate) const ZSTD_SUFFIX: &str = ".zst";
+
+/// How a file's content is compressed in storage.  Objects are keyed by the
+/// sha256 of the raw content, so compressed ones are stored under a suffix
+/// to stay apart from raw copies uploaded without compression.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionCodec {
+    Zstd { level: i32 },
+}
+
+impl CompressionCodec {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            CompressionCodec::Zstd { .. } => ZSTD_SUFFIX,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            CompressionCodec::Zstd { .. } => "application/zstd",
+        }
+    }
+
+    /// Compress reader into a temporary file, ready to upload from its start
+    pub(crate) async fn compress<R: AsyncRead + Unpin + ?Sized>(&self, reader: &mut R) -> Result<Compressed> {
+        let path = TempPath(std::env::temp_dir().join(format!("s3-cache-{}{}", uuid::Uuid::new_v4(), self.suffix())));
+        let out = tokio::fs::File::create(&path.0).await?;
+        let mut file = match self {
+            CompressionCodec::Zstd { level } => {
+                let mut encoder = ZstdEncoder::with_quality(out, async_compression::Level::Precise(*level));
+                tokio::io::copy(reader, &mut encoder).await?;
+                encoder.shutdown().await?;
+                encoder.into_inner()
+            },
+        };
+        file.rewind().await?;
+        Ok(Compressed { file, _path: path })
+    }
+
+    /// Writer restoring the raw content of what's written to it
+    pub(crate) fn decoder<W: AsyncWrite>(&self, writer: W) -> ZstdDecoder<W> {
+        match self {
+            CompressionCodec::Zstd { .. } => ZstdDecoder::new(writer),
+        }
+    }
+
+    /// Reader of the raw content of what's read through it
+    pub(crate) fn reader<R: AsyncBufRead>(&self, reader: R) -> async_compression::tokio::bufread::ZstdDecoder<R> {
+        match self {
+            CompressionCodec::Zstd { .. } => async_compression::tokio::bufread::ZstdDecoder::new(reader),
+        }
+    }
+}
+
+/// Deleted once dropped
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            log::warn!("Unable to remove temporary file {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Compressed content awaiting upload, removed once dropped
+pub(crate) struct Compressed {
+    pub file: tokio::fs::File,
+    // after file, so it's closed before removal
+    _path: TempPath,
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let raw = "compressible text ".repeat(1000).into_bytes();
+        let codec = CompressionCodec::Zstd { level: 3 };
+        let mut compressed = codec.compress(&mut raw.as_slice()).await.unwrap();
+        let mut stored = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut compressed.file, &mut stored).await.unwrap();
+        assert!(stored.len() < raw.len() / 10);
+
+        let mut decoder = codec.decoder(Vec::new());
+        decoder.write_all(&stored).await.unwrap();
+        decoder.shutdown().await.unwrap();
+        assert_eq!(decoder.into_inner(), raw);
+
+        let path = compressed._path.0.clone();
+        drop(compressed);
+        assert!(!path.exists(), "temporary file should be removed");
+    }
+
+    #[test]
+    fn codec_is_recorded() {
+        let codec = CompressionCodec::Zstd { level: 19 };
+        assert_eq!(serde_json::to_string(&codec).unwrap(), r#"{"zstd":{"level":19}}"#);
+        assert_eq!(serde_json::from_str::<CompressionCodec>(r#"{"zstd":{"level":19}}"#).unwrap(), codec);
+    }
+}
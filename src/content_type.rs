@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+/// What everything was stored as before detection, and still is for objects
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Extensions worth rendering in a browser, eg reports opened via presigned URLs
+const TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("log", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("ico", "image/x-icon"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+];
+
+/// Content type for path by its extension, falling back to [OCTET_STREAM]
+pub fn detect(path: &str) -> &'static str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let Some((_, ext)) = name.rsplit_once('.') else {
+        return OCTET_STREAM;
+    };
+    TYPES.iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        .map_or(OCTET_STREAM, |(_, t)| t)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn by_extension() {
+        assert_eq!(detect("report/index.html"), "text/html");
+        assert_eq!(detect("shots/FAIL.PNG"), "image/png");
+        assert_eq!(detect("build/out.bin"), OCTET_STREAM);
+        assert_eq!(detect("Makefile"), OCTET_STREAM);
+        assert_eq!(detect("dir.html/README"), OCTET_STREAM);
+    }
+}
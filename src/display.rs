@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::ffi::OsStr;
+
+/// How commands print their results
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Text for people, as commands print by default
+    #[default]
+    Human,
+    /// JSON, as with each command's --json
+    Json,
+}
+
+/// Local path in the platform's native form, as a user would type it:
+/// Windows paths get backslashes and lose any verbatim (\\?\) prefix
+pub fn local_display(path: &(impl AsRef<OsStr> + ?Sized)) -> String {
+    native(&path.as_ref().to_string_lossy(), cfg!(windows))
+}
+
+/// Storage key, always with forward slashes
+pub fn key_display(key: &(impl AsRef<OsStr> + ?Sized)) -> String {
+    key.as_ref().to_string_lossy().replace('\\', "/")
+}
+
+fn native(path: &str, windows: bool) -> String {
+    if !windows {
+        return path.to_owned();
+    }
+    let path = if let Some(unc) = path.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{}", unc)
+    } else {
+        path.strip_prefix(r"\\?\").unwrap_or(path).to_owned()
+    };
+    path.replace('/', "\\")
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn windows_paths() {
+        assert_eq!(native(r"\\?\C:\build\out\a.txt", true), r"C:\build\out\a.txt");
+        assert_eq!(native(r"\\?\UNC\server\share\a.txt", true), r"\\server\share\a.txt");
+        assert_eq!(native("out/dir\\a.txt", true), r"out\dir\a.txt");
+        assert_eq!(native(r"\\server\share", true), r"\\server\share");
+    }
+
+    #[test]
+    fn unix_paths() {
+        assert_eq!(native("out/dir/a.txt", false), "out/dir/a.txt");
+        // a legal, if unlikely, file name
+        assert_eq!(native(r"out/a\b", false), r"out/a\b");
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(key_display(r"cache\name\files\dir\a.txt"), "cache/name/files/dir/a.txt");
+        assert_eq!(key_display(std::path::Path::new("objects/aa/bin")), "objects/aa/bin");
+    }
+
+    /// Arguments of a macro call up to its closing bracket, split at
+    /// top-level commas
+    fn split_args(args: &str) -> Vec<&str> {
+        let (mut depth, mut start, mut out) = (0, 0, Vec::new());
+        for (i, c) in args.char_indices() {
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' if depth == 0 => {
+                    out.push(args[start..i].trim());
+                    return out;
+                },
+                ')' | ']' | '}' => depth -= 1,
+                ',' if depth == 0 => {
+                    out.push(args[start..i].trim());
+                    start = i + 1;
+                },
+                _ => {},
+            }
+        }
+        out.push(args[start..].trim());
+        out
+    }
+
+    /// Placeholders in a format string, eg ["", ":?", ":.3"]; named ones are skipped
+    fn placeholders(format: &str) -> Vec<&str> {
+        let mut out = Vec::new();
+        let mut rest = format;
+        while let Some(open) = rest.find('{') {
+            if rest[open..].starts_with("{{") {
+                rest = &rest[open + 2..];
+                continue;
+            }
+            let close = open + rest[open..].find('}').expect("unbalanced format string");
+            let spec = &rest[open + 1..close];
+            if spec.is_empty() || spec.starts_with(':') {
+                out.push(spec);
+            }
+            rest = &rest[close + 1..];
+        }
+        out
+    }
+
+    /// Debug formatted arguments that look like paths in a macro's text
+    fn debug_paths(call: &str) -> Vec<String> {
+        let Some(open) = call.find('"') else {
+            return Vec::new();
+        };
+        let mut close = open + 1;
+        let bytes = call.as_bytes();
+        while close < bytes.len() && bytes[close] != b'"' {
+            close += if bytes[close] == b'\\' { 2 } else { 1 };
+        }
+        let Some(args) = call.get(close + 1..).and_then(|a| a.trim_start().strip_prefix(',')) else {
+            return Vec::new();
+        };
+        let args = split_args(args);
+        placeholders(&call[open + 1..close]).iter().zip(args)
+            .filter(|(spec, arg)| spec.starts_with(":?") && {
+                let name = arg.trim_start_matches('&').rsplit('.').next().unwrap_or(arg);
+                name == "p" || name.ends_with("path") || name.ends_with("path()")
+            })
+            .map(|(_, arg)| arg.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn lint_finds_debug_paths() {
+        assert_eq!(debug_paths(r#"log::info!("creating {:?} for {:?}", &p, path);"#), vec!["&p", "path"]);
+        assert_eq!(debug_paths(r#"log::debug!("{{}} {:?} {}", meta.path, x);"#), vec!["meta.path"]);
+        assert!(debug_paths(r#"log::debug!("{:?} {}", meta, local_display(&path));"#).is_empty());
+        assert!(debug_paths(r#"log::debug!("{name} {:?}", policy);"#).is_empty());
+    }
+
+    /// No log or error message should debug format a path, which shows
+    /// Windows paths with doubled backslashes and their verbatim prefix
+    #[test]
+    fn no_debug_formatted_paths() {
+        const MACROS: [&str; 7] = ["log::trace!(", "log::debug!(", "log::info!(", "log::warn!(", "log::error!(",
+                                   "format!(", "context("];
+        let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut offenders = Vec::new();
+        for entry in std::fs::read_dir(src).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some(OsStr::new("rs")) {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path).unwrap();
+            // tests print whatever helps when they fail
+            let text = text.split("#[cfg(test)]").next().unwrap();
+            for m in MACROS {
+                for (at, _) in text.match_indices(m) {
+                    let end = text[at..].find(';').map_or(text.len(), |e| at + e);
+                    for arg in debug_paths(&text[at..end]) {
+                        offenders.push(format!("{}: {}", path.file_name().unwrap().to_string_lossy(), arg));
+                    }
+                }
+            }
+        }
+        assert!(offenders.is_empty(), "use display::local_display or key_display for: {:?}", offenders);
+    }
+}
@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+use crate::{actions::UploadOptions, plan::PlannedFile, Result, Storage};
+
+/// Files with the same content
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DupGroup {
+    pub sha256: String,
+    /// Size of each copy
+    pub size: u64,
+    /// Bytes hardlinking the copies to one file would save.  Paths already
+    /// hardlinked together count once.
+    pub wasted: u64,
+    pub paths: Vec<String>,
+}
+
+/// Duplicate content among the files upload would cache
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DupReport {
+    pub files: usize,
+    pub bytes: u64,
+    /// Bytes in files with at least one copy
+    pub duplicated_bytes: u64,
+    /// Bytes hardlinking every group would save
+    pub savings: u64,
+    /// Most wasted bytes first
+    pub groups: Vec<DupGroup>,
+}
+
+/// A hashed file, and where it is on disk if known
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Hashed {
+    path: String,
+    sha256: String,
+    size: u64,
+    /// Device and inode, so hardlinks aren't counted as copies
+    inode: Option<(u64, u64)>,
+}
+
+#[cfg(unix)]
+fn inode(path: &std::path::Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_path: &std::path::Path) -> Option<(u64, u64)> {
+    None
+}
+
+fn hashed(planned: &PlannedFile) -> Option<Hashed> {
+    let sha256 = planned.entry.sha256.clone().filter(|_| planned.entry.link_target.is_none())?;
+    Some(Hashed {
+        path: planned.entry.path_str().to_owned(),
+        sha256,
+        size: planned.size,
+        inode: inode(&planned.local_path()),
+    })
+}
+
+/// Group files by content, keeping groups of more than one non-empty file
+fn group(files: Vec<Hashed>) -> DupReport {
+    let mut report = DupReport { files: files.len(), bytes: files.iter().map(|f| f.size).sum(), ..Default::default() };
+    let mut by_hash: BTreeMap<(String, u64), Vec<Hashed>> = BTreeMap::new();
+    for f in files.into_iter().filter(|f| f.size > 0) {
+        by_hash.entry((f.sha256.clone(), f.size)).or_default().push(f);
+    }
+    for ((sha256, size), members) in by_hash.into_iter().filter(|(_, m)| m.len() > 1) {
+        let mut inodes = BTreeSet::new();
+        let distinct = members.iter().filter(|f| f.inode.is_none_or(|i| inodes.insert(i))).count() as u64;
+        let mut paths: Vec<String> = members.into_iter().map(|f| f.path).collect();
+        paths.sort();
+        report.duplicated_bytes += size * paths.len() as u64;
+        report.savings += size * (distinct - 1);
+        report.groups.push(DupGroup { sha256, size, wasted: size * (distinct - 1), paths });
+    }
+    report.groups.sort_by(|a, b| b.wasted.cmp(&a.wasted).then_with(|| a.paths.cmp(&b.paths)));
+    report
+}
+
+impl DupReport {
+    pub fn print(&self) {
+        println!("{:>16} {:>7} {:>16}  paths", "wasted", "copies", "size");
+        for g in &self.groups {
+            println!("{:>16} {:>7} {:>16}  {}", g.wasted, g.paths.len(), g.size, g.paths[0]);
+            for p in &g.paths[1..] {
+                println!("{:>42}{}", "", p);
+            }
+        }
+        println!("{} of {} files duplicated, {} of {} bytes; hardlinking would save {} bytes",
+                 self.groups.iter().map(|g| g.paths.len()).sum::<usize>(), self.files,
+                 self.duplicated_bytes, self.bytes, self.savings);
+    }
+}
+
+/// Hash paths as upload would and report duplicate content.  Nothing is
+/// read from or written to the bucket.
+pub async fn dups(storage: &Storage, paths: &[std::path::PathBuf], options: &UploadOptions) -> Result<DupReport> {
+    let plan = crate::actions::plan_upload(storage, "dups", paths, options, false).await?;
+    Ok(group(plan.files.iter().filter_map(hashed).collect()))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn file(path: &str, content: &str, inode: Option<(u64, u64)>) -> Hashed {
+        Hashed { path: path.into(), sha256: content.into(), size: content.len() as u64, inode }
+    }
+
+    #[test]
+    fn groups_sort_by_wasted_bytes() {
+        let report = group(vec![
+            file("a/small", "x", None),
+            file("b/small", "x", None),
+            file("c/small", "x", None),
+            file("big", "xxxxxxxxxx", None),
+            file("big.copy", "xxxxxxxxxx", None),
+            file("unique", "yyyy", None),
+            file("empty", "", None),
+            file("empty2", "", None),
+        ]);
+        assert_eq!(report.files, 8);
+        assert_eq!(report.bytes, 27);
+        assert_eq!(report.groups.iter().map(|g| (g.size, g.wasted, g.paths.len())).collect::<Vec<_>>(),
+                   [(10, 10, 2), (1, 2, 3)]);
+        assert_eq!(report.groups[1].paths, ["a/small", "b/small", "c/small"]);
+        assert_eq!(report.duplicated_bytes, 23);
+        assert_eq!(report.savings, 12);
+    }
+
+    #[test]
+    fn hardlinks_are_not_copies() {
+        let report = group(vec![
+            file("a", "content", Some((1, 10))),
+            file("b", "content", Some((1, 10))),
+            file("c", "content", Some((1, 11))),
+            file("d", "other!!", Some((1, 12))),
+            file("e", "other!!", Some((1, 12))),
+        ]);
+        assert_eq!(report.groups.iter().map(|g| (g.wasted, g.paths.len())).collect::<Vec<_>>(), [(7, 3), (0, 2)]);
+        assert_eq!(report.savings, 7);
+    }
+
+    #[tokio::test]
+    async fn duplicates_in_a_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        let big = "b".repeat(100_000);
+        for (name, content) in [("one", "same"), ("sub/one", "same"), ("two", big.as_str()), ("sub/two", big.as_str()),
+                                ("sub/three", big.as_str()), ("other", "diff")] {
+            std::fs::write(root.join(name), content).unwrap();
+        }
+        let credentials = || Ok::<_, crate::Error>(::s3::creds::Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap());
+        let storage = Storage::builder("bucket").endpoint("http://127.0.0.1:9").credentials(std::sync::Arc::new(credentials))
+            .offline(true).connect().await.unwrap();
+        let options = UploadOptions { recurse: true, ..Default::default() };
+        let report = dups(&storage, &[root.to_owned()], &options).await.unwrap();
+        assert_eq!(report.files, 6);
+        assert_eq!(report.groups.iter().map(|g| (g.size, g.paths.len())).collect::<Vec<_>>(), [(100_000, 3), (4, 2)]);
+        assert_eq!(report.savings, 200_004);
+    }
+}
@@ -11,15 +11,21 @@ pub enum Error {
     #[error("Bucket named '{0}' not found, and create not allowed")]
     BucketNotFound(String),
 
-    #[error("Unable to respresent path '{0}'")]
+    #[error("Unable to respresent path '{}'", crate::display::local_display(.0))]
     InvalidPath(std::path::PathBuf),
 
     #[error("S3 Credential error: {0}")]
     S3CredentialsError(#[from] s3::creds::error::CredentialsError),
 
+    #[error("Unable to load credentials from {from}: {error}")]
+    CredentialsFrom { from: String, error: s3::creds::error::CredentialsError },
+
     #[error("Error from S3 service: {0}")]
     S3Error(#[from] s3::error::S3Error),
 
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+
     #[error("Error creating bucket: {0}")]
     BucketCreationError(s3::error::S3Error),
 
@@ -32,4 +38,272 @@ pub enum Error {
     #[error("Unable to determine expiry time from {0} days")]
     ExpiryAgeConversionError(u32),
 
+    #[error("Unexpected response {status} from {operation} on '{path}'")]
+    UnexpectedStatus { operation: &'static str, path: String, status: u16 },
+
+    #[error("Failed to set permissions on '{0}': {1}")]
+    SetPermissions(String, std::io::Error),
+
+    #[error("Invalid hash manifest: {0}")]
+    InvalidHashManifest(String),
+
+    #[error("Hash manifest is wrong for '{path}': expected {expected} but file hashes to {actual}")]
+    HashManifestMismatch { path: String, expected: String, actual: String },
+
+    #[error("Invalid prefix '{0}': expected eg 'org/repo/' outside cache/, objects/ and stats/")]
+    InvalidPrefix(String),
+
+    #[error("Migrated '{key}' has size {actual:?}, expected {expected}")]
+    MigrationVerifyFailed { key: String, expected: u64, actual: Option<u64> },
+
+    #[error("'{path}' would be stored at '{key}', which is reserved for cache metadata")]
+    ReservedKey { path: String, key: String },
+
+    #[error("Invalid time '{0}': expected RFC 3339 (eg 2025-01-31T12:00:00Z) or @reference-file")]
+    InvalidInstant(String),
+
+    #[error("Cache '{0}' doesn't record modification times - upload it with --preserve-times to use --newer-than")]
+    NoRecordedTimes(String),
+
+    #[error("Cache '{cache}' has {count} missing or damaged files (use --keep-going to restore the rest): {details}")]
+    CacheDamaged { cache: String, count: usize, details: String },
+
+    #[error("Invalid object '{0}': expected a sha256 split as 8/8/8/40 lowercase hex")]
+    InvalidObject(String),
+
+    #[error("Unreadable layout marker: {0}")]
+    InvalidLayout(String),
+
+    #[error("Bucket layout incompatible with this release: {0}.  Use --force-layout to continue anyway")]
+    IncompatibleLayout(String),
+
+    #[error("'{path}' has changed since the upload was planned: {reason}")]
+    PlanStale { path: String, reason: String },
+
+    #[error("Plan is for cache '{planned}', not '{requested}'")]
+    PlanCacheMismatch { planned: String, requested: String },
+
+    #[error("Symlinks with absolute targets (use --absolute-symlinks=keep or rewrite to allow): {0}")]
+    AbsoluteSymlinks(String),
+
+    #[error("Paths collide once Unicode normalised (upload with --unicode-normalize=off to keep both): {0}")]
+    UnicodeCollision(String),
+
+    #[error("Caches would overwrite each other's files (use --outpath-per-name to keep them apart): {0}")]
+    CacheConflict(String),
+
+    #[error("Entry of cache '{cache}' is corrupt: {reason}")]
+    CorruptEntry { cache: String, reason: String },
+
+    #[error("Entry of cache '{cache}' is format '{version}', newer than this release understands - upgrade s3-cache to read it")]
+    UnsupportedEntryVersion { cache: String, version: String },
+
+    #[error("Offline, but {operation} of '{key}' needs the bucket")]
+    OfflineModeViolation { operation: &'static str, key: String },
+
+    #[error("Maintenance lease taken over by '{0}'")]
+    LeaseLost(String),
+
+    #[error("Upload of {files} files totalling {bytes} bytes exceeds {limits}.  Exclude some, or use --truncate-to-limits.  Largest: {largest}")]
+    UploadTooLarge { files: usize, bytes: u64, limits: String, largest: String },
+
+    #[error("Invalid POSIX ACL: {0}")]
+    InvalidAcl(String),
+
+    #[error("'{path}' doesn't match its object after download: expected {expected} but file hashes to {actual}")]
+    IntegrityError { path: String, expected: String, actual: String },
+
+    #[error("'{path}' doesn't match the sha256 recorded in its entry: expected {expected} but file hashes to {actual}")]
+    ChecksumMismatch { path: String, expected: String, actual: String },
+
+    #[error("No S3 endpoint given")]
+    NoEndpoint,
+
+    #[error("Timed out connecting to S3 after {}", humantime::format_duration(*.0))]
+    ConnectTimeout(std::time::Duration),
+
+    #[error("Selftest mismatch on {0}")]
+    SelftestMismatch(String),
+
+    #[error("Cache named '{0}' already exists (use --overwrite to replace it)")]
+    CacheAlreadyExists(String),
+
+    #[error("Caches left unable to restore after expiry: {0}")]
+    BrokenCaches(String),
+
+    #[error("Unable to create symlink '{path}' -> '{target}': {source}")]
+    SymlinkFailed { path: String, target: String, source: std::io::Error },
+
+    #[error("Symlinks not restored: {0}")]
+    SymlinksNotRestored(String),
+
+    #[error("Permission denied reading '{path}' (use --keep-going to skip it, or --exclude it): {source}")]
+    PermissionDenied { path: String, source: std::io::Error },
+
+    #[error("Failed to {operation} {} files of '{cache}': {}", .failures.len(), FileFailure::list(.failures))]
+    FilesFailed { operation: &'static str, cache: String, failures: Vec<FileFailure> },
+
+    #[error("Invalid cache name template '{template}': {reason}")]
+    InvalidTemplate { template: String, reason: String },
+
+    #[error("'{pattern}' matches {count} caches (use --yes to delete them all)")]
+    AmbiguousMatch { pattern: String, count: usize },
+
+    #[error("An interrupted download left {0} half moved into place (use download --recover to finish it)")]
+    InterruptedCommit(String),
+
+    #[error("'{path}' not found in cache '{cache}'")]
+    FileNotFound { cache: String, path: String },
+
+    #[error("Unable to read '{path}' of cache '{cache}': {reason}")]
+    UnreadableLink { cache: String, path: String, reason: String },
+
+    #[error("Unsafe path '{0}' in cache entry, which would restore outside the output directory")]
+    UnsafePath(String),
+
+}
+
+/// A file that failed to transfer under --keep-going, and why
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileFailure {
+    pub path: String,
+    pub error: String,
+}
+
+impl FileFailure {
+    fn list(failures: &[FileFailure]) -> String {
+        failures.iter().map(|f| format!("'{}' ({})", f.path, f.error)).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Transient io failures, typically network trouble
+fn is_transient_io(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(e.kind(), TimedOut | ConnectionReset | ConnectionAborted | ConnectionRefused
+             | BrokenPipe | Interrupted | UnexpectedEof | WouldBlock)
+}
+
+impl Error {
+    /// The service rejected the request because temporary credentials
+    /// (eg an STS session token) have expired
+    pub fn is_expired_credentials(&self) -> bool {
+        match self {
+            Error::S3Error(s3::error::S3Error::HttpFailWithBody(400 | 403, body)) => {
+                ["ExpiredToken", "InvalidToken", "TokenRefreshRequired", "RequestExpired"]
+                    .iter().any(|code| body.contains(code))
+            },
+            _ => false,
+        }
+    }
+
+    /// Trying the same request again might well succeed: throttling,
+    /// server errors and network trouble
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::S3Error(s3::error::S3Error::HttpFailWithBody(status, body)) => {
+                matches!(status, 408 | 429 | 500 | 502 | 503 | 504)
+                    || ["RequestTimeout", "SlowDown", "InternalError", "ServiceUnavailable"]
+                        .iter().any(|code| body.contains(code))
+            },
+            Error::S3Error(s3::error::S3Error::Io(e)) => is_transient_io(e),
+            Error::S3Error(s3::error::S3Error::Reqwest(e)) => e.is_timeout() || e.is_connect(),
+            Error::IoError(e) => is_transient_io(e),
+            Error::ConnectTimeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The provider refused the request as one of too many: 429, or a 503
+    /// SlowDown
+    pub fn is_throttled(&self) -> bool {
+        match self {
+            Error::S3Error(s3::error::S3Error::HttpFailWithBody(status, body)) => {
+                *status == 429 || ["SlowDown", "TooManyRequests"].iter().any(|code| body.contains(code))
+            },
+            _ => false,
+        }
+    }
+
+    /// The connection to the service failed, rather than the request, so
+    /// it's worth connecting afresh.  Local IO errors aren't.
+    pub fn is_connection_failure(&self) -> bool {
+        match self {
+            Error::S3Error(s3::error::S3Error::Io(e)) => is_transient_io(e),
+            Error::S3Error(s3::error::S3Error::Reqwest(e)) => e.is_timeout() || e.is_connect(),
+            _ => false,
+        }
+    }
+
+    /// The object, cache or file asked for doesn't exist
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Error::S3Error(s3::error::S3Error::HttpFailWithBody(404, _)) => true,
+            Error::S3Error(s3::error::S3Error::Io(e)) | Error::IoError(e) => e.kind() == std::io::ErrorKind::NotFound,
+            Error::CacheNotFound(_) | Error::BucketNotFound(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Credentials are missing, wrong, expired or lack permission
+    pub fn is_auth(&self) -> bool {
+        match self {
+            Error::S3Error(s3::error::S3Error::HttpFailWithBody(401 | 403, _)) => true,
+            Error::S3CredentialsError(_) | Error::CredentialsFrom { .. } => true,
+            _ => self.is_expired_credentials(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn http(status: u16, body: &str) -> Error {
+        Error::S3Error(s3::error::S3Error::HttpFailWithBody(status, body.into()))
+    }
+
+    fn io(kind: std::io::ErrorKind) -> Error {
+        Error::IoError(kind.into())
+    }
+
+    #[test]
+    fn classification() {
+        use std::io::ErrorKind;
+        // error, retryable, not found, auth
+        let table = [
+            (http(404, "NoSuchKey"), false, true, false),
+            (http(403, "AccessDenied"), false, false, true),
+            (http(401, ""), false, false, true),
+            (http(400, "<Code>ExpiredToken</Code>"), false, false, true),
+            (http(400, "<Code>RequestTimeout</Code>"), true, false, false),
+            (http(400, "MalformedXML"), false, false, false),
+            (http(429, ""), true, false, false),
+            (http(500, ""), true, false, false),
+            (http(503, "<Code>SlowDown</Code>"), true, false, false),
+            (http(501, "NotImplemented"), false, false, false),
+            (io(ErrorKind::TimedOut), true, false, false),
+            (io(ErrorKind::ConnectionReset), true, false, false),
+            (io(ErrorKind::NotFound), false, true, false),
+            (io(ErrorKind::PermissionDenied), false, false, false),
+            (Error::S3Error(s3::error::S3Error::Io(ErrorKind::BrokenPipe.into())), true, false, false),
+            (Error::CacheNotFound("c".into()), false, true, false),
+            (Error::BucketNotFound("b".into()), false, true, false),
+            (Error::InvalidPath("p".into()), false, false, false),
+            (Error::UnexpectedStatus { operation: "put_file", path: "p".into(), status: 500 }, false, false, false),
+        ];
+        for (e, retryable, not_found, auth) in table {
+            assert_eq!(e.is_retryable(), retryable, "retryable {:?}", e);
+            assert_eq!(e.is_not_found(), not_found, "not found {:?}", e);
+            assert_eq!(e.is_auth(), auth, "auth {:?}", e);
+        }
+        assert!(http(429, "").is_throttled());
+        assert!(http(503, "<Code>SlowDown</Code>").is_throttled());
+        assert!(!http(503, "<Code>ServiceUnavailable</Code>").is_throttled());
+        assert!(!http(500, "").is_throttled());
+        assert!(!io(ErrorKind::TimedOut).is_throttled());
+        assert!(Error::S3Error(s3::error::S3Error::Io(ErrorKind::ConnectionReset.into())).is_connection_failure());
+        assert!(!io(ErrorKind::ConnectionReset).is_connection_failure(), "local IO");
+        assert!(!http(503, "").is_connection_failure());
+    }
 }
@@ -0,0 +1,795 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{access, cache::{self, Cache, CacheKey}, clock, display::key_display, object, s3::ObjectInfo, Result, Storage};
+
+/// Bump when the checkpoint format changes; other versions are discarded
+const VERSION: u32 = 1;
+
+/// Tuning for [expire]
+#[derive(Debug, Clone)]
+pub struct ExpireOptions {
+    /// Age of objects to expire unconditionally
+    pub days: u32,
+    /// Record progress here, and resume from what an earlier run recorded
+    pub checkpoint: Option<PathBuf>,
+    /// Stop cleanly, saving the checkpoint, once this has elapsed
+    pub time_budget: Option<Duration>,
+    /// Keep objects of caches downloaded at least this often within window
+    pub min_reads: Option<usize>,
+    pub window: Duration,
+    /// Only expire objects no cache entry refers to
+    pub unused: bool,
+    /// Also delete whole caches created more than this many days ago,
+    /// independently of days
+    pub cache_age_days: Option<u32>,
+    /// Only delete whole caches whose names match this glob
+    pub cache_match: Option<glob::Pattern>,
+    /// Afterwards, check a sample of the files of every cache left, failing
+    /// if any are gone
+    pub verify: bool,
+}
+
+impl Default for ExpireOptions {
+    fn default() -> Self {
+        ExpireOptions {
+            days: 14, checkpoint: None, time_budget: None,
+            min_reads: None, window: Duration::from_secs(30 * 24 * 60 * 60),
+            unused: false, cache_age_days: None, cache_match: None, verify: false,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExpireReport {
+    pub examined: usize,
+    pub deleted: usize,
+    /// Old, but used by a frequently read cache, or by any with --unused
+    pub kept: usize,
+    /// Reached the end of the listing, rather than running out of time
+    pub complete: bool,
+    /// Whole caches deleted for their age, with entries
+    pub caches_deleted: usize,
+    /// Caches left whose sampled files are gone, when verified
+    pub broken: usize,
+}
+
+/// The last key fully processed.  Keys list in order, so a rerun lists
+/// from just after it.  It's advisory: keys added or removed meanwhile
+/// only mean some are examined again, or on the next pass.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Checkpoint {
+    version: u32,
+    prefix: String,
+    after: String,
+}
+
+/// Where to resume listing prefix from, if path holds a usable checkpoint
+fn load_checkpoint(path: &Path, prefix: &str) -> Option<String> {
+    let bytes = std::fs::read(path).ok()?;
+    match serde_json::from_slice::<Checkpoint>(&bytes) {
+        Ok(c) if c.version == VERSION && c.prefix == prefix => Some(c.after),
+        Ok(_) => {
+            log::warn!("Ignoring checkpoint in {} from another version or prefix", path.display());
+            None
+        },
+        Err(e) => {
+            log::warn!("Ignoring damaged checkpoint {}: {}", path.display(), e);
+            None
+        },
+    }
+}
+
+fn save_checkpoint(path: &Path, prefix: &str, after: &str) -> Result<()> {
+    let c = Checkpoint { version: VERSION, prefix: prefix.to_owned(), after: after.to_owned() };
+    let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+    std::fs::write(&tmp, serde_json::to_vec(&c)?)?;
+    std::fs::rename(&tmp, path).inspect_err(|_| { let _ = std::fs::remove_file(&tmp); })?;
+    Ok(())
+}
+
+/// What expiry needs of a bucket
+pub(crate) trait Target {
+    /// Objects below prefix, in key order, starting after the given key
+    async fn list_page(&self, prefix: &str, after: Option<&str>) -> Result<Vec<ObjectInfo>>;
+    async fn delete(&self, key: &str) -> Result<()>;
+    fn strict_deletes(&self) -> bool;
+}
+
+impl Target for Storage {
+    async fn list_page(&self, prefix: &str, after: Option<&str>) -> Result<Vec<ObjectInfo>> {
+        Ok(Storage::list_page(self, prefix, after).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        Ok(Storage::delete(self, key).await?)
+    }
+
+    fn strict_deletes(&self) -> bool {
+        self.strictness().deletes
+    }
+}
+
+/// Objects without a modification time can't be shown to be young
+fn is_expired(o: &ObjectInfo, expiry_time: chrono::DateTime<chrono::Utc>) -> bool {
+    match o.last_modified {
+        Some(modified) => modified < expiry_time,
+        None => {
+            log::info!("Unable to find modification time while expiring '{}': expiring it", o.key);
+            true
+        },
+    }
+}
+
+/// Add the storage keys of objects an entry refers to
+fn add_references(objects: &mut HashSet<String>, cache_name: &str, entry: &[u8]) -> Result<()> {
+    let (c, _) = cache::decode(cache_name, entry)?;
+    objects.extend(c.files.iter().filter_map(cache::File::object_storage_key));
+    Ok(())
+}
+
+/// Storage keys of objects used by the caches hot, read often of late
+async fn protected_objects(storage: &Storage, hot: &[String], min_reads: usize) -> Result<HashSet<String>> {
+    let mut protected = HashSet::new();
+    for name in hot {
+        let entry = Cache::entry_location(name);
+        let vec = match cache::read_entry(storage, name, entry.to_str().expect("entry location is utf8")).await {
+            Ok(vec) => vec,
+            Err(e) => {
+                log::info!("Unable to read '{}' to protect its objects: {}", name, e);
+                continue;
+            },
+        };
+        add_references(&mut protected, name, &vec).with_context(|| format!("Failed to decode entry of '{}'", name))?;
+        log::info!("Keeping objects of '{}', read at least {} times", name, min_reads);
+    }
+    Ok(protected)
+}
+
+/// Caches whose entries are examined at once by [expire_caches]
+const CACHES_IN_FLIGHT: usize = 8;
+
+/// Delete cache name if its entry was created before cutoff, returning
+/// whether it was
+async fn expire_cache(storage: Storage, name: String, cutoff: chrono::DateTime<chrono::Utc>) -> Result<bool> {
+    let entry = Cache::entry_location(&name);
+    let vec = match cache::read_entry(&storage, &name, entry.to_str().expect("entry location is utf8")).await {
+        Ok(vec) => vec,
+        // deleted meanwhile, or never finished uploading
+        Err(e) if e.is_not_found() => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read entry of '{}'", name)),
+    };
+    let Some(created) = created_before(&name, &vec, cutoff) else {
+        return Ok(false);
+    };
+    delete_cache(&storage, &name).await?;
+    log::info!("Expired cache '{}' created {}", name, created.to_rfc3339());
+    Ok(true)
+}
+
+/// Delete everything below a cache's prefix, its entries last, so it's
+/// never left looking present with its files gone.  Files that fail to
+/// delete are left as garbage rather than keep the entry: the cache either
+/// goes whole or, if the entry itself can't be deleted, fails the expiry.
+pub(crate) async fn delete_cache<T: Target>(target: &T, name: &str) -> Result<()> {
+    let prefix = format!("{}/", Cache::location(name).to_str().expect("cache location is utf8"));
+    let mut keys = Vec::new();
+    loop {
+        let page = target.list_page(&prefix, keys.last().map(String::as_str)).await?;
+        if page.is_empty() {
+            break;
+        }
+        keys.extend(page.into_iter().map(|o| o.key));
+    }
+    let entry = Cache::entry_location(name);
+    let entry = entry.to_str().expect("entry location is utf8");
+    // the entry itself very last, after any kept from earlier uploads
+    keys.sort_by_key(|key| (is_entry_key(key), key == entry));
+    debug_assert!(keys.iter().position(|k| k == entry).is_none_or(|i| i == keys.len() - 1));
+
+    let mut failed = None;
+    for key in &keys {
+        match target.delete(key).await {
+            Ok(()) => {},
+            Err(e) if e.downcast_ref::<crate::Error>().is_some_and(crate::Error::is_not_found) =>
+                log::debug!("'{}' already gone", key_display(key)),
+            Err(e) if is_entry_key(key) => return Err(e).with_context(|| format!("Failed to delete '{}'", key_display(key))),
+            Err(e) if target.strict_deletes() => {
+                failed.get_or_insert(e);
+            },
+            Err(e) => log::warn!("Error deleting '{}': {}, continuing...", key_display(key), e),
+        }
+    }
+    match failed {
+        Some(e) => Err(e).with_context(|| format!("Failed to delete all of '{}'", name)),
+        None => Ok(()),
+    }
+}
+
+/// Files of each cache looked up by [broken_caches]
+const VERIFY_SAMPLE: usize = 8;
+
+/// Whether the files sampled from cache name's entry are all present, or
+/// None if it has no entry to check
+async fn is_intact(storage: Storage, name: String) -> Result<Option<(String, bool)>> {
+    let entry = Cache::entry_location(&name);
+    let vec = match cache::read_entry(&storage, &name, entry.to_str().expect("entry location is utf8")).await {
+        Ok(vec) => vec,
+        Err(e) if e.is_not_found() => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read entry of '{}'", name)),
+    };
+    let c = match cache::decode(&name, &vec) {
+        Ok((c, _)) => c,
+        Err(e) => {
+            log::warn!("Unable to decode entry of '{}': {}", name, e);
+            return Ok(Some((name, false)));
+        },
+    };
+    let mut keys: Vec<String> = c.files.iter()
+        .filter(|f| f.link_target.is_none())
+        .map(|f| f.storage_path(&name).to_str().expect("Invalid storage_path -> string").to_owned())
+        .collect();
+    fastrand::shuffle(&mut keys);
+    for key in keys.iter().take(VERIFY_SAMPLE) {
+        if storage.head(key).await?.is_none() {
+            log::warn!("Cache '{}' is missing '{}'", name, key_display(key));
+            return Ok(Some((name, false)));
+        }
+    }
+    Ok(Some((name, true)))
+}
+
+/// Caches whose entries refer to files that are gone, judged by a sample
+/// of each
+async fn broken_caches(storage: &Storage) -> Result<Vec<String>> {
+    let mut broken = Vec::new();
+    let mut set = tokio::task::JoinSet::new();
+    let mut handle = |work: std::result::Result<Result<Option<(String, bool)>>, tokio::task::JoinError>| -> Result<()> {
+        if let Some((name, false)) = work.with_context(|| "Failure waiting on cache checks")?? {
+            broken.push(name);
+        }
+        Ok(())
+    };
+    for name in storage.list_dirs("cache/").await? {
+        while set.len() >= CACHES_IN_FLIGHT {
+            if let Some(work) = set.join_next().await {
+                handle(work)?;
+            }
+        }
+        set.spawn(is_intact(storage.clone(), name));
+    }
+    while let Some(work) = set.join_next().await {
+        handle(work)?;
+    }
+    broken.sort();
+    Ok(broken)
+}
+
+/// When the entry was created, if that was before cutoff.  Entries without
+/// a creation time, or that can't be decoded, are never old enough.
+fn created_before(name: &str, entry: &[u8], cutoff: chrono::DateTime<chrono::Utc>) -> Option<chrono::DateTime<chrono::Utc>> {
+    let (c, _) = cache::decode(name, entry)
+        .inspect_err(|e| log::warn!("Unable to decode entry of '{}', not expiring it: {}", name, e))
+        .ok()?;
+    if c.created_at.is_none() {
+        log::debug!("Cache '{}' has no creation time, not expiring it", name);
+    }
+    c.created_at.filter(|created| *created < cutoff)
+}
+
+/// Delete whole caches created before cutoff, other than those in keep.
+/// Entries written before creation times were recorded are left alone.
+async fn expire_caches(storage: &Storage, cutoff: chrono::DateTime<chrono::Utc>, keep: &[String],
+                       matching: Option<&glob::Pattern>) -> Result<usize> {
+    let mut deleted = 0;
+    let mut set = tokio::task::JoinSet::new();
+    for name in storage.list_dirs("cache/").await? {
+        if matching.is_some_and(|glob| !glob.matches(&name)) {
+            continue;
+        }
+        if keep.contains(&name) {
+            log::info!("Keeping cache '{}', read often", name);
+            continue;
+        }
+        while set.len() >= CACHES_IN_FLIGHT {
+            if let Some(work) = set.join_next().await {
+                deleted += usize::from(work.with_context(|| "Failure waiting on cache expiry")??);
+            }
+        }
+        set.spawn(expire_cache(storage.clone(), name, cutoff));
+    }
+    while let Some(work) = set.join_next().await {
+        deleted += usize::from(work.with_context(|| "Failure waiting on cache expiry")??);
+    }
+    Ok(deleted)
+}
+
+/// Entries, current or kept from earlier uploads, whose objects are in use
+fn is_entry(meta: &str) -> bool {
+    meta == "entry" || meta.starts_with("entry.prev.")
+}
+
+fn is_entry_key(key: &str) -> bool {
+    matches!(cache::parse_key(key), Some((_, CacheKey::Meta(meta))) if is_entry(meta))
+}
+
+/// Storage keys of objects referred to by any cache entry.  An entry that
+/// can't be read or decoded fails the whole set, rather than leaving its
+/// objects looking unused.
+async fn referenced_objects(storage: &Storage) -> Result<HashSet<String>> {
+    let mut referenced = HashSet::new();
+    let mut entries = 0;
+    for o in storage.list_objects("cache/").await? {
+        let Some((name, CacheKey::Meta(meta))) = cache::parse_key(&o.key) else {
+            continue;
+        };
+        if !is_entry(meta) {
+            continue;
+        }
+        let vec = match cache::read_entry(storage, name, &o.key).await {
+            Ok(vec) => vec,
+            // deleted since listed, so its objects are no longer referenced
+            Err(e) if e.is_not_found() => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read '{}'", key_display(&o.key))),
+        };
+        add_references(&mut referenced, name, &vec)
+            .with_context(|| format!("Failed to decode '{}' of cache '{}'", key_display(&o.key), name))?;
+        entries += 1;
+    }
+    log::info!("{} objects referenced by {} entries", referenced.len(), entries);
+    Ok(referenced)
+}
+
+/// Delete objects below prefix older than expiry_time, page by page.  The
+/// checkpoint is saved after each page and when time runs out; at least one
+/// object is examined per run so tiny budgets still make progress.
+pub(crate) async fn expire_below<T: Target>(target: &T, prefix: &str, expiry_time: chrono::DateTime<chrono::Utc>,
+                                            protected: &HashSet<String>, checkpoint: Option<&Path>,
+                                            time_budget: Option<Duration>) -> Result<ExpireReport> {
+    let deadline = time_budget.map(|budget| tokio::time::Instant::now() + budget);
+    let mut after = checkpoint.and_then(|path| load_checkpoint(path, prefix));
+    if let Some(after) = after.as_ref() {
+        log::warn!("Resuming expiry after '{}'", after);
+    }
+
+    let save = |after: &str| -> Result<()> {
+        match checkpoint {
+            Some(path) => save_checkpoint(path, prefix, after)
+                .with_context(|| format!("Failed to save checkpoint {}", path.display())),
+            None => Ok(()),
+        }
+    };
+
+    let mut report = ExpireReport::default();
+    loop {
+        let page = target.list_page(prefix, after.as_deref()).await?;
+        if page.is_empty() {
+            break;
+        }
+        for o in page {
+            report.examined += 1;
+            if !is_expired(&o, expiry_time) {
+                // young, nothing to do
+            } else if protected.contains(&o.key) {
+                report.kept += 1;
+            } else {
+                match target.delete(&o.key).await {
+                    Ok(()) => report.deleted += 1,
+                    Err(e) if target.strict_deletes() => {
+                        // so the rerun tries this one again
+                        if let Some(after) = after.as_deref() {
+                            save(after)?;
+                        }
+                        return Err(e);
+                    },
+                    Err(e) => log::info!("Failed to delete expired object '{}': {}: continuing...", o.key, e),
+                }
+            }
+            after = Some(o.key);
+
+            if deadline.is_some_and(|d| tokio::time::Instant::now() >= d) {
+                let after = after.as_deref().expect("an object was examined");
+                save(after)?;
+                log::warn!("Time budget spent after '{}'; rerun to continue", after);
+                return Ok(report);
+            }
+        }
+        save(after.as_deref().expect("page was not empty"))?;
+    }
+
+    if let Some(path) = checkpoint {
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Unable to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+    report.complete = true;
+    Ok(report)
+}
+
+/// Delete objects older than options.days, or with options.unused only
+/// those no entry refers to
+pub async fn expire(storage: &Storage, options: &ExpireOptions) -> Result<ExpireReport> {
+    // objects are stamped by the server's clock, so judge their age by it
+    let skew = clock::measure(storage).await?.unwrap_or_default();
+    let now = chrono::Utc::now();
+    let expiry_time = clock::cutoff(skew, now, chrono::TimeDelta::days(options.days.into()))
+        .ok_or(crate::Error::ExpiryAgeConversionError(options.days))?;
+    let (hot, protected) = match options.min_reads {
+        Some(min_reads) => {
+            let window = chrono::Duration::from_std(options.window).context("Read window out of range")?;
+            // reads are stamped by the downloading client's clock
+            let hot = access::hot_caches(storage, now - window, min_reads).await?;
+            let protected = protected_objects(storage, &hot, min_reads).await?;
+            (hot, protected)
+        },
+        None => (Vec::new(), HashSet::new()),
+    };
+    // first, so objects only they used are unused below
+    let caches_deleted = match options.cache_age_days {
+        Some(days) => {
+            // as are creation times, by the uploading client's
+            let cutoff = now - chrono::TimeDelta::days(days.into());
+            let deleted = expire_caches(storage, cutoff, &hot, options.cache_match.as_ref()).await?;
+            log::warn!("Expired {} caches created more than {} days ago", deleted, days);
+            deleted
+        },
+        None => 0,
+    };
+    let protected = match options.unused {
+        true => protected.into_iter().chain(referenced_objects(storage).await?).collect(),
+        false => protected,
+    };
+    let mut report = expire_below(storage, object::ROOT, expiry_time, &protected,
+                                  options.checkpoint.as_deref(), options.time_budget).await?;
+    report.caches_deleted = caches_deleted;
+    log::warn!("Expired {} of {} objects examined, kept {} in use{}", report.deleted, report.examined, report.kept,
+               if report.complete { "" } else { ", stopped early" });
+    if options.verify {
+        let broken = broken_caches(storage).await?;
+        report.broken = broken.len();
+        if !broken.is_empty() {
+            return Err(crate::Error::BrokenCaches(broken.join(", ")).into());
+        }
+        log::info!("Every cache left has the files sampled");
+    }
+    Ok(report)
+}
+
+/// Modification time objects must be newer than for the newest of them to
+/// fit within max_total bytes, None if they all fit
+fn size_cutoff(objects: &mut [ObjectInfo], max_total: u64) -> Option<chrono::DateTime<chrono::Utc>> {
+    // newest first; those without a time can't be shown to be young, so go last
+    objects.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
+    let mut total = 0;
+    let over = objects.iter().position(|o| {
+        total += o.size;
+        total > max_total
+    })?;
+    Some(match objects[over].last_modified {
+        Some(t) => t + chrono::TimeDelta::nanoseconds(1),
+        // only the undated ones need go, and they always expire
+        None => objects[..over].last().and_then(|o| o.last_modified).unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC),
+    })
+}
+
+/// Expire the oldest objects until those left total at most max_total bytes
+pub async fn trim(storage: &Storage, max_total: u64) -> Result<ExpireReport> {
+    let mut objects = storage.list_objects(object::ROOT).await?;
+    let Some(cutoff) = size_cutoff(&mut objects, max_total) else {
+        log::warn!("{} objects fit within {} bytes, nothing to trim", objects.len(), max_total);
+        return Ok(ExpireReport { examined: objects.len(), complete: true, ..Default::default() });
+    };
+    let report = expire_below(storage, object::ROOT, cutoff, &HashSet::new(), None, None).await?;
+    log::warn!("Trimmed {} of {} objects examined to fit within {} bytes", report.deleted, report.examined, max_total);
+    Ok(report)
+}
+
+/// Tuning for [purge_orphans]
+#[derive(Debug, Clone)]
+pub struct PurgeOptions {
+    /// Report what would be deleted without deleting it
+    pub dry_run: bool,
+    /// Keep orphans younger than this, as uploads put objects before the
+    /// entry referring to them
+    pub grace: Duration,
+}
+
+impl Default for PurgeOptions {
+    fn default() -> Self {
+        PurgeOptions { dry_run: false, grace: Duration::from_secs(60 * 60) }
+    }
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PurgeReport {
+    /// Orphans deleted, or that would be with dry_run
+    pub deleted_count: usize,
+    pub deleted_bytes: u64,
+    /// Referenced objects, and orphans within the grace period
+    pub retained_count: usize,
+}
+
+/// Delete objects below prefix that aren't referenced, unless modified
+/// after cutoff
+async fn purge_below<T: Target>(target: &T, prefix: &str, referenced: &HashSet<String>,
+                                cutoff: chrono::DateTime<chrono::Utc>, dry_run: bool) -> Result<PurgeReport> {
+    let mut report = PurgeReport::default();
+    let mut after: Option<String> = None;
+    loop {
+        let page = target.list_page(prefix, after.as_deref()).await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some(last.key.clone());
+        for o in page {
+            if referenced.contains(&o.key) || !is_expired(&o, cutoff) {
+                report.retained_count += 1;
+                continue;
+            }
+            if dry_run {
+                log::info!("Would delete orphan '{}'", o.key);
+            } else {
+                match target.delete(&o.key).await {
+                    Ok(()) => {},
+                    Err(e) if target.strict_deletes() => return Err(e),
+                    Err(e) => {
+                        log::info!("Failed to delete orphan '{}': {}: continuing...", o.key, e);
+                        continue;
+                    },
+                }
+            }
+            report.deleted_count += 1;
+            report.deleted_bytes += o.size;
+        }
+    }
+    Ok(report)
+}
+
+/// Delete objects no cache entry refers to
+pub async fn purge_orphans(storage: &Storage, options: &PurgeOptions) -> Result<PurgeReport> {
+    let skew = clock::measure(storage).await?.unwrap_or_default();
+    let grace = chrono::Duration::from_std(options.grace).context("Grace period out of range")?;
+    let cutoff = clock::cutoff(skew, chrono::Utc::now(), grace).context("Grace period out of range")?;
+    let referenced = referenced_objects(storage).await?;
+    let report = purge_below(storage, object::ROOT, &referenced, cutoff, options.dry_run).await?;
+    log::warn!("{} {} orphaned objects ({} bytes), retained {}",
+               if options.dry_run { "Would delete" } else { "Deleted" },
+               report.deleted_count, report.deleted_bytes, report.retained_count);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    const PAGE: usize = 3;
+
+    /// Objects by key, old ones at odd indices, counting deletes of each
+    struct MockBucket {
+        objects: Mutex<BTreeMap<String, chrono::DateTime<chrono::Utc>>>,
+        deletes: Mutex<BTreeMap<String, usize>>,
+        /// Keys deleted, in order
+        order: Mutex<Vec<String>>,
+        /// A key that refuses to be deleted
+        failing: Option<String>,
+    }
+
+    impl MockBucket {
+        fn new(count: usize) -> MockBucket {
+            let now = chrono::Utc::now();
+            let objects = (0..count).map(|i| {
+                let age = if i % 2 == 1 { chrono::Duration::days(30) } else { chrono::Duration::days(1) };
+                (format!("objects/{:04}/bin", i), now - age)
+            }).collect();
+            MockBucket { objects: Mutex::new(objects), deletes: Mutex::default(), order: Mutex::default(), failing: None }
+        }
+
+        /// A cache whose files and older entry were uploaded a month before
+        /// its entry was last written, beside another cache and an object
+        fn with_cache() -> MockBucket {
+            let now = chrono::Utc::now();
+            let objects = [("cache/c/files/a", 40), ("cache/c/files/d/b", 40), ("cache/c/entry.prev.1", 35),
+                           ("cache/c/entry", 1), ("cache/cd/entry", 40), ("objects/0001/bin", 40)]
+                .map(|(key, days)| (key.to_owned(), now - chrono::Duration::days(days)));
+            MockBucket { objects: Mutex::new(objects.into()), ..MockBucket::new(0) }
+        }
+
+        fn keys_below(&self, prefix: &str) -> Vec<String> {
+            self.objects.lock().unwrap().keys().filter(|k| k.starts_with(prefix)).cloned().collect()
+        }
+    }
+
+    impl Target for MockBucket {
+        async fn list_page(&self, prefix: &str, after: Option<&str>) -> Result<Vec<ObjectInfo>> {
+            Ok(self.objects.lock().unwrap().iter()
+               .filter(|(k, _)| k.starts_with(prefix) && after.is_none_or(|a| k.as_str() > a))
+               .take(PAGE)
+               .map(|(k, t)| ObjectInfo { key: k.clone(), size: 1, last_modified: Some(*t) })
+               .collect())
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            if self.failing.as_deref() == Some(key) {
+                anyhow::bail!("refused to delete {}", key);
+            }
+            self.order.lock().unwrap().push(key.to_owned());
+            self.objects.lock().unwrap().remove(key);
+            *self.deletes.lock().unwrap().entry(key.to_owned()).or_default() += 1;
+            Ok(())
+        }
+
+        fn strict_deletes(&self) -> bool {
+            true
+        }
+    }
+
+    fn expiry_time() -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now() - chrono::Duration::days(14)
+    }
+
+    fn dated(key: &str, size: u64, days: Option<i64>) -> ObjectInfo {
+        let now = "2025-06-01T00:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+        ObjectInfo { key: key.to_owned(), size, last_modified: days.map(|d| now - chrono::Duration::days(d)) }
+    }
+
+    #[test]
+    fn size_cutoff_keeps_newest() {
+        let mut objects = vec![dated("old", 10, Some(9)), dated("new", 10, Some(1)), dated("mid", 10, Some(5))];
+        assert_eq!(size_cutoff(&mut objects.clone(), 30), None);
+        let cutoff = size_cutoff(&mut objects, 25).unwrap();
+        let kept: Vec<&str> = objects.iter().filter(|o| !is_expired(o, cutoff)).map(|o| o.key.as_str()).collect();
+        assert_eq!(kept, vec!["new", "mid"]);
+
+        let mut undated = vec![dated("none", 10, None), dated("new", 10, Some(1))];
+        let cutoff = size_cutoff(&mut undated, 15).unwrap();
+        assert!(!is_expired(&undated[0], cutoff) && is_expired(&undated[1], cutoff));
+        assert_eq!(size_cutoff(&mut [dated("none", 10, None)], 5), Some(chrono::DateTime::<chrono::Utc>::MIN_UTC));
+    }
+
+    #[tokio::test]
+    async fn budgeted_runs_resume_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = dir.path().join("checkpoint");
+        let bucket = MockBucket::new(10);
+
+        // out of time after the first object each
+        let first = expire_below(&bucket, "objects/", expiry_time(), &HashSet::new(), Some(&checkpoint), Some(Duration::ZERO)).await.unwrap();
+        assert_eq!(first, ExpireReport { examined: 1, deleted: 0, kept: 0, complete: false, caches_deleted: 0, broken: 0 });
+        assert_eq!(load_checkpoint(&checkpoint, "objects/").as_deref(), Some("objects/0000/bin"));
+        let second = expire_below(&bucket, "objects/", expiry_time(), &HashSet::new(), Some(&checkpoint), Some(Duration::ZERO)).await.unwrap();
+        assert_eq!(second, ExpireReport { examined: 1, deleted: 1, kept: 0, complete: false, caches_deleted: 0, broken: 0 });
+
+        let rest = expire_below(&bucket, "objects/", expiry_time(), &HashSet::new(), Some(&checkpoint), None).await.unwrap();
+        assert_eq!(rest, ExpireReport { examined: 8, deleted: 4, kept: 0, complete: true, caches_deleted: 0, broken: 0 });
+        assert!(!checkpoint.exists());
+
+        let deletes = bucket.deletes.lock().unwrap();
+        assert_eq!(deletes.len(), 5);
+        assert!(deletes.values().all(|n| *n == 1));
+        assert!(bucket.objects.lock().unwrap().keys().all(|k| k[8..12].parse::<usize>().unwrap() % 2 == 0));
+    }
+
+    #[tokio::test]
+    async fn without_checkpoint_runs_start_over() {
+        let bucket = MockBucket::new(4);
+        expire_below(&bucket, "objects/", expiry_time(), &HashSet::new(), None, Some(Duration::ZERO)).await.unwrap();
+        let report = expire_below(&bucket, "objects/", expiry_time(), &HashSet::new(), None, None).await.unwrap();
+        assert_eq!(report, ExpireReport { examined: 4, deleted: 2, kept: 0, complete: true, caches_deleted: 0, broken: 0 });
+    }
+
+    #[tokio::test]
+    async fn protected_objects_are_kept() {
+        let bucket = MockBucket::new(4);
+        let protected = HashSet::from(["objects/0001/bin".to_owned()]);
+        let report = expire_below(&bucket, "objects/", expiry_time(), &protected, None, None).await.unwrap();
+        assert_eq!(report, ExpireReport { examined: 4, deleted: 1, kept: 1, complete: true, caches_deleted: 0, broken: 0 });
+        assert!(bucket.objects.lock().unwrap().contains_key("objects/0001/bin"));
+    }
+
+    #[tokio::test]
+    async fn caches_go_whole_or_not_at_all() {
+        let bucket = MockBucket::with_cache();
+        // age only ever expires objects, never a cache's own files
+        let report = expire_below(&bucket, object::ROOT, expiry_time(), &HashSet::new(), None, None).await.unwrap();
+        assert_eq!(report.deleted, 1);
+        assert_eq!(bucket.keys_below("cache/c/").len(), 4);
+
+        delete_cache(&bucket, "c").await.unwrap();
+        assert!(bucket.keys_below("cache/c/").is_empty());
+        assert_eq!(bucket.keys_below("cache/"), vec!["cache/cd/entry"]);
+        let order = bucket.order.lock().unwrap();
+        assert_eq!(order[order.len() - 2..], ["cache/c/entry.prev.1", "cache/c/entry"], "entries go last");
+
+        // a file that won't go is left as garbage, never an entry without it
+        let bucket = MockBucket { failing: Some("cache/c/files/a".into()), ..MockBucket::with_cache() };
+        assert!(delete_cache(&bucket, "c").await.is_err());
+        assert_eq!(bucket.keys_below("cache/c/"), vec!["cache/c/files/a"]);
+
+        // an entry that won't go fails the expiry, rather than pass unnoticed
+        let bucket = MockBucket { failing: Some("cache/c/entry".into()), ..MockBucket::with_cache() };
+        assert!(delete_cache(&bucket, "c").await.is_err());
+        assert_eq!(bucket.keys_below("cache/c/"), vec!["cache/c/entry"]);
+    }
+
+    #[tokio::test]
+    async fn orphans_are_purged() {
+        let bucket = MockBucket::new(6);
+        let referenced = HashSet::from(["objects/0001/bin".to_owned(), "objects/0002/bin".to_owned()]);
+        // objects at even indices are a day old, so within this grace
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(2);
+
+        let dry = purge_below(&bucket, "objects/", &referenced, cutoff, true).await.unwrap();
+        assert_eq!(dry, PurgeReport { deleted_count: 2, deleted_bytes: 2, retained_count: 4 });
+        assert_eq!(bucket.objects.lock().unwrap().len(), 6);
+
+        let report = purge_below(&bucket, "objects/", &referenced, cutoff, false).await.unwrap();
+        assert_eq!(report, dry);
+        let deletes = bucket.deletes.lock().unwrap();
+        assert_eq!(deletes.keys().collect::<Vec<_>>(), vec!["objects/0003/bin", "objects/0005/bin"]);
+    }
+
+    #[test]
+    fn entries_reference_objects() {
+        let entry = br#"{"v1": {"files": [
+            {"path": "a", "object": "aa/bb/cc/dd", "size": 30000000, "mode": null, "link_target": null},
+            {"path": "b", "object": null, "size": 2, "mode": null, "link_target": null}
+        ]}}"#;
+        let mut referenced = HashSet::new();
+        add_references(&mut referenced, "c", entry).unwrap();
+        // as File::storage_path lays them out
+        assert_eq!(referenced, HashSet::from(["objects/aa/bb/cc/dd/bin".to_owned()]));
+
+        assert!(add_references(&mut referenced, "c", b"{\"v1\": {\"fil").is_err());
+        assert!(is_entry("entry") && is_entry("entry.prev.1") && !is_entry("access-log"));
+    }
+
+    #[test]
+    fn caches_expire_by_creation_time() {
+        let cutoff = "2025-06-01T00:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+        let entry = |created: Option<&str>| {
+            let c = Cache { created_at: created.map(|t| t.parse().unwrap()), origin_name: Some("c".into()), ..Default::default() };
+            c.into_string().into_bytes()
+        };
+        assert_eq!(created_before("c", &entry(Some("2025-05-01T00:00:00Z")), cutoff), Some("2025-05-01T00:00:00Z".parse().unwrap()));
+        assert_eq!(created_before("c", &entry(Some("2025-06-02T00:00:00Z")), cutoff), None);
+        assert_eq!(created_before("c", &entry(None), cutoff), None);
+        // from before creation times were recorded
+        assert_eq!(created_before("c", br#"{"v1": {"files": []}}"#, cutoff), None);
+        assert_eq!(created_before("c", b"{\"v1\": {\"fil", cutoff), None);
+    }
+
+    #[test]
+    fn mixed_entries_reference_both_forms() {
+        // the same content uploaded raw, then compressed after a flag change
+        let entry = br#"{"v1": {"files": [
+            {"path": "a", "object": "aa/bb/cc/dd", "size": 30000000},
+            {"path": "b", "object": "aa/bb/cc/dd", "size": 30000000, "compression": {"zstd": {"level": 3}}}
+        ]}}"#;
+        let mut referenced = HashSet::new();
+        add_references(&mut referenced, "c", entry).unwrap();
+        assert_eq!(referenced, HashSet::from(["objects/aa/bb/cc/dd/bin".to_owned(), "objects/aa/bb/cc/dd/bin.zst".to_owned()]));
+    }
+
+    #[test]
+    fn checkpoints_for_other_prefixes_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint");
+        save_checkpoint(&path, "objects/", "objects/0001/bin").unwrap();
+        assert_eq!(load_checkpoint(&path, "objects/").as_deref(), Some("objects/0001/bin"));
+        assert_eq!(load_checkpoint(&path, "other/objects/"), None);
+        std::fs::write(&path, "{\"version\": 1, \"pre").unwrap();
+        assert_eq!(load_checkpoint(&path, "objects/"), None);
+    }
+}
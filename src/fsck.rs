@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt as _;
+
+use crate::{merkle::{self, MerkleHasher}, object::{self, ObjectKey}, resume::UploadState, s3::ObjectInfo, Result, Storage};
+
+/// Recorded in place of a cache name in fsck state files
+const STATE_LABEL: &str = "fsck";
+
+/// Tuning for [fsck]
+#[derive(Debug, Clone)]
+pub struct FsckOptions {
+    /// Download objects and check their content hashes to their key
+    pub deep: bool,
+    /// Percentage of objects to check deeply
+    pub sample: u8,
+    /// Maximum number of parallel network connections
+    pub max_in_flight: u32,
+    /// Record verified objects here, and skip those an earlier run verified
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for FsckOptions {
+    fn default() -> Self {
+        FsckOptions { deep: false, sample: 100, max_in_flight: 3, state_file: None }
+    }
+}
+
+/// Percentage, with or without a trailing %
+pub fn parse_percent(s: &str) -> std::result::Result<u8, String> {
+    let n: u8 = s.strip_suffix('%').unwrap_or(s).parse().map_err(|e| format!("{}", e))?;
+    if !(1..=100).contains(&n) {
+        return Err("expected 1-100%".into());
+    }
+    Ok(n)
+}
+
+/// One damaged object
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub key: String,
+    pub size: u64,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct FsckReport {
+    pub listed: usize,
+    /// Downloaded and hashed
+    pub hashed: usize,
+    /// Verified by an earlier, interrupted run
+    pub resumed: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    fn add(&mut self, (o, problem): (ObjectInfo, Option<String>), state: Option<&UploadState>) {
+        self.hashed += 1;
+        match problem {
+            Some(reason) => self.problems.push(Problem { key: o.key, size: o.size, reason }),
+            None => if let Some(state) = state {
+                state.confirm(&o.key);
+            },
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn print(&self) {
+        for p in &self.problems {
+            println!("DAMAGED {} ({} bytes): {}", p.key, p.size, p.reason);
+        }
+        println!("{} objects listed, {} hashed, {} verified previously, {} damaged",
+                 self.listed, self.hashed, self.resumed, self.problems.len());
+        if !self.is_clean() {
+            println!("Delete damaged objects; the next upload of their content puts them back");
+        }
+    }
+}
+
+/// Problems visible in the listing alone
+fn check_listed(o: &ObjectInfo) -> Option<String> {
+    if let Err(e) = ObjectKey::from_storage_key(&o.key) {
+        return Some(e.to_string());
+    }
+    (o.size == 0).then(|| "empty".to_owned())
+}
+
+/// Compare content hashed on download with the key it's stored under, by
+/// any algorithm an entry may key objects with.  sha256-merkle keys are
+/// only recognised at the default chunk size.
+fn check_hash(key: &ObjectKey, digests: &KeyDigests) -> Option<String> {
+    let actual = ObjectKey::from_digest(&digests.sha256);
+    let matches = [digests.sha256, digests.blake3, digests.merkle].iter().any(|d| ObjectKey::from_digest(d) == *key);
+    (!matches).then(|| format!("content hashes to {}", actual))
+}
+
+/// Digests of an object by each [HashAlgorithm](crate::cache::HashAlgorithm)
+struct KeyDigests {
+    sha256: [u8; 32],
+    blake3: [u8; 32],
+    merkle: [u8; 32],
+}
+
+/// Hashes what's written, so objects needn't be held in memory
+#[derive(Default)]
+pub(crate) struct HashWriter(pub(crate) Sha256);
+
+/// Hashes what's written by every algorithm, as an object's content
+/// doesn't say which its key came from
+struct KeyWriter {
+    sha256: Sha256,
+    blake3: blake3::Hasher,
+    merkle: MerkleHasher,
+}
+
+impl Default for KeyWriter {
+    fn default() -> Self {
+        KeyWriter { sha256: Sha256::new(), blake3: blake3::Hasher::new(), merkle: MerkleHasher::new(merkle::DEFAULT_CHUNK) }
+    }
+}
+
+impl KeyWriter {
+    fn finalize(self) -> KeyDigests {
+        KeyDigests {
+            sha256: self.sha256.finalize().into(),
+            blake3: *self.blake3.finalize().as_bytes(),
+            merkle: self.merkle.finalize(),
+        }
+    }
+}
+
+impl tokio::io::AsyncWrite for KeyWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        this.sha256.update(buf);
+        this.blake3.update(buf);
+        this.merkle.update(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl tokio::io::AsyncWrite for HashWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.get_mut().0.update(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn deep_check(storage: Storage, o: ObjectInfo) -> Result<(ObjectInfo, Option<String>)> {
+    let key = ObjectKey::from_storage_key(&o.key)?;
+    // compressed objects are keyed by their raw content
+    let hasher = if o.key.ends_with(crate::compression::ZSTD_SUFFIX) {
+        let mut decoder = async_compression::tokio::write::ZstdDecoder::new(KeyWriter::default());
+        storage.get_file(&mut decoder, &o.key).await
+            .with_context(|| format!("Failed to download {}", o.key))?;
+        if let Err(e) = decoder.shutdown().await {
+            return Ok((o, Some(format!("doesn't decompress: {}", e))));
+        }
+        decoder.into_inner()
+    } else {
+        let mut hasher = KeyWriter::default();
+        storage.get_file(&mut hasher, &o.key).await
+            .with_context(|| format!("Failed to download {}", o.key))?;
+        hasher
+    };
+    let problem = check_hash(&key, &hasher.finalize());
+    Ok((o, problem))
+}
+
+/// Check every object under objects/ is well formed and non-empty, and with
+/// deep, that its content matches its key
+pub async fn fsck(storage: Storage, options: &FsckOptions) -> Result<FsckReport> {
+    crate::layout::check(&storage, false).await?;
+    let listed = storage.list_objects(object::ROOT).await?;
+    let state = options.state_file.as_deref()
+        .map(|path| UploadState::open(path, STATE_LABEL)).transpose()?;
+
+    let mut report = FsckReport { listed: listed.len(), ..Default::default() };
+    let mut deep = Vec::new();
+    for o in listed {
+        if let Some(reason) = check_listed(&o) {
+            report.problems.push(Problem { key: o.key, size: o.size, reason });
+        } else if options.deep && fastrand::u8(0..100) < options.sample {
+            if state.as_ref().is_some_and(|s| s.is_confirmed(&o.key)) {
+                report.resumed += 1;
+            } else {
+                deep.push(o);
+            }
+        }
+    }
+    log::warn!("{} objects listed, hashing {}", report.listed, deep.len());
+
+    let mut set = tokio::task::JoinSet::new();
+    for o in deep {
+        while set.len() >= options.max_in_flight as usize {
+            if let Some(work) = set.join_next().await {
+                report.add(work.with_context(|| "Failure waiting on fsck jobs")??, state.as_deref());
+            }
+        }
+        set.spawn(deep_check(storage.clone(), o));
+    }
+    while let Some(work) = set.join_next().await {
+        report.add(work.with_context(|| "Failure waiting on fsck jobs")??, state.as_deref());
+    }
+
+    if let Some(state) = state.as_ref() {
+        state.finish();
+    }
+    report.problems.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn object(key: &str, size: u64) -> ObjectInfo {
+        ObjectInfo { key: key.into(), size, last_modified: None }
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(parse_percent("10%"), Ok(10));
+        assert_eq!(parse_percent("100"), Ok(100));
+        assert!(parse_percent("0").is_err());
+        assert!(parse_percent("101%").is_err());
+        assert!(parse_percent("ten").is_err());
+    }
+
+    #[test]
+    fn listing_checks() {
+        let good = ObjectKey::from_digest(&[1; 32]).storage_key();
+        assert_eq!(check_listed(&object(&good, 10)), None);
+        assert_eq!(check_listed(&object(&good, 0)), Some("empty".into()));
+        assert!(check_listed(&object("objects/aa/bin", 10)).is_some());
+        assert!(check_listed(&object(&format!("{}.tmp", good), 10)).is_some());
+    }
+
+    #[tokio::test]
+    async fn corrupted_content_is_found() {
+        let content = b"the original content";
+        let key = ObjectKey::from_digest(&Sha256::digest(content).into());
+
+        let mut hasher = KeyWriter::default();
+        hasher.write_all(content).await.unwrap();
+        assert_eq!(check_hash(&key, &hasher.finalize()), None);
+
+        // truncated upload
+        let mut hasher = KeyWriter::default();
+        hasher.write_all(&content[..10]).await.unwrap();
+        let problem = check_hash(&key, &hasher.finalize()).unwrap();
+        assert!(problem.starts_with("content hashes to "), "{}", problem);
+
+        // keyed by an entry with --hash-algorithm=blake3
+        let mut hasher = KeyWriter::default();
+        hasher.write_all(content).await.unwrap();
+        assert_eq!(check_hash(&ObjectKey::from_digest(blake3::hash(content).as_bytes()), &hasher.finalize()), None);
+    }
+}
@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use crate::Result;
+
+/// How hard to push restored files to disk
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave it to the OS
+    #[default]
+    None,
+    /// fsync each restored file
+    Files,
+    /// fsync files, and the directories they and any symlinks were created in
+    FilesAndDirs,
+}
+
+/// The syscalls, separated out so tests can see what's attempted
+pub trait Syncer: Send + Sync {
+    fn sync_file(&self, file: &std::fs::File) -> std::io::Result<()>;
+    fn sync_dir(&self, dir: &Path) -> std::io::Result<()>;
+}
+
+pub struct OsSyncer;
+
+impl Syncer for OsSyncer {
+    fn sync_file(&self, file: &std::fs::File) -> std::io::Result<()> {
+        file.sync_all()
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> std::io::Result<()> {
+        std::fs::File::open(dir)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _dir: &Path) -> std::io::Result<()> {
+        // Directories can't be opened for syncing on Windows
+        Ok(())
+    }
+}
+
+/// Applies a policy, keeping count of the time spent so the cost is visible
+#[derive(Clone)]
+pub(crate) struct Fsync {
+    policy: FsyncPolicy,
+    syncer: Arc<dyn Syncer>,
+    spent: Arc<AtomicU64>,
+}
+
+impl Fsync {
+    pub fn new(policy: FsyncPolicy) -> Fsync {
+        Self::with_syncer(policy, Arc::new(OsSyncer))
+    }
+
+    pub fn with_syncer(policy: FsyncPolicy, syncer: Arc<dyn Syncer>) -> Fsync {
+        Fsync { policy, syncer, spent: Arc::new(AtomicU64::new(0)) }
+    }
+
+    fn timed(&self, f: impl FnOnce() -> std::io::Result<()>) -> std::io::Result<()> {
+        let start = Instant::now();
+        let result = f();
+        self.spent.fetch_add(start.elapsed().as_nanos().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+        result
+    }
+
+    pub fn file(&self, file: &std::fs::File, path: &Path) -> Result<()> {
+        if self.policy == FsyncPolicy::None {
+            return Ok(());
+        }
+        self.timed(|| self.syncer.sync_file(file))
+            .with_context(|| format!("Failed to fsync {}", path.display()))
+    }
+
+    /// Sync the directory path was created in
+    pub fn parent_of(&self, path: &Path) -> Result<()> {
+        if self.policy != FsyncPolicy::FilesAndDirs {
+            return Ok(());
+        }
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        self.timed(|| self.syncer.sync_dir(dir))
+            .with_context(|| format!("Failed to fsync directory {}", dir.display()))
+    }
+
+    pub fn spent(&self) -> Duration {
+        Duration::from_nanos(self.spent.load(Ordering::Relaxed))
+    }
+
+    pub fn policy(&self) -> FsyncPolicy {
+        self.policy
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Syncer for Recorder {
+        fn sync_file(&self, _file: &std::fs::File) -> std::io::Result<()> {
+            self.calls.lock().unwrap().push("file".into());
+            Ok(())
+        }
+
+        fn sync_dir(&self, dir: &Path) -> std::io::Result<()> {
+            self.calls.lock().unwrap().push(format!("dir {}", dir.display()));
+            Ok(())
+        }
+    }
+
+    fn calls(policy: FsyncPolicy) -> Vec<String> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let fsync = Fsync::with_syncer(policy, recorder.clone());
+        fsync.file(file.as_file(), Path::new("out/a.txt")).unwrap();
+        fsync.parent_of(Path::new("out/a.txt")).unwrap();
+        fsync.parent_of(Path::new("link")).unwrap();
+        let calls = recorder.calls.lock().unwrap();
+        calls.clone()
+    }
+
+    #[test]
+    fn default_does_nothing() {
+        assert_eq!(FsyncPolicy::default(), FsyncPolicy::None);
+        assert!(calls(FsyncPolicy::None).is_empty());
+    }
+
+    #[test]
+    fn policies_sync_what_they_say() {
+        assert_eq!(calls(FsyncPolicy::Files), vec!["file"]);
+        assert_eq!(calls(FsyncPolicy::FilesAndDirs), vec!["file", "dir out", "dir ."]);
+    }
+
+    #[test]
+    fn os_syncer_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        let file = std::fs::File::create(&path).unwrap();
+        let fsync = Fsync::new(FsyncPolicy::FilesAndDirs);
+        fsync.file(&file, &path).unwrap();
+        fsync.parent_of(&path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use path_slash::PathExt as _;
+
+use crate::{Error, Result};
+
+/// Pre-computed SHA-256 digests supplied by the build system, keyed by the
+/// slash-form path as given to upload.
+#[derive(Debug, Default, Clone)]
+pub struct HashManifest {
+    hashes: HashMap<String, [u8;32]>,
+    verify_sample: u8,
+}
+
+fn normalise(path: &str) -> String {
+    let mut p = path.trim();
+    while let Some(rest) = p.strip_prefix("./") {
+        p = rest;
+    }
+    p.to_owned()
+}
+
+fn decode_hex(path: &str, hex: &str) -> Result<[u8;32]> {
+    let mut hash = [0u8;32];
+    faster_hex::hex_decode(hex.trim().as_bytes(), &mut hash)
+        .map_err(|_| Error::InvalidHashManifest(format!("bad sha256 '{}' for '{}'", hex, path)))?;
+    Ok(hash)
+}
+
+impl HashManifest {
+
+    /// Parse either a JSON object of path -> hex digest, or `sha256sum` output
+    pub fn parse(text: &str) -> Result<HashManifest> {
+        if text.trim_start().starts_with('{') {
+            Self::parse_json(text)
+        } else {
+            Self::parse_sha256sum(text)
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<HashManifest> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text)
+    }
+
+    fn parse_json(text: &str) -> Result<HashManifest> {
+        let map: HashMap<String, String> = serde_json::from_str(text)?;
+        let mut hashes = HashMap::new();
+        for (path, hex) in map {
+            hashes.insert(normalise(&path), decode_hex(&path, &hex)?);
+        }
+        Ok(HashManifest { hashes, ..Default::default() })
+    }
+
+    fn parse_sha256sum(text: &str) -> Result<HashManifest> {
+        let mut hashes = HashMap::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            // "<hex>  <path>" for text mode, "<hex> *<path>" for binary mode
+            let (hex, path) = line.split_once(' ')
+                .ok_or_else(|| Error::InvalidHashManifest(format!("malformed line '{}'", line)))?;
+            let path = path.strip_prefix(' ').or_else(|| path.strip_prefix('*')).unwrap_or(path);
+            hashes.insert(normalise(path), decode_hex(path, hex)?);
+        }
+        Ok(HashManifest { hashes, ..Default::default() })
+    }
+
+    /// Re-hash roughly percent% of the listed files to catch a lying manifest
+    pub fn with_verify_sample(mut self, percent: u8) -> HashManifest {
+        self.verify_sample = percent.min(100);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<[u8;32]> {
+        let p = path.to_slash()?;
+        self.hashes.get(&normalise(&p)).copied()
+    }
+
+    /// Should this file be re-hashed as part of the sanity check sample
+    pub fn sample(&self) -> bool {
+        self.verify_sample > 0 && fastrand::u8(0..100) < self.verify_sample
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const HASH_A: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const HASH_B: &str = "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447";
+
+    #[test]
+    fn parse_sha256sum_format() {
+        let m = HashManifest::parse(&format!("{}  ./dir/a.txt\n{} *b.bin\n\n", HASH_A, HASH_B)).unwrap();
+        assert_eq!(m.len(), 2);
+        assert_eq!(faster_hex::hex_string(&m.get(Path::new("dir/a.txt")).unwrap()), HASH_A);
+        assert_eq!(faster_hex::hex_string(&m.get(Path::new("./b.bin")).unwrap()), HASH_B);
+        assert!(m.get(Path::new("c")).is_none());
+    }
+
+    #[test]
+    fn parse_json_format() {
+        let m = HashManifest::parse(&format!(r#"{{ "dir/a.txt": "{}", "b.bin": "{}" }}"#, HASH_A, HASH_B)).unwrap();
+        assert_eq!(m.len(), 2);
+        assert_eq!(faster_hex::hex_string(&m.get(Path::new("./dir/a.txt")).unwrap()), HASH_A);
+        assert_eq!(faster_hex::hex_string(&m.get(Path::new("b.bin")).unwrap()), HASH_B);
+    }
+
+    #[test]
+    fn parse_rejects_bad_digest() {
+        assert!(HashManifest::parse("abcd  a.txt\n").is_err());
+        assert!(HashManifest::parse(r#"{ "a.txt": "xyz" }"#).is_err());
+        assert!(HashManifest::parse(&format!("{}\n", HASH_A)).is_err());
+    }
+
+    #[test]
+    fn sample_bounds() {
+        let m = HashManifest::default();
+        assert!((0..100).all(|_| !m.sample()));
+        let m = m.with_verify_sample(100);
+        assert!((0..100).all(|_| m.sample()));
+    }
+}
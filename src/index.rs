@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::{Result, Storage};
+
+/// Compact once the log holds this many more lines than live entries
+const COMPACT_SLACK: usize = 1000;
+
+/// Local record of deduplicated objects known to exist, so warm uploads can
+/// skip asking.  Each object is recorded with its modification time in the
+/// bucket, and only trusted while younger than the ttl: expire deletes by
+/// that same age, so a ttl below the expiry age never trusts an object
+/// expire could have removed.
+///
+/// The file is an append-only log of "time\tkey" lines.  Concurrent
+/// processes each append whole lines; compaction rewrites via a rename, and
+/// a line lost in a race only costs a HEAD.
+pub struct DedupIndex {
+    path: PathBuf,
+    ttl: chrono::Duration,
+    entries: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+/// Per-user cache directory, following XDG on unix
+fn cache_dir() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let base = std::env::var_os("LOCALAPPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let base = std::env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()).map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")));
+    base.map(|b| b.join("s3-cache"))
+}
+
+fn parse(content: &str) -> HashMap<String, DateTime<Utc>> {
+    let mut entries = HashMap::new();
+    for line in content.lines() {
+        let Some((time, key)) = line.split_once('\t') else {
+            continue;
+        };
+        let Ok(time) = DateTime::parse_from_rfc3339(time) else {
+            log::debug!("Skipping bad index line: {:?}", line);
+            continue;
+        };
+        let time = time.to_utc();
+        let entry = entries.entry(key.to_owned()).or_insert(time);
+        *entry = time.max(*entry);
+    }
+    entries
+}
+
+fn line(key: &str, time: &DateTime<Utc>) -> String {
+    format!("{}\t{}\n", time.to_rfc3339(), key)
+}
+
+impl DedupIndex {
+    /// Index file for storage's bucket and endpoint under the user's cache directory
+    pub fn default_path(storage: &Storage) -> Option<PathBuf> {
+        // each prefix has objects of its own
+        let id = match storage.prefix() {
+            None => Sha256::digest(format!("{}\n{}", storage.endpoint(), storage.bucket_name())),
+            Some(prefix) => Sha256::digest(format!("{}\n{}\n{}", storage.endpoint(), storage.bucket_name(), prefix)),
+        };
+        cache_dir().map(|d| d.join(format!("index-{}.log", faster_hex::hex_string(&id[..8]))))
+    }
+
+    pub fn open(path: PathBuf, ttl: std::time::Duration) -> Result<DedupIndex> {
+        let content = match std::fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let ttl = chrono::Duration::from_std(ttl)?;
+        let index = DedupIndex { path, ttl, entries: Mutex::new(parse(&content)) };
+
+        let lines = content.lines().count();
+        let live = index.entries.lock().expect("index lock poisoned").len();
+        if lines > live + COMPACT_SLACK {
+            if let Err(e) = index.compact(Utc::now()) {
+                log::info!("Unable to compact {}: {}", index.path.display(), e);
+            }
+        }
+        log::debug!("Loaded {} index entries from {}", live, index.path.display());
+        Ok(index)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether key was recently enough known to exist to skip checking
+    pub fn is_fresh(&self, key: &str, now: DateTime<Utc>) -> bool {
+        self.entries.lock().expect("index lock poisoned").get(key)
+            .is_some_and(|time| now - *time < self.ttl)
+    }
+
+    /// Note key exists in the bucket, last modified at time.  Best-effort:
+    /// failure to save only means checking again next time.
+    pub fn record(&self, key: &str, time: DateTime<Utc>) {
+        let mut entries = self.entries.lock().expect("index lock poisoned");
+        entries.insert(key.to_owned(), time);
+        // whole line in one write so concurrent appenders don't interleave
+        let result = self.path.parent().map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::OpenOptions::new().create(true).append(true).open(&self.path))
+            .and_then(|mut f| f.write_all(line(key, &time).as_bytes()));
+        if let Err(e) = result {
+            log::info!("Unable to update {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Rewrite the log with one line per entry, dropping those past the ttl
+    fn compact(&self, now: DateTime<Utc>) -> std::io::Result<()> {
+        let mut entries = self.entries.lock().expect("index lock poisoned");
+        entries.retain(|_, time| now - *time < self.ttl);
+        let content: String = entries.iter().map(|(key, time)| line(key, time)).collect();
+
+        let tmp = self.path.with_extension(format!("tmp.{}", std::process::id()));
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, &self.path).inspect_err(|_| { let _ = std::fs::remove_file(&tmp); })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const DAY: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn open(dir: &tempfile::TempDir) -> DedupIndex {
+        DedupIndex::open(dir.path().join("index.log"), 7 * DAY).unwrap()
+    }
+
+    #[test]
+    fn ttl_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = open(&dir);
+        index.record("objects/a/bin", time("2025-01-01T00:00:00Z"));
+        assert!(index.is_fresh("objects/a/bin", time("2025-01-07T23:00:00Z")));
+        // past the ttl the object may have been expired, so it must be checked
+        assert!(!index.is_fresh("objects/a/bin", time("2025-01-08T00:00:00Z")));
+        assert!(!index.is_fresh("objects/b/bin", time("2025-01-02T00:00:00Z")));
+    }
+
+    #[test]
+    fn never_trusts_what_expire_may_have_removed() {
+        // objects expired server-side since they were recorded fall outside
+        // the ttl, so upload checks again and puts them back
+        let dir = tempfile::tempdir().unwrap();
+        let index = open(&dir);
+        let now = time("2025-02-01T00:00:00Z");
+        let expire_days = 14;
+        for age in 0..30 {
+            let key = format!("objects/{}/bin", age);
+            index.record(&key, now - chrono::Duration::days(age));
+            let expired = age >= expire_days;
+            assert!(!(expired && index.is_fresh(&key, now)), "trusted object {} days old", age);
+        }
+    }
+
+    #[test]
+    fn persists_and_merges_concurrent_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (one, two) = (open(&dir), open(&dir));
+        one.record("objects/a/bin", time("2025-01-01T00:00:00Z"));
+        two.record("objects/b/bin", time("2025-01-02T00:00:00Z"));
+        two.record("objects/a/bin", time("2025-01-03T00:00:00Z"));
+
+        let merged = open(&dir);
+        let now = time("2025-01-09T12:00:00Z");
+        assert!(merged.is_fresh("objects/a/bin", now), "newest time for a key wins");
+        assert!(merged.is_fresh("objects/b/bin", now));
+    }
+
+    #[test]
+    fn damaged_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.log"),
+                       "2025-01-01T00:00:00Z\tobjects/a/bin\ngarbage\nnot-a-time\tobjects/b/bin\n2025-01").unwrap();
+        let index = open(&dir);
+        assert!(index.is_fresh("objects/a/bin", time("2025-01-02T00:00:00Z")));
+        assert!(!index.is_fresh("objects/b/bin", time("2025-01-02T00:00:00Z")));
+    }
+
+    #[test]
+    fn compaction_drops_duplicates_and_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = open(&dir);
+        let now = Utc::now();
+        for _ in 0..COMPACT_SLACK + 10 {
+            index.record("objects/a/bin", now);
+        }
+        index.record("objects/old/bin", now - chrono::Duration::days(30));
+
+        let index = open(&dir);
+        let content = std::fs::read_to_string(index.path()).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        assert!(index.is_fresh("objects/a/bin", now));
+        assert!(!index.is_fresh("objects/old/bin", now));
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use serde::{Deserialize, Serialize};
+
+use crate::{object, Error, Result, Storage};
+
+/// Key of the marker describing a bucket's layout, at its root
+pub const MARKER: &str = "s3-cache.json";
+
+/// Bump when the layout changes in a way older releases would misread
+pub const VERSION: u32 = 1;
+
+/// How a bucket is laid out, as recorded in its marker
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub version: u32,
+    /// Hex lengths object hashes are split into below objects/
+    pub object_split: Vec<usize>,
+    /// Release that wrote the marker
+    #[serde(default)]
+    pub written_by: String,
+}
+
+impl Layout {
+    /// What this release writes, and what a bucket without a marker holds
+    pub fn current() -> Layout {
+        Layout {
+            version: VERSION,
+            object_split: object::SPLIT.to_vec(),
+            written_by: format!("s3-cache {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> std::result::Result<Layout, Error> {
+        serde_json::from_slice(bytes).map_err(|e| Error::InvalidLayout(e.to_string()))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).expect("layout serialises")
+    }
+
+    /// Why this release can't safely use a bucket with this layout
+    pub fn incompatibility(&self) -> Option<String> {
+        if self.version > VERSION {
+            return Some(format!("layout version {} is newer than {} supported by this release", self.version, VERSION));
+        }
+        if self.object_split != object::SPLIT {
+            return Some(format!("objects are split {:?}, not {:?}", self.object_split, object::SPLIT));
+        }
+        None
+    }
+
+    pub fn print(&self) {
+        println!("layout version {}", self.version);
+        println!("object split   {:?}", self.object_split);
+        println!("written by     {}", self.written_by);
+    }
+}
+
+/// Check the bucket's layout before using it, writing the marker first if
+/// write and it has none.  A bucket without a marker has the legacy layout,
+/// which is the current one.  An incompatible layout is refused unless the
+/// storage was told to force it.
+pub async fn check(storage: &Storage, write: bool) -> Result<Layout> {
+    let layout = match storage.layout().await? {
+        Some(layout) => layout,
+        None if write => {
+            let layout = Layout::current();
+            log::info!("Recording layout version {} in {}", layout.version, MARKER);
+            storage.put_layout(&layout).await?;
+            layout
+        },
+        None => return Ok(Layout::current()),
+    };
+    if let Some(reason) = layout.incompatibility() {
+        if !storage.force_layout() {
+            return Err(Error::IncompatibleLayout(reason).into());
+        }
+        log::warn!("Continuing despite incompatible layout: {}", reason);
+    }
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn current_round_trips() {
+        let layout = Layout::current();
+        assert_eq!(Layout::decode(&layout.encode()).unwrap(), layout);
+        assert_eq!(layout.incompatibility(), None);
+    }
+
+    #[test]
+    fn written_by_is_optional() {
+        let layout = Layout::decode(br#"{"version": 1, "object_split": [8, 8, 8, 40]}"#).unwrap();
+        assert_eq!(layout.written_by, "");
+        assert_eq!(layout.incompatibility(), None);
+    }
+
+    #[test]
+    fn incompatible_layouts_are_refused() {
+        let newer = Layout { version: VERSION + 1, ..Layout::current() };
+        assert!(newer.incompatibility().unwrap().contains("newer"));
+        let split = Layout { object_split: vec![2, 62], ..Layout::current() };
+        assert!(split.incompatibility().unwrap().contains("split"));
+    }
+
+    #[test]
+    fn damaged_markers_are_errors() {
+        assert!(matches!(Layout::decode(b"{\"version\": "), Err(Error::InvalidLayout(_))));
+        assert!(matches!(Layout::decode(br#"{"object_split": []}"#), Err(Error::InvalidLayout(_))));
+    }
+}
@@ -5,7 +5,51 @@ pub mod error;
 pub mod s3;
 pub mod actions;
 pub mod cache;
+pub mod stats;
+pub mod hashes;
+pub mod strict;
+pub mod migrate;
+pub mod namespaces;
+pub mod fsck;
+pub mod local_state;
+pub mod times;
+pub mod selftest;
+pub mod fsync;
+pub mod index;
+pub mod object;
+pub mod plan;
+pub mod content_type;
+pub mod links;
+pub mod resume;
+pub mod xattrs;
+pub mod acls;
+pub mod layout;
+pub mod expire;
+pub mod access;
+pub mod display;
+pub mod run_result;
+pub mod clock;
+pub mod unicode;
+pub mod multi;
+pub mod maintain;
+pub mod compression;
+pub mod limits;
+pub mod checksums;
+pub mod paths;
+pub mod sentinel;
+pub mod ranged;
+pub mod skip;
+pub mod throttle;
+pub mod support;
+pub mod dups;
+pub mod merkle;
+pub mod progress;
+pub mod provider;
+pub mod archive;
+pub mod names;
+pub mod commit;
 
-pub use s3::Storage;
-pub use error::Error;
+pub use s3::{CredentialsChoice, RetryConfig, Storage, StorageBuilder};
+pub use error::{Error, FileFailure};
+pub use strict::Strictness;
 pub use anyhow::Result;
@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use chrono::{DateTime, Utc};
+
+use crate::{plan::UploadPlan, skip::SkipReason, Error, Result};
+
+/// How many of the largest files to name when refusing an upload
+const OFFENDERS: usize = 10;
+
+/// Caps on what one upload may cache, checked once the files are planned
+#[derive(Debug, Clone, Default)]
+pub struct UploadLimits {
+    pub max_files: Option<usize>,
+    pub max_total_size: Option<u64>,
+    /// Drop files to fit, rather than refusing the upload
+    pub truncate: bool,
+    /// Paths kept ahead of others when truncating, earliest pattern first
+    pub priority: Vec<glob::Pattern>,
+}
+
+/// Parse a --priority-glob
+pub fn parse_glob(s: &str) -> std::result::Result<glob::Pattern, String> {
+    glob::Pattern::new(s).map_err(|e| e.to_string())
+}
+
+/// What the limits need to know of a planned file
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate<'a> {
+    path: &'a str,
+    size: u64,
+    mtime: Option<DateTime<Utc>>,
+}
+
+impl UploadLimits {
+    fn fits(&self, files: usize, bytes: u64) -> bool {
+        self.max_files.is_none_or(|max| files <= max) && self.max_total_size.is_none_or(|max| bytes <= max)
+    }
+
+    fn describe(&self) -> String {
+        let mut limits = Vec::new();
+        if let Some(max) = self.max_files {
+            limits.push(format!("--max-files={}", max));
+        }
+        if let Some(max) = self.max_total_size {
+            limits.push(format!("--max-total-size={}", max));
+        }
+        limits.join(" ")
+    }
+
+    /// Position of the first priority pattern path matches, past them all if none
+    fn priority_of(&self, path: &str) -> usize {
+        self.priority.iter().position(|p| p.matches(path)).unwrap_or(self.priority.len())
+    }
+
+    /// Which candidates to keep: in order of priority, then most recently
+    /// modified, each that still fits
+    fn select(&self, candidates: &[Candidate]) -> Vec<bool> {
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by(|&a, &b| {
+            let (a, b) = (&candidates[a], &candidates[b]);
+            self.priority_of(a.path).cmp(&self.priority_of(b.path))
+                .then(b.mtime.cmp(&a.mtime))
+                .then(a.path.cmp(b.path))
+        });
+        let mut keep = vec![false; candidates.len()];
+        let (mut files, mut bytes) = (0, 0);
+        for i in order {
+            if self.fits(files + 1, bytes + candidates[i].size) {
+                keep[i] = true;
+                files += 1;
+                bytes += candidates[i].size;
+            }
+        }
+        keep
+    }
+}
+
+/// The largest candidates, for pointing at what to exclude
+fn offenders(candidates: &[Candidate]) -> String {
+    let mut largest: Vec<&Candidate> = candidates.iter().collect();
+    largest.sort_by(|a, b| b.size.cmp(&a.size).then(a.path.cmp(b.path)));
+    largest.iter().take(OFFENDERS).map(|c| format!("{} ({} bytes)", c.path, c.size)).collect::<Vec<_>>().join(", ")
+}
+
+/// Refuse a plan over the limits, or with truncate, drop the files least
+/// worth keeping and record them in the plan
+pub(crate) fn enforce(plan: &mut UploadPlan, limits: &UploadLimits) -> Result<()> {
+    let candidates: Vec<Candidate> = plan.files.iter()
+        .map(|f| Candidate { path: f.entry.path_str(), size: f.size, mtime: f.local_mtime })
+        .collect();
+    let bytes = candidates.iter().map(|c| c.size).sum();
+    if limits.fits(candidates.len(), bytes) {
+        return Ok(());
+    }
+    if !limits.truncate {
+        return Err(Error::UploadTooLarge {
+            files: candidates.len(), bytes, limits: limits.describe(), largest: offenders(&candidates),
+        }.into());
+    }
+
+    let keep = limits.select(&candidates);
+    let mut dropped = Vec::new();
+    let mut keep = keep.into_iter();
+    plan.files.retain(|f| {
+        let kept = keep.next().expect("a decision per file");
+        if !kept {
+            dropped.push(f.entry.path_str().to_owned());
+        }
+        kept
+    });
+    dropped.sort();
+    log::warn!("Dropped {} files to fit {}: {}", dropped.len(), limits.describe(), dropped.join(", "));
+    for path in dropped {
+        plan.skipped.add(SkipReason::OverLimit, path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn candidate(path: &str, size: u64, age_days: i64) -> Candidate<'_> {
+        let now = "2025-06-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        Candidate { path, size, mtime: Some(now - chrono::Duration::days(age_days)) }
+    }
+
+    #[test]
+    fn limits_are_inclusive() {
+        let limits = UploadLimits { max_files: Some(2), max_total_size: Some(100), ..Default::default() };
+        assert!(limits.fits(2, 100));
+        assert!(!limits.fits(3, 100));
+        assert!(!limits.fits(2, 101));
+        assert!(UploadLimits::default().fits(usize::MAX, u64::MAX));
+        assert_eq!(limits.describe(), "--max-files=2 --max-total-size=100");
+    }
+
+    #[test]
+    fn truncation_keeps_priority_then_newest() {
+        let candidates = [
+            candidate("old.o", 40, 9),
+            candidate("new.o", 40, 1),
+            candidate("bin/tool", 50, 30),
+            candidate("mid.o", 40, 5),
+        ];
+        let limits = UploadLimits {
+            max_total_size: Some(100), truncate: true,
+            priority: vec![parse_glob("bin/*").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(limits.select(&candidates), vec![false, true, true, false]);
+
+        // smaller files still fill the space left
+        let limits = UploadLimits { max_total_size: Some(100), ..Default::default() };
+        let candidates = [candidate("big", 90, 1), candidate("bigger", 95, 2), candidate("small", 10, 3)];
+        assert_eq!(limits.select(&candidates), vec![true, false, true]);
+
+        let limits = UploadLimits { max_files: Some(1), ..Default::default() };
+        assert_eq!(limits.select(&candidates), vec![true, false, false]);
+    }
+
+    #[test]
+    fn offenders_are_largest_first() {
+        let candidates: Vec<String> = (0..12).map(|i| format!("f{:02}", i)).collect();
+        let candidates: Vec<Candidate> = candidates.iter().enumerate().map(|(i, p)| candidate(p, i as u64, 0)).collect();
+        let named = offenders(&candidates);
+        assert!(named.starts_with("f11 (11 bytes), f10 (10 bytes)"));
+        assert!(!named.contains("f01"));
+    }
+}
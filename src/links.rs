@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// What upload does with symlinks whose targets are absolute paths
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AbsoluteSymlinks {
+    /// Store the target as is
+    #[default]
+    Keep,
+    /// Fail the upload, listing the offending links
+    Reject,
+    /// Make targets inside the uploaded paths relative; keep the others
+    Rewrite,
+}
+
+/// What download does with a symlink it can't create, as on Windows
+/// without Developer Mode
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Nothing: the restore fails on the first one
+    #[default]
+    Native,
+    /// Copy the content of its target, when that's within the restored files
+    Copy,
+    /// Leave it out, restoring the rest but exiting non-zero
+    Skip,
+    /// Restore everything else, then fail listing the links left out
+    Error,
+}
+
+/// What a symlink points at, which Windows needs to know to create one
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    File,
+    Dir,
+}
+
+impl LinkKind {
+    /// Of the symlink at path: as made on Windows, otherwise by what its
+    /// target is now.  None when it dangles.
+    pub fn of(path: &Path) -> Option<LinkKind> {
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::FileTypeExt;
+            let t = std::fs::symlink_metadata(path).ok()?.file_type();
+            if t.is_symlink_dir() {
+                return Some(LinkKind::Dir);
+            }
+            if t.is_symlink_file() {
+                return Some(LinkKind::File);
+            }
+        }
+        let target = std::fs::metadata(path).ok()?;
+        Some(if target.is_dir() { LinkKind::Dir } else { LinkKind::File })
+    }
+}
+
+/// Absolute form of path against cwd, with "." and ".." resolved lexically
+/// rather than by following symlinks
+pub fn absolute(path: &Path, cwd: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in cwd.join(path).components() {
+        match c {
+            Component::CurDir => {},
+            Component::ParentDir => { out.pop(); },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Relative path from directory from to to, both absolute and normalised
+pub fn relative(from: &Path, to: &Path) -> PathBuf {
+    let (from, to): (Vec<_>, Vec<_>) = (from.components().collect(), to.components().collect());
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut out = PathBuf::new();
+    for _ in common..from.len() {
+        out.push("..");
+    }
+    for c in &to[common..] {
+        out.push(c);
+    }
+    if out.as_os_str().is_empty() {
+        out.push(".");
+    }
+    out
+}
+
+/// Relative replacement for the absolute target of link, when the target
+/// lies inside one of roots.  link and roots are as given to upload, so
+/// relative to cwd.
+pub fn rewrite(link: &Path, target: &Path, roots: &[PathBuf], cwd: &Path) -> Option<PathBuf> {
+    let target = absolute(target, cwd);
+    if !roots.iter().any(|root| target.starts_with(absolute(root, cwd))) {
+        return None;
+    }
+    let link = absolute(link, cwd);
+    Some(relative(link.parent()?, &target))
+}
+
+/// Entry path of what the symlink at entry path link points to, None when
+/// the target is absolute or leads out of the restored files
+pub fn resolve_in_entry(link: &str, target: &str) -> Option<String> {
+    if target.starts_with(['/', '\\']) || target.contains(':') {
+        return None;
+    }
+    let dir = link.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let mut parts = Vec::new();
+    for part in dir.split('/').chain(target.split(['/', '\\'])) {
+        match part {
+            "" | "." => {},
+            ".." => { parts.pop()?; },
+            part => parts.push(part),
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+#[cfg(test)]
+mod entry_test {
+
+    use super::*;
+
+    #[test]
+    fn targets_resolve_within_the_entry() {
+        assert_eq!(resolve_in_entry("lib/libfoo.so", "libfoo.so.1").as_deref(), Some("lib/libfoo.so.1"));
+        assert_eq!(resolve_in_entry("bin/tool", "../libexec/./tool").as_deref(), Some("libexec/tool"));
+        assert_eq!(resolve_in_entry("./top", "dir/file").as_deref(), Some("dir/file"));
+        assert_eq!(resolve_in_entry("a/b/c", "..\\d").as_deref(), Some("a/d"));
+        assert_eq!(resolve_in_entry("a/link", "../b/").as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn targets_outside_are_never_followed() {
+        assert_eq!(resolve_in_entry("link", "../outside"), None);
+        assert_eq!(resolve_in_entry("a/link", "../../outside"), None);
+        assert_eq!(resolve_in_entry("a/link", "b/../../../x"), None);
+        assert_eq!(resolve_in_entry("a/link", "/etc/passwd"), None);
+        assert_eq!(resolve_in_entry("a/link", "C:\\Windows"), None);
+        assert_eq!(resolve_in_entry("a/link", "\\\\server\\share"), None);
+        // the root itself can't be copied into itself
+        assert_eq!(resolve_in_entry("a/link", ".."), None);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+
+    use super::*;
+
+    fn p(s: &str) -> PathBuf {
+        PathBuf::from(s)
+    }
+
+    #[test]
+    fn absolute_is_lexical() {
+        assert_eq!(absolute(&p("a/./b/../c"), &p("/work")), p("/work/a/c"));
+        assert_eq!(absolute(&p("../x"), &p("/work/dir")), p("/work/x"));
+        assert_eq!(absolute(&p("/abs/../y"), &p("/work")), p("/y"));
+        assert_eq!(absolute(&p("../../.."), &p("/work")), p("/"));
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative(&p("/a/b"), &p("/a/b/c")), p("c"));
+        assert_eq!(relative(&p("/a/b"), &p("/a/c/d")), p("../c/d"));
+        assert_eq!(relative(&p("/a/b/c"), &p("/x")), p("../../../x"));
+        assert_eq!(relative(&p("/a/b"), &p("/a/b")), p("."));
+        assert_eq!(relative(&p("/a/b"), &p("/a")), p(".."));
+        // shared prefixes of a component name aren't common components
+        assert_eq!(relative(&p("/a/bc"), &p("/a/b/x")), p("../b/x"));
+    }
+
+    #[test]
+    fn rewrites_inside_roots() {
+        let cwd = p("/work");
+        let roots = [p("build"), p("/opt/sdk")];
+        assert_eq!(rewrite(&p("build/lib/libfoo.so"), &p("/work/build/lib/libfoo.so.1"), &roots, &cwd),
+                   Some(p("libfoo.so.1")));
+        assert_eq!(rewrite(&p("build/bin/tool"), &p("/work/build/lib/../libexec/tool"), &roots, &cwd),
+                   Some(p("../libexec/tool")));
+        assert_eq!(rewrite(&p("build/link"), &p("/work/build"), &roots, &cwd), Some(p(".")));
+    }
+
+    #[test]
+    fn rewrites_across_roots() {
+        let cwd = p("/work");
+        let roots = [p("build"), p("/opt/sdk"), p("../shared")];
+        assert_eq!(rewrite(&p("build/lib/sdk.so"), &p("/opt/sdk/lib/sdk.so"), &roots, &cwd),
+                   Some(p("../../../opt/sdk/lib/sdk.so")));
+        assert_eq!(rewrite(&p("/opt/sdk/include/build"), &p("/work/build/include"), &roots, &cwd),
+                   Some(p("../../../work/build/include")));
+        assert_eq!(rewrite(&p("build/common"), &p("/shared/common"), &roots, &cwd),
+                   Some(p("../../shared/common")));
+    }
+
+    #[test]
+    fn leaves_outside_roots() {
+        let cwd = p("/work");
+        let roots = [p("build")];
+        assert_eq!(rewrite(&p("build/libc.so"), &p("/usr/lib/libc.so"), &roots, &cwd), None);
+        assert_eq!(rewrite(&p("build/x"), &p("/work/buildx/y"), &roots, &cwd), None);
+        assert_eq!(rewrite(&p("build/x"), &p("/work/build/../other"), &roots, &cwd), None);
+    }
+
+    #[test]
+    fn link_kinds() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("d")).unwrap();
+        std::fs::write(root.join("f"), "content").unwrap();
+        for (link, target) in [("to_dir", "d"), ("to_file", "f"), ("dangling", "missing")] {
+            std::os::unix::fs::symlink(target, root.join(link)).unwrap();
+        }
+        assert_eq!(LinkKind::of(&root.join("to_dir")), Some(LinkKind::Dir));
+        assert_eq!(LinkKind::of(&root.join("to_file")), Some(LinkKind::File));
+        assert_eq!(LinkKind::of(&root.join("dangling")), None);
+        assert_eq!(serde_json::to_string(&LinkKind::Dir).unwrap(), r#""dir""#);
+    }
+}
@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::{self, HashAlgorithm}, object::ObjectKey, Result};
+
+/// Kept in the download outpath, and never uploaded
+pub const FILE_NAME: &str = ".s3-cache.state";
+
+/// Bump when the format changes; other versions are discarded
+const VERSION: u32 = 1;
+
+/// What a restored file looked like, and the content it had
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Tracked {
+    size: u64,
+    mtime: Option<DateTime<Utc>>,
+    /// Content hash, in object key form
+    object: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StateFile {
+    version: u32,
+    files: BTreeMap<String, Tracked>,
+}
+
+/// Deduplicated files a download restored, so the next download into the
+/// same place can skip those still holding the right content.  As with
+/// rsync or git, a file whose size and mtime are unchanged is trusted
+/// without hashing.
+pub struct LocalState {
+    root: PathBuf,
+    files: BTreeMap<String, Tracked>,
+}
+
+fn modified(meta: &std::fs::Metadata) -> Option<DateTime<Utc>> {
+    meta.modified().ok().map(DateTime::from)
+}
+
+impl LocalState {
+    /// State for root, starting empty if there's none usable
+    pub fn load(root: &Path) -> LocalState {
+        let path = root.join(FILE_NAME);
+        let files = match std::fs::read(&path).map(|b| serde_json::from_slice::<StateFile>(&b)) {
+            Ok(Ok(state)) if state.version == VERSION => state.files,
+            Ok(Ok(state)) => {
+                log::info!("Ignoring {} from version {}", path.display(), state.version);
+                BTreeMap::new()
+            },
+            Ok(Err(e)) => {
+                log::warn!("Ignoring damaged {}: {}", path.display(), e);
+                BTreeMap::new()
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        LocalState { root: root.to_owned(), files }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let state = StateFile { version: VERSION, files: self.files.clone() };
+        let path = self.root.join(FILE_NAME);
+        let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+        std::fs::write(&tmp, serde_json::to_vec(&state)?)?;
+        std::fs::rename(&tmp, &path).inspect_err(|_| { let _ = std::fs::remove_file(&tmp); })?;
+        Ok(())
+    }
+
+    /// Whether the file at path, relative to root, already holds object.
+    /// Its recorded hash is trusted while size and mtime match; otherwise a
+    /// file of the right size is hashed with algorithm.
+    pub async fn has_content(&self, path: &str, size: u64, object: &ObjectKey, algorithm: HashAlgorithm) -> Result<bool> {
+        let local = self.root.join(cache::File::path_of(path));
+        let Ok(meta) = std::fs::symlink_metadata(&local) else {
+            return Ok(false);
+        };
+        if !meta.is_file() || meta.len() != size {
+            return Ok(false);
+        }
+        if let Some(tracked) = self.files.get(path) {
+            if tracked.size == meta.len() && tracked.mtime.is_some() && tracked.mtime == modified(&meta) {
+                return Ok(tracked.object == object.as_str());
+            }
+        }
+        log::debug!("Hashing {} to check it's unchanged", local.display());
+        let hash = cache::read_hash_with(async_std::path::Path::new(local.as_os_str()), &Some(size), algorithm).await?;
+        Ok(ObjectKey::from_digest(&hash) == *object)
+    }
+
+    /// Note path now holds object, as it is on disk
+    pub fn record(&mut self, path: &str, object: &ObjectKey) {
+        match std::fs::metadata(self.root.join(cache::File::path_of(path))) {
+            Ok(meta) => {
+                self.files.insert(path.to_owned(), Tracked {
+                    size: meta.len(), mtime: modified(&meta), object: object.as_str().to_owned(),
+                });
+            },
+            Err(_) => self.forget(path),
+        }
+    }
+
+    pub fn forget(&mut self, path: &str) {
+        self.files.remove(path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn key(content: &[u8]) -> ObjectKey {
+        use sha2::Digest;
+        ObjectKey::from_digest(&sha2::Sha256::digest(content).into())
+    }
+
+    fn set_mtime(path: &Path, secs: i64) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs as u64)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn untracked_files_are_hashed() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), "content").unwrap();
+        let state = LocalState::load(dir.path());
+        assert!(state.has_content("a", 7, &key(b"content"), HashAlgorithm::Sha256).await.unwrap());
+        assert!(!state.has_content("a", 7, &key(b"CONTENT"), HashAlgorithm::Sha256).await.unwrap());
+        assert!(!state.has_content("a", 8, &key(b"content"), HashAlgorithm::Sha256).await.unwrap());
+        assert!(!state.has_content("missing", 7, &key(b"content"), HashAlgorithm::Sha256).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unchanged_files_are_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, "content").unwrap();
+        set_mtime(&path, 1_000_000);
+
+        // recorded against other content to prove the hash isn't recomputed
+        let mut state = LocalState::load(dir.path());
+        state.record("a", &key(b"recorded"));
+        state.save().unwrap();
+        let state = LocalState::load(dir.path());
+        assert!(state.has_content("a", 7, &key(b"recorded"), HashAlgorithm::Sha256).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn same_size_modifications_are_caught() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, "content").unwrap();
+        set_mtime(&path, 1_000_000);
+        let mut state = LocalState::load(dir.path());
+        state.record("a", &key(b"content"));
+
+        std::fs::write(&path, "CONTENT").unwrap();
+        set_mtime(&path, 1_000_100);
+        assert!(!state.has_content("a", 7, &key(b"content"), HashAlgorithm::Sha256).await.unwrap());
+        assert!(state.has_content("a", 7, &key(b"CONTENT"), HashAlgorithm::Sha256).await.unwrap());
+    }
+
+    #[test]
+    fn other_versions_and_damage_start_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(FILE_NAME), r#"{"version": 99, "files": {"a": {"size": 1, "mtime": null, "object": "x"}}}"#).unwrap();
+        assert!(LocalState::load(dir.path()).files.is_empty());
+        std::fs::write(dir.path().join(FILE_NAME), "{\"version\": 1, \"fil").unwrap();
+        assert!(LocalState::load(dir.path()).files.is_empty());
+    }
+}
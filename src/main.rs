@@ -1,8 +1,10 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // (C) Copyright 2025 Greg Whiteley
 
+use anyhow::Context as _;
 use clap::Parser;
 use s3_cache::Result;
+use s3_cache::run_result::{Outcome, RunResult};
 use std::path::PathBuf;
 use::std::io::Write;
 
@@ -31,33 +33,407 @@ async fn main() -> Result<()> {
     logger.format_timestamp(None).init();
 
     if let Ok(path) = dotenv {
-        log::info!("Loaded environment from {:?}", path);
+        log::info!("Loaded environment from {}", s3_cache::display::local_display(&path));
     }
     log::debug!("args={:?}", args);
 
-    let bucket = s3_cache::Storage::new_dangerous(args.bucket.as_str(), args.region.as_str(), args.endpoint.as_str(), false, args.skip_cert_validation).await
+    let started = chrono::Utc::now();
+    let outcome = run(&args).await;
+    if let Some(path) = &args.result_file {
+        let result = RunResult::new(args.command.name(), std::env::args().collect(), started, &outcome);
+        if let Err(e) = result.write(path) {
+            log::warn!("Unable to write {}: {:#}", s3_cache::display::local_display(path), e);
+        }
+    }
+    let outcome = outcome?;
+    if outcome.exit_code != 0 {
+        std::process::exit(outcome.exit_code);
+    }
+    Ok(())
+}
+
+/// Connect and dispatch the subcommand
+async fn run(args: &Options) -> Result<Outcome> {
+    let mut builder = s3_cache::Storage::builder(&args.bucket)
+        .endpoint(&args.endpoint)
+        .region(&args.region)
+        .provider_profile(args.provider)
+        .accept_invalid_certs(args.skip_cert_validation)
+        .retry(s3_cache::RetryConfig { max_attempts: args.retries + 1, ..Default::default() })
+        .max_requests(args.max_requests)
+        .offline(args.offline || matches!(args.command, Commands::Dups(_)));
+    if let Some(prefix) = &args.bucket_prefix {
+        builder = builder.prefix(prefix);
+    }
+    let credentials = s3_cache::CredentialsChoice {
+        access_key_id: args.access_key_id.clone(),
+        secret_access_key: args.secret_access_key.clone(),
+        profile: args.profile.clone(),
+    }.provider();
+    let default_credentials = credentials.is_none();
+    if let Some(provider) = credentials {
+        builder = builder.credentials(provider);
+    }
+    let bucket = builder
+        .connect().await
         .inspect_err(|_| {
-            println!("\nFailed to initialise connection to S3.\n\nCheck AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment\nvariables are set.\n");
-        })?;
+            // otherwise the error names the source tried
+            if default_credentials {
+                println!("\nFailed to initialise connection to S3.\n\nCheck AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment\nvariables are set.\n");
+            }
+        })?
+        .with_strictness(args.strictness())
+        .with_force_layout(args.force_layout)
+        .with_max_entry_size(args.max_entry_size);
+    let throttle = bucket.clone();
 
-    match &args.command {
+    let mut outcome = match &args.command {
         Commands::Upload(arg) => {
-            s3_cache::actions::upload(bucket, arg.cache.name.as_str(), &arg.files, arg.recurse, arg.dry_run, arg.threshold, arg.max_in_flight).await?;
+            let hashes = match &arg.hashes_from {
+                Some(path) => Some(std::sync::Arc::new(
+                    s3_cache::hashes::HashManifest::from_file(path)?
+                        .with_verify_sample(arg.hashes_verify_sample))),
+                None => None,
+            };
+            let index = match (arg.dedup_index, s3_cache::index::DedupIndex::default_path(&bucket)) {
+                (true, Some(path)) => Some(std::sync::Arc::new(
+                    s3_cache::index::DedupIndex::open(path, arg.index_ttl.into())?)),
+                (true, None) => {
+                    log::warn!("No cache directory found for --dedup-index, continuing without it");
+                    None
+                },
+                (false, _) => None,
+            };
+            let options = s3_cache::actions::UploadOptions {
+                recurse: arg.recurse,
+                dry_run: arg.dry_run,
+                threshold: arg.threshold,
+                max_in_flight: arg.max_in_flight,
+                hashes,
+                preserve_times: arg.preserve_times,
+                index,
+                detect_content_type: arg.content_type_detect,
+                absolute_symlinks: arg.absolute_symlinks,
+                xattrs: arg.xattrs,
+                acls: arg.acls,
+                unicode_normalize: arg.unicode_normalize,
+                limits: s3_cache::limits::UploadLimits {
+                    max_files: arg.max_files,
+                    max_total_size: arg.max_total_size,
+                    truncate: arg.truncate_to_limits,
+                    priority: arg.priority_glob.clone(),
+                },
+                compression: arg.compress.then_some(s3_cache::compression::CompressionCodec::Zstd { level: arg.compress_level }),
+                label: arg.label.clone(),
+                hash_algorithm: arg.hash_algorithm,
+                hash_jobs: arg.hash_jobs,
+                keep_going: arg.keep_going,
+                exclude: s3_cache::paths::GlobSet::new(arg.exclude.iter().map(String::as_str), arg.unicode_normalize.into())
+                    .context("Invalid --exclude")?,
+                state: match &arg.state_file {
+                    Some(path) => Some(std::sync::Arc::new(
+                        s3_cache::resume::UploadState::open(path, arg.cache.name.as_str())?)),
+                    None => None,
+                },
+                progress: args.progress_sink(),
+            };
+            let name = arg.cache.name.as_str();
+            if let Some(path) = &arg.from_plan {
+                let plan = s3_cache::plan::UploadPlan::read(path)?;
+                if plan.cache != name {
+                    return Err(s3_cache::Error::PlanCacheMismatch { planned: plan.cache, requested: name.into() }.into());
+                }
+                Outcome::with_report(&s3_cache::actions::execute_plan(bucket, &plan, &options).await?)?
+            } else if let Some(path) = &arg.plan_out {
+                let plan = s3_cache::actions::plan_upload(&bucket, name, &arg.files, &options, arg.check_existing).await?;
+                plan.write(path)?;
+                let (objects, local) = plan.upload_bytes();
+                log::warn!("Planned {} files for '{}': {} bytes of objects and {} bytes with the cache to upload",
+                           plan.files.len(), name, objects, local);
+                Outcome::default()
+            } else {
+                Outcome::with_report(&s3_cache::actions::upload(bucket, name, &arg.files, &options).await?)?
+            }
         },
         Commands::Download(arg) => {
-            s3_cache::actions::download(bucket, arg.cache.name.as_str(), arg.outpath.clone(), arg.max_in_flight).await?;
+            if arg.recover {
+                let (finished, discarded) = s3_cache::commit::recover(&arg.outpath)?;
+                log::warn!("Recovered {}: {} interrupted downloads finished, {} discarded",
+                           s3_cache::display::local_display(&arg.outpath), finished, discarded);
+                if arg.names.is_empty() {
+                    return Ok(Outcome::default());
+                }
+            }
+            let options = s3_cache::actions::DownloadOptions {
+                max_in_flight: arg.max_in_flight,
+                newer_than: arg.newer_than.as_deref().map(s3_cache::times::parse_instant).transpose()?,
+                fsync: arg.fsync,
+                preflight: !arg.no_preflight,
+                keep_going: arg.keep_going,
+                xattrs: arg.xattrs,
+                acls: arg.acls,
+                local_state: arg.local_state,
+                verify: arg.verify,
+                fallbacks: arg.fallbacks.clone(),
+                write_checksums: arg.write_checksums,
+                ranged_threshold: arg.ranged_threshold,
+                dry_run: arg.dry_run,
+                symlinks: arg.symlinks,
+                if_changed: arg.if_changed,
+                hardlink: arg.hardlink,
+                progress: args.progress_sink(),
+                filter: match arg.include.as_slice() {
+                    [] => None,
+                    include => Some(s3_cache::paths::GlobSet::new(include.iter().map(String::as_str), Default::default())
+                                    .context("Invalid --include")?),
+                },
+            };
+            let multi = s3_cache::multi::MultiOptions {
+                outpath_per_name: arg.outpath_per_name,
+                atomic: arg.atomic,
+            };
+            let sentinel = arg.sentinel.as_ref()
+                .map(|path| path.clone().unwrap_or_else(|| arg.outpath.join(s3_cache::sentinel::FILE_NAME)));
+            let reports = match s3_cache::multi::download(bucket.clone(), &arg.names, &arg.outpath, &options, &multi).await {
+                Err(e) if arg.ok_if_missing => match e.downcast_ref::<s3_cache::Error>() {
+                    Some(s3_cache::Error::CacheNotFound(name)) => {
+                        log::warn!("Cache '{}' not found, nothing restored", name);
+                        let miss = s3_cache::sentinel::Sentinel::miss(name);
+                        if let Some(path) = &sentinel {
+                            s3_cache::sentinel::write(path, &miss)?;
+                        }
+                        return Outcome::with_report(&miss);
+                    },
+                    _ => return Err(e),
+                },
+                result => result?,
+            };
+            if let Some(path) = &sentinel {
+                let mut hits = Vec::new();
+                for report in &reports {
+                    hits.push(s3_cache::sentinel::Sentinel::hit(&bucket, report).await?);
+                }
+                match hits.as_slice() {
+                    [one] => s3_cache::sentinel::write(path, one)?,
+                    all => s3_cache::sentinel::write(path, &all)?,
+                }
+            }
+            let links_skipped = reports.iter()
+                .any(|report| report.skipped.contains_key(&s3_cache::skip::DownloadSkipReason::Symlink));
+            match reports.as_slice() {
+                [one] => Outcome::with_report(one)?,
+                all => Outcome::with_report(&all)?,
+            }.with_exit_code(if links_skipped { 1 } else { 0 })
         },
         Commands::Delete(arg) => {
-            s3_cache::actions::delete(bucket, arg.cache.name.as_str()).await?;
+            match (&arg.name, &arg.name_from) {
+                (Some(name), _) => s3_cache::actions::delete(bucket, name, arg.dry_run).await?,
+                (None, Some(template)) => {
+                    s3_cache::actions::delete_matching(bucket, template, arg.dry_run, arg.yes).await?;
+                },
+                (None, None) => unreachable!("clap requires --name or --name-from"),
+            }
+            Outcome::default()
+        },
+        Commands::Copy(arg) if arg.delete_source => {
+            s3_cache::actions::rename(bucket, &arg.cache.from, &arg.cache.to, arg.cache.overwrite).await?;
+            Outcome::default()
+        },
+        Commands::Copy(arg) => {
+            s3_cache::actions::copy(bucket, &arg.cache.from, &arg.cache.to, arg.cache.overwrite).await?;
+            Outcome::default()
+        },
+        Commands::Rename(arg) => {
+            s3_cache::actions::rename(bucket, &arg.from, &arg.to, arg.overwrite).await?;
+            Outcome::default()
+        },
+        Commands::Export(arg) => {
+            s3_cache::actions::export(bucket, arg.cache.name.as_str(), &arg.output).await?;
+            Outcome::default()
+        },
+        Commands::Import(arg) => {
+            Outcome::with_report(&s3_cache::actions::import(bucket, &arg.input, arg.cache.name.as_str()).await?)?
+        },
+        Commands::Stat(arg) => {
+            let stat = s3_cache::actions::stat(bucket, arg.cache.name.as_str()).await?;
+            if arg.json || args.json_output() {
+                println!("{}", serde_json::to_string_pretty(&stat)?);
+            } else {
+                stat.print_table();
+            }
+            Outcome::with_report(&stat)?
+        },
+        Commands::Exists(arg) => {
+            let name = arg.cache.name.as_str();
+            let exists = s3_cache::actions::exists(bucket.clone(), name).await?;
+            let stat = if exists && args.verbose {
+                Some(s3_cache::actions::stat(bucket, name).await?)
+            } else {
+                None
+            };
+            let report = s3_cache::actions::ExistsReport { cache: name.to_owned(), exists, stat };
+            report.print();
+            Outcome::with_report(&report)?.with_exit_code(if exists { 0 } else { 2 })
+        },
+        Commands::List(arg) if arg.limit.is_some() || arg.start_after.is_some() || arg.prefix.is_some() => {
+            let json = arg.json || args.json_output();
+            let page = s3_cache::actions::list_caches_page(&bucket, arg.start_after.as_deref(),
+                                                           arg.limit.unwrap_or(1000), arg.prefix.as_deref(), json).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&page)?);
+            } else {
+                for c in &page.caches {
+                    println!("{}", c.name);
+                }
+                if let Some(next) = &page.next {
+                    log::warn!("More caches follow, continue with --start-after={}", next);
+                }
+            }
+            Outcome::default()
         },
         Commands::List(arg) => {
-            s3_cache::actions::list(bucket, arg.name.as_deref()).await?;
+            let json = arg.json || args.json_output();
+            let listing = s3_cache::actions::list(bucket, arg.name.as_deref(), json).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&listing)?);
+            } else {
+                print!("{}", listing.render());
+            }
+            Outcome::default()
         },
         Commands::Expire(arg) => {
-            s3_cache::actions::expire(bucket, arg.days).await?;
+            let options = s3_cache::expire::ExpireOptions {
+                days: arg.days,
+                checkpoint: arg.checkpoint.clone(),
+                time_budget: arg.time_budget.map(Into::into),
+                min_reads: arg.min_reads,
+                window: arg.window.into(),
+                unused: arg.unused,
+                cache_age_days: arg.cache_days.or(arg.entries.then_some(arg.days)),
+                cache_match: match &arg.match_from {
+                    Some(template) => {
+                        let pattern = s3_cache::names::resolve(template)?;
+                        log::warn!("'{}' resolves to '{}'", template, pattern);
+                        Some(glob::Pattern::new(&pattern).map_err(|e| s3_cache::Error::InvalidTemplate {
+                            template: template.clone(), reason: e.to_string() })?)
+                    },
+                    None => None,
+                },
+                verify: arg.verify || args.strict,
+            };
+            Outcome::with_report(&s3_cache::actions::expire(bucket, &options).await?)?
+        },
+        Commands::PurgeOrphans(arg) => {
+            let options = s3_cache::expire::PurgeOptions {
+                dry_run: arg.dry_run,
+                grace: arg.grace.into(),
+            };
+            Outcome::with_report(&s3_cache::actions::purge_orphans(bucket, &options).await?)?
+        },
+        Commands::Maintain(arg) => {
+            let options = s3_cache::maintain::MaintainOptions {
+                every: arg.every.into(),
+                once: arg.once,
+                expire_days: arg.expire_days,
+                max_total_size: arg.max_total_size,
+                gc: arg.gc,
+                grace: arg.grace.into(),
+                lease_ttl: arg.lease_ttl.into(),
+            };
+            let report = s3_cache::actions::maintain(bucket, &options, |cycle| {
+                if !arg.once {
+                    println!("{}", serde_json::to_string(cycle).expect("reports serialise"));
+                }
+            }).await?;
+            Outcome::with_report(&report)?
+        },
+        Commands::Report(arg) => {
+            s3_cache::actions::report(bucket, arg.since.into(), arg.access, arg.json).await?;
+            Outcome::default()
+        },
+        Commands::Status(arg) => {
+            let report = s3_cache::actions::status(bucket, arg.cache.name.as_str(), &arg.files, arg.recurse, arg.threshold).await?;
+            report.print(args.verbose);
+            Outcome::with_report(&report)?.with_exit_code(if report.in_sync() { 0 } else { 1 })
+        },
+        Commands::Verify(arg) => {
+            let report = s3_cache::actions::verify(bucket, arg.cache.name.as_str(), arg.max_in_flight).await?;
+            report.print();
+            Outcome::with_report(&report)?.with_exit_code(if report.is_ok() { 0 } else { 1 })
+        },
+        Commands::Checksums(arg) => {
+            let sums = s3_cache::checksums::checksums(bucket, arg.cache.name.as_str(), arg.max_in_flight).await?;
+            match &arg.output {
+                Some(path) => std::fs::write(path, sums)
+                    .with_context(|| format!("Failed to write {}", s3_cache::display::local_display(path)))?,
+                None => print!("{}", sums),
+            }
+            Outcome::default()
         },
+        Commands::Migrate(arg) => {
+            let options = s3_cache::migrate::MigrateOptions {
+                delete_source: arg.delete_source,
+                max_in_flight: arg.max_in_flight,
+            };
+            Outcome::with_report(&s3_cache::actions::migrate(bucket, arg.to_prefix.as_str(), &options).await?)?
+        },
+        Commands::Fsck(arg) => {
+            let options = s3_cache::fsck::FsckOptions {
+                deep: arg.deep,
+                sample: arg.sample,
+                max_in_flight: arg.max_in_flight,
+                state_file: arg.state_file.clone(),
+            };
+            let report = s3_cache::fsck::fsck(bucket, &options).await?;
+            report.print();
+            Outcome::with_report(&report)?.with_exit_code(if report.is_clean() { 0 } else { 1 })
+        },
+        Commands::Namespaces(arg) => {
+            s3_cache::actions::namespaces(bucket, arg.max_in_flight, arg.json).await?;
+            Outcome::default()
+        },
+        Commands::Info => {
+            s3_cache::actions::info(bucket).await?;
+            Outcome::default()
+        },
+        Commands::Selftest(arg) => {
+            let options = s3_cache::selftest::SelftestOptions {
+                delete_objects: arg.delete_objects,
+                max_in_flight: arg.max_in_flight,
+            };
+            s3_cache::actions::selftest(bucket, &options).await?;
+            Outcome::default()
+        },
+        Commands::SupportBundle(arg) => {
+            let options = s3_cache::support::SupportOptions {
+                output: arg.output.clone(),
+                redact_names: arg.redact_names,
+                last_result: arg.last_result.clone(),
+            };
+            Outcome::with_report(&s3_cache::support::bundle(&bucket, &options).await?)?
+        },
+        Commands::Dups(arg) => {
+            let options = s3_cache::actions::UploadOptions {
+                recurse: arg.recurse,
+                exclude: s3_cache::paths::GlobSet::new(arg.exclude.iter().map(String::as_str), Default::default())
+                    .context("Invalid --exclude")?,
+                ..Default::default()
+            };
+            let report = s3_cache::dups::dups(&bucket, &arg.files, &options).await?;
+            if arg.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                report.print();
+            }
+            Outcome::with_report(&report)?
+        },
+    };
+    let stats = throttle.throttle_stats();
+    if stats.throttled > 0 {
+        log::warn!("Throttled {} times, finishing with {} requests in flight", stats.throttled, stats.concurrency);
     }
-    Ok(())
+    outcome.throttle = Some(stats);
+    Ok(outcome)
 }
 
 #[derive(Parser, Debug)]
@@ -69,6 +445,8 @@ file). eg:
 
    AWS_ACCESS_KEY_ID=8dq14eEakqwmEko9XjUd
    AWS_SECRET_ACCESS_KEY=0TX3ZyiadJIC7w7NPqbeu7VzKcbHDheVovq7UB9rOBw
+
+--access-key-id and --secret-access-key, then --profile, take precedence.
 ")]
 struct Options {
     #[command(subcommand)]
@@ -86,10 +464,87 @@ struct Options {
     #[arg(long, global=true, default_value="global", env="S3_CACHE_REGION")]
     region: String,
 
+    /// The service behind the endpoint, so its quirks are expected: which
+    /// statuses it answers, how it throttles, how buckets are addressed.
+    /// auto recognises AWS, R2 and B2 endpoints, and tolerates any known
+    /// quirk otherwise.
+    #[arg(long, global=true, value_enum, default_value_t, env="S3_CACHE_PROVIDER")]
+    provider: s3_cache::provider::Provider,
+
+    /// Use credentials from this section of ~/.aws/credentials rather than
+    /// the environment
+    #[arg(long, global=true)]
+    profile: Option<String>,
+
+    /// Access key, ahead of --profile and the environment
+    #[arg(long, global=true, env="S3_CACHE_ACCESS_KEY_ID", requires="secret_access_key")]
+    access_key_id: Option<String>,
+
+    /// Secret key to go with --access-key-id
+    #[arg(long, global=true, env="S3_CACHE_SECRET_ACCESS_KEY", hide_env_values=true, requires="access_key_id")]
+    secret_access_key: Option<String>,
+
     /// Skip HTTPS certificate validation.  This affects security.  Use with care.
     #[arg(long, global=true, env="S3_CACHE_SKIP_CERT_VALIDATION")]
     skip_cert_validation: bool,
 
+    /// Retry S3 requests failing with server errors, throttling or network
+    /// trouble this many times, backing off exponentially.  0 never retries.
+    #[arg(long, global=true, default_value_t=2, env="S3_CACHE_RETRIES")]
+    retries: u32,
+
+    /// Most S3 requests in flight at once.  Fewer are made while the
+    /// provider throttles them, ramping back up once it stops.
+    #[arg(long, global=true, default_value_t=s3_cache::throttle::DEFAULT_MAX_REQUESTS,
+          value_parser=|s: &str| clap_num::number_range(s, 1, usize::MAX), env="S3_CACHE_MAX_REQUESTS")]
+    max_requests: usize,
+
+    /// Keep caches and objects below this prefix, eg team-a/, so several
+    /// teams can share a bucket without seeing each other's caches
+    #[arg(long, global=true, env="S3_CACHE_PREFIX")]
+    bucket_prefix: Option<String>,
+
+    /// Fail straight away, naming the key, rather than reach the bucket.
+    /// Only work needing nothing from it, eg upload --plan-out, succeeds.
+    #[arg(long, global=true, env="S3_CACHE_OFFLINE")]
+    offline: bool,
+
+    /// Treat all recoverable problems (bad status codes, failed deletes,
+    /// permissions, missing caches) as errors
+    #[arg(long, global=true)]
+    strict: bool,
+
+    /// Fail if file permissions can't be restored
+    #[arg(long, global=true)]
+    strict_permissions: bool,
+
+    /// Fail on unexpected S3 status codes
+    #[arg(long, global=true)]
+    strict_status: bool,
+
+    /// Fail if any delete fails during recursive delete or expire
+    #[arg(long, global=true)]
+    strict_deletes: bool,
+
+    /// Fail when deleting a cache that doesn't exist
+    #[arg(long, global=true)]
+    strict_missing: bool,
+
+    /// Carry on when the bucket's layout marker says it's incompatible with
+    /// this release
+    #[arg(long, global=true)]
+    force_layout: bool,
+
+    /// Refuse cache entries larger than this many bytes, rather than risk
+    /// reading a corrupt or hostile one into memory
+    #[arg(long, global=true, default_value_t=s3_cache::cache::DEFAULT_MAX_ENTRY_SIZE)]
+    max_entry_size: u64,
+
+    /// On exit, write a JSON summary of the run here: command, arguments,
+    /// timings, the command's report and any error
+    #[arg(long, global=true)]
+    result_file: Option<PathBuf>,
+
     /// Add additional debug output
     #[arg(long, global=true)]
     debug: bool,
@@ -97,6 +552,73 @@ struct Options {
     /// Add additional output
     #[arg(long, global=true)]
     verbose: bool,
+
+    /// Report files and bytes as upload and download transfer them: as
+    /// progress bars when built with the 'progress' feature, otherwise as
+    /// log lines shown with --verbose
+    #[arg(long, global=true)]
+    progress: bool,
+
+    /// How list and stat print, eg '--output=json list'.  Given before the
+    /// command, as some commands' --output names a file.
+    #[arg(long, value_enum, default_value_t=s3_cache::display::OutputFormat::Human)]
+    output: s3_cache::display::OutputFormat,
+}
+
+impl Commands {
+    fn name(&self) -> &'static str {
+        match self {
+            Commands::Upload(_) => "upload",
+            Commands::Download(_) => "download",
+            Commands::Delete(_) => "delete",
+            Commands::Copy(_) => "copy",
+            Commands::Rename(_) => "rename",
+            Commands::Export(_) => "export",
+            Commands::Import(_) => "import",
+            Commands::List(_) => "list",
+            Commands::Stat(_) => "stat",
+            Commands::Exists(_) => "exists",
+            Commands::Expire(_) => "expire",
+            Commands::PurgeOrphans(_) => "purge-orphans",
+            Commands::Maintain(_) => "maintain",
+            Commands::Report(_) => "report",
+            Commands::Status(_) => "status",
+            Commands::Verify(_) => "verify",
+            Commands::Checksums(_) => "checksums",
+            Commands::Migrate(_) => "migrate",
+            Commands::Fsck(_) => "fsck",
+            Commands::Namespaces(_) => "namespaces",
+            Commands::Info => "info",
+            Commands::Selftest(_) => "selftest",
+            Commands::SupportBundle(_) => "support-bundle",
+            Commands::Dups(_) => "dups",
+        }
+    }
+}
+
+impl Options {
+    fn json_output(&self) -> bool {
+        self.output == s3_cache::display::OutputFormat::Json
+    }
+
+    fn progress_sink(&self) -> std::sync::Arc<dyn s3_cache::progress::ProgressSink> {
+        if !self.progress {
+            return s3_cache::progress::noop();
+        }
+        #[cfg(feature = "progress")]
+        return std::sync::Arc::new(s3_cache::progress::IndicatifProgressSink::new());
+        #[cfg(not(feature = "progress"))]
+        return std::sync::Arc::new(s3_cache::progress::LogProgressSink::default());
+    }
+
+    fn strictness(&self) -> s3_cache::Strictness {
+        s3_cache::Strictness {
+            permissions: self.strict || self.strict_permissions,
+            status: self.strict || self.strict_status,
+            deletes: self.strict || self.strict_deletes,
+            missing_cache: self.strict || self.strict_missing,
+        }
+    }
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -107,11 +629,76 @@ enum Commands {
     Download(Download),
     /// Delete a cache - files will not be accessible, but they won't be deleted.
     Delete(Delete),
+    /// Copy a cache under a new name, sharing its deduplicated objects
+    Copy(Copy),
+    /// Move a cache to a new name, without transferring its objects
+    Rename(CopyCache),
+    /// Write a cache's files and entry to a local tar, for use without the bucket
+    Export(Export),
+    /// Upload the files of an exported tar as a cache
+    Import(Import),
     /// List files from a cache
     List(List),
+    /// Summarise a cache: file counts, size and deduplication
+    Stat(Stat),
+    /// Check a cache exists without reading it.  Exits 0 if it does, 2 if
+    /// it doesn't, and 1 on errors; --verbose adds its file count and size.
+    Exists(Exists),
 
-    /// Expire old or unused files from cache.  Currently only age is implemented.
+    /// Expire old files from cache, or with --unused those no cache uses.
     Expire(Expire),
+
+    /// Delete deduplicated objects no cache refers to, eg left behind by
+    /// deleted caches
+    PurgeOrphans(PurgeOrphans),
+
+    /// Run expiry and purges every --every, printing a JSON report per
+    /// cycle.  A lease in the bucket keeps runners from colliding; SIGINT or
+    /// SIGTERM stops at the next task boundary.
+    Maintain(Maintain),
+
+    /// Report bucket usage and upload activity over a time window
+    Report(Report),
+
+    /// Compare local files with a cache without transferring content.  Exits
+    /// 1 if they differ; --verbose lists the paths.
+    Status(Status),
+
+    /// Check every deduplicated object a cache refers to exists, without
+    /// downloading.  Exits 1 listing the paths if any are missing.
+    Verify(Verify),
+
+    /// Print sha256sum compatible checksums of a cache's files, hashing
+    /// cache-local files lacking a recorded hash
+    Checksums(Checksums),
+
+    /// Server-side copy cache/ and objects/ below a new prefix.  Safe to
+    /// re-run: keys already copied are skipped.
+    Migrate(Migrate),
+
+    /// Check objects are well formed and non-empty, and with --deep that
+    /// their content matches their hash.  Exits 1 if any are damaged.
+    Fsck(Fsck),
+
+    /// List namespaces (top-level prefixes, eg from migrate) with their
+    /// cache count and size
+    Namespaces(Namespaces),
+
+    /// Show the bucket, its layout marker and clock skew
+    Info,
+
+    /// Round trip a generated fixture through a throwaway cache to check a
+    /// deployment works end to end
+    Selftest(Selftest),
+
+    /// Collect version, configuration, timed probes of the bucket and
+    /// recent caches into a .tar.gz to attach to bug reports.  Credentials
+    /// are never included.
+    SupportBundle(SupportBundle),
+
+    /// Hash files as upload would and report content duplicated among
+    /// them, most wasted bytes first.  Never reaches the bucket.
+    Dups(Dups),
 }
 
 #[derive(clap::Args, Debug)]
@@ -149,20 +736,280 @@ struct Upload {
     /// will just be stored with the cache and not deduplicated
     #[arg(long, default_value_t=25*1024*1024)]
     threshold: usize,
+
+    /// File of pre-computed SHA-256 hashes (JSON path->hex map or sha256sum
+    /// output).  Listed files are not hashed locally.
+    #[arg(long)]
+    hashes_from: Option<PathBuf>,
+
+    /// Percentage of files from --hashes-from to re-hash as a sanity check
+    #[arg(long, default_value_t=0, value_parser=clap::value_parser!(u8).range(0..=100), requires="hashes_from")]
+    hashes_verify_sample: u8,
+
+    /// File timestamps to record and restore on download.  Creation time
+    /// is only captured where the platform reports it, and can't be
+    /// restored on Linux.
+    #[arg(long, value_enum, default_value_t=s3_cache::times::PreserveTimes::None)]
+    preserve_times: s3_cache::times::PreserveTimes,
+
+    /// Keep a local index of deduplicated objects known to exist, to skip
+    /// checking them on later uploads
+    #[arg(long)]
+    dedup_index: bool,
+
+    /// Check objects anyway once they're older than this.  Keep it below
+    /// the expire age so expired objects aren't trusted.
+    #[arg(long, default_value="7d", requires="dedup_index")]
+    index_ttl: humantime::Duration,
+
+    /// Store files kept with the cache with a Content-Type from their
+    /// extension, so eg HTML reports render in browsers.  Deduplicated
+    /// objects are always application/octet-stream.
+    #[arg(long, default_value_t=true, action=clap::ArgAction::Set)]
+    content_type_detect: bool,
+
+    /// Symlinks with absolute targets restore differently per machine:
+    /// keep them, reject the upload, or rewrite those pointing inside the
+    /// uploaded paths to be relative
+    #[arg(long, value_enum, default_value_t=s3_cache::links::AbsoluteSymlinks::Keep)]
+    absolute_symlinks: s3_cache::links::AbsoluteSymlinks,
+
+    /// Record file capabilities (security.capability) and user.* extended
+    /// attributes.  Linux only.
+    #[arg(long)]
+    xattrs: bool,
+
+    /// Record POSIX ACLs of files, and access and default ACLs of
+    /// directories.  Linux only.
+    #[arg(long)]
+    acls: bool,
+
+    /// Record entry paths in this Unicode form, so names from macOS (NFD)
+    /// and Linux (NFC) tools cache as the same file.  Uploads where two
+    /// paths become one are refused.
+    #[arg(long, value_enum, default_value_t=s3_cache::unicode::Normalization::Off)]
+    unicode_normalize: s3_cache::unicode::Normalization,
+
+    /// Compress file content with zstd before storing it.  Compressed
+    /// objects are stored apart from uncompressed copies of the same content.
+    #[arg(long)]
+    compress: bool,
+
+    /// Refuse to cache more than this many files, naming the largest
+    #[arg(long)]
+    max_files: Option<usize>,
+
+    /// Refuse to cache more than this many bytes, eg 20G, naming the largest
+    /// files
+    #[arg(long, value_parser=clap_num::si_number::<u64>)]
+    max_total_size: Option<u64>,
+
+    /// Instead of refusing an upload over --max-files or --max-total-size,
+    /// drop files to fit: those matching --priority-glob first, then the most
+    /// recently modified
+    #[arg(long)]
+    truncate_to_limits: bool,
+
+    /// Skip paths matching this glob, eg '**/*.o'.  Matched against paths as
+    /// given or walked, without a leading './'.  Repeat for several.
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Record this in the entry for list to show, eg the git SHA built
+    #[arg(long)]
+    label: Option<String>,
+
+    /// Hash to key deduplicated objects by: sha256, blake3 or
+    /// sha256-merkle[-<chunk bytes>].  blake3 is faster, but its objects
+    /// aren't shared with caches uploaded with sha256, and entries record no
+    /// sha256 for download to check.  sha256-merkle hashes chunks of large
+    /// files (64MiB unless given) in parallel; files of one chunk or less
+    /// share objects with sha256 caches, larger ones only with caches using
+    /// the same chunk size.
+    #[arg(long, default_value_t=s3_cache::cache::HashAlgorithm::Sha256, conflicts_with="hashes_from")]
+    hash_algorithm: s3_cache::cache::HashAlgorithm,
+
+    /// Chunks sha256-merkle hashes at once, across all files
+    #[arg(long, default_value_t=s3_cache::cache::default_hash_jobs(), value_parser=clap::value_parser!(usize))]
+    hash_jobs: usize,
+
+    /// Skip and report files this process can't read, rather than failing,
+    /// and upload every file before reporting those that failed.  The
+    /// entry isn't written if any did.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Paths to keep ahead of others with --truncate-to-limits, eg 'bin/*'.
+    /// Give several in order of priority.
+    #[arg(long, value_parser=s3_cache::limits::parse_glob, requires="truncate_to_limits")]
+    priority_glob: Vec<glob::Pattern>,
+
+    /// zstd level for --compress, 1 (fastest) to 22 (smallest)
+    #[arg(long, default_value_t=3, value_parser=clap::value_parser!(i32).range(1..=22), requires="compress")]
+    compress_level: i32,
+
+    /// Record objects confirmed uploaded in this file, so rerunning an
+    /// interrupted upload with the same file skips them.  Removed once the
+    /// upload completes.
+    #[arg(long, conflicts_with="plan_out")]
+    state_file: Option<PathBuf>,
+
+    /// Write what would be uploaded to this JSON file instead of uploading
+    #[arg(long, conflicts_with="from_plan")]
+    plan_out: Option<PathBuf>,
+
+    /// Look up which objects already exist while planning
+    #[arg(long, requires="plan_out")]
+    check_existing: bool,
+
+    /// Upload exactly what a --plan-out file lists, failing if any of the
+    /// files have changed since
+    #[arg(long, conflicts_with_all=["files", "recurse", "hashes_from"])]
+    from_plan: Option<PathBuf>,
 }
 
 #[derive(clap::Args, Debug)]
 struct Download {
-    #[command(flatten)]
-    cache: CacheArgs,
+    /// The name of the cache.  Required unless recovering; repeat to
+    /// restore several in turn.
+    #[arg(long = "name", required_unless_present = "recover")]
+    names: Vec<String>,
+
+    /// When a cache is missing, restore the newest cache whose name starts
+    /// with this instead.  Repeat to try several prefixes in order.
+    #[arg(long = "fallback", value_name = "PREFIX")]
+    fallbacks: Vec<String>,
 
     /// Where to put the output
     #[arg(long, short='o', default_value=".")]
     outpath: PathBuf,
 
+    /// Restore each cache below OUTPATH/<name> instead of all into OUTPATH,
+    /// where caches sharing paths are refused
+    #[arg(long)]
+    outpath_per_name: bool,
+
+    /// Restore into a staging directory below OUTPATH, and move files into
+    /// place only once every cache has been restored
+    #[arg(long, conflicts_with="local_state")]
+    atomic: bool,
+
+    /// First finish moving into OUTPATH what an interrupted --atomic
+    /// download had begun to, and discard what it hadn't.  Only while no
+    /// other download into OUTPATH is running.
+    #[arg(long, conflicts_with="dry_run")]
+    recover: bool,
+
     #[arg(long, default_value_t=3, value_parser=greater_than_0)]
     /// Maximum number of parallel network connections
     max_in_flight: u32,
+
+    /// Only restore files modified after this RFC 3339 time, or after the
+    /// modification time of @file.  Needs an upload with --preserve-times.
+    #[arg(long)]
+    newer_than: Option<String>,
+
+    /// Log the files that would be restored without writing anything
+    #[arg(long, short='n', conflicts_with_all=["atomic", "local_state", "write_checksums", "sentinel"])]
+    dry_run: bool,
+
+    /// Only restore files whose path in the cache matches this glob, eg
+    /// 'include/**/*.h'.  Repeat for several; by default all are restored.
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// fsync restored files (and directories) before returning, at some
+    /// cost in speed - the time taken is reported
+    #[arg(long, value_enum, default_value_t=s3_cache::fsync::FsyncPolicy::None)]
+    fsync: s3_cache::fsync::FsyncPolicy,
+
+    /// Don't list larger caches up front to find missing files before
+    /// downloading
+    #[arg(long)]
+    no_preflight: bool,
+
+    /// Restore what's available, skipping files the preflight finds missing
+    /// or the wrong size, and restoring every file before reporting those
+    /// that failed
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Track restored files in OUTPATH/.s3-cache.state, and skip those
+    /// still holding the right content next time.  Files with unchanged
+    /// size and modification time are trusted without hashing.
+    #[arg(long)]
+    local_state: bool,
+
+    /// Skip files already in OUTPATH with the same size and recorded
+    /// sha256, or symlink target, fetching only what's missing or
+    /// different.  Permissions of skipped files are still restored.
+    #[arg(long, conflicts_with="atomic")]
+    if_changed: bool,
+
+    /// Restore files sharing content as hard links to one another, rather
+    /// than copies, where nothing else recorded about them differs and the
+    /// filesystem allows
+    #[arg(long)]
+    hardlink: bool,
+
+    /// Re-hash each deduplicated file once written, failing if it doesn't
+    /// match the object it was stored as.  Only needed for entries uploaded
+    /// before hashes were recorded; files with a recorded sha256 are always
+    /// checked.
+    #[arg(long)]
+    verify: bool,
+
+    /// Restore recorded extended attributes where permitted.  Setting
+    /// capabilities usually needs privileges; failures are only warned about.
+    #[arg(long)]
+    xattrs: bool,
+
+    /// Restore recorded POSIX ACLs, after permissions.  Failures, eg on a
+    /// filesystem without ACL support, are only warned about.
+    #[arg(long)]
+    acls: bool,
+
+    /// What to do with symlinks that can't be created, eg on Windows
+    /// without Developer Mode: fail the restore, copy what they point to
+    /// from within the restored files, or skip them and exit with 1
+    #[arg(long, value_enum, default_value_t=s3_cache::links::Symlinks::Native)]
+    symlinks: s3_cache::links::Symlinks,
+
+    /// Fetch uncompressed objects this size or larger (eg 256M) in parallel
+    /// ranges, over connections not in use for other files
+    #[arg(long, default_value_t=s3_cache::ranged::DEFAULT_THRESHOLD, value_parser=clap_num::si_number::<u64>)]
+    ranged_threshold: u64,
+
+    /// Write sha256sum compatible checksums of the restored files to
+    /// SHA256SUMS in OUTPATH, for checking with `sha256sum -c`
+    #[arg(long)]
+    write_checksums: bool,
+
+    /// Once restored, write a JSON file saying so, with "hit": true, to PATH
+    /// or OUTPATH/.s3-cache.sentinel.json.  Uploads skip files of that name.
+    #[arg(long, value_name = "PATH")]
+    sentinel: Option<Option<PathBuf>>,
+
+    /// Succeed when a cache is missing, restoring nothing, and write the
+    /// sentinel with "hit": false
+    #[arg(long)]
+    ok_if_missing: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct Stat {
+    #[command(flatten)]
+    cache: CacheArgs,
+
+    /// Output JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct Exists {
+    #[command(flatten)]
+    cache: CacheArgs,
 }
 
 #[derive(clap::Args, Debug)]
@@ -170,12 +1017,90 @@ struct List {
     /// The name of the cache to list. If not presented list the caches.
     #[arg(long)]
     name: Option<String>,
+
+    /// Output JSON, with the totals and last modification of each cache
+    /// when listing caches
+    #[arg(long)]
+    json: bool,
+
+    /// List at most this many caches, naming the one to continue after
+    #[arg(long, conflicts_with="name", value_parser=|s: &str| clap_num::number_range(s, 1, usize::MAX))]
+    limit: Option<usize>,
+
+    /// List caches after this one, eg the last of an earlier page
+    #[arg(long, conflicts_with="name")]
+    start_after: Option<String>,
+
+    /// Only list caches whose names start with this
+    #[arg(long, conflicts_with="name")]
+    prefix: Option<String>,
 }
 
 #[derive(clap::Args, Debug)]
 struct Delete {
+    /// The name of the cache
+    #[arg(long, required_unless_present="name_from", conflicts_with="name_from")]
+    name: Option<String>,
+
+    /// Delete the caches whose names match this template, eg
+    /// 'deps-{env:BRANCH}-*'.  {env:NAME} and {hash:PATH} are resolved now,
+    /// and globs such as * match the rest.
+    #[arg(long)]
+    name_from: Option<String>,
+
+    /// Delete every cache --name-from matches, when it matches more than one
+    #[arg(long, short='y')]
+    yes: bool,
+
+    /// Log each key that would be deleted, with the count and total size,
+    /// without deleting anything
+    #[arg(long, short='n')]
+    dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct Export {
     #[command(flatten)]
     cache: CacheArgs,
+
+    /// The tar to write
+    #[arg(long, short='o')]
+    output: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct Import {
+    #[command(flatten)]
+    cache: CacheArgs,
+
+    /// A tar written by export
+    #[arg(long, short='i')]
+    input: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct CopyCache {
+    /// The existing cache
+    #[arg(long)]
+    from: String,
+
+    /// The name to give it
+    #[arg(long)]
+    to: String,
+
+    /// Replace the cache named by --to if it exists
+    #[arg(long, alias="force")]
+    overwrite: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct Copy {
+    #[command(flatten)]
+    cache: CopyCache,
+
+    /// Delete the cache named by --from once copied, as rename does
+    #[arg(long)]
+    delete_source: bool,
 }
 
 #[derive(clap::Args, Debug)]
@@ -184,6 +1109,242 @@ struct Expire {
     /// Age of objects to expire unconditionally
     #[arg(long, default_value_t=14)]
     days: u32,
+
+    /// Record progress in this file, so a rerun after the process is killed
+    /// or out of time resumes where it stopped.  Removed once a pass
+    /// completes.
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Stop cleanly after this long, eg 45m or 2h, saving the checkpoint
+    #[arg(long)]
+    time_budget: Option<humantime::Duration>,
+
+    /// Keep objects used by caches downloaded at least this often within
+    /// --window, whatever their age
+    #[arg(long)]
+    min_reads: Option<usize>,
+
+    /// Window for --min-reads, eg 30d or 2w
+    #[arg(long, default_value="30d")]
+    window: humantime::Duration,
+
+    /// Only expire objects no cache entry refers to, and still older than
+    /// --days.  Uploads reuse existing objects, so avoid running this
+    /// alongside them with a small --days.
+    #[arg(long)]
+    unused: bool,
+
+    /// Also delete whole caches whose entry was created more than this many
+    /// days ago, other than those kept by --min-reads.  Entries uploaded by
+    /// releases that didn't record creation times are left alone.
+    #[arg(long)]
+    cache_days: Option<u32>,
+
+    /// Same as --cache-days with the value of --days
+    #[arg(long, conflicts_with="cache_days")]
+    entries: bool,
+
+    /// Only delete whole caches whose names match this template, as for
+    /// delete --name-from, eg --match-from='deps-{env:BRANCH}-*' --cache-days=0
+    #[arg(long, requires="cache_days")]
+    match_from: Option<String>,
+
+    /// Afterwards, look up a sample of the files of every cache left, and
+    /// fail if any are gone.  Implied by --strict.
+    #[arg(long)]
+    verify: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct PurgeOrphans {
+    /// Report what would be deleted without deleting anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Keep orphans younger than this, eg 1h or 2d, so objects of uploads
+    /// still in progress survive
+    #[arg(long, default_value="1h")]
+    grace: humantime::Duration,
+}
+
+#[derive(clap::Args, Debug)]
+struct Maintain {
+    /// Time between the start of each cycle, eg 6h
+    #[arg(long, default_value="6h")]
+    every: humantime::Duration,
+
+    /// Run one cycle and exit, eg from cron
+    #[arg(long)]
+    once: bool,
+
+    /// Expire objects older than this many days, as expire --days
+    #[arg(long)]
+    expire_days: Option<u32>,
+
+    /// Expire the oldest objects until those left total at most this many
+    /// bytes, eg 200G
+    #[arg(long, value_parser=clap_num::si_number::<u64>)]
+    max_total_size: Option<u64>,
+
+    /// Delete objects no cache refers to, as purge-orphans
+    #[arg(long)]
+    gc: bool,
+
+    /// Keep orphans younger than this with --gc
+    #[arg(long, default_value="1h")]
+    grace: humantime::Duration,
+
+    /// How long the lease lasts unless renewed.  A runner that dies holding
+    /// it is taken over once it expires.
+    #[arg(long, default_value="10m")]
+    lease_ttl: humantime::Duration,
+}
+
+#[derive(clap::Args, Debug)]
+struct Report {
+    /// How far back to report on, eg 30d, 2w, 12h
+    #[arg(long, default_value="30d")]
+    since: humantime::Duration,
+
+    /// Report downloads per cache, from their access logs, instead
+    #[arg(long)]
+    access: bool,
+
+    /// Output JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct Status {
+    /// Files to compare, as given to upload
+    files: Vec<PathBuf>,
+
+    #[arg(long, short='r', default_value_t=false)]
+    /// Compare all files in directories
+    recurse: bool,
+
+    #[command(flatten)]
+    cache: CacheArgs,
+
+    /// Dedupe file threshold size in bytes, as given to upload
+    #[arg(long, default_value_t=25*1024*1024)]
+    threshold: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct Verify {
+    #[command(flatten)]
+    cache: CacheArgs,
+
+    #[arg(long, default_value_t=3, value_parser=greater_than_0)]
+    /// Maximum number of parallel network connections
+    max_in_flight: u32,
+}
+
+#[derive(clap::Args, Debug)]
+struct Checksums {
+    #[command(flatten)]
+    cache: CacheArgs,
+
+    /// Write to this file instead of stdout
+    #[arg(long, short='o')]
+    output: Option<PathBuf>,
+
+    #[arg(long, default_value_t=3, value_parser=greater_than_0)]
+    /// Maximum number of parallel network connections
+    max_in_flight: u32,
+}
+
+#[derive(clap::Args, Debug)]
+struct Migrate {
+    /// Destination prefix, eg org/repo/
+    #[arg(long)]
+    to_prefix: String,
+
+    /// Delete each original once its copy has been verified
+    #[arg(long)]
+    delete_source: bool,
+
+    #[arg(long, default_value_t=3, value_parser=greater_than_0)]
+    /// Maximum number of parallel network connections
+    max_in_flight: u32,
+}
+
+#[derive(clap::Args, Debug)]
+struct Fsck {
+    /// Download and hash objects
+    #[arg(long)]
+    deep: bool,
+
+    /// Percentage of objects to hash in deep mode, eg 10%
+    #[arg(long, default_value="100%", value_parser=s3_cache::fsck::parse_percent, requires="deep")]
+    sample: u8,
+
+    /// Record verified objects in this file, so an interrupted deep check
+    /// can carry on where it left off.  Removed once the check completes.
+    #[arg(long, requires="deep")]
+    state_file: Option<PathBuf>,
+
+    #[arg(long, default_value_t=3, value_parser=greater_than_0)]
+    /// Maximum number of parallel network connections
+    max_in_flight: u32,
+}
+
+#[derive(clap::Args, Debug)]
+struct Namespaces {
+    /// Output JSON instead of a table
+    #[arg(long)]
+    json: bool,
+
+    #[arg(long, default_value_t=3, value_parser=greater_than_0)]
+    /// Maximum number of parallel network connections
+    max_in_flight: u32,
+}
+
+#[derive(clap::Args, Debug)]
+struct Selftest {
+    /// Also delete the deduplicated objects uploaded by the test
+    #[arg(long)]
+    delete_objects: bool,
+
+    #[arg(long, default_value_t=3, value_parser=greater_than_0)]
+    /// Maximum number of parallel network connections
+    max_in_flight: u32,
+}
+
+#[derive(clap::Args, Debug)]
+struct SupportBundle {
+    /// Where to write the bundle
+    #[arg(long, short='o', default_value="s3-cache-support.tar.gz")]
+    output: PathBuf,
+
+    /// List recent caches by a hash of their names
+    #[arg(long)]
+    redact_names: bool,
+
+    /// Include this result file, as written by --result-file on an earlier run
+    #[arg(long, value_name = "PATH")]
+    last_result: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct Dups {
+    /// Files to check, as given to upload
+    files: Vec<PathBuf>,
+
+    #[arg(long, short='r', default_value_t=false)]
+    /// Check all files in directories
+    recurse: bool,
+
+    /// Skip paths matching this glob, as given to upload
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Output JSON instead of a table
+    #[arg(long)]
+    json: bool,
 }
 
 // Claps' built-in self test
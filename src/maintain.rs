@@ -0,0 +1,393 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::expire::{self, ExpireOptions, ExpireReport, PurgeOptions, PurgeReport};
+use crate::{clock, Error, Result, Storage};
+
+/// Held by whichever runner is maintaining the bucket
+pub const LEASE_KEY: &str = "maintenance/lock";
+
+/// Tuning for [maintain]
+#[derive(Debug, Clone)]
+pub struct MaintainOptions {
+    /// Time between the start of one cycle and the next
+    pub every: Duration,
+    /// Run a single cycle, eg from cron
+    pub once: bool,
+    /// Expire objects older than this many days
+    pub expire_days: Option<u32>,
+    /// Expire the oldest objects until those left total at most this
+    pub max_total_size: Option<u64>,
+    /// Purge objects no cache refers to
+    pub gc: bool,
+    /// Keep orphans younger than this with gc
+    pub grace: Duration,
+    /// How long the lease lasts unless renewed
+    pub lease_ttl: Duration,
+}
+
+impl Default for MaintainOptions {
+    fn default() -> Self {
+        MaintainOptions {
+            every: Duration::from_secs(6 * 60 * 60), once: false,
+            expire_days: None, max_total_size: None, gc: false,
+            grace: PurgeOptions::default().grace,
+            lease_ttl: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// What one cycle did
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CycleReport {
+    /// Another runner held the lease, so nothing was done
+    pub skipped: bool,
+    pub expire: Option<ExpireReport>,
+    pub trim: Option<ExpireReport>,
+    pub gc: Option<PurgeReport>,
+    /// Stopped between tasks, on a signal or losing the lease
+    pub interrupted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Lease {
+    owner: String,
+    /// By the server's clock
+    expires: DateTime<Utc>,
+}
+
+/// What leasing needs of a bucket
+pub(crate) trait LeaseStore {
+    async fn read(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    async fn write(&self, key: &str, bytes: Vec<u8>) -> Result<()>;
+    async fn remove(&self, key: &str) -> Result<()>;
+}
+
+impl LeaseStore for Storage {
+    async fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut vec = Vec::new();
+        match self.get_file(&mut vec, key).await {
+            Ok(()) => Ok(Some(vec)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        Ok(self.put_file_as(&mut std::io::Cursor::new(bytes), key, "application/json", &[]).await?)
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        Ok(self.delete(key).await?)
+    }
+}
+
+/// The lease as stored, None if there's none.  A damaged one is treated as
+/// stale, so it can't wedge maintenance forever.
+async fn read_lease<S: LeaseStore>(store: &S) -> Result<Option<Lease>> {
+    let Some(bytes) = store.read(LEASE_KEY).await? else {
+        return Ok(None);
+    };
+    match serde_json::from_slice(&bytes) {
+        Ok(lease) => Ok(Some(lease)),
+        Err(e) => {
+            log::warn!("Ignoring damaged maintenance lease: {}", e);
+            Ok(Some(Lease { owner: String::new(), expires: DateTime::<Utc>::MIN_UTC }))
+        },
+    }
+}
+
+async fn write_lease<S: LeaseStore>(store: &S, owner: &str, ttl: TimeDelta, now: DateTime<Utc>) -> Result<()> {
+    let lease = Lease { owner: owner.to_owned(), expires: now + ttl };
+    store.write(LEASE_KEY, serde_json::to_vec(&lease)?).await
+}
+
+/// Take the lease unless another owner holds it unexpired, stealing it
+/// once stale.  There's no compare-and-swap, so it's read back to see who
+/// won any race.
+async fn acquire<S: LeaseStore>(store: &S, owner: &str, ttl: TimeDelta, now: DateTime<Utc>) -> Result<bool> {
+    match read_lease(store).await? {
+        Some(lease) if lease.owner != owner && lease.expires > now => {
+            log::info!("Maintenance lease held by '{}' until {}", lease.owner, lease.expires.to_rfc3339());
+            return Ok(false);
+        },
+        Some(lease) if lease.owner != owner => {
+            log::warn!("Taking over maintenance lease of '{}', which expired at {}", lease.owner, lease.expires.to_rfc3339());
+        },
+        _ => {},
+    }
+    write_lease(store, owner, ttl, now).await?;
+    match read_lease(store).await? {
+        Some(lease) if lease.owner == owner => Ok(true),
+        other => {
+            log::info!("Lost race for maintenance lease to '{}'", other.map(|l| l.owner).unwrap_or_default());
+            Ok(false)
+        },
+    }
+}
+
+/// Extend a lease still held, failing if another owner has taken it
+async fn renew<S: LeaseStore>(store: &S, owner: &str, ttl: TimeDelta, now: DateTime<Utc>) -> Result<()> {
+    match read_lease(store).await? {
+        Some(lease) if lease.owner != owner => Err(Error::LeaseLost(lease.owner).into()),
+        _ => write_lease(store, owner, ttl, now).await,
+    }
+}
+
+/// Give up a lease, unless it's already someone else's
+async fn release<S: LeaseStore>(store: &S, owner: &str) -> Result<()> {
+    match read_lease(store).await? {
+        Some(lease) if lease.owner == owner => store.remove(LEASE_KEY).await,
+        _ => Ok(()),
+    }
+}
+
+/// Asks maintenance to stop at the next task boundary
+#[derive(Clone, Default)]
+struct Stop(Arc<(AtomicBool, Notify)>);
+
+impl Stop {
+    fn request(&self) {
+        self.0.0.store(true, Ordering::SeqCst);
+        self.0.1.notify_waiters();
+    }
+
+    fn requested(&self) -> bool {
+        self.0.0.load(Ordering::SeqCst)
+    }
+
+    async fn wait(&self) {
+        loop {
+            let notified = self.0.1.notified();
+            if self.requested() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = term.recv() => {},
+            },
+            Err(e) => {
+                log::warn!("Unable to catch SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            },
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Renew the lease every third of its ttl, asking to stop if it's lost
+async fn keep_renewed(storage: Storage, owner: String, ttl: TimeDelta, skew: TimeDelta, lost: Stop) {
+    let period = (ttl / 3).to_std().unwrap_or(Duration::from_secs(1));
+    loop {
+        tokio::time::sleep(period).await;
+        if let Err(e) = renew(&storage, &owner, ttl, Utc::now() + skew).await {
+            log::warn!("Failed to renew maintenance lease, stopping after this task: {:#}", e);
+            lost.request();
+            return;
+        }
+    }
+}
+
+/// Run each configured task in turn, until asked to stop
+async fn run_tasks(storage: &Storage, options: &MaintainOptions, report: &mut CycleReport, stops: &[&Stop]) -> Result<()> {
+    let stopping = |report: &mut CycleReport| {
+        report.interrupted = stops.iter().any(|s| s.requested());
+        report.interrupted
+    };
+    if let Some(days) = options.expire_days {
+        if stopping(report) {
+            return Ok(());
+        }
+        report.expire = Some(expire::expire(storage, &ExpireOptions { days, ..Default::default() }).await?);
+    }
+    if let Some(max_total) = options.max_total_size {
+        if stopping(report) {
+            return Ok(());
+        }
+        report.trim = Some(expire::trim(storage, max_total).await?);
+    }
+    if options.gc {
+        if stopping(report) {
+            return Ok(());
+        }
+        let purge = PurgeOptions { grace: options.grace, ..Default::default() };
+        report.gc = Some(expire::purge_orphans(storage, &purge).await?);
+    }
+    Ok(())
+}
+
+/// One cycle: take the lease, run the tasks, give it back
+async fn cycle(storage: &Storage, options: &MaintainOptions, owner: &str, stop: &Stop) -> Result<CycleReport> {
+    let ttl = TimeDelta::from_std(options.lease_ttl).context("Lease ttl out of range")?;
+    // leases are compared by every runner, so judge them by the server's clock
+    let skew = clock::measure(storage).await?.unwrap_or_default();
+    let mut report = CycleReport::default();
+    if !acquire(storage, owner, ttl, Utc::now() + skew).await? {
+        report.skipped = true;
+        return Ok(report);
+    }
+    let lost = Stop::default();
+    let renewer = tokio::spawn(keep_renewed(storage.clone(), owner.to_owned(), ttl, skew, lost.clone()));
+    let result = run_tasks(storage, options, &mut report, &[stop, &lost]).await;
+    renewer.abort();
+    if let Err(e) = release(storage, owner).await {
+        log::warn!("Failed to release maintenance lease, it will expire by itself: {:#}", e);
+    }
+    result.map(|()| report)
+}
+
+/// Who holds the lease, readable enough to track down
+fn owner_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_owned());
+    format!("{}:{}:{}", host, std::process::id(), uuid::Uuid::new_v4())
+}
+
+/// Run maintenance cycles every options.every, or once, reporting each.
+/// SIGINT or SIGTERM stops it at the next task boundary.
+pub async fn maintain(storage: &Storage, options: &MaintainOptions,
+                      mut on_cycle: impl FnMut(&CycleReport)) -> Result<CycleReport> {
+    let owner = owner_id();
+    let stop = Stop::default();
+    let signalled = stop.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::warn!("Stopping maintenance once the current task finishes");
+        signalled.request();
+    });
+
+    loop {
+        let started = tokio::time::Instant::now();
+        let report = cycle(storage, options, &owner, &stop).await?;
+        on_cycle(&report);
+        if options.once || stop.requested() {
+            return Ok(report);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep_until(started + options.every) => {},
+            _ = stop.wait() => return Ok(report),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::sync::Mutex;
+
+    /// The lease object, optionally overwritten by an interloper on each write
+    #[derive(Default)]
+    struct MockStore {
+        lease: Mutex<Option<Vec<u8>>>,
+        interloper: Option<String>,
+    }
+
+    impl LeaseStore for MockStore {
+        async fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            assert_eq!(key, LEASE_KEY);
+            Ok(self.lease.lock().unwrap().clone())
+        }
+
+        async fn write(&self, _key: &str, bytes: Vec<u8>) -> Result<()> {
+            let bytes = match &self.interloper {
+                Some(owner) => serde_json::to_vec(&Lease { owner: owner.clone(), expires: DateTime::<Utc>::MAX_UTC })?,
+                None => bytes,
+            };
+            *self.lease.lock().unwrap() = Some(bytes);
+            Ok(())
+        }
+
+        async fn remove(&self, _key: &str) -> Result<()> {
+            *self.lease.lock().unwrap() = None;
+            Ok(())
+        }
+    }
+
+    impl MockStore {
+        fn owner(&self) -> Option<Lease> {
+            self.lease.lock().unwrap().as_ref().map(|b| serde_json::from_slice(b).unwrap())
+        }
+    }
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    const TTL: TimeDelta = TimeDelta::minutes(10);
+
+    #[tokio::test]
+    async fn lease_is_exclusive_until_stale() {
+        let store = MockStore::default();
+        let now = time("2025-01-01T00:00:00Z");
+        assert!(acquire(&store, "a", TTL, now).await.unwrap());
+        assert_eq!(store.owner(), Some(Lease { owner: "a".into(), expires: now + TTL }));
+        // the holder may take it again, others wait
+        assert!(acquire(&store, "a", TTL, now).await.unwrap());
+        assert!(!acquire(&store, "b", TTL, now + TimeDelta::minutes(9)).await.unwrap());
+
+        // renewal keeps it from going stale
+        renew(&store, "a", TTL, now + TimeDelta::minutes(9)).await.unwrap();
+        assert!(!acquire(&store, "b", TTL, now + TimeDelta::minutes(15)).await.unwrap());
+
+        // then it's stolen, and the old holder finds out on renewal
+        let later = now + TimeDelta::minutes(20);
+        assert!(acquire(&store, "b", TTL, later).await.unwrap());
+        assert_eq!(store.owner().unwrap().owner, "b");
+        let e = renew(&store, "a", TTL, later).await.unwrap_err();
+        assert!(matches!(e.downcast_ref::<Error>(), Some(Error::LeaseLost(o)) if o == "b"));
+
+        // only the holder can release it
+        release(&store, "a").await.unwrap();
+        assert_eq!(store.owner().unwrap().owner, "b");
+        release(&store, "b").await.unwrap();
+        assert_eq!(store.owner(), None);
+    }
+
+    #[tokio::test]
+    async fn lease_races_are_lost() {
+        let store = MockStore { interloper: Some("b".into()), ..Default::default() };
+        assert!(!acquire(&store, "a", TTL, time("2025-01-01T00:00:00Z")).await.unwrap());
+        assert_eq!(store.owner().unwrap().owner, "b");
+    }
+
+    #[tokio::test]
+    async fn damaged_lease_is_stale() {
+        let store = MockStore { lease: Mutex::new(Some(b"{\"own".to_vec())), ..Default::default() };
+        assert!(acquire(&store, "a", TTL, time("2025-01-01T00:00:00Z")).await.unwrap());
+        assert_eq!(store.owner().unwrap().owner, "a");
+    }
+
+    #[tokio::test]
+    async fn stop_wakes_waiters() {
+        let stop = Stop::default();
+        let waiter = tokio::spawn({
+            let stop = stop.clone();
+            async move { stop.wait().await }
+        });
+        stop.request();
+        tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        assert!(stop.requested());
+        // and returns at once when already requested
+        stop.wait().await;
+    }
+}
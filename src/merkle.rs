@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+
+use crate::Result;
+
+/// Chunk size when none is given
+pub const DEFAULT_CHUNK: u64 = 64 << 20;
+
+/// Smallest chunk accepted, so a file isn't split into millions of them
+pub const MIN_CHUNK: u64 = 1 << 20;
+
+/// Starts the root hash, so it can't be mistaken for a hash of content
+const TAG: &[u8] = b"s3-cache sha256-merkle\0";
+
+/// Two level sha256 of fixed size chunks, so large files can be hashed in
+/// parallel.  Content of up to one chunk hashes as plain sha256, sharing
+/// objects with caches keyed by it.  Larger content hashes to the sha256 of
+/// a tag, the chunk size and each chunk's sha256 in order, so its objects
+/// are only shared with caches using the same chunk size.
+fn root(chunk: u64, digests: &[[u8; 32]]) -> [u8; 32] {
+    match digests {
+        [] => Sha256::digest(b"").into(),
+        [only] => *only,
+        _ => {
+            let mut root = Sha256::new();
+            root.update(TAG);
+            root.update(chunk.to_be_bytes());
+            for d in digests {
+                root.update(d);
+            }
+            root.finalize().into()
+        },
+    }
+}
+
+/// Hashes content written in order, a chunk at a time
+#[derive(Debug, Clone)]
+pub(crate) struct MerkleHasher {
+    chunk: u64,
+    current: Sha256,
+    in_current: u64,
+    digests: Vec<[u8; 32]>,
+}
+
+impl MerkleHasher {
+    pub fn new(chunk: u64) -> MerkleHasher {
+        MerkleHasher { chunk, current: Sha256::new(), in_current: 0, digests: Vec::new() }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (self.chunk - self.in_current).min(data.len() as u64) as usize;
+            self.current.update(&data[..take]);
+            self.in_current += take as u64;
+            data = &data[take..];
+            if self.in_current == self.chunk {
+                self.digests.push(std::mem::take(&mut self.current).finalize().into());
+                self.in_current = 0;
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        if self.in_current > 0 {
+            self.digests.push(self.current.finalize().into());
+        }
+        root(self.chunk, &self.digests)
+    }
+}
+
+/// sha256 of len bytes of the file at path from start
+fn hash_segment(path: &Path, start: u64, len: u64) -> std::io::Result<[u8; 32]> {
+    let mut f = std::fs::File::open(path)?;
+    f.seek(std::io::SeekFrom::Start(start))?;
+    let mut segment = f.take(len);
+    let mut buf = vec![0; len.clamp(4096, 1024 * 1024) as usize];
+    let mut sha = Sha256::new();
+    loop {
+        let read = segment.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        sha.update(&buf[..read]);
+    }
+    Ok(sha.finalize().into())
+}
+
+/// Hash the file at path, its chunks in parallel with a permit from jobs
+/// each
+pub(crate) async fn hash_file(path: &Path, chunk: u64, jobs: &Arc<Semaphore>) -> Result<[u8; 32]> {
+    let size = tokio::fs::metadata(path).await?.len();
+    let count = size.div_ceil(chunk);
+    let mut set = tokio::task::JoinSet::new();
+    for i in 0..count {
+        let permit = jobs.clone().acquire_owned().await?;
+        let path = path.to_owned();
+        set.spawn_blocking(move || {
+            let _permit = permit;
+            hash_segment(&path, i * chunk, chunk).map(|digest| (i as usize, digest))
+        });
+    }
+    let mut digests = vec![[0; 32]; count as usize];
+    while let Some(work) = set.join_next().await {
+        let (i, digest) = work.with_context(|| "Failure waiting on hash jobs")??;
+        digests[i] = digest;
+    }
+    Ok(root(chunk, &digests))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const CHUNK: u64 = 4096;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn streamed(data: &[u8], chunk: u64, write: usize) -> [u8; 32] {
+        let mut hasher = MerkleHasher::new(chunk);
+        for part in data.chunks(write) {
+            hasher.update(part);
+        }
+        hasher.finalize()
+    }
+
+    #[tokio::test]
+    async fn parallel_and_streamed_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs = Arc::new(Semaphore::new(3));
+        for len in [0, 1, CHUNK as usize - 1, CHUNK as usize, CHUNK as usize + 1, CHUNK as usize * 5 + 17] {
+            let data = content(len);
+            let path = dir.path().join(len.to_string());
+            std::fs::write(&path, &data).unwrap();
+            let parallel = hash_file(&path, CHUNK, &jobs).await.unwrap();
+            for write in [1, 1000, CHUNK as usize, 100_000] {
+                assert_eq!(streamed(&data, CHUNK, write), parallel, "{} bytes written {} at a time", len, write);
+            }
+        }
+    }
+
+    #[test]
+    fn one_chunk_is_plain_sha256() {
+        for len in [0, 1, CHUNK as usize] {
+            let data = content(len);
+            assert_eq!(streamed(&data, CHUNK, 100), <[u8; 32]>::from(Sha256::digest(&data)));
+        }
+        let data = content(CHUNK as usize + 1);
+        assert_ne!(streamed(&data, CHUNK, 100), <[u8; 32]>::from(Sha256::digest(&data)));
+    }
+
+    #[test]
+    fn keys_depend_on_chunk_size() {
+        let data = content(CHUNK as usize * 4);
+        let a = streamed(&data, CHUNK, 512);
+        // same content, same scheme: the same object
+        assert_eq!(streamed(&data, CHUNK, 3000), a);
+        assert_ne!(streamed(&data, CHUNK * 2, 512), a);
+        let mut changed = data.clone();
+        changed[CHUNK as usize * 3 + 5] ^= 1;
+        assert_ne!(streamed(&changed, CHUNK, 512), a);
+    }
+}
@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+
+use crate::{Error, Result, Storage, s3::ObjectInfo};
+
+/// Top-level roots of the flat layout that are moved under the new prefix.
+/// Entries only refer to objects by hash relative to `objects/`, so they
+/// are copied verbatim - nothing inside them needs rewriting.
+const ROOTS: [&str; 2] = ["cache/", crate::object::ROOT];
+
+/// Log progress every this many keys
+const PROGRESS_EVERY: usize = 1000;
+
+/// Tuning for [migrate]
+#[derive(Debug, Clone)]
+pub struct MigrateOptions {
+    /// Remove each original once its copy has been verified
+    pub delete_source: bool,
+    /// Maximum number of parallel network connections
+    pub max_in_flight: u32,
+}
+
+impl Default for MigrateOptions {
+    fn default() -> Self {
+        MigrateOptions { delete_source: false, max_in_flight: 3 }
+    }
+}
+
+/// Tidy up a user supplied prefix into "a/b/" form
+pub(crate) fn normalise_prefix(prefix: &str) -> Result<String> {
+    let trimmed = prefix.trim_matches('/');
+    let invalid = || Error::InvalidPrefix(prefix.to_owned());
+    if trimmed.is_empty() || trimmed.contains('\\') || trimmed.split('/').any(|p| p.is_empty() || p == "." || p == "..") {
+        return Err(invalid().into());
+    }
+    let normalised = format!("{}/", trimmed);
+    // Copying into a root we're walking would never finish
+    if ROOTS.iter().any(|root| normalised.starts_with(root)) || normalised == "stats/" {
+        return Err(invalid().into());
+    }
+    Ok(normalised)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Step {
+    Copy,
+    /// Already at the destination from an earlier run
+    Skip,
+}
+
+/// What to do with source given what's already at its destination
+pub(crate) fn step_for(source: &ObjectInfo, existing: Option<u64>) -> Step {
+    match existing {
+        Some(size) if size == source.size => Step::Skip,
+        Some(size) => {
+            log::info!("{} exists at destination with size {} not {}, copying again", source.key, size, source.size);
+            Step::Copy
+        },
+        None => Step::Copy,
+    }
+}
+
+/// Running totals for progress and the final summary
+#[derive(serde::Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub total: usize,
+    pub done: usize,
+    pub copied: usize,
+    pub skipped: usize,
+    pub deleted: usize,
+    pub bytes: u64,
+}
+
+impl Progress {
+    fn add(&mut self, outcome: &Outcome) {
+        self.done += 1;
+        match outcome.step {
+            Step::Copy => {
+                self.copied += 1;
+                self.bytes += outcome.size;
+            },
+            Step::Skip => self.skipped += 1,
+        }
+        if outcome.deleted {
+            self.deleted += 1;
+        }
+        if self.done.is_multiple_of(PROGRESS_EVERY) {
+            log::warn!("Migrated {}/{} keys ({} copied, {} skipped, {} bytes)",
+                       self.done, self.total, self.copied, self.skipped, self.bytes);
+        }
+    }
+}
+
+struct Outcome {
+    step: Step,
+    size: u64,
+    deleted: bool,
+}
+
+/// Confirm the object at key is the size we expect
+async fn verify(storage: &Storage, key: &str, expected: u64) -> Result<()> {
+    let actual = storage.head(key).await?.map(|o| o.size);
+    if actual != Some(expected) {
+        return Err(Error::MigrationVerifyFailed { key: key.to_owned(), expected, actual }.into());
+    }
+    Ok(())
+}
+
+async fn migrate_object(storage: Storage, source: ObjectInfo, destination: String,
+                        step: Step, delete_source: bool) -> Result<Outcome> {
+    if step == Step::Copy {
+        log::info!("Copying {} to {}", source.key, destination);
+        storage.copy(&source.key, &destination).await
+            .with_context(|| format!("Failed to copy {} to {}", source.key, destination))?;
+    }
+    verify(&storage, &destination, source.size).await?;
+
+    if delete_source {
+        storage.delete(&source.key).await
+            .with_context(|| format!("Failed to delete migrated {}", source.key))?;
+    }
+    Ok(Outcome { step, size: source.size, deleted: delete_source })
+}
+
+/// Server-side copy everything in the flat layout to below prefix.  Keys
+/// already present at the destination with the right size are skipped, so
+/// an interrupted run can simply be started again.
+pub async fn migrate(storage: Storage, prefix: &str, options: &MigrateOptions) -> Result<Progress> {
+    let prefix = normalise_prefix(prefix)?;
+    let mut progress = Progress::default();
+
+    for root in ROOTS {
+        let sources = storage.list_objects(root).await?;
+        let existing: HashMap<String, u64> = storage.list_objects(&format!("{}{}", prefix, root)).await?
+            .into_iter().map(|o| (o.key, o.size)).collect();
+        log::warn!("Migrating {} keys from {} to {}{} ({} already present)",
+                   sources.len(), root, prefix, root, existing.len());
+        progress.total += sources.len();
+
+        let mut set = tokio::task::JoinSet::<Result<Outcome>>::new();
+        for source in sources {
+            while set.len() >= options.max_in_flight as usize {
+                if let Some(work) = set.join_next().await {
+                    progress.add(&work.with_context(|| "Failure waiting on migration jobs")??);
+                }
+            }
+            let destination = format!("{}{}", prefix, source.key);
+            let step = step_for(&source, existing.get(&destination).copied());
+            set.spawn(migrate_object(storage.clone(), source, destination, step, options.delete_source));
+        }
+        while let Some(work) = set.join_next().await {
+            progress.add(&work.with_context(|| "Failure waiting on migration jobs")??);
+        }
+    }
+
+    // the namespace describes its own layout, as the bucket root does
+    let marker = crate::layout::Layout::current();
+    storage.put_file_as(&mut std::io::Cursor::new(marker.encode()),
+                        &format!("{}{}", prefix, crate::layout::MARKER), "application/json", &[]).await?;
+
+    log::warn!("Migrated {} keys to {}: {} copied ({} bytes), {} already present, {} originals deleted",
+               progress.done, prefix, progress.copied, progress.bytes, progress.skipped, progress.deleted);
+    Ok(progress)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn object(key: &str, size: u64) -> ObjectInfo {
+        ObjectInfo { key: key.into(), size, last_modified: None }
+    }
+
+    #[test]
+    fn prefix_normalisation() {
+        assert_eq!(normalise_prefix("org/repo/").unwrap(), "org/repo/");
+        assert_eq!(normalise_prefix("/org/repo").unwrap(), "org/repo/");
+        assert_eq!(normalise_prefix("org").unwrap(), "org/");
+    }
+
+    #[test]
+    fn prefix_rejects_bad_or_overlapping() {
+        for p in ["", "/", "org//repo", "org/../repo", "org\\repo", "cache/x", "objects/", "stats"] {
+            assert!(matches!(normalise_prefix(p).unwrap_err().downcast_ref::<Error>(),
+                             Some(Error::InvalidPrefix(_))), "{:?}", p);
+        }
+        // only whole roots overlap
+        assert!(normalise_prefix("cached/").is_ok());
+    }
+
+    #[test]
+    fn resume_skips_present_keys() {
+        let source = object("objects/aa/bin", 10);
+        assert_eq!(step_for(&source, None), Step::Copy);
+        assert_eq!(step_for(&source, Some(10)), Step::Skip);
+        assert_eq!(step_for(&source, Some(3)), Step::Copy);
+    }
+
+    #[test]
+    fn progress_totals() {
+        let mut p = Progress { total: 3, ..Default::default() };
+        p.add(&Outcome { step: Step::Copy, size: 10, deleted: true });
+        p.add(&Outcome { step: Step::Skip, size: 5, deleted: true });
+        p.add(&Outcome { step: Step::Copy, size: 7, deleted: false });
+        assert_eq!(p, Progress { total: 3, done: 3, copied: 2, skipped: 1, deleted: 2, bytes: 17 });
+    }
+}
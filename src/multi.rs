@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::{actions::{self, DownloadOptions, DownloadReport}, cache::Cache, commit::{self, STAGING_PREFIX},
+            display::local_display, Error, Result, Storage};
+
+/// How several caches are restored by one download
+#[derive(Debug, Clone, Default)]
+pub struct MultiOptions {
+    /// Restore each cache below OUTPATH/<name>, rather than all into OUTPATH
+    pub outpath_per_name: bool,
+    /// Restore into a staging directory, moving into place only once every
+    /// cache has succeeded
+    pub atomic: bool,
+}
+
+/// Paths restored by more than one cache into the same directory,
+/// including a file of one cache where another needs a directory
+fn conflicts(entries: &[(&str, &Cache)]) -> Vec<String> {
+    let mut owner = HashMap::<Cow<str>, &str>::new();
+    let mut found = Vec::new();
+    for (name, c) in entries {
+        let rules = c.path_rules();
+        for f in &c.files {
+            let key = rules.key(f.path_str());
+            match owner.get(&key) {
+                Some(first) if first != name => found.push(format!("{} (in '{}' and '{}')", f.path_str(), first, name)),
+                Some(_) => {},
+                None => { owner.insert(key, *name); },
+            }
+        }
+    }
+    for (path, name) in &owner {
+        let mut parent = path.as_ref();
+        while let Some((p, _)) = parent.rsplit_once('/') {
+            if let Some(other) = owner.get(p).filter(|other| *other != name) {
+                found.push(format!("{} (a file in '{}', a directory in '{}')", p, other, name));
+            }
+            parent = p;
+        }
+    }
+    found.sort();
+    found.dedup();
+    found
+}
+
+/// Write the checksums of caches restored to the same place together
+fn write_sums(reports: &[DownloadReport], destination: impl Fn(&str) -> PathBuf) -> Result<()> {
+    let mut sums = BTreeMap::<PathBuf, String>::new();
+    for r in reports {
+        if let Some(s) = &r.sums {
+            sums.entry(destination(&r.cache)).or_default().push_str(s);
+        }
+    }
+    for (dir, s) in sums {
+        crate::checksums::write(&dir, &s)?;
+    }
+    Ok(())
+}
+
+/// Restore each of names, in order, into outpath
+pub async fn download(storage: Storage, names: &[String], outpath: &Path, options: &DownloadOptions,
+                      multi: &MultiOptions) -> Result<Vec<DownloadReport>> {
+    let mut entries: Vec<(String, Cache)> = Vec::new();
+    let mut fallback_for = HashMap::new();
+    for name in names {
+        if entries.iter().any(|(n, _)| n == name) || fallback_for.values().any(|n| n == name) {
+            continue;
+        }
+        let (found, c) = actions::resolve_cache(&storage, name, &options.fallbacks).await
+            .with_context(|| format!("Failed to read '{}'", name))?;
+        if found != *name {
+            fallback_for.insert(found.clone(), name.clone());
+        }
+        if !entries.iter().any(|(n, _)| *n == found) {
+            entries.push((found, c));
+        }
+    }
+    if !multi.outpath_per_name {
+        let found = conflicts(&entries.iter().map(|(n, c)| (n.as_str(), c)).collect::<Vec<_>>());
+        if !found.is_empty() {
+            return Err(Error::CacheConflict(found.join(", ")).into());
+        }
+    }
+    // per name directories are named as asked for, even when a fallback fills them
+    let destination = |name: &str| if multi.outpath_per_name {
+        outpath.join(fallback_for.get(name).map_or(name, String::as_str))
+    } else {
+        outpath.to_owned()
+    };
+
+    // a dry run writes nothing, so there's nothing to stage
+    if !multi.atomic || options.dry_run {
+        let mut reports = Vec::new();
+        for (name, c) in entries {
+            let mut report = actions::download_entry(storage.clone(), &name, c, destination(&name), options).await
+                .with_context(|| format!("Failed to restore '{}'", name))?;
+            report.fallback_for = fallback_for.get(&name).cloned();
+            reports.push(report);
+        }
+        write_sums(&reports, destination)?;
+        return Ok(reports);
+    }
+
+    if let Some((interrupted, _)) = commit::leftovers(outpath)?.into_iter().find(|(_, journaled)| *journaled) {
+        return Err(Error::InterruptedCommit(local_display(&interrupted)).into());
+    }
+    let staging = outpath.join(format!("{}{}", STAGING_PREFIX, std::process::id()));
+    std::fs::create_dir_all(&staging).with_context(|| format!("Failed to create {}", local_display(&staging)))?;
+    let mut reports = Vec::new();
+    let mut staged = Vec::new();
+    for (i, (name, c)) in entries.into_iter().enumerate() {
+        match actions::download_entry(storage.clone(), &name, c, staging.join(i.to_string()), options).await {
+            Ok(mut report) => {
+                report.fallback_for = fallback_for.get(&name).cloned();
+                reports.push(report);
+            },
+            Err(e) => {
+                if let Err(e) = std::fs::remove_dir_all(&staging) {
+                    log::warn!("Unable to remove {}: {}", local_display(&staging), e);
+                }
+                return Err(e.context(format!("Failed to restore '{}', nothing moved into place", name)));
+            },
+        }
+        let to = destination(&name);
+        staged.push((PathBuf::from(i.to_string()), to.strip_prefix(outpath).unwrap_or(&to).to_owned()));
+    }
+
+    let moves = commit::plan(&staging, &staged)?;
+    commit::commit(outpath, &staging, moves)
+        .with_context(|| format!("Failed to move restored files into {}, finish with --recover", local_display(outpath)))?;
+    write_sums(&reports, destination)?;
+    log::warn!("Moved {} caches into place", reports.len());
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::cache::File;
+
+    fn cache(paths: &[&str]) -> Cache {
+        let files = paths.iter()
+            .map(|p| File::new_async(async_std::path::Path::new(p), None, 1, None, None))
+            .collect();
+        Cache { files, ..Default::default() }
+    }
+
+    #[test]
+    fn overlapping_paths_conflict() {
+        let (a, b) = (cache(&["bin/tool", "lib/x.so"]), cache(&["lib/x.so", "data"]));
+        assert_eq!(conflicts(&[("a", &a), ("b", &b)]), vec!["lib/x.so (in 'a' and 'b')"]);
+        assert!(conflicts(&[("a", &a), ("c", &cache(&["lib/y.so"]))]).is_empty());
+    }
+
+    #[test]
+    fn file_where_directory_needed_conflicts() {
+        let (a, b) = (cache(&["out"]), cache(&["out/report.html"]));
+        assert_eq!(conflicts(&[("a", &a), ("b", &b)]), vec!["out (a file in 'a', a directory in 'b')"]);
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Hex digits of a file's sha256 that {hash:PATH} expands to
+const HASH_LEN: usize = 16;
+
+fn invalid(template: &str, reason: impl Into<String>) -> Error {
+    Error::InvalidTemplate { template: template.to_owned(), reason: reason.into() }
+}
+
+/// Expand a cache name template as it would be now: {env:NAME} to the
+/// variable's value, and {hash:PATH} to the start of the sha256 of the
+/// file at PATH.  Anything else is kept, including glob characters such
+/// as * standing for a hash that can't be reproduced.
+pub fn resolve(template: &str) -> Result<String> {
+    let mut resolved = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        resolved.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| invalid(template, "unclosed '{'"))? + start;
+        let placeholder = &rest[start + 1..end];
+        match placeholder.split_once(':') {
+            Some(("env", name)) => resolved.push_str(&std::env::var(name)
+                .map_err(|_| invalid(template, format!("environment variable {} is not set", name)))?),
+            Some(("hash", path)) => {
+                let content = std::fs::read(path).map_err(|e| invalid(template, format!("unable to hash {}: {}", path, e)))?;
+                let digest = faster_hex::hex_string(&Sha256::digest(&content));
+                resolved.push_str(&digest[..HASH_LEN]);
+            },
+            _ => return Err(invalid(template, format!("unknown placeholder {{{}}}", placeholder))),
+        }
+        rest = &rest[end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+/// Of names, those matching pattern as a glob, in order
+pub fn matching(pattern: &str, names: &[String]) -> Result<Vec<String>> {
+    let glob = glob::Pattern::new(pattern).map_err(|e| invalid(pattern, e.to_string()))?;
+    Ok(names.iter().filter(|name| glob.matches(name)).cloned().collect())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn literals_and_globs_are_kept() {
+        assert_eq!(resolve("deps-main").unwrap(), "deps-main");
+        assert_eq!(resolve("deps-*-linux").unwrap(), "deps-*-linux");
+        assert_eq!(resolve("").unwrap(), "");
+    }
+
+    #[test]
+    fn placeholders_resolve_now() {
+        std::env::set_var("S3_CACHE_TEST_BRANCH", "pr-1234");
+        assert_eq!(resolve("deps-{env:S3_CACHE_TEST_BRANCH}-*").unwrap(), "deps-pr-1234-*");
+        assert_eq!(resolve("{env:S3_CACHE_TEST_BRANCH}{env:S3_CACHE_TEST_BRANCH}").unwrap(), "pr-1234pr-1234");
+
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("Cargo.lock");
+        std::fs::write(&lock, "").unwrap();
+        let template = format!("deps-{{env:S3_CACHE_TEST_BRANCH}}-{{hash:{}}}", lock.display());
+        // sha256 of nothing
+        assert_eq!(resolve(&template).unwrap(), "deps-pr-1234-e3b0c44298fc1c14");
+    }
+
+    #[test]
+    fn bad_templates_fail() {
+        for template in ["deps-{env:S3_CACHE_TEST_UNSET}", "deps-{branch}", "deps-{env:X", "deps-{hash:/no/such/file}"] {
+            assert!(matches!(resolve(template), Err(Error::InvalidTemplate { .. })), "{}", template);
+        }
+    }
+
+    #[test]
+    fn names_match_as_globs() {
+        let names: Vec<String> = ["deps-main", "deps-pr-1234-abc", "deps-pr-1234-def", "deps-pr-12345-abc", "tools-pr-1234"]
+            .into_iter().map(String::from).collect();
+        assert_eq!(matching("deps-pr-1234-*", &names).unwrap(), ["deps-pr-1234-abc", "deps-pr-1234-def"]);
+        assert_eq!(matching("deps-main", &names).unwrap(), ["deps-main"]);
+        assert_eq!(matching("*-pr-1234*", &names).unwrap(),
+                   ["deps-pr-1234-abc", "deps-pr-1234-def", "deps-pr-12345-abc", "tools-pr-1234"]);
+        assert!(matching("nothing-*", &names).unwrap().is_empty());
+        assert!(matching("deps-[", &names).is_err());
+    }
+}
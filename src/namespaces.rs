@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{cache::{self, CacheKey}, s3::ObjectInfo, Result, Storage};
+
+/// Top-level prefixes of the flat layout, rather than namespaces
+const RESERVED: [&str; 3] = ["cache", "objects", "stats"];
+
+/// Usage of one namespace: a top-level prefix holding its own cache/ and
+/// objects/, eg as created by migrate
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Namespace {
+    pub name: String,
+    pub caches: usize,
+    pub keys: usize,
+    pub bytes: u64,
+}
+
+/// Top-level directories of a bucket that are namespaces
+fn namespace_names(dirs: Vec<String>) -> Vec<String> {
+    let mut names: Vec<String> = dirs.into_iter()
+        .map(|d| d.trim_end_matches('/').to_owned())
+        .filter(|d| !d.is_empty() && !RESERVED.contains(&d.as_str()))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Totals for name from a listing of everything below it
+fn summarise(name: &str, listed: &[ObjectInfo]) -> Namespace {
+    let prefix = format!("{}/", name);
+    let caches = listed.iter()
+        .filter_map(|o| cache::parse_key(o.key.strip_prefix(&prefix)?))
+        .filter(|(_, key)| *key == CacheKey::Meta("entry"))
+        .count();
+    Namespace {
+        name: name.to_owned(),
+        caches,
+        keys: listed.len(),
+        bytes: listed.iter().map(|o| o.size).sum(),
+    }
+}
+
+/// Summarise every namespace in the bucket, listing up to max_in_flight at once
+pub async fn namespaces(storage: &Storage, max_in_flight: u32) -> Result<Vec<Namespace>> {
+    let names = namespace_names(storage.list_dirs("").await?);
+
+    let mut set = tokio::task::JoinSet::<Result<Namespace>>::new();
+    let mut found = Vec::new();
+    for name in names {
+        while set.len() >= max_in_flight as usize {
+            if let Some(work) = set.join_next().await {
+                found.push(work.with_context(|| "Failure waiting on namespace listings")??);
+            }
+        }
+        let storage = storage.clone();
+        set.spawn(async move {
+            let listed = storage.list_objects(&format!("{}/", name)).await?;
+            Ok(summarise(&name, &listed))
+        });
+    }
+    while let Some(work) = set.join_next().await {
+        found.push(work.with_context(|| "Failure waiting on namespace listings")??);
+    }
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
+
+pub fn print_table(namespaces: &[Namespace]) {
+    let len = namespaces.iter().map(|n| n.name.len()).max().unwrap_or(0).max(30);
+    println!("{:<len$} {:>8} {:>10} {:>16}", "namespace", "caches", "keys", "bytes");
+    for n in namespaces {
+        println!("{:<len$} {:>8} {:>10} {:>16}", n.name, n.caches, n.keys, n.bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn object(key: &str, size: u64) -> ObjectInfo {
+        ObjectInfo { key: key.into(), size, last_modified: None }
+    }
+
+    /// A bucket with the flat layout alongside three namespaces
+    fn bucket() -> Vec<ObjectInfo> {
+        vec![
+            object("cache/flat/entry", 1),
+            object("objects/aa/bin", 100),
+            object("stats/uploads-2025-01.jsonl", 5),
+            object("alpha/cache/one/entry", 10),
+            object("alpha/cache/one/files/a.txt", 20),
+            object("alpha/cache/two/entry", 10),
+            object("alpha/objects/bb/bin", 1000),
+            object("beta/cache/three/entry", 10),
+            object("beta/cache/three/entry.prev.1", 10),
+            object("gamma/objects/cc/bin", 500),
+        ]
+    }
+
+    #[test]
+    fn names_exclude_flat_layout() {
+        let dirs = ["stats", "gamma", "cache", "alpha/", "objects", "beta"].map(String::from).to_vec();
+        assert_eq!(namespace_names(dirs), vec!["alpha", "beta", "gamma"]);
+    }
+
+    #[test]
+    fn summaries() {
+        let listed = bucket();
+        let summary = |name: &str| {
+            let below: Vec<ObjectInfo> = listed.iter().filter(|o| o.key.starts_with(&format!("{}/", name)))
+                .cloned().collect();
+            summarise(name, &below)
+        };
+        assert_eq!(summary("alpha"), Namespace { name: "alpha".into(), caches: 2, keys: 4, bytes: 1040 });
+        assert_eq!(summary("beta"), Namespace { name: "beta".into(), caches: 1, keys: 2, bytes: 20 });
+        assert_eq!(summary("gamma"), Namespace { name: "gamma".into(), caches: 0, keys: 1, bytes: 500 });
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use crate::Error;
+
+/// Storage prefix holding all deduplicated content
+pub const ROOT: &str = "objects/";
+
+/// Hex lengths of the components a sha256 is split into
+pub(crate) const SPLIT: [usize; 4] = [8, 8, 8, 40];
+
+/// Deduplicated content, as recorded in a cache entry: the hex sha256 split
+/// into directories, eg "aabbccdd/eeff0011/22334455/6677...".  Stored at
+/// objects/<key>/bin.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ObjectKey(String);
+
+/// Storage key for an object as recorded, valid or not, so older and
+/// hand-edited entries keep resolving to where they always did
+pub(crate) fn storage_key(object: &str) -> String {
+    format!("{}{}/bin", ROOT, object)
+}
+
+impl ObjectKey {
+    pub fn from_digest(hash: &[u8; 32]) -> ObjectKey {
+        let mut parts = Vec::with_capacity(SPLIT.len());
+        let mut start = 0;
+        for len in SPLIT {
+            parts.push(faster_hex::hex_string(&hash[start / 2..(start + len) / 2]));
+            start += len;
+        }
+        ObjectKey(parts.join("/"))
+    }
+
+    /// Parse an object string from an entry, checking it has the shape
+    /// [ObjectKey::from_digest] produces
+    pub fn parse(s: &str) -> Result<ObjectKey, Error> {
+        let invalid = || Error::InvalidObject(s.to_owned());
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.len() != SPLIT.len() {
+            return Err(invalid());
+        }
+        for (part, len) in parts.iter().zip(SPLIT) {
+            if part.len() != len || !part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+                return Err(invalid());
+            }
+        }
+        Ok(ObjectKey(s.to_owned()))
+    }
+
+    /// Parse a full storage key, eg from a listing of [ROOT], compressed or not
+    pub fn from_storage_key(key: &str) -> Result<ObjectKey, Error> {
+        key.strip_prefix(ROOT)
+            .map(|k| k.strip_suffix(crate::compression::ZSTD_SUFFIX).unwrap_or(k))
+            .and_then(|k| k.strip_suffix("/bin"))
+            .ok_or_else(|| Error::InvalidObject(key.to_owned()))
+            .and_then(Self::parse)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn storage_key(&self) -> String {
+        storage_key(&self.0)
+    }
+}
+
+impl std::fmt::Display for ObjectKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const HASH: [u8; 32] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32, 0x10,
+    ];
+
+    #[test]
+    fn layout_compat() {
+        let key = ObjectKey::from_digest(&HASH);
+        assert_eq!(key.as_str(), "00112233/44556677/8899aabb/ccddeeff0123456789abcdeffedcba9876543210");
+        assert_eq!(key.storage_key(), format!("objects/{}/bin", key));
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = ObjectKey::from_digest(&HASH);
+        assert_eq!(ObjectKey::parse(key.as_str()).unwrap(), key);
+        assert_eq!(ObjectKey::from_storage_key(&key.storage_key()).unwrap(), key);
+        assert_eq!(ObjectKey::from_storage_key(&format!("{}.zst", key.storage_key())).unwrap(), key);
+    }
+
+    #[test]
+    fn malformed() {
+        let good = ObjectKey::from_digest(&HASH).to_string();
+        let cases = [
+            String::new(),
+            "aa/bb/cc/dddd".into(),
+            good.to_uppercase(),
+            good.replace('/', ""),
+            format!("{}/", good),
+            format!("/{}", good),
+            format!("{}/bin", good),
+            good.replacen('0', "g", 1),
+            good.replacen("00112233", "0011223", 1),
+            good.replacen("00112233/", "../", 1),
+            good.replace('/', "\\"),
+            format!(" {}", good),
+        ];
+        for s in cases {
+            assert!(matches!(ObjectKey::parse(&s), Err(Error::InvalidObject(_))), "accepted {:?}", s);
+        }
+        for key in ["objects/aa/bin", &good, &format!("cache/{}/bin", good), &format!("objects/{}", good)] {
+            assert!(ObjectKey::from_storage_key(key).is_err(), "accepted {:?}", key);
+        }
+    }
+
+    #[test]
+    fn storage_key_is_lenient() {
+        assert_eq!(storage_key("dir2/file2"), "objects/dir2/file2/bin");
+    }
+}
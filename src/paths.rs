@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use path_slash::PathExt as _;
+
+use crate::unicode::Normalization;
+
+/// How entry paths compare, with each other and with local files.  Status,
+/// downloads and comparisons of entries all go through these, so they agree
+/// on which paths are the same.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PathRules {
+    /// Unicode form, as recorded in the entry
+    pub normalization: Normalization,
+    /// Ignore case, as a case-insensitive filesystem would
+    pub case_fold: bool,
+}
+
+impl From<Normalization> for PathRules {
+    fn from(normalization: Normalization) -> Self {
+        PathRules { normalization, case_fold: false }
+    }
+}
+
+impl PathRules {
+    /// The form of a slash separated path that's compared
+    pub fn key<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.case_fold {
+            Cow::Owned(self.normalization.apply(&path.to_lowercase()).into_owned())
+        } else {
+            self.normalization.apply(path)
+        }
+    }
+
+    /// The form of a local path that's compared
+    pub fn local_key(&self, path: &std::path::Path) -> String {
+        let path = path.to_slash().expect("path->slash");
+        self.key(&path).into_owned()
+    }
+
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        self.key(a).cmp(&self.key(b))
+    }
+
+    pub fn same(&self, a: &str, b: &str) -> bool {
+        self.compare(a, b) == Ordering::Equal
+    }
+}
+
+/// Globs matched against entry paths under some [PathRules]
+#[derive(Debug, Default, Clone)]
+pub struct GlobSet {
+    patterns: Vec<glob::Pattern>,
+    rules: PathRules,
+}
+
+impl GlobSet {
+    pub fn new<'a>(patterns: impl IntoIterator<Item = &'a str>, rules: PathRules) -> Result<GlobSet, glob::PatternError> {
+        let patterns = patterns.into_iter()
+            .map(|p| glob::Pattern::new(&rules.key(p)))
+            .collect::<Result<_, _>>()?;
+        Ok(GlobSet { patterns, rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Does any pattern match path
+    pub fn matches(&self, path: &str) -> bool {
+        let key = self.rules.key(path);
+        self.patterns.iter().any(|p| p.matches(&key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::cache::File;
+
+    const COMPOSED: &str = "dir/caf\u{e9}.txt";
+    const DECOMPOSED: &str = "dir/cafe\u{301}.txt";
+
+    fn file(path: &str, size: u64) -> File {
+        File::new_async(async_std::path::Path::new(path), None, size, None, None)
+    }
+
+    fn rules(normalization: Normalization, case_fold: bool) -> PathRules {
+        PathRules { normalization, case_fold }
+    }
+
+    #[test]
+    fn path_equality_matrix() {
+        // (a, b, normalization, case_fold, same)
+        let cases = [
+            (COMPOSED, DECOMPOSED, Normalization::Off, false, false),
+            (COMPOSED, DECOMPOSED, Normalization::Nfc, false, true),
+            (COMPOSED, DECOMPOSED, Normalization::Nfd, false, true),
+            ("Dir/A.txt", "dir/a.txt", Normalization::Off, false, false),
+            ("Dir/A.txt", "dir/a.txt", Normalization::Off, true, true),
+            ("DIR/CAF\u{c9}.TXT", DECOMPOSED, Normalization::Nfc, true, true),
+            ("DIR/CAF\u{c9}.TXT", DECOMPOSED, Normalization::Off, true, false),
+        ];
+        for (a, b, normalization, case_fold, same) in cases {
+            let rules = rules(normalization, case_fold);
+            assert_eq!(rules.same(a, b), same, "{:?} vs {:?} under {:?}", a, b, rules);
+            assert_eq!(file(a, 1).cmp_path(&file(b, 1), &rules) == Ordering::Equal, same);
+        }
+    }
+
+    #[test]
+    fn local_paths_use_slash_form() {
+        let rules = rules(Normalization::Nfc, false);
+        assert_eq!(rules.local_key(std::path::Path::new(DECOMPOSED)), COMPOSED);
+        assert!(rules.same(&rules.local_key(std::path::Path::new("dir/a")), "dir/a"));
+    }
+
+    #[test]
+    fn globs_follow_the_rules() {
+        let exact = GlobSet::new(["dir/*.txt"], PathRules::default()).unwrap();
+        assert!(file(COMPOSED, 1).matches_glob(&exact));
+        assert!(!file("DIR/A.TXT", 1).matches_glob(&exact));
+
+        let folded = GlobSet::new(["dir/*.txt"], rules(Normalization::Off, true)).unwrap();
+        assert!(file("DIR/A.TXT", 1).matches_glob(&folded));
+
+        let composed = GlobSet::new([COMPOSED], rules(Normalization::Nfc, false)).unwrap();
+        assert!(file(DECOMPOSED, 1).matches_glob(&composed));
+        let raw = GlobSet::new([COMPOSED], PathRules::default()).unwrap();
+        assert!(!file(DECOMPOSED, 1).matches_glob(&raw));
+
+        assert!(GlobSet::default().is_empty());
+        assert!(!file("a", 1).matches_glob(&GlobSet::default()));
+        assert!(GlobSet::new(["[unclosed"], PathRules::default()).is_err());
+    }
+
+    #[test]
+    fn content_compared_by_hash_and_size() {
+        let hash = [0x5a; 32];
+        let hex = faster_hex::hex_string(&hash);
+        let object = crate::object::ObjectKey::from_digest(&hash);
+        let deduped = File::new_async(async_std::path::Path::new("a"), Some(object.clone()), 10, None, None);
+        assert_eq!(deduped.known_sha256(), Some(hex.clone()));
+        let mut local = file("b", 10);
+        assert_eq!(local.known_sha256(), None);
+        assert!(!deduped.same_content(&local), "unknown content can't be the same");
+        local.sha256 = Some(hex.to_uppercase());
+        assert!(deduped.same_content(&local));
+        assert!(local.same_content(&deduped));
+
+        let mut compressed = File::new_async(async_std::path::Path::new("c"), Some(object), 10, None, None);
+        compressed.compression = Some(crate::compression::CompressionCodec::Zstd { level: 3 });
+        assert!(deduped.same_content(&compressed));
+
+        let mut resized = local.clone();
+        resized.size = 11;
+        assert!(!local.same_content(&resized));
+
+        let link = |target: &str| File::new_async(async_std::path::Path::new("l"), None, 0, None, Some(target.into()));
+        assert!(link("x").same_content(&link("x")));
+        assert!(!link("x").same_content(&link("y")));
+        assert!(!link("x").same_content(&local));
+    }
+}
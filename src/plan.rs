@@ -0,0 +1,287 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::{self, CacheV2, CacheVersions, HashAlgorithm, SchemaVersion}, skip::{SkipReason, Skips}, unicode::Normalization, Error, Result};
+
+/// Where a planned file's content goes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Destination {
+    /// Deduplicated under objects/
+    Object,
+    /// Stored with the cache
+    Cache,
+    /// Symlink, recorded in the entry only
+    Link,
+}
+
+/// One file as upload would handle it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannedFile {
+    pub destination: Destination,
+    /// Storage key the content is put to, none for links and for objects
+    /// hashed as they're uploaded
+    pub key: Option<String>,
+    pub size: u64,
+    /// Whether the object already exists, when that was checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exists: Option<bool>,
+    /// Local modification time when planned, so changes can be spotted
+    pub local_mtime: Option<DateTime<Utc>>,
+    /// Content-Type the content is stored with
+    #[serde(default = "octet_stream")]
+    pub content_type: String,
+    /// Symlink target on disk, when the entry records it rewritten
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_target: Option<String>,
+    /// Path on disk, when the entry records it normalised
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_path: Option<String>,
+    /// As it will be recorded in the cache entry
+    pub(crate) entry: cache::File,
+}
+
+/// Everything upload would do for one cache, computed without uploading
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadPlan {
+    pub cache: String,
+    pub files: Vec<PlannedFile>,
+    /// Directories walked that nothing else recreates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) dirs: Vec<cache::Dir>,
+    /// POSIX ACLs of directories walked, by entry path
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub(crate) dir_acls: std::collections::BTreeMap<String, crate::acls::Acls>,
+    /// Unicode form entry paths were recorded in
+    #[serde(default, skip_serializing_if = "Normalization::is_off")]
+    pub normalization: Normalization,
+    /// Paths left out, and why
+    #[serde(default, skip_serializing_if = "Skips::is_empty")]
+    pub skipped: Skips<SkipReason>,
+    /// Recorded in the entry, as given to upload --label
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// What objects are keyed by, as given to upload --hash-algorithm
+    #[serde(default, skip_serializing_if = "HashAlgorithm::is_sha256")]
+    pub hash_algorithm: HashAlgorithm,
+}
+
+/// Files serialised as a list as they're iterated
+struct EntryFiles<I>(I);
+
+impl<'a, I: Iterator<Item = &'a cache::File> + Clone> Serialize for EntryFiles<I> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.clone())
+    }
+}
+
+fn octet_stream() -> String {
+    crate::content_type::OCTET_STREAM.into()
+}
+
+fn stale(file: &PlannedFile, reason: impl Into<String>) -> anyhow::Error {
+    Error::PlanStale { path: file.entry.path_str().to_owned(), reason: reason.into() }.into()
+}
+
+impl PlannedFile {
+    /// Where the file is on disk
+    pub fn local_path(&self) -> std::path::PathBuf {
+        self.local_path.as_deref().map_or_else(|| self.entry.path(), cache::File::path_of)
+    }
+
+    /// An object whose key is only known once upload hashes it
+    pub(crate) fn is_unhashed(&self) -> bool {
+        self.destination == Destination::Object && self.key.is_none()
+    }
+
+    /// Check the local file still matches what was planned
+    pub(crate) fn check(&self) -> Result<()> {
+        let path = self.local_path();
+        let meta = std::fs::symlink_metadata(&path).map_err(|e| stale(self, e.to_string()))?;
+
+        if let Some(target) = self.local_target.as_deref().or(self.entry.link_target.as_deref()) {
+            let actual = std::fs::read_link(&path).map_err(|_| stale(self, "no longer a symlink"))?;
+            if actual.to_str() != Some(target) {
+                return Err(stale(self, format!("symlink now points to {}", actual.display())));
+            }
+            return Ok(());
+        }
+
+        if !meta.is_file() {
+            return Err(stale(self, "no longer a file"));
+        }
+        if meta.len() != self.size {
+            return Err(stale(self, format!("size {} was {}", meta.len(), self.size)));
+        }
+        let mtime = meta.modified().ok().map(DateTime::<Utc>::from);
+        if mtime != self.local_mtime {
+            return Err(stale(self, "modification time changed"));
+        }
+        Ok(())
+    }
+}
+
+impl UploadPlan {
+    pub fn read(path: &Path) -> Result<UploadPlan> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read plan {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Invalid plan {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Unable to write plan {}", path.display()))
+    }
+
+    /// Entries as recorded, with those upload changed (objects hashed on
+    /// upload) swapped in by index
+    pub(crate) fn entry_files<'a>(&'a self, changed: &'a BTreeMap<usize, cache::File>) -> impl Iterator<Item = &'a cache::File> + Clone {
+        self.files.iter().enumerate().map(|(i, f)| changed.get(&i).unwrap_or(&f.entry))
+    }
+
+    /// Write the entry pushed once the plan has been carried out, with
+    /// changed swapped in, straight from the plan rather than copying every
+    /// file into it
+    pub(crate) fn write_entry(&self, changed: &BTreeMap<usize, cache::File>, label: Option<String>,
+                              writer: impl std::io::Write) -> Result<()> {
+        let files = EntryFiles(self.entry_files(changed));
+        let entry = CacheVersions::V2(CacheV2 {
+            created: Some(Utc::now()),
+            writer_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            label: label.or_else(|| self.label.clone()),
+            size: files.0.clone().map(|f| f.size).sum(),
+            files,
+            dirs: self.dirs.clone(),
+            dir_acls: self.dir_acls.clone(),
+            normalization: self.normalization,
+            origin_name: Some(self.cache.clone()),
+            hash_algorithm: self.hash_algorithm,
+            schema_version: SchemaVersion::V2.number(),
+        });
+        Ok(serde_json::to_writer(writer, &entry)?)
+    }
+
+    /// Bytes to be uploaded as objects and with the cache, skipping objects
+    /// known to exist
+    pub fn upload_bytes(&self) -> (u64, u64) {
+        let sum = |d| self.files.iter()
+            .filter(|f| f.destination == d && f.exists != Some(true))
+            .map(|f| f.size).sum();
+        (sum(Destination::Object), sum(Destination::Cache))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn planned(path: &Path) -> PlannedFile {
+        let meta = std::fs::symlink_metadata(path).unwrap();
+        let entry = cache::File::new_async(async_std::path::Path::new(path.as_os_str()), None, meta.len(), None, None);
+        PlannedFile {
+            destination: Destination::Cache,
+            key: Some(entry.storage_path("c").to_string_lossy().into_owned()),
+            size: meta.len(),
+            exists: None,
+            local_mtime: meta.modified().ok().map(DateTime::from),
+            content_type: octet_stream(),
+            local_target: None,
+            local_path: None,
+            entry,
+        }
+    }
+
+    fn is_stale(r: Result<()>) -> bool {
+        matches!(r.unwrap_err().downcast_ref::<Error>(), Some(Error::PlanStale { .. }))
+    }
+
+    #[test]
+    fn unchanged_files_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, "content").unwrap();
+        planned(&path).check().unwrap();
+    }
+
+    #[test]
+    fn changes_are_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, "content").unwrap();
+
+        let mut plan = planned(&path);
+        plan.size += 1;
+        assert!(is_stale(plan.check()));
+
+        let mut plan = planned(&path);
+        plan.local_mtime = plan.local_mtime.map(|t| t - chrono::Duration::seconds(10));
+        assert!(is_stale(plan.check()));
+
+        let plan = planned(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(is_stale(plan.check()));
+    }
+
+    #[test]
+    fn round_trip_and_totals() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, "content").unwrap();
+        let mut object = planned(&path);
+        object.destination = Destination::Object;
+        object.exists = Some(true);
+        let plan = UploadPlan { cache: "c".into(), files: vec![planned(&path), object], dirs: Vec::new(), dir_acls: Default::default(),
+                               normalization: Normalization::Off, skipped: Skips::default(), label: Some("abc123".into()),
+                               hash_algorithm: HashAlgorithm::Blake3 };
+
+        let file = dir.path().join("plan.json");
+        plan.write(&file).unwrap();
+        assert_eq!(UploadPlan::read(&file).unwrap(), plan);
+        assert_eq!(plan.upload_bytes(), (0, 7));
+        let mut entry = Vec::new();
+        plan.write_entry(&BTreeMap::new(), None, &mut entry).unwrap();
+        let (entry, _) = cache::decode("c", &entry).unwrap();
+        assert_eq!(entry.files.len(), 2);
+        assert_eq!(entry.label.as_deref(), Some("abc123"));
+        assert_eq!(entry.hash_algorithm, HashAlgorithm::Blake3);
+    }
+
+    #[test]
+    fn entries_are_written_from_large_plans() {
+        const FILES: usize = 200_000;
+        let files = (0..FILES).map(|i| {
+            let entry = cache::File::new_async(async_std::path::Path::new(&format!("d{}/f{}", i % 100, i)), None, i as u64, None, None);
+            PlannedFile {
+                destination: Destination::Object, key: None, size: entry.size, exists: None, local_mtime: None,
+                content_type: octet_stream(), local_target: None, local_path: None, entry,
+            }
+        }).collect();
+        let plan = UploadPlan { cache: "big".into(), files, dirs: Vec::new(), dir_acls: Default::default(),
+                               normalization: Normalization::Off, skipped: Skips::default(), label: None,
+                               hash_algorithm: HashAlgorithm::Sha256 };
+
+        // as upload hashing the last file would
+        let mut hashed = plan.files[FILES - 1].entry.clone();
+        hashed.object = Some("aaaaaaaa/bbbbbbbb/cccccccc/dddddddddddddddddddddddddddddddddddddddd".into());
+        let changed = BTreeMap::from([(FILES - 1, hashed.clone())]);
+
+        let mut entry = Vec::new();
+        plan.write_entry(&changed, Some("label".into()), &mut entry).unwrap();
+        let (entry, _) = cache::decode("big", &entry).unwrap();
+        assert_eq!(entry.files.len(), FILES);
+        assert_eq!(entry.files[..FILES - 1], plan.files[..FILES - 1].iter().map(|f| f.entry.clone()).collect::<Vec<_>>()[..]);
+        assert_eq!(entry.files[FILES - 1], hashed);
+        assert_eq!(entry.size(), (0..FILES as u64).sum::<u64>());
+        assert_eq!(entry.origin_name.as_deref(), Some("big"));
+        assert_eq!(entry.label.as_deref(), Some("label"));
+    }
+}
@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+/// Told what upload and download are doing as they do it.  Called from
+/// many tasks at once.  Bytes are counted as they cross the network, so
+/// compressed content counts its compressed size, and retried requests
+/// count again.
+pub trait ProgressSink: Send + Sync {
+    /// A file's transfer is starting; size is its size as restored
+    fn on_file_start(&self, _path: &str, _size: u64) {}
+
+    /// A file's transfer finished, whether or not it succeeded
+    fn on_file_done(&self, _path: &str) {}
+
+    /// n more bytes of some file crossed the network
+    fn on_bytes_transferred(&self, _n: u64) {}
+}
+
+impl std::fmt::Debug for dyn ProgressSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressSink")
+    }
+}
+
+/// Reports nothing
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopProgressSink;
+
+impl ProgressSink for NoopProgressSink {}
+
+/// The default sink for options
+pub fn noop() -> Arc<dyn ProgressSink> {
+    Arc::new(NoopProgressSink)
+}
+
+/// Logs each file at info level, with the running total of bytes
+#[derive(Debug, Default)]
+pub struct LogProgressSink {
+    transferred: AtomicU64,
+}
+
+impl ProgressSink for LogProgressSink {
+    fn on_file_start(&self, path: &str, size: u64) {
+        log::info!("Transferring {} ({} bytes)", path, size);
+    }
+
+    fn on_file_done(&self, path: &str) {
+        log::info!("Finished {}, {} bytes transferred so far", path, self.transferred.load(Ordering::Relaxed));
+    }
+
+    fn on_bytes_transferred(&self, n: u64) {
+        self.transferred.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// A bar of bytes transferred against the total size of files started,
+/// above a line for each file in flight
+#[cfg(feature = "progress")]
+pub struct IndicatifProgressSink {
+    multi: indicatif::MultiProgress,
+    total: indicatif::ProgressBar,
+    files: std::sync::Mutex<std::collections::HashMap<String, indicatif::ProgressBar>>,
+}
+
+#[cfg(feature = "progress")]
+impl IndicatifProgressSink {
+    pub fn new() -> IndicatifProgressSink {
+        let multi = indicatif::MultiProgress::new();
+        let total = multi.add(indicatif::ProgressBar::new(0));
+        total.set_style(indicatif::ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {bytes_per_sec} {eta}")
+            .expect("progress template should be valid"));
+        IndicatifProgressSink { multi, total, files: Default::default() }
+    }
+}
+
+#[cfg(feature = "progress")]
+impl Default for IndicatifProgressSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "progress")]
+impl ProgressSink for IndicatifProgressSink {
+    fn on_file_start(&self, path: &str, size: u64) {
+        self.total.inc_length(size);
+        let bar = self.multi.insert_before(&self.total, indicatif::ProgressBar::new_spinner());
+        bar.set_message(path.to_owned());
+        bar.enable_steady_tick(std::time::Duration::from_millis(100));
+        self.files.lock().expect("progress lock poisoned").insert(path.to_owned(), bar);
+    }
+
+    fn on_file_done(&self, path: &str) {
+        if let Some(bar) = self.files.lock().expect("progress lock poisoned").remove(path) {
+            bar.finish_and_clear();
+        }
+    }
+
+    fn on_bytes_transferred(&self, n: u64) {
+        self.total.inc(n);
+    }
+}
+
+/// Reports bytes read or written through it
+pub(crate) struct Counting<T> {
+    inner: T,
+    sink: Arc<dyn ProgressSink>,
+}
+
+impl<T> Counting<T> {
+    pub fn new(inner: T, sink: Arc<dyn ProgressSink>) -> Counting<T> {
+        Counting { inner, sink }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counting<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let n = buf.filled().len() - before;
+            if n > 0 {
+                this.sink.on_bytes_transferred(n as u64);
+            }
+        }
+        poll
+    }
+}
+
+impl<T: AsyncSeek + Unpin> AsyncSeek for Counting<T> {
+    fn start_seek(self: Pin<&mut Self>, position: std::io::SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.get_mut().inner).start_seek(position)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.get_mut().inner).poll_complete(cx)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counting<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                this.sink.on_bytes_transferred(n as u64);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    #[derive(Default)]
+    struct Totals(AtomicU64);
+
+    impl ProgressSink for Totals {
+        fn on_bytes_transferred(&self, n: u64) {
+            self.0.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn bytes_are_counted_both_ways() {
+        let totals = Arc::new(Totals::default());
+        let mut written = Counting::new(Vec::new(), totals.clone());
+        written.write_all(b"hello world").await.unwrap();
+        assert_eq!(totals.0.load(Ordering::Relaxed), 11);
+
+        let mut read = Counting::new(std::io::Cursor::new(written.inner), totals.clone());
+        let mut buf = String::new();
+        read.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hello world");
+        assert_eq!(totals.0.load(Ordering::Relaxed), 22);
+
+        // a retry reads again, and counts again
+        read.rewind().await.unwrap();
+        read.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(totals.0.load(Ordering::Relaxed), 33);
+    }
+}
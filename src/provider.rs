@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use crate::Error;
+
+/// S3 compatible services whose quirks are known
+#[derive(clap::ValueEnum, serde::Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// Guess from the endpoint, falling back to tolerating every known quirk
+    #[default]
+    Auto,
+    Aws,
+    Minio,
+    R2,
+    Ceph,
+    B2,
+}
+
+/// How a service answers where services differ, consulted by [crate::Storage]
+/// rather than each operation special-casing providers
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProviderProfile {
+    pub provider: Provider,
+    /// Statuses a successful delete returns; anything else is unexpected
+    pub delete_statuses: &'static [u16],
+    /// Error codes besides 429, SlowDown and TooManyRequests that mean the
+    /// service wants fewer requests
+    pub throttle_codes: &'static [&'static str],
+    /// Address the bucket in the path rather than the host name
+    pub path_style: bool,
+    /// Objects can be tagged
+    pub tagging: bool,
+}
+
+impl Default for ProviderProfile {
+    /// For services not recognised: any success is expected, and buckets
+    /// are addressed by path as every known service supports it
+    fn default() -> Self {
+        ProviderProfile {
+            provider: Provider::Auto, delete_statuses: &[200, 204], throttle_codes: &[], path_style: true, tagging: true,
+        }
+    }
+}
+
+impl Provider {
+    /// The service an endpoint is hosted by, if its host name says
+    pub fn detect(endpoint: &str) -> Provider {
+        let host = endpoint.split_once("://").map_or(endpoint, |(_, rest)| rest);
+        let host = host.split(['/', ':']).next().unwrap_or_default().to_ascii_lowercase();
+        let within = |domain: &str| host == domain || host.ends_with(&format!(".{}", domain));
+        if within("amazonaws.com") {
+            Provider::Aws
+        } else if within("r2.cloudflarestorage.com") {
+            Provider::R2
+        } else if within("backblazeb2.com") {
+            Provider::B2
+        } else {
+            Provider::Auto
+        }
+    }
+
+    /// The profile for this provider; Auto detects from endpoint
+    pub fn profile(self, endpoint: &str) -> ProviderProfile {
+        let defaults = ProviderProfile { provider: self, delete_statuses: &[204], ..Default::default() };
+        match self {
+            Provider::Auto => match Provider::detect(endpoint) {
+                Provider::Auto => ProviderProfile::default(),
+                detected => detected.profile(endpoint),
+            },
+            Provider::Aws => ProviderProfile { path_style: false, ..defaults },
+            Provider::Minio | Provider::Ceph => defaults,
+            Provider::R2 => ProviderProfile { tagging: false, ..defaults },
+            // B2 answers 503 ServiceUnavailable when it wants clients to back off
+            Provider::B2 => ProviderProfile { throttle_codes: &["ServiceUnavailable"], tagging: false, ..defaults },
+        }
+    }
+}
+
+impl ProviderProfile {
+    /// Whether status is what a successful delete returns
+    pub fn delete_ok(&self, status: u16) -> bool {
+        self.delete_statuses.contains(&status)
+    }
+
+    /// Whether e means the service wants fewer requests
+    pub fn is_throttled(&self, e: &Error) -> bool {
+        e.is_throttled() || match e {
+            Error::S3Error(s3::error::S3Error::HttpFailWithBody(_, body)) =>
+                self.throttle_codes.iter().any(|code| body.contains(code)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn http(status: u16, body: &str) -> Error {
+        Error::S3Error(s3::error::S3Error::HttpFailWithBody(status, body.into()))
+    }
+
+    #[test]
+    fn detected_from_endpoint_host() {
+        for (endpoint, provider) in [
+            ("https://s3.ap-southeast-2.amazonaws.com", Provider::Aws),
+            ("https://S3.AMAZONAWS.COM/", Provider::Aws),
+            ("https://0123abcd.r2.cloudflarestorage.com", Provider::R2),
+            ("https://s3.us-west-004.backblazeb2.com:443/path", Provider::B2),
+            ("http://localhost:9000", Provider::Auto),
+            ("http://amazonaws.com.example.com", Provider::Auto),
+            ("minio.internal:9000", Provider::Auto),
+        ] {
+            assert_eq!(Provider::detect(endpoint), provider, "{}", endpoint);
+            assert_eq!(Provider::Auto.profile(endpoint).provider, provider, "{}", endpoint);
+        }
+    }
+
+    #[test]
+    fn named_profiles_ignore_endpoint() {
+        let profile = Provider::Minio.profile("https://s3.amazonaws.com");
+        assert_eq!(profile.provider, Provider::Minio);
+        assert!(profile.path_style);
+    }
+
+    #[test]
+    fn aws() {
+        let profile = Provider::Aws.profile("");
+        assert!(profile.delete_ok(204) && !profile.delete_ok(200));
+        assert!(!profile.path_style);
+        assert!(!profile.is_throttled(&http(503, "<Code>ServiceUnavailable</Code>")));
+    }
+
+    #[test]
+    fn minio() {
+        let profile = Provider::Minio.profile("");
+        assert!(profile.delete_ok(204) && !profile.delete_ok(200));
+        assert!(profile.path_style);
+        assert!(profile.tagging);
+        // MinIO's SlowDownRead and SlowDownWrite
+        assert!(profile.is_throttled(&http(503, "<Code>SlowDownWrite</Code>")));
+    }
+
+    #[test]
+    fn r2() {
+        let profile = Provider::R2.profile("");
+        assert!(profile.delete_ok(204) && !profile.delete_ok(200));
+        assert!(profile.path_style);
+        assert!(!profile.tagging);
+        assert!(profile.is_throttled(&http(429, "")));
+    }
+
+    #[test]
+    fn ceph() {
+        let profile = Provider::Ceph.profile("");
+        assert!(profile.delete_ok(204) && !profile.delete_ok(200));
+        assert!(profile.path_style);
+        assert!(profile.is_throttled(&http(503, "<Code>SlowDown</Code>")));
+    }
+
+    #[test]
+    fn b2() {
+        let profile = Provider::B2.profile("");
+        assert!(profile.delete_ok(204));
+        assert!(profile.is_throttled(&http(503, "<Code>ServiceUnavailable</Code>")));
+        assert!(!profile.is_throttled(&http(500, "<Code>InternalError</Code>")));
+    }
+
+    #[test]
+    fn unknown_services_tolerate_every_quirk() {
+        let profile = Provider::Auto.profile("http://localhost:9000");
+        assert!(profile.delete_ok(204) && profile.delete_ok(200));
+        assert!(profile.path_style);
+    }
+}
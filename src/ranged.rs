@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{cache::HashAlgorithm, display::{key_display, local_display}, object::ObjectKey,
+            progress::{Counting, ProgressSink}, Result, Storage};
+
+/// Objects at least this large are fetched in parallel ranges unless
+/// --ranged-threshold says otherwise
+pub const DEFAULT_THRESHOLD: u64 = 256 << 20;
+
+/// Smallest range worth a connection of its own
+const MIN_PART: u64 = 16 << 20;
+
+/// Connections a restore may use at once, shared by every file.  Large
+/// objects take whatever is spare for extra ranges, so they never hold up
+/// the rest of the restore.
+#[derive(Debug, Clone)]
+pub(crate) struct Budget {
+    permits: Arc<Semaphore>,
+    /// Size from which objects are fetched in ranges
+    pub threshold: u64,
+    /// Told of every file and byte the restore fetches
+    pub progress: Arc<dyn ProgressSink>,
+}
+
+impl Budget {
+    pub fn new(max_in_flight: u32, threshold: u64) -> Budget {
+        Budget { permits: Arc::new(Semaphore::new(max_in_flight as usize)), threshold, progress: crate::progress::noop() }
+    }
+
+    pub fn with_progress(mut self, progress: Arc<dyn ProgressSink>) -> Budget {
+        self.progress = progress;
+        self
+    }
+
+    /// Wait for a connection
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        Ok(self.permits.clone().acquire_owned().await?)
+    }
+
+    /// Up to max connections, if they're free now
+    fn spare(&self, max: usize) -> Vec<OwnedSemaphorePermit> {
+        std::iter::from_fn(|| self.permits.clone().try_acquire_owned().ok()).take(max).collect()
+    }
+}
+
+/// Split size bytes into at most parts inclusive ranges of near equal size,
+/// each at least MIN_PART unless there's only one
+fn ranges(size: u64, parts: usize) -> Vec<(u64, u64)> {
+    let parts = (parts as u64).min(size / MIN_PART).max(1);
+    let part = size.div_ceil(parts);
+    (0..parts)
+        .map(|i| (i * part, ((i + 1) * part).min(size)))
+        .filter(|(start, end)| start < end)
+        .map(|(start, end)| (start, end - 1))
+        .collect()
+}
+
+/// Fetch one range into place in the file at path, holding a connection
+async fn fetch_part(storage: Storage, key: String, path: PathBuf, (start, end): (u64, u64),
+                    _permit: OwnedSemaphorePermit, progress: Arc<dyn ProgressSink>) -> Result<bool> {
+    let mut f = tokio::fs::OpenOptions::new().write(true).open(&path).await?;
+    f.seek(std::io::SeekFrom::Start(start)).await?;
+    let ranged = storage.get_file_range(&mut Counting::new(&mut f, progress), &key, start, end).await
+        .with_context(|| format!("Failed to download bytes {}-{} of {}", start, end, key_display(&key)))?;
+    f.flush().await?;
+    Ok(ranged)
+}
+
+/// Where a file is assembled before it's checked and renamed into place
+fn part_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.s3-cache-part.{}", name, std::process::id()))
+}
+
+async fn assemble(storage: &Storage, key: &str, size: u64, part: &Path, budget: &Budget) -> Result<()> {
+    let first = budget.acquire().await?;
+    tokio::fs::File::create(part).await?.set_len(size).await?;
+    let spare = budget.spare(size.div_ceil(MIN_PART) as usize);
+    let mut ranges = ranges(size, spare.len() + 1).into_iter();
+    let Some(probe) = ranges.next() else {
+        return Ok(());
+    };
+    // one range first, as servers may ignore them and send the whole object
+    if !fetch_part(storage.clone(), key.to_owned(), part.to_owned(), probe, first, budget.progress.clone()).await? {
+        log::info!("Ranges not supported fetching {}, downloaded whole", key_display(key));
+        return Ok(());
+    }
+    log::debug!("Fetching {} in {} ranges", key_display(key), ranges.len() + 1);
+    let mut set = tokio::task::JoinSet::new();
+    for (range, permit) in ranges.zip(spare) {
+        set.spawn(fetch_part(storage.clone(), key.to_owned(), part.to_owned(), range, permit, budget.progress.clone()));
+    }
+    while let Some(work) = set.join_next().await {
+        work.with_context(|| "Failure waiting on range downloads")??;
+    }
+    Ok(())
+}
+
+/// Download the size byte object at key into path, fetching ranges over
+/// spare connections, then check it's the expected content before it's
+/// renamed into place
+pub(crate) async fn download(storage: &Storage, key: &str, object: &ObjectKey, size: u64, path: &Path,
+                             algorithm: HashAlgorithm, budget: &Budget) -> Result<()> {
+    let part = part_path(path);
+    let result = async {
+        assemble(storage, key, size, &part, budget).await?;
+        crate::actions::verify_download(async_std::path::Path::new(part.as_os_str()), size, object, algorithm).await
+    }.await;
+    if let Err(e) = result {
+        if let Err(e) = std::fs::remove_file(&part) {
+            log::warn!("Unable to remove {}: {}", local_display(&part), e);
+        }
+        return Err(e);
+    }
+    std::fs::rename(&part, path).with_context(|| format!("Failed to move download into place at {}", local_display(path)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn ranges_cover_the_object() {
+        for (size, parts) in [(0, 4), (1, 4), (MIN_PART * 4, 4), (MIN_PART * 4 + 1, 4), (MIN_PART * 10 - 3, 3), (MIN_PART * 2, 8)] {
+            let r = ranges(size, parts);
+            assert!(!r.is_empty() || size == 0, "{} bytes gave no ranges", size);
+            assert!(r.len() <= parts);
+            let mut next = 0;
+            for (start, end) in &r {
+                assert_eq!(*start, next, "gap or overlap in {:?}", r);
+                next = end + 1;
+            }
+            assert_eq!(next, size, "{:?} doesn't cover {} bytes", r, size);
+            if r.len() > 1 {
+                assert!(r.iter().all(|(start, end)| end - start + 1 >= MIN_PART / 2), "{:?} too small", r);
+            }
+        }
+        assert_eq!(ranges(MIN_PART * 2, 8).len(), 2);
+        assert_eq!(ranges(MIN_PART - 1, 8), vec![(0, MIN_PART - 2)]);
+    }
+
+    #[tokio::test]
+    async fn spare_connections_are_only_those_free() {
+        let budget = Budget::new(4, DEFAULT_THRESHOLD);
+        let held = budget.acquire().await.unwrap();
+        let spare = budget.spare(10);
+        assert_eq!(spare.len(), 3);
+        assert!(budget.spare(1).is_empty());
+        drop(spare);
+        assert_eq!(budget.spare(2).len(), 2);
+        drop(held);
+    }
+
+    #[test]
+    fn parts_are_hidden_beside_the_file() {
+        let part = part_path(Path::new("out/big.bin"));
+        assert_eq!(part.parent(), Some(Path::new("out")));
+        assert!(part.file_name().unwrap().to_string_lossy().starts_with(".big.bin.s3-cache-part."));
+    }
+}
@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Bump when the line format changes; other versions are discarded
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum Line {
+    Header { version: u32, cache: String },
+    Confirmed { key: String },
+}
+
+/// Objects confirmed in the bucket during an upload (or fsck), so a rerun
+/// after the process is killed skips them.  Only deduplicated objects are recorded:
+/// their key is the content hash, so a file changed since the first run
+/// maps to a different key and is uploaded again.
+///
+/// The file is JSON lines, appended as each object is confirmed.  A torn
+/// last line is ignored, costing one check.
+pub struct UploadState {
+    path: PathBuf,
+    confirmed: HashSet<String>,
+    file: Mutex<std::fs::File>,
+}
+
+fn line(l: &Line) -> String {
+    format!("{}\n", serde_json::to_string(l).expect("state lines serialise"))
+}
+
+/// Keys confirmed in content, if it's state for this version and cache
+fn parse(content: &str, cache_name: &str) -> Option<HashSet<String>> {
+    let mut lines = content.lines();
+    match serde_json::from_str(lines.next()?) {
+        Ok(Line::Header { version: VERSION, cache }) if cache == cache_name => {},
+        _ => return None,
+    }
+    Some(lines.filter_map(|l| match serde_json::from_str(l) {
+        Ok(Line::Confirmed { key }) => Some(key),
+        _ => {
+            log::debug!("Skipping bad state line: {:?}", l);
+            None
+        },
+    }).collect())
+}
+
+impl UploadState {
+    /// Resume from path if it holds state for cache_name, otherwise start afresh
+    pub fn open(path: &Path, cache_name: &str) -> Result<UploadState> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let confirmed = match parse(&content, cache_name) {
+            Some(confirmed) => {
+                log::warn!("Resuming upload to '{}': {} objects already confirmed", cache_name, confirmed.len());
+                // a torn line would swallow the next one appended
+                if !content.ends_with('\n') {
+                    file.write_all(b"\n")?;
+                }
+                confirmed
+            },
+            None => {
+                if !content.is_empty() {
+                    log::warn!("Ignoring state in {} from another cache or version", path.display());
+                }
+                file.set_len(0)?;
+                file.write_all(line(&Line::Header { version: VERSION, cache: cache_name.to_owned() }).as_bytes())?;
+                HashSet::new()
+            },
+        };
+        Ok(UploadState { path: path.to_owned(), confirmed, file: Mutex::new(file) })
+    }
+
+    /// Whether an earlier run confirmed key
+    pub fn is_confirmed(&self, key: &str) -> bool {
+        self.confirmed.contains(key)
+    }
+
+    /// Note key is in the bucket.  Best-effort: failure only means checking
+    /// it again on a rerun.
+    pub fn confirm(&self, key: &str) {
+        let l = line(&Line::Confirmed { key: key.to_owned() });
+        if let Err(e) = self.file.lock().expect("state lock poisoned").write_all(l.as_bytes()) {
+            log::info!("Unable to update {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Remove the state once the upload is complete
+    pub fn finish(&self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("Unable to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn interrupted_then_resumed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+
+        let first = UploadState::open(&path, "c").unwrap();
+        assert!(!first.is_confirmed("objects/a/bin"));
+        first.confirm("objects/a/bin");
+        first.confirm("objects/b/bin");
+        drop(first); // killed before the entry was pushed
+
+        let second = UploadState::open(&path, "c").unwrap();
+        assert!(second.is_confirmed("objects/a/bin"));
+        assert!(second.is_confirmed("objects/b/bin"));
+        assert!(!second.is_confirmed("objects/c/bin"));
+        second.confirm("objects/c/bin");
+        second.finish();
+        assert!(!path.exists());
+
+        let third = UploadState::open(&path, "c").unwrap();
+        assert!(!third.is_confirmed("objects/a/bin"));
+    }
+
+    #[test]
+    fn torn_writes_are_tolerated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        let header = line(&Line::Header { version: VERSION, cache: "c".into() });
+        std::fs::write(&path, format!("{}{{\"key\":\"objects/a/bin\"}}\n{{\"key\":\"objects/b", header)).unwrap();
+
+        let state = UploadState::open(&path, "c").unwrap();
+        assert!(state.is_confirmed("objects/a/bin"));
+        assert!(!state.is_confirmed("objects/b"));
+        state.confirm("objects/c/bin");
+        drop(state);
+
+        let state = UploadState::open(&path, "c").unwrap();
+        assert!(state.is_confirmed("objects/c/bin"));
+    }
+
+    #[test]
+    fn other_caches_and_versions_start_afresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        UploadState::open(&path, "c").unwrap().confirm("objects/a/bin");
+        assert!(!UploadState::open(&path, "other").unwrap().is_confirmed("objects/a/bin"));
+
+        let old = line(&Line::Header { version: VERSION + 1, cache: "c".into() });
+        std::fs::write(&path, format!("{}{{\"key\":\"objects/a/bin\"}}\n", old)).unwrap();
+        assert!(!UploadState::open(&path, "c").unwrap().is_confirmed("objects/a/bin"));
+    }
+}
@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{throttle::ThrottleStats, Error, Result};
+
+/// Bump when fields change meaning or are removed
+const VERSION: u32 = 1;
+
+/// Argument names whose values are never written out
+const SECRET_WORDS: [&str; 4] = ["secret", "token", "password", "credential"];
+
+const REDACTED: &str = "<redacted>";
+
+/// What a subcommand produced, for --result-file
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub exit_code: i32,
+    pub report: Option<serde_json::Value>,
+    /// How the provider throttled the run's requests
+    pub throttle: Option<ThrottleStats>,
+}
+
+impl Outcome {
+    pub fn with_report(report: &impl Serialize) -> Result<Outcome> {
+        Ok(Outcome { exit_code: 0, report: Some(serde_json::to_value(report)?), throttle: None })
+    }
+
+    pub fn with_exit_code(mut self, code: i32) -> Outcome {
+        self.exit_code = code;
+        self
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ErrorDetails {
+    pub message: String,
+    /// auth, not_found, retryable or other
+    pub kind: &'static str,
+    /// Context first, root cause last
+    pub chain: Vec<String>,
+}
+
+/// Summary of a whole run, written by --result-file
+#[derive(Serialize, Debug)]
+pub struct RunResult {
+    pub version: u32,
+    pub command: String,
+    pub arguments: Vec<String>,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub exit_code: i32,
+    pub report: Option<serde_json::Value>,
+    pub error: Option<ErrorDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<ThrottleStats>,
+}
+
+/// How a failure might be dealt with, from the first library error in its chain
+pub fn classify(e: &anyhow::Error) -> &'static str {
+    let Some(error) = e.chain().find_map(|c| c.downcast_ref::<Error>()) else {
+        return "other";
+    };
+    if error.is_auth() {
+        "auth"
+    } else if error.is_not_found() {
+        "not_found"
+    } else if error.is_retryable() {
+        "retryable"
+    } else {
+        "other"
+    }
+}
+
+fn is_secret(flag: &str) -> bool {
+    let flag = flag.to_ascii_lowercase();
+    SECRET_WORDS.iter().any(|w| flag.contains(w))
+}
+
+/// Drop any user:password from a URL
+fn redact_url(value: &str) -> String {
+    let Some((scheme, rest)) = value.split_once("://") else {
+        return value.to_owned();
+    };
+    let authority = rest.split('/').next().unwrap_or(rest);
+    match authority.rsplit_once('@') {
+        Some((_, host)) => format!("{}://{}@{}{}", scheme, REDACTED, host, &rest[authority.len()..]),
+        None => value.to_owned(),
+    }
+}
+
+/// Command line with the values of secret-looking options and URL
+/// credentials replaced
+pub fn redact(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut hide_next = false;
+    for arg in args {
+        if std::mem::take(&mut hide_next) {
+            out.push(REDACTED.to_owned());
+            continue;
+        }
+        match arg.split_once('=') {
+            Some((flag, _)) if flag.starts_with("--") && is_secret(flag) => out.push(format!("{}={}", flag, REDACTED)),
+            None if arg.starts_with("--") && is_secret(&arg) => {
+                hide_next = true;
+                out.push(arg);
+            },
+            _ => out.push(redact_url(&arg)),
+        }
+    }
+    out
+}
+
+impl RunResult {
+    pub fn new(command: &str, arguments: Vec<String>, started: DateTime<Utc>, outcome: &Result<Outcome>) -> RunResult {
+        let (exit_code, report, error, throttle) = match outcome {
+            Ok(o) => (o.exit_code, o.report.clone(), None, o.throttle),
+            Err(e) => (1, None, Some(ErrorDetails {
+                message: format!("{:#}", e),
+                kind: classify(e),
+                chain: e.chain().map(|c| c.to_string()).collect(),
+            }), None),
+        };
+        RunResult {
+            version: VERSION, command: command.to_owned(), arguments: redact(arguments),
+            started, finished: Utc::now(), exit_code, report, error, throttle,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        assert_eq!(redact(args(&["s3-cache", "--session-token=abc", "--aws-secret", "xyz", "upload", "--name=c"])),
+                   args(&["s3-cache", "--session-token=<redacted>", "--aws-secret", "<redacted>", "upload", "--name=c"]));
+        assert_eq!(redact(args(&["--endpoint=https://user:pw@minio:9000/x", "https://minio:9000"])),
+                   args(&["--endpoint=https://<redacted>@minio:9000/x", "https://minio:9000"]));
+        assert_eq!(redact(args(&["--name=token-cache", "upload"])), args(&["--name=token-cache", "upload"]));
+    }
+
+    #[test]
+    fn failures_are_classified() {
+        let not_found = anyhow::Error::from(Error::CacheNotFound("c".into())).context("Failed to download");
+        assert_eq!(classify(&not_found), "not_found");
+        assert_eq!(classify(&anyhow::anyhow!("plain")), "other");
+
+        let result = RunResult::new("download", args(&["s3-cache"]), Utc::now(), &Err(not_found));
+        assert_eq!(result.exit_code, 1);
+        let error = result.error.unwrap();
+        assert_eq!(error.kind, "not_found");
+        assert_eq!(error.chain, vec!["Failed to download", "Cache named 'c' not found"]);
+    }
+
+    #[test]
+    fn reports_are_embedded() {
+        let outcome = Outcome::with_report(&serde_json::json!({"files": 2})).map(|o| o.with_exit_code(3));
+        let result = RunResult::new("status", Vec::new(), Utc::now(), &outcome);
+        assert_eq!(result.exit_code, 3);
+        assert_eq!(result.report, Some(serde_json::json!({"files": 2})));
+        assert!(result.error.is_none());
+        assert!(!serde_json::to_string(&result).unwrap().contains("throttle"));
+
+        let stats = ThrottleStats { throttled: 3, concurrency: 16, lowest: 8 };
+        let outcome = Ok(Outcome { throttle: Some(stats), ..Outcome::default() });
+        let result = RunResult::new("upload", Vec::new(), Utc::now(), &outcome);
+        assert!(serde_json::to_string(&result).unwrap().contains(r#""throttle":{"throttled":3,"concurrency":16,"lowest":8}"#));
+    }
+}
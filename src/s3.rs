@@ -1,45 +1,385 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // (C) Copyright 2025 Greg Whiteley
 
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Mutex;
 
 use s3::creds::Credentials;
 use s3::region::Region;
 use s3::{Bucket, BucketConfiguration};
 
-use crate::Error;
+use crate::{display::key_display, layout::{self, Layout}, provider::{Provider, ProviderProfile},
+            throttle::{Throttle, ThrottleStats}, Error, Strictness};
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Source of credentials for [Storage], consulted again whenever the
+/// service reports the current ones have expired (eg STS session tokens).
+pub trait CredentialsProvider: Send + Sync {
+    fn credentials(&self) -> Result<Credentials>;
+}
+
+impl<F> CredentialsProvider for F
+where F: Fn() -> Result<Credentials> + Send + Sync {
+    fn credentials(&self) -> Result<Credentials> {
+        self()
+    }
+}
+
+/// The default chain: environment, profile, then instance metadata
+fn default_credentials() -> Result<Credentials> {
+    Ok(Credentials::default()?)
+}
+
+/// Credentials named on the command line.  Keys given outright win over a
+/// profile, which wins over the default chain.
+#[derive(Clone, Default)]
+pub struct CredentialsChoice {
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Section of ~/.aws/credentials
+    pub profile: Option<String>,
+}
+
+impl CredentialsChoice {
+    /// Provider for [StorageBuilder::credentials], None to keep the default
+    /// chain.  Its errors name the source tried.
+    pub fn provider(&self) -> Option<Arc<dyn CredentialsProvider>> {
+        let provider: Arc<dyn CredentialsProvider> = match (&self.access_key_id, &self.secret_access_key, &self.profile) {
+            (Some(id), Some(secret), _) => {
+                let (id, secret) = (id.clone(), secret.clone());
+                Arc::new(move || Credentials::new(Some(&id), Some(&secret), None, None, None).map_err(|error| {
+                    Error::CredentialsFrom { from: "--access-key-id and --secret-access-key".into(), error }
+                }))
+            },
+            (_, _, Some(profile)) => {
+                let profile = profile.clone();
+                Arc::new(move || Credentials::from_profile(Some(&profile)).map_err(|error| {
+                    Error::CredentialsFrom { from: format!("profile '{}'", profile), error }
+                }))
+            },
+            _ => return None,
+        };
+        Some(provider)
+    }
+}
+
+/// Current credentials, shared between clones of a Storage so one refresh
+/// serves every in-flight task
+#[derive(Clone)]
+struct CredentialSource {
+    current: Arc<RwLock<Credentials>>,
+    provider: Arc<dyn CredentialsProvider>,
+}
+
+impl CredentialSource {
+    fn new(provider: Arc<dyn CredentialsProvider>) -> Result<CredentialSource> {
+        let current = Arc::new(RwLock::new(provider.credentials()?));
+        Ok(CredentialSource { current, provider })
+    }
+
+    fn get(&self) -> Credentials {
+        self.current.read().expect("credentials lock poisoned").clone()
+    }
+
+    /// Replace stale credentials from the provider, unless another task
+    /// already has
+    fn refresh(&self, stale: &Credentials) -> Result<()> {
+        let mut current = self.current.write().expect("credentials lock poisoned");
+        if *current == *stale {
+            log::info!("Credentials expired, refreshing");
+            *current = self.provider.credentials()?;
+        }
+        Ok(())
+    }
+
+    /// Run op with the current credentials, refreshing and retrying once if
+    /// they turn out to have expired
+    async fn with_refresh<T, F, Fut>(&self, mut op: F) -> Result<T>
+    where F: FnMut(Credentials) -> Fut,
+          Fut: std::future::Future<Output = Result<T>>
+    {
+        let credentials = self.get();
+        match op(credentials.clone()).await {
+            Err(e) if e.is_expired_credentials() => {
+                self.refresh(&credentials)?;
+                op(self.get()).await
+            },
+            result => result,
+        }
+    }
+}
+
+/// How transient failures, those [Error::is_retryable], are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// Including the first, so 1 never retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig { max_attempts: 3, initial_backoff: Duration::from_millis(100), max_backoff: Duration::from_secs(10) }
+    }
+}
+
+impl RetryConfig {
+    /// Delay after a failed attempt, doubling from initial_backoff up to
+    /// max_backoff.  Half of it is random, so failed clients don't all come
+    /// back at once.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let full = self.initial_backoff.saturating_mul(1 << attempt.saturating_sub(1).min(31)).min(self.max_backoff);
+        full / 2 + full.mul_f64(fastrand::f64()) / 2
+    }
+}
+
+/// Counts what reaches a writer, as a download can only be retried into
+/// one that's received nothing
+struct CountingWriter<'a, W: ?Sized> {
+    inner: &'a mut W,
+    written: &'a AtomicU64,
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin + ?Sized> tokio::io::AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.written.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Bytes of an object fetched ahead of [ObjectStream]'s reader
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// An object read as a task fetches it into a pipe.  A failed fetch ends
+/// the read with its error, rather than early.
+pub struct ObjectStream {
+    pipe: tokio::io::DuplexStream,
+    fetch: Option<tokio::task::JoinHandle<Result<()>>>,
+}
+
+impl ObjectStream {
+    fn spawn<F, Fut>(fetch: F) -> ObjectStream
+    where F: FnOnce(tokio::io::DuplexStream) -> Fut, Fut: std::future::Future<Output = Result<()>> + Send + 'static {
+        let (pipe, writer) = tokio::io::duplex(PIPE_CAPACITY);
+        ObjectStream { pipe, fetch: Some(tokio::spawn(fetch(writer))) }
+    }
+}
+
+impl tokio::io::AsyncRead for ObjectStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        std::task::ready!(Pin::new(&mut this.pipe).poll_read(cx, buf))?;
+        if buf.filled().len() > before || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        // the pipe ends once the fetch is done, which may have failed
+        let Some(fetch) = this.fetch.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = std::task::ready!(std::future::Future::poll(Pin::new(fetch), cx));
+        this.fetch = None;
+        Poll::Ready(match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(std::io::Error::other(e)),
+            Err(e) => Err(std::io::Error::other(e)),
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct Storage {
     bucket_name: String,
     region: Region,
-    credentials: Credentials,
+    credentials: CredentialSource,
     accept_invalid_certs: bool,
+    connect_timeout: Option<Duration>,
+    retry: RetryConfig,
+    strict: Strictness,
+    /// The layout marker once read, None within if the bucket has none
+    layout: Arc<Mutex<Option<Option<Layout>>>>,
+    force_layout: bool,
+    max_entry_size: u64,
+    /// The bucket as configured for the credentials it was built with,
+    /// shared between clones so operations needn't reconnect
+    configured: Arc<RwLock<Option<(Credentials, Arc<Bucket>)>>>,
+    /// Requests in flight, cut back while the provider throttles them
+    throttle: Arc<Throttle>,
+    /// Prepended to every key, in "a/b/" form, so teams can share a bucket
+    prefix: Option<String>,
+    /// Refuse anything needing the network
+    offline: bool,
+    /// What the service answers where services differ
+    profile: ProviderProfile,
 }
 
-impl Storage {
+/// Settings for connecting a [Storage], from [Storage::builder]
+pub struct StorageBuilder {
+    bucket_name: String,
+    endpoint: Option<String>,
+    region: String,
+    accept_invalid_certs: bool,
+    create_if_missing: bool,
+    connect_timeout: Option<Duration>,
+    retry: RetryConfig,
+    max_requests: usize,
+    prefix: Option<String>,
+    offline: bool,
+    provider: Arc<dyn CredentialsProvider>,
+    service: Provider,
+}
 
-    // TODO replace this with a builder
-    pub async fn new(bucket_name: &str, region: &str, endpoint: &str, create: bool) -> Result<Storage> {
-        Self::new_dangerous(bucket_name, region, endpoint, create, false).await
+impl StorageBuilder {
+    fn defaults(bucket_name: &str) -> StorageBuilder {
+        StorageBuilder {
+            bucket_name: bucket_name.to_owned(),
+            endpoint: None,
+            region: "global".to_owned(),
+            accept_invalid_certs: false,
+            create_if_missing: false,
+            connect_timeout: None,
+            retry: RetryConfig::default(),
+            max_requests: crate::throttle::DEFAULT_MAX_REQUESTS,
+            prefix: None,
+            offline: false,
+            provider: Arc::new(default_credentials),
+            service: Provider::Auto,
+        }
     }
 
-    pub async fn new_dangerous(bucket_name: &str, region: &str, endpoint: &str, create: bool, accept_invalid_certs: bool) -> Result<Storage> {
+    /// As [Storage::builder] with endpoint already given
+    pub fn new(bucket_name: &str, endpoint: &str) -> StorageBuilder {
+        StorageBuilder::defaults(bucket_name).endpoint(endpoint)
+    }
 
-        let region = Region::Custom {
-            region: region.to_owned(),
-            endpoint: endpoint.to_owned(),
-        };
+    /// Service URL, eg https://minio.example.com:9000 - required
+    pub fn endpoint(mut self, endpoint: &str) -> StorageBuilder {
+        self.endpoint = Some(endpoint.to_owned());
+        self
+    }
 
-        let credentials = Credentials::default()?;
+    pub fn region(mut self, region: &str) -> StorageBuilder {
+        self.region = region.to_owned();
+        self
+    }
 
-        let s = Storage {
-            bucket_name: bucket_name.to_owned(),
-            region, credentials,
-            accept_invalid_certs,
-        };
+    /// Accept invalid TLS certificates - only for testing
+    pub fn accept_invalid_certs(mut self, accept: bool) -> StorageBuilder {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// As [StorageBuilder::accept_invalid_certs]
+    pub fn skip_cert_validation(self, skip: bool) -> StorageBuilder {
+        self.accept_invalid_certs(skip)
+    }
+
+    /// Create the bucket if it doesn't exist, rather than failing
+    pub fn create_if_missing(mut self, create: bool) -> StorageBuilder {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Give up on a connection whose first listing takes longer than this
+    pub fn connect_timeout(mut self, timeout: Duration) -> StorageBuilder {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// How transient failures are retried, including while connecting
+    pub fn retry(mut self, retry: RetryConfig) -> StorageBuilder {
+        self.retry = retry;
+        self
+    }
+
+    /// Most requests in flight at once, shared by every clone.  Fewer are
+    /// allowed while the provider answers 429 or SlowDown.
+    pub fn max_requests(mut self, max: usize) -> StorageBuilder {
+        self.max_requests = max;
+        self
+    }
+
+    /// Keep everything below this prefix, eg team-a/, isolated from other
+    /// prefixes in the bucket
+    pub fn prefix(mut self, prefix: &str) -> StorageBuilder {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Fail anything that would reach the bucket, and don't connect
+    pub fn offline(mut self, offline: bool) -> StorageBuilder {
+        self.offline = offline;
+        self
+    }
+
+    /// Where credentials come from, instead of the environment, profile
+    /// and instance metadata
+    pub fn credentials(mut self, provider: Arc<dyn CredentialsProvider>) -> StorageBuilder {
+        self.provider = provider;
+        self
+    }
+
+    /// Which service's quirks to expect, detected from the endpoint if Auto
+    pub fn provider_profile(mut self, provider: Provider) -> StorageBuilder {
+        self.service = provider;
+        self
+    }
+
+    /// The Storage described, without connecting
+    fn storage(self) -> Result<Storage> {
+        let endpoint = self.endpoint.ok_or(Error::NoEndpoint)?;
+        let prefix = self.prefix.map(|prefix| crate::migrate::normalise_prefix(&prefix)
+                                     .map_err(|_| Error::InvalidPrefix(prefix.clone()))).transpose()?;
+        let profile = self.service.profile(&endpoint);
+        Ok(Storage {
+            bucket_name: self.bucket_name,
+            region: Region::Custom { region: self.region, endpoint },
+            credentials: CredentialSource::new(self.provider)?,
+            accept_invalid_certs: self.accept_invalid_certs,
+            connect_timeout: self.connect_timeout,
+            retry: self.retry,
+            strict: Strictness::default(),
+            layout: Arc::default(),
+            force_layout: false,
+            max_entry_size: crate::cache::DEFAULT_MAX_ENTRY_SIZE,
+            configured: Arc::default(),
+            throttle: Arc::new(Throttle::new(self.max_requests)),
+            prefix,
+            offline: self.offline,
+            profile,
+        })
+    }
+
+    /// Connect, creating the bucket if allowed and it's missing
+    pub async fn connect(self) -> Result<Storage> {
+        let create = self.create_if_missing;
+        let s = self.storage()?;
+        if s.offline {
+            log::info!("Offline, not connecting to '{}'", s.bucket_name);
+            return Ok(s);
+        }
 
         match s.connect().await {
             Ok(_) => Ok(s),
@@ -55,43 +395,309 @@ impl Storage {
         }
     }
 
+    /// As [StorageBuilder::connect]
+    pub async fn build(self) -> Result<Storage> {
+        self.connect().await
+    }
+}
+
+impl Storage {
+
+    pub fn builder(bucket_name: &str) -> StorageBuilder {
+        StorageBuilder::defaults(bucket_name)
+    }
+
+    #[deprecated(note = "use Storage::builder")]
+    pub async fn new(bucket_name: &str, region: &str, endpoint: &str, create: bool) -> Result<Storage> {
+        Self::builder(bucket_name)
+            .endpoint(endpoint)
+            .region(region)
+            .create_if_missing(create)
+            .connect().await
+    }
+
+    #[deprecated(note = "use Storage::builder")]
+    pub async fn new_dangerous(bucket_name: &str, region: &str, endpoint: &str, create: bool, accept_invalid_certs: bool) -> Result<Storage> {
+        Self::builder(bucket_name)
+            .endpoint(endpoint)
+            .region(region)
+            .create_if_missing(create)
+            .accept_invalid_certs(accept_invalid_certs)
+            .connect().await
+    }
+
+    #[deprecated(note = "use Storage::builder")]
+    pub async fn new_with_credentials_provider(bucket_name: &str, region: &str, endpoint: &str, create: bool,
+                                               accept_invalid_certs: bool,
+                                               provider: Arc<dyn CredentialsProvider>) -> Result<Storage> {
+        Self::builder(bucket_name)
+            .endpoint(endpoint)
+            .region(region)
+            .create_if_missing(create)
+            .accept_invalid_certs(accept_invalid_certs)
+            .credentials(provider)
+            .connect().await
+    }
+
+    /// How transient failures are retried from now on
+    pub fn with_retries(mut self, retry: RetryConfig) -> Storage {
+        self.retry = retry;
+        self
+    }
+
+    pub fn retries(&self) -> RetryConfig {
+        self.retry
+    }
+
+    /// How often the provider throttled requests, and how many are allowed
+    /// in flight as a result
+    pub fn throttle_stats(&self) -> ThrottleStats {
+        self.throttle.stats()
+    }
+
+    /// Promote the selected warnings to errors
+    pub fn with_strictness(mut self, strict: Strictness) -> Storage {
+        self.strict = strict;
+        self
+    }
+
+    pub fn strictness(&self) -> Strictness {
+        self.strict
+    }
+
+    /// Carry on when the bucket's layout is incompatible
+    pub fn with_force_layout(mut self, force: bool) -> Storage {
+        self.force_layout = force;
+        self
+    }
+
+    pub fn force_layout(&self) -> bool {
+        self.force_layout
+    }
+
+    /// Refuse cache entries larger than this many bytes
+    pub fn with_max_entry_size(mut self, bytes: u64) -> Storage {
+        self.max_entry_size = bytes;
+        self
+    }
+
+    pub fn max_entry_size(&self) -> u64 {
+        self.max_entry_size
+    }
+
+    /// The bucket's layout marker, read once and shared between clones
+    pub async fn layout(&self) -> Result<Option<Layout>> {
+        let mut cached = self.layout.lock().await;
+        if let Some(layout) = cached.as_ref() {
+            return Ok(layout.clone());
+        }
+        let layout = match self.head(layout::MARKER).await? {
+            Some(_) => {
+                let mut vec = Vec::<u8>::new();
+                self.get_file(&mut vec, layout::MARKER).await?;
+                Some(Layout::decode(&vec)?)
+            },
+            None => None,
+        };
+        *cached = Some(layout.clone());
+        Ok(layout)
+    }
+
+    pub async fn put_layout(&self, layout: &Layout) -> Result<()> {
+        let mut cached = self.layout.lock().await;
+        self.put_file_as(&mut std::io::Cursor::new(layout.encode()), layout::MARKER, "application/json", &[]).await?;
+        *cached = Some(Some(layout.clone()));
+        Ok(())
+    }
+
+    pub fn bucket_name(&self) -> &str {
+        &self.bucket_name
+    }
+
+    /// Prefix every key is below, ending in '/'
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// The current credentials' values, for scrubbing from anything shared
+    pub(crate) fn secrets(&self) -> Vec<String> {
+        let c = self.credentials.get();
+        [c.access_key, c.secret_key, c.security_token, c.session_token].into_iter()
+            .flatten()
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    fn require_network(&self, operation: &'static str, path: &str) -> Result<()> {
+        if self.offline {
+            return Err(Error::OfflineModeViolation { operation, key: key_display(&self.key(path)) });
+        }
+        Ok(())
+    }
+
+    /// Key in the bucket of path
+    fn key(&self, path: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}{}", prefix, path),
+            None => path.to_owned(),
+        }
+    }
+
+    /// Path of a key listed from the bucket
+    fn path_of(&self, key: String) -> String {
+        match self.prefix.as_deref().and_then(|prefix| key.strip_prefix(prefix)) {
+            Some(path) => path.to_owned(),
+            None => key,
+        }
+    }
+
+    fn info_of(&self, info: ObjectInfo) -> ObjectInfo {
+        ObjectInfo { key: self.path_of(info.key), ..info }
+    }
+
+    pub fn provider_profile(&self) -> ProviderProfile {
+        self.profile
+    }
+
+    pub fn endpoint(&self) -> String {
+        self.region.endpoint()
+    }
+
     async fn connect(&self) -> Result<Connection> {
-        let bucket = Bucket::new(self.bucket_name.as_str(), self.region.clone(), self.credentials.clone())?
-            .set_dangereous_config(self.accept_invalid_certs, false)?
-            .with_path_style();
+        self.retrying(|| self.credentials.with_refresh(|credentials| self.connect_with(credentials)), || true).await
+    }
+
+    /// Run op until it succeeds, fails for good, runs out of attempts, or
+    /// may_retry says a retry is unsafe
+    async fn retrying<T, F, Fut>(&self, op: F, may_retry: impl Fn() -> bool) -> Result<T>
+    where F: Fn() -> Fut,
+          Fut: std::future::Future<Output = Result<T>>
+    {
+        let mut attempt = 1;
+        loop {
+            let slot = self.throttle.acquire().await;
+            let result = op().await;
+            match &result {
+                Ok(_) => slot.succeeded(),
+                Err(e) if self.profile.is_throttled(e) => slot.throttled(),
+                Err(_) => drop(slot),
+            }
+            match result {
+                Err(e) if e.is_retryable() && attempt < self.retry.max_attempts && may_retry() => {
+                    let delay = self.retry.backoff(attempt);
+                    log::info!("Attempt {} of {} failed, retrying in {:?}: {}", attempt, self.retry.max_attempts, delay, e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn bucket(&self, credentials: Credentials) -> Result<Box<Bucket>> {
+        let bucket = Bucket::new(self.bucket_name.as_str(), self.region.clone(), credentials)?
+            .set_dangereous_config(self.accept_invalid_certs, false)?;
+        Ok(if self.profile.path_style { bucket.with_path_style() } else { bucket })
+    }
 
-        let connection = Connection { bucket };
-        connection.check_connect().await?;
+    /// A connection using the configured bucket, configuring it again only
+    /// when the credentials have changed
+    fn connection(&self, credentials: Credentials) -> Result<Connection> {
+        if let Some((configured_with, bucket)) = self.configured.read().expect("bucket lock poisoned").as_ref() {
+            if *configured_with == credentials {
+                return Ok(Connection { bucket: bucket.clone(), strict: self.strict, profile: self.profile });
+            }
+        }
+        let bucket: Arc<Bucket> = self.bucket(credentials.clone())?.into();
+        *self.configured.write().expect("bucket lock poisoned") = Some((credentials, bucket.clone()));
+        Ok(Connection { bucket, strict: self.strict, profile: self.profile })
+    }
+
+    /// Drop bucket, if it's still the one configured, so the next request
+    /// configures it afresh with new connections
+    fn forget(&self, bucket: &Arc<Bucket>) {
+        let mut configured = self.configured.write().expect("bucket lock poisoned");
+        if configured.as_ref().is_some_and(|(_, current)| Arc::ptr_eq(current, bucket)) {
+            log::info!("Connection failed, reconnecting to '{}'", self.bucket_name);
+            *configured = None;
+        }
+    }
+
+    /// Connect and check the bucket exists, as done once on building
+    async fn connect_with(&self, credentials: Credentials) -> Result<Connection> {
+        let connection = self.connection(credentials)?;
+        match self.connect_timeout {
+            Some(limit) => tokio::time::timeout(limit, connection.check_connect()).await
+                .map_err(|_| Error::ConnectTimeout(limit))??,
+            None => connection.check_connect().await?,
+        };
         Ok(connection)
     }
 
-    async fn create(&self) -> Result<Connection> {
-        let bucket = Bucket::create_with_path_style(
-            self.bucket_name.as_str(), self.region.clone(),
-            self.credentials.clone(), BucketConfiguration::default()).await
-            .map_err(Error::BucketCreationError)?
-            .bucket;
-        Ok(Connection { bucket })
+    /// Run op on the configured bucket, refreshing credentials if they
+    /// expire and retrying transient failures
+    async fn run<T, F, Fut>(&self, op: F) -> Result<T>
+    where F: Fn(Connection) -> Fut,
+          Fut: std::future::Future<Output = Result<T>>
+    {
+        self.run_retrying(op, || true).await
     }
 
-    pub async fn put_file_unless_exists<R: tokio::io::AsyncRead + Unpin + ?Sized>(
-        &self, reader: &mut R, s3_path: &str) -> Result<()> {
+    async fn run_retrying<T, F, Fut>(&self, op: F, may_retry: impl Fn() -> bool) -> Result<T>
+    where F: Fn(Connection) -> Fut,
+          Fut: std::future::Future<Output = Result<T>>
+    {
+        let op = &op;
+        self.retrying(|| self.credentials.with_refresh(move |credentials| async move {
+            let connection = self.connection(credentials)?;
+            let bucket = connection.bucket.clone();
+            op(connection).await.inspect_err(|e| if e.is_connection_failure() { self.forget(&bucket) })
+        }), may_retry).await
+    }
 
-        let connection = self.connect().await?;
+    async fn create(&self) -> Result<Connection> {
+        let (name, region, credentials) = (self.bucket_name.as_str(), self.region.clone(), self.credentials.get());
+        let created = if self.profile.path_style {
+            Bucket::create_with_path_style(name, region, credentials, BucketConfiguration::default()).await
+        } else {
+            Bucket::create(name, region, credentials, BucketConfiguration::default()).await
+        };
+        let bucket = created.map_err(Error::BucketCreationError)?.bucket;
+        Ok(Connection { bucket: bucket.into(), strict: self.strict, profile: self.profile })
+    }
 
-        if connection.exists(s3_path).await? {
-            log::info!("File {} exists, not putting", s3_path);
-            return Ok(());
-        }
+    /// Put reader at s3_path, with tags, unless something is there already.
+    /// Deduplicated objects are put with If-None-Match too, so of uploads
+    /// racing past the check only one lands.
+    pub async fn put_file_unless_exists<R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin + ?Sized>(
+        &self, reader: &mut R, s3_path: &str, content_type: &str, tags: &[(&str, &str)]) -> Result<()> {
+
+        self.require_network("put", s3_path)?;
+        let start = reader.stream_position().await?;
+        let reader = &Mutex::new(reader);
+        let key = &self.key(s3_path);
+        let if_none_match = s3_path.starts_with(crate::object::ROOT);
+        self.run(|connection| async move {
+            if connection.exists(key).await? {
+                log::info!("File {} exists, not putting", key_display(s3_path));
+                return Ok(());
+            }
 
-        connection.put_file(reader, s3_path).await
+            let mut reader = reader.lock().await;
+            reader.seek(std::io::SeekFrom::Start(start)).await?;
+            connection.put_file(&mut **reader, key, content_type, tags, if_none_match).await
+        }).await
     }
 
     pub async fn list_dirs(&self, path: &str) -> Result<Vec<String>> {
+        self.require_network("list", path)?;
         // Async variant with `tokio` or `async-std` features
-        let connection = self.connect().await?;
-
-        connection.list_dirs(path).await
+        let key = &self.key(path);
+        self.run(|connection| async move { connection.list_dirs(key).await }).await
     }
 
     pub async fn recursive_delete_p(&self, path: &Path) -> Result<()> {
@@ -99,44 +705,217 @@ impl Storage {
     }
 
     pub async fn recursive_delete(&self, path: &str) -> Result<()> {
+        self.require_network("delete", path)?;
         // Async variant with `tokio` or `async-std` features
-        let connection = self.connect().await?;
-
-        connection.recursive_delete(path).await
+        let key = &self.key(path);
+        self.run(|connection| async move { connection.recursive_delete(key).await }).await
     }
 
-    pub async fn put_file<R: tokio::io::AsyncRead + Unpin + ?Sized>(
+    pub async fn put_file<R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin + ?Sized>(
         &self, reader: &mut R, s3_path: &str) -> Result<()> {
+        self.put_file_as(reader, s3_path, crate::content_type::OCTET_STREAM, &[]).await
+    }
 
-        let connection = self.connect().await?;
+    /// Put reader at s3_path, then tag it unless tags is empty
+    pub async fn put_file_as<R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin + ?Sized>(
+        &self, reader: &mut R, s3_path: &str, content_type: &str, tags: &[(&str, &str)]) -> Result<()> {
+
+        self.require_network("put", s3_path)?;
+        let start = reader.stream_position().await?;
+        let reader = &Mutex::new(reader);
+        let key = &self.key(s3_path);
+        self.run(|connection| async move {
+            let mut reader = reader.lock().await;
+            reader.seek(std::io::SeekFrom::Start(start)).await?;
+            connection.put_file(&mut **reader, key, content_type, tags, false).await
+        }).await
+    }
 
-        connection.put_file(reader, s3_path).await
+    /// Tags of the object at s3_path
+    pub async fn get_tags(&self, s3_path: &str) -> Result<HashMap<String, String>> {
+        self.require_network("get_tags", s3_path)?;
+        let key = &self.key(s3_path);
+        self.run(|connection| async move { connection.get_tags(key).await }).await
     }
 
     pub async fn get_file<W: tokio::io::AsyncWrite + Send + Unpin + ?Sized>(
         &self, writer: &mut W, s3_path: &str) -> Result<()> {
 
-        let connection = self.connect().await?;
+        self.require_network("get", s3_path)?;
+        let written = AtomicU64::new(0);
+        let writer = &Mutex::new(CountingWriter { inner: writer, written: &written });
+        let key = &self.key(s3_path);
+        self.run_retrying(|connection| async move {
+            let mut writer = writer.lock().await;
+            connection.get_file_stream(key, &mut *writer).await
+        }, || written.load(Ordering::Relaxed) == 0).await
+    }
+
+    /// Read s3_path as it arrives.  The get is retried as by
+    /// [Storage::get_file] until any of it has arrived.
+    pub fn get_stream(&self, s3_path: &str) -> Result<ObjectStream> {
+        self.require_network("get", s3_path)?;
+        let (storage, s3_path) = (self.clone(), s3_path.to_owned());
+        Ok(ObjectStream::spawn(|mut writer| async move { storage.get_file(&mut writer, &s3_path).await }))
+    }
 
-        connection.get_file_stream(s3_path, writer).await
+    /// Bytes start to end inclusive of s3_path into writer.  False if the
+    /// server ignored the range, in which case the whole object was written.
+    pub async fn get_file_range<W: tokio::io::AsyncWrite + Send + Unpin + ?Sized>(
+        &self, writer: &mut W, s3_path: &str, start: u64, end: u64) -> Result<bool> {
+
+        self.require_network("get", s3_path)?;
+        let written = AtomicU64::new(0);
+        let writer = &Mutex::new(CountingWriter { inner: writer, written: &written });
+        let key = &self.key(s3_path);
+        self.run_retrying(|connection| async move {
+            let mut writer = writer.lock().await;
+            connection.get_file_range(key, start, end, &mut *writer).await
+        }, || written.load(Ordering::Relaxed) == 0).await
     }
 
     pub async fn delete(&self, s3_path: &str) -> Result<()> {
+        self.require_network("delete", s3_path)?;
+        let key = &self.key(s3_path);
+        self.run(|connection| async move { connection.delete(key).await }).await
+    }
+
+    /// List every object below path (no delimiter), with size and modification time
+    pub async fn list_objects(&self, path: &str) -> Result<Vec<ObjectInfo>> {
+        self.require_network("list", path)?;
+        let key = &self.key(path);
+        let objects = self.run(|connection| async move { connection.list_objects(key).await }).await?;
+        Ok(objects.into_iter().map(|o| self.info_of(o)).collect())
+    }
+
+    /// One page of the objects below path, in key order, starting after the given key
+    pub async fn list_page(&self, path: &str, after: Option<&str>) -> Result<Vec<ObjectInfo>> {
+        self.require_network("list", path)?;
+        let (key, after) = (&self.key(path), &after.map(|after| self.key(after)));
+        let objects = self.run(|connection| async move { connection.list_page(key, after.as_deref()).await }).await?;
+        Ok(objects.into_iter().map(|o| self.info_of(o)).collect())
+    }
 
-        let connection = self.connect().await?;
+    /// Does the object at s3_path exist.  A 404 is checked against the
+    /// bucket, so a missing bucket is [Error::BucketNotFound], not false.
+    pub async fn exists(&self, s3_path: &str) -> Result<bool> {
+        self.require_network("head", s3_path)?;
+        let key = &self.key(s3_path);
+        self.run(|connection| async move {
+            if connection.exists(key).await? {
+                return Ok(true);
+            }
+            connection.check_connect().await?;
+            Ok(false)
+        }).await
+    }
 
-        connection.delete(s3_path).await
+    /// One page of the directories directly below prefix, as full keys ending
+    /// in '/', in key order after the key start_after.  With them is the key
+    /// to start the next page after, if there's more.
+    pub async fn list_dirs_page(&self, prefix: &str, start_after: Option<&str>, limit: usize)
+                                -> Result<(Vec<String>, Option<String>)> {
+        self.require_network("list", prefix)?;
+        let (key, start_after) = (&self.key(prefix), &start_after.map(|after| self.key(after)));
+        let (dirs, next) = self.run(|connection| async move {
+            connection.list_dirs_page(key, start_after.as_deref(), limit).await
+        }).await?;
+        Ok((dirs.into_iter().map(|d| self.path_of(d)).collect(), next.map(|n| self.path_of(n))))
     }
 
-    pub async fn recursive_expire(&self, path: impl AsRef<str>,
-                                  expiry_time: chrono::DateTime<chrono::Utc>) -> Result<()> {
-        let connection = self.connect().await?;
-        connection.recursive_expire(path, expiry_time).await
+    /// Size and modification time of one object, None if it doesn't exist
+    pub async fn head(&self, s3_path: &str) -> Result<Option<ObjectInfo>> {
+        self.require_network("head", s3_path)?;
+        let key = &self.key(s3_path);
+        self.run(|connection| async move {
+            match connection.head(key).await {
+                Ok(head) => Ok(Some(ObjectInfo {
+                    key: s3_path.to_owned(),
+                    size: head.content_length.unwrap_or(0).try_into().unwrap_or(0),
+                    last_modified: head.last_modified.and_then(
+                        |d| chrono::DateTime::parse_from_rfc2822(&d).ok().map(|d| d.to_utc())),
+                })),
+                Err(e) if e.is_not_found() => Ok(None),
+                Err(e) => Err(e),
+            }
+        }).await
+    }
+
+    /// Date header of the response to reading s3_path, None if it's missing
+    pub async fn server_date(&self, s3_path: &str) -> Result<Option<String>> {
+        self.require_network("get", s3_path)?;
+        let key = &self.key(s3_path);
+        self.run(|connection| async move { connection.server_date(key).await }).await
+    }
+
+    /// Server-side copy of one object within the bucket
+    pub async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.require_network("copy", from)?;
+        let (from, to) = (&self.key(from), &self.key(to));
+        self.run(|connection| async move { connection.copy(from, to).await }).await
+    }
+}
+
+/// A key after every key below dir, a prefix ending in '/', and before any
+/// other: '0' follows '/'.  A listing starting after it skips dir.
+pub fn key_after_dir(dir: &str) -> String {
+    format!("{}0", dir.strip_suffix('/').unwrap_or(dir))
+}
+
+/// Summary of a stored object as returned by a listing
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<s3::serde_types::Object> for ObjectInfo {
+    fn from(o: s3::serde_types::Object) -> Self {
+        let last_modified = chrono::DateTime::parse_from_rfc3339(&o.last_modified)
+            .map(|d| d.to_utc())
+            .inspect_err(|e| log::debug!("Unparseable last_modified '{}' on {}: {}", o.last_modified, o.key, e))
+            .ok();
+        ObjectInfo { key: o.key, size: o.size, last_modified }
     }
 }
 
 struct Connection {
-    bucket: Box<Bucket>,
+    bucket: Arc<Bucket>,
+    strict: Strictness,
+    profile: ProviderProfile,
+}
+
+/// Warn about an unexpected, but successful, status - or fail if strict
+fn check_status(strict: bool, operation: &'static str, path: &str, status: u16, expected: &[u16]) -> Result<()> {
+    if expected.contains(&status) {
+        return Ok(());
+    }
+    if strict {
+        return Err(Error::UnexpectedStatus { operation, path: key_display(path), status });
+    }
+    log::warn!("{}: unexpected response {} on {}", operation, status, key_display(path));
+    Ok(())
+}
+
+/// Content put in one request, or in parts of this size when there's more
+const PART_SIZE: usize = 8 << 20;
+
+/// Up to [PART_SIZE] bytes of reader, fewer only at its end
+async fn read_part<R: tokio::io::AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut part = Vec::new();
+    (&mut *reader).take(PART_SIZE as u64).read_to_end(&mut part).await?;
+    Ok(part)
+}
+
+/// Status of a put, or None where a conditional put found something there
+fn put_status(result: std::result::Result<u16, s3::error::S3Error>, if_none_match: bool) -> Result<Option<u16>> {
+    const PRECONDITION_FAILED: u16 = 412;
+    match result {
+        Ok(PRECONDITION_FAILED) | Err(s3::error::S3Error::HttpFailWithBody(PRECONDITION_FAILED, _)) if if_none_match => Ok(None),
+        Ok(status) => Ok(Some(status)),
+        Err(e) => Err(e.into()),
+    }
 }
 
 impl Connection {
@@ -163,12 +942,12 @@ impl Connection {
         let result = self.head(path).await;
         match result {
             Ok(_r) => {
-                log::debug!("exists: {} last_modified={} content_length={}", path,
+                log::debug!("exists: {} last_modified={} content_length={}", key_display(path),
                             _r.last_modified.unwrap_or("".into()),
                             _r.content_length.unwrap_or(0));
                 Ok(true)
             },
-            Err(Error::S3Error(s3::error::S3Error::HttpFailWithBody(404 ,_))) => Ok(false),
+            Err(e) if e.is_not_found() => Ok(false),
             Err(e) => Err(e),
         }
     }
@@ -177,37 +956,123 @@ impl Connection {
         assert!(s.find('\\').is_none(), "invalid path {:?}", s);
     }
 
+    /// Put reader at s3_path.  With if_none_match the put is conditional
+    /// on nothing being there, so of racing puts only one lands, and the
+    /// rest succeed without tagging what they find.
     async fn put_file<R: tokio::io::AsyncRead + Unpin + ?Sized>(
-        &self, reader: &mut R, s3_path: impl AsRef<str>) -> Result<()> {
+        &self, reader: &mut R, s3_path: impl AsRef<str>, content_type: &str, tags: &[(&str, &str)],
+        if_none_match: bool) -> Result<()> {
         Self::validate_path(s3_path.as_ref());
-        let response = self.bucket.put_object_stream(reader, s3_path.as_ref()).await?;
+        let status = if if_none_match {
+            self.put_unless_there(reader, s3_path.as_ref(), content_type).await?
+        } else {
+            let response = self.bucket.put_object_stream_with_content_type(reader, s3_path.as_ref(), content_type).await?;
+            Some(response.status_code())
+        };
 
-        if response.status_code() != 200 {
-            log::warn!("put_file: unexpected response {} putting {}", response.status_code(), s3_path.as_ref());
+        let Some(status) = status else {
+            log::info!("File {} exists, not putting", key_display(s3_path.as_ref()));
+            return Ok(());
+        };
+        check_status(self.strict.status, "put_file", s3_path.as_ref(), status, &[200])?;
+        if tags.is_empty() || !self.profile.tagging {
+            return Ok(());
+        }
+        // the object's in place either way, so a provider without tagging needn't fail the put
+        match self.bucket.put_object_tagging(s3_path.as_ref(), tags).await.map_err(Error::from) {
+            Ok(response) => check_status(self.strict.status, "put_tags", s3_path.as_ref(), response.status_code(), &[200]),
+            Err(e) if !self.strict.status && !e.is_retryable() => {
+                log::warn!("Unable to tag {}: {}", key_display(s3_path.as_ref()), e);
+                Ok(())
+            },
+            Err(e) => Err(e),
         }
-        Ok(())
+    }
+
+    /// Put reader at path with If-None-Match on the one request making the
+    /// object: the put, or completing the upload of content needing several
+    /// parts, which is aborted if refused.  None if something was there.
+    async fn put_unless_there<R: tokio::io::AsyncRead + Unpin + ?Sized>(
+        &self, reader: &mut R, path: &str, content_type: &str) -> Result<Option<u16>> {
+        let mut conditional = (*self.bucket).clone();
+        conditional.add_header("If-None-Match", "*");
+        let part = read_part(reader).await?;
+        if part.len() < PART_SIZE {
+            let result = conditional.put_object_with_content_type(path, &part, content_type).await;
+            return put_status(result.map(|response| response.status_code()), true);
+        }
+
+        let upload_id = self.bucket.initiate_multipart_upload(path, content_type).await?.upload_id;
+        let result = async {
+            let (mut part, mut parts) = (part, Vec::new());
+            while !part.is_empty() {
+                let number = parts.len() as u32 + 1;
+                parts.push(self.bucket.put_multipart_chunk(part, path, number, &upload_id, content_type).await?);
+                part = read_part(reader).await?;
+            }
+            put_status(conditional.complete_multipart_upload(path, &upload_id, parts).await
+                       .map(|response| response.status_code()), true)
+        }.await;
+        if !matches!(result, Ok(Some(_))) {
+            if let Err(e) = self.bucket.abort_upload(path, &upload_id).await {
+                log::warn!("Unable to abort upload of {}: {}", key_display(path), e);
+            }
+        }
+        result
+    }
+
+    async fn get_tags(&self, path: &str) -> Result<HashMap<String, String>> {
+        Self::validate_path(path);
+        let (tags, code) = self.bucket.get_object_tagging(path).await?;
+        check_status(self.strict.status, "get_tags", path, code, &[200])?;
+        Ok(tags.into_iter().map(|tag| (tag.key(), tag.value())).collect())
     }
 
     async fn get_file_stream<W: tokio::io::AsyncWrite + Send + Unpin + ?Sized>(&self, s3_path: impl AsRef<str>, w: &mut W) -> Result<()> {
         Self::validate_path(s3_path.as_ref());
         let code = self.bucket.get_object_to_writer(s3_path.as_ref(), w).await?;
 
-        if code != 200 {
-            log::warn!("get_file_stream: unexpected response {} getting {}", code, s3_path.as_ref());
+        check_status(self.strict.status, "get_file_stream", s3_path.as_ref(), code, &[200])
+    }
+
+    async fn get_file_range<W: tokio::io::AsyncWrite + Send + Unpin + ?Sized>(&self, s3_path: impl AsRef<str>,
+                                                                            start: u64, end: u64, w: &mut W) -> Result<bool> {
+        Self::validate_path(s3_path.as_ref());
+        let code = self.bucket.get_object_range_to_writer(s3_path.as_ref(), start, Some(end), w).await?;
+        if code == 200 {
+            return Ok(false);
         }
-        Ok(())
+        check_status(self.strict.status, "get_file_range", s3_path.as_ref(), code, &[206])?;
+        Ok(true)
     }
 
     async fn delete(&self, s3_path: impl AsRef<str>) -> Result<()> {
         Self::validate_path(s3_path.as_ref());
         let response = self.bucket.delete_object(s3_path.as_ref()).await?;
 
-        log::info!("deleted '{}'", s3_path.as_ref());
+        log::info!("deleted '{}'", key_display(s3_path.as_ref()));
+
+        check_status(self.strict.status, "delete", s3_path.as_ref(), response.status_code(),
+                     self.profile.delete_statuses)
+    }
+
+    async fn copy(&self, from: impl AsRef<str>, to: impl AsRef<str>) -> Result<()> {
+        Self::validate_path(from.as_ref());
+        Self::validate_path(to.as_ref());
+        let code = self.bucket.copy_object_internal(from.as_ref(), to.as_ref()).await?;
+        log::debug!("copied '{}' to '{}'", key_display(from.as_ref()), key_display(to.as_ref()));
+        check_status(self.strict.status, "copy", to.as_ref(), code, &[200])
+    }
 
-        if response.status_code() != 204 {
-            log::warn!("delete: unexpected response {} deleting {}", response.status_code(), s3_path.as_ref());
+    async fn server_date(&self, path: &str) -> Result<Option<String>> {
+        Self::validate_path(path);
+        match self.bucket.get_object(path).await {
+            Ok(response) => Ok(response.headers().iter()
+                               .find(|(k, _)| k.eq_ignore_ascii_case("date"))
+                               .map(|(_, v)| v.clone())),
+            Err(s3::error::S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
         }
-        Ok(())
     }
 
     async fn head(&self, path: impl AsRef<str>) -> Result<s3::serde_types::HeadObjectResult> {
@@ -244,6 +1109,35 @@ impl Connection {
         Ok(vec![])
     }
 
+    async fn list_objects(&self, path: impl AsRef<str>) -> Result<Vec<ObjectInfo>> {
+        Self::validate_path(path.as_ref());
+        let mut objects = Vec::new();
+        for result in self.bucket.list(String::from(path.as_ref()), None).await? {
+            objects.extend(result.contents.into_iter().map(ObjectInfo::from));
+        }
+        Ok(objects)
+    }
+
+    async fn list_page(&self, path: &str, after: Option<&str>) -> Result<Vec<ObjectInfo>> {
+        Self::validate_path(path);
+        let (result, _) = self.bucket.list_page(path.to_owned(), None, None, after.map(String::from), None).await?;
+        Ok(result.contents.into_iter().map(ObjectInfo::from).collect())
+    }
+
+    async fn list_dirs_page(&self, prefix: &str, start_after: Option<&str>, limit: usize)
+                            -> Result<(Vec<String>, Option<String>)> {
+        Self::validate_path(prefix);
+        let (result, _) = self.bucket.list_page(prefix.to_owned(), Some("/".to_owned()), None,
+                                                start_after.map(String::from), Some(limit)).await?;
+        let dirs: Vec<String> = result.common_prefixes.unwrap_or_default().into_iter().map(|cp| cp.prefix).collect();
+        let next = if result.is_truncated {
+            dirs.last().map(|d| key_after_dir(d)).max(result.contents.last().map(|o| o.key.clone()))
+        } else {
+            None
+        };
+        Ok((dirs, next))
+    }
+
     async fn recursive_visit_<F, Fut>(&self, path: impl AsRef<str>, f: F) -> Result<()>
      where F: Sync + Send + Fn(String) -> Fut,
            Fut: std::future::Future<Output = Result<()>>
@@ -275,48 +1169,406 @@ impl Connection {
         self.recursive_visit_(path, |x| async {
             let p = x.clone();
             if let Err(e) = self.delete(x).await {
-                log::warn!("Error deleting '{:?}': {}, continuing...", p, e);
+                if e.is_not_found() {
+                    log::debug!("'{}' already gone", key_display(&p));
+                    return Ok(());
+                }
+                if self.strict.deletes {
+                    return Err(e);
+                }
+                log::warn!("Error deleting '{}': {}, continuing...", key_display(&p), e);
             }
             Ok(()) // squash the error and continue
         }).await
     }
 
-    async fn recursive_expire(&self, path: impl AsRef<str>,
-                              expiry_time: chrono::DateTime<chrono::Utc>) -> Result<()> {
-        log::debug!("recursive_expire {} older than {}", path.as_ref(), &expiry_time);
-        self.recursive_visit_(path, |obj_path| async {
-            let p = obj_path.clone();
-
-            match self.head(obj_path).await {
-                Ok(result) => {
-                    match result.last_modified.ok_or(Error::OptionWasNoneError)
-                        .and_then(|d| chrono::DateTime::parse_from_rfc2822(d.as_ref())
-                                  .map_err(Error::DateTimeParseError)) {
-                            Ok(modified) => {
-                                if modified < expiry_time {
-                                    if let Err(e) =  self.delete(&p).await {
-                                        log::info!("Failed to delete expired object '{:?}': {}: continuing...", &p, e);
-                                    }
-                                }
-                            },
-                            Err(e) => {
-                                log::info!("Unable to find modification time while expiring '{:?}': {}: continuing...", &p, e);
-                                if let Err(e) = self.delete(&p).await {
-                                    log::debug!("Delete failed on object '{:?}' that doesn't have valid modification time: {}", p, e);
-                                }
-                            }
-                    }
-                },
-                Err(e) => {
-                    // if its not there - try deleting it
-                    log::warn!("Error calling head while expiring '{:?}': {}: expiring it...", &p, e);
-                    if let Err(e) = self.delete(&p).await {
-                        log::debug!("Delete failed on object '{:?}' that doesn't respond to head: {}", p, e);
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn credentials(key: &str) -> Credentials {
+        Credentials::new(Some(key), Some("secret"), None, None, None).unwrap()
+    }
+
+    fn expired() -> Error {
+        Error::S3Error(s3::error::S3Error::HttpFailWithBody(
+            400, "<Error><Code>ExpiredToken</Code></Error>".into()))
+    }
+
+    /// Hands out key0, key1, ... counting how often it was asked
+    fn counting_source() -> (CredentialSource, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let provider = move || Ok(credentials(&format!("key{}", c.fetch_add(1, Ordering::SeqCst))));
+        (CredentialSource::new(Arc::new(provider)).unwrap(), calls)
+    }
+
+    #[test]
+    fn credentials_by_precedence() {
+        assert!(CredentialsChoice::default().provider().is_none());
+
+        let keys = CredentialsChoice {
+            access_key_id: Some("id".into()),
+            secret_access_key: Some("secret".into()),
+            profile: Some("s3-cache-no-such-profile".into()),
+        };
+        assert_eq!(keys.provider().unwrap().credentials().unwrap().access_key.as_deref(), Some("id"));
+
+        let profile = CredentialsChoice { access_key_id: None, secret_access_key: None, ..keys };
+        let err = profile.provider().unwrap().credentials().unwrap_err();
+        assert!(matches!(&err, Error::CredentialsFrom { from, .. } if from == "profile 's3-cache-no-such-profile'"), "{}", err);
+        assert!(err.is_auth());
+    }
+
+    #[tokio::test]
+    async fn expired_credentials_are_refreshed() {
+        let (source, calls) = counting_source();
+        let key = source.with_refresh(|c| async move {
+            if c.access_key.as_deref() == Some("key0") { Err(expired()) } else { Ok(c.access_key) }
+        }).await.unwrap();
+        assert_eq!(key.as_deref(), Some("key1"));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(source.get().access_key.as_deref(), Some("key1"));
+    }
+
+    #[tokio::test]
+    async fn other_errors_do_not_refresh() {
+        let (source, calls) = counting_source();
+        let result: Result<()> = source.with_refresh(|_| async {
+            Err(Error::S3Error(s3::error::S3Error::HttpFailWithBody(403, "AccessDenied".into())))
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn unexpected_status() {
+        assert!(check_status(false, "put_file", "a", 200, &[200]).is_ok());
+        assert!(check_status(true, "put_file", "a", 200, &[200]).is_ok());
+        assert!(check_status(false, "delete", "a", 200, &[204]).is_ok());
+        assert!(check_status(true, "delete", "a", 200, &[200, 204]).is_ok());
+        assert!(matches!(check_status(true, "delete", "a", 200, &[204]),
+                         Err(Error::UnexpectedStatus { operation: "delete", status: 200, .. })));
+    }
+
+    #[tokio::test]
+    async fn object_streams_end_in_the_fetch_error() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut content = Vec::new();
+        let mut whole = ObjectStream::spawn(|mut w| async move { Ok(w.write_all(&[7; PIPE_CAPACITY * 3]).await?) });
+        whole.read_to_end(&mut content).await.unwrap();
+        assert_eq!(content, vec![7; PIPE_CAPACITY * 3]);
+
+        let mut failed = ObjectStream::spawn(|mut w| async move {
+            w.write_all(b"partial").await?;
+            Err(Error::OptionWasNoneError)
+        });
+        content.clear();
+        assert!(failed.read_to_end(&mut content).await.is_err());
+        assert_eq!(content, b"partial");
+    }
+
+    /// An endpoint where nothing is found by HEAD, but conditional puts and
+    /// upload completions are refused as if something landed since
+    fn racing_endpoint(head: &str) -> String {
+        let body = |status: &str, xml: &str| format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}", status, xml.len(), xml);
+        let refused = || body("412 Precondition Failed", "<Error><Code>PreconditionFailed</Code></Error>");
+        let conditional = head.contains("if-none-match: *");
+        match head.split(' ').next() {
+            Some("head") => empty_response("404 Not Found"),
+            Some("post") if head.contains("?uploads") => body("200 OK", concat!(
+                "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>k</Key>",
+                "<UploadId>u1</UploadId></InitiateMultipartUploadResult>")),
+            Some("post") | Some("put") if conditional => refused(),
+            Some("put") => "HTTP/1.1 200 OK\r\nETag: \"e\"\r\nContent-Length: 0\r\n\r\n".into(),
+            Some("delete") => empty_response("204 No Content"),
+            _ => empty_response("500 Internal Server Error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_request_making_an_object_is_conditional() {
+        let (endpoint, _, heads) = mock_endpoint(Duration::ZERO, racing_endpoint);
+        let s = builder().endpoint(&endpoint).storage().unwrap();
+        // each request's method, marked if conditional or part of the upload
+        let requests = || std::mem::take(&mut *heads.lock().unwrap()).into_iter()
+            .map(|h| format!("{}{}{}", h.split(' ').next().unwrap(),
+                             if h.contains("if-none-match") { " if-none-match" } else { "" },
+                             if h.contains("uploadid=u1") { " u1" } else { "" }))
+            .collect::<Vec<_>>();
+        let put = |key: &'static str, size: usize| {
+            let s = s.clone();
+            async move { s.put_file_unless_exists(&mut std::io::Cursor::new(vec![7u8; size]), key, "text/plain", &[]).await }
+        };
+
+        put("objects/aa/bin", 10).await.unwrap();
+        assert_eq!(requests(), ["head", "put if-none-match"]);
+
+        // only objects are shared between uploads
+        put("cache/c/files/a", 10).await.unwrap();
+        assert_eq!(requests(), ["head", "put"]);
+
+        // the parts of a larger object aren't, and the refused upload is aborted
+        put("objects/bb/bin", PART_SIZE + 1).await.unwrap();
+        assert_eq!(requests(), ["head", "post", "put u1", "put u1", "post if-none-match u1", "delete u1"]);
+    }
+
+    #[test]
+    fn conditional_put_finding_an_object_succeeds() {
+        let refused = || Err(s3::error::S3Error::HttpFailWithBody(412, "<Code>PreconditionFailed</Code>".into()));
+        assert!(matches!(put_status(refused(), true), Ok(None)));
+        assert!(matches!(put_status(Ok(412), true), Ok(None)));
+        assert!(matches!(put_status(Ok(200), true), Ok(Some(200))));
+        // only a conditional put expects to be refused
+        assert!(matches!(put_status(refused(), false), Err(Error::S3Error(_))));
+        assert!(matches!(put_status(Ok(412), false), Ok(Some(412))));
+        assert!(put_status(Err(s3::error::S3Error::HttpFailWithBody(500, "".into())), true).is_err());
+    }
+
+    fn builder() -> StorageBuilder {
+        Storage::builder("bucket").credentials(Arc::new(|| Ok(credentials("key"))))
+    }
+
+    #[test]
+    fn builder_defaults() {
+        let b = builder();
+        assert!(!b.create_if_missing);
+        let s = b.endpoint("http://localhost:9000").storage().unwrap();
+        assert_eq!(s.bucket_name(), "bucket");
+        assert_eq!(s.endpoint(), "http://localhost:9000");
+        assert_eq!(s.region.to_string(), "global");
+        assert!(!s.accept_invalid_certs);
+        assert_eq!(s.connect_timeout, None);
+        assert_eq!(s.strictness(), Strictness::default());
+        assert!(!s.force_layout());
+        assert!(s.bucket(credentials("key")).unwrap().is_path_style());
+        assert_eq!(s.provider_profile(), ProviderProfile::default());
+    }
+
+    #[test]
+    fn profile_chooses_addressing() {
+        let s = builder().endpoint("https://s3.ap-southeast-2.amazonaws.com").storage().unwrap();
+        assert_eq!(s.provider_profile().provider, Provider::Aws);
+        assert!(!s.bucket(credentials("key")).unwrap().is_path_style());
+        let s = builder().endpoint("https://s3.ap-southeast-2.amazonaws.com").provider_profile(Provider::Minio)
+            .storage().unwrap();
+        assert!(s.bucket(credentials("key")).unwrap().is_path_style());
+    }
+
+    #[test]
+    fn prefix_is_applied_to_keys() {
+        let s = builder().endpoint("http://localhost:9000").storage().unwrap();
+        assert_eq!((s.prefix(), s.key("cache/c/entry").as_str()), (None, "cache/c/entry"));
+
+        let s = builder().endpoint("http://localhost:9000").prefix("/team-a").storage().unwrap();
+        assert_eq!(s.prefix(), Some("team-a/"));
+        assert_eq!(s.key("cache/c/entry"), "team-a/cache/c/entry");
+        assert_eq!(s.path_of("team-a/objects/aa".into()), "objects/aa");
+        let info = ObjectInfo { key: "team-a/cache/c/entry".into(), size: 1, last_modified: None };
+        assert_eq!(s.info_of(info).key, "cache/c/entry");
+
+        for bad in ["", "a/../b", "cache/"] {
+            assert!(matches!(builder().endpoint("http://localhost:9000").prefix(bad).storage(),
+                             Err(Error::InvalidPrefix(_))), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn offline_fails_before_the_network() {
+        // nothing listens on port 9, so any request would fail differently
+        let s = builder().endpoint("http://127.0.0.1:9").prefix("team").offline(true).connect().await.unwrap();
+        assert!(s.is_offline());
+        match s.get_file(&mut Vec::<u8>::new(), "cache/c/entry").await {
+            Err(Error::OfflineModeViolation { operation: "get", key }) => assert_eq!(key, "team/cache/c/entry"),
+            other => panic!("expected OfflineModeViolation, got {:?}", other),
+        }
+        assert!(matches!(s.copy("a", "b").await, Err(Error::OfflineModeViolation { operation: "copy", .. })));
+        assert!(matches!(s.get_tags("a").await, Err(Error::OfflineModeViolation { operation: "get_tags", .. })));
+        assert!(matches!(s.layout().await, Err(Error::OfflineModeViolation { operation: "head", .. })));
+    }
+
+    #[test]
+    fn bucket_is_configured_once() {
+        let s = builder().endpoint("http://localhost:9000").storage().unwrap();
+        let first = s.connection(credentials("key")).unwrap();
+        let again = s.clone().connection(credentials("key")).unwrap();
+        assert!(Arc::ptr_eq(&first.bucket, &again.bucket), "clones should share the bucket");
+
+        // new credentials need a new bucket, which is then shared in turn
+        let refreshed = s.connection(credentials("other")).unwrap();
+        assert!(!Arc::ptr_eq(&first.bucket, &refreshed.bucket));
+        assert!(Arc::ptr_eq(&refreshed.bucket, &s.connection(credentials("other")).unwrap().bucket));
+    }
+
+    #[test]
+    fn failed_connections_are_replaced_once() {
+        let s = builder().endpoint("http://localhost:9000").storage().unwrap();
+        let failed = s.connection(credentials("key")).unwrap();
+        // two requests failing on the same bucket replace it once
+        s.forget(&failed.bucket);
+        let fresh = s.connection(credentials("key")).unwrap();
+        assert!(!Arc::ptr_eq(&failed.bucket, &fresh.bucket));
+        s.forget(&failed.bucket);
+        assert!(Arc::ptr_eq(&fresh.bucket, &s.connection(credentials("key")).unwrap().bucket));
+    }
+
+    /// Requests a [mock_endpoint] was sent, each as its lowercased head
+    type Heads = Arc<std::sync::Mutex<Vec<String>>>;
+
+    /// An endpoint taking delay to accept each connection, as a TLS
+    /// handshake would, and replying to each request with what answer gives
+    /// for its head.  Returns where it is, the connections it accepted and
+    /// the requests.
+    fn mock_endpoint(delay: Duration, answer: fn(&str) -> String) -> (String, Arc<AtomicUsize>, Heads) {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (accepted, heads) = (Arc::new(AtomicUsize::new(0)), Heads::default());
+        let (counted, sent) = (accepted.clone(), heads.clone());
+        std::thread::spawn(move || for stream in listener.incoming() {
+            let (mut stream, sent) = (stream.unwrap(), sent.clone());
+            counted.fetch_add(1, Ordering::SeqCst);
+            std::thread::spawn(move || {
+                std::thread::sleep(delay);
+                let (mut request, mut buf) = (Vec::new(), vec![0; 1 << 16]);
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    request.extend_from_slice(&buf[..n]);
+                    while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                        let body = head.lines().find_map(|l| l.strip_prefix("content-length:"))
+                            .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+                        if request.len() < end + 4 + body {
+                            break;
+                        }
+                        request.drain(..end + 4 + body);
+                        stream.write_all(answer(&head).as_bytes()).unwrap();
+                        sent.lock().unwrap().push(head);
                     }
                 }
+            });
+        });
+        (endpoint, accepted, heads)
+    }
+
+    fn empty_response(status: &str) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status)
+    }
+
+    #[tokio::test]
+    async fn sequential_requests_share_a_connection() {
+        const CALLS: usize = 100;
+        let (endpoint, accepted, _) = mock_endpoint(Duration::from_millis(5), |_| empty_response("200 OK"));
+        let s = builder().endpoint(&endpoint).storage().unwrap();
+
+        let started = std::time::Instant::now();
+        for _ in 0..CALLS {
+            assert!(s.exists("key").await.unwrap());
+        }
+        let persistent = started.elapsed();
+        assert_eq!(accepted.swap(0, Ordering::SeqCst), 1);
+
+        // as if every request connected afresh
+        let started = std::time::Instant::now();
+        for _ in 0..CALLS {
+            s.forget(&s.connection(credentials("key")).unwrap().bucket);
+            assert!(s.exists("key").await.unwrap());
+        }
+        let fresh = started.elapsed();
+        assert_eq!(accepted.load(Ordering::SeqCst), CALLS);
+        assert!(persistent < fresh, "{} exists calls took {:?} on one connection, {:?} connecting each time",
+                CALLS, persistent, fresh);
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let retry = RetryConfig { max_attempts: 10, initial_backoff: Duration::from_millis(100), max_backoff: Duration::from_secs(1) };
+        for (attempt, full) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+            let delay = retry.backoff(attempt);
+            let full = Duration::from_millis(full);
+            assert!(delay >= full / 2 && delay <= full, "attempt {} waited {:?}", attempt, delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let retry = RetryConfig { max_attempts: 3, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+        let s = builder().endpoint("http://localhost:9000").retry(retry).storage().unwrap();
+        let attempts = AtomicU64::new(0);
+        let failing = |e: fn() -> Error| {
+            let attempts = &attempts;
+            move || async move {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(e())
             }
-            Ok(()) // squash the error and continue
-        }).await
+        };
+        let transient = || Error::ConnectTimeout(Duration::from_secs(1));
+
+        assert!(s.retrying(failing(transient), || true).await.is_err());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 3);
+        assert!(s.retrying(failing(|| Error::NoEndpoint), || true).await.is_err());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 1, "permanent failures aren't retried");
+        assert!(s.retrying(failing(transient), || false).await.is_err());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 1, "unsafe retries aren't made");
+
+        let flaky = || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(transient()),
+                n => Ok(n),
+            }
+        };
+        assert_eq!(s.retrying(flaky, || true).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn throttling_cuts_requests_in_flight() {
+        let retry = RetryConfig { max_attempts: 50, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+        let s = builder().endpoint("http://localhost:9000").retry(retry).max_requests(32).storage().unwrap();
+        // a provider that refuses more than 4 requests at once
+        let busy = Arc::new(AtomicUsize::new(0));
+        let mut set = tokio::task::JoinSet::new();
+        for _ in 0..32 {
+            let (s, busy) = (s.clone(), busy.clone());
+            set.spawn(async move {
+                s.retrying(|| async {
+                    let load = busy.fetch_add(1, Ordering::SeqCst) + 1;
+                    tokio::time::sleep(Duration::from_millis(2)).await;
+                    busy.fetch_sub(1, Ordering::SeqCst);
+                    if load > 4 {
+                        Err(Error::S3Error(s3::error::S3Error::HttpFailWithBody(429, String::new())))
+                    } else {
+                        Ok(())
+                    }
+                }, || true).await
+            });
+        }
+        while let Some(done) = set.join_next().await {
+            done.unwrap().expect("every request completes once throttled");
+        }
+        let stats = s.throttle_stats();
+        assert!(stats.throttled > 0);
+        assert!(stats.lowest <= 4, "{:?}", stats);
+        assert!(stats.concurrency < 32, "{:?}", stats);
     }
 
+    #[test]
+    fn builder_needs_endpoint() {
+        assert!(matches!(builder().storage(), Err(Error::NoEndpoint)));
+        let s = StorageBuilder::new("bucket", "http://localhost:9000").skip_cert_validation(true)
+            .credentials(Arc::new(|| Ok(credentials("key")))).storage().unwrap();
+        assert!(s.accept_invalid_certs);
+    }
+
+    #[test]
+    fn stale_refresh_is_ignored() {
+        let (source, calls) = counting_source();
+        let stale = source.get();
+        source.refresh(&stale).unwrap();
+        // a second task holding the same stale credentials shouldn't refresh again
+        source.refresh(&stale).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
 }
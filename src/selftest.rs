@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use crate::{actions, cache::{self, Cache}, object::ObjectKey, Error, Result, Storage};
+
+/// Small enough that the fixture exercises both storage routes quickly
+const THRESHOLD: usize = 1024;
+
+/// Tuning for [selftest]
+#[derive(Debug, Clone)]
+pub struct SelftestOptions {
+    /// Also delete the deduplicated objects the fixture uploaded
+    pub delete_objects: bool,
+    /// Maximum number of parallel network connections
+    pub max_in_flight: u32,
+}
+
+impl Default for SelftestOptions {
+    fn default() -> Self {
+        SelftestOptions { delete_objects: false, max_in_flight: 3 }
+    }
+}
+
+fn write(root: &Path, path: &str, content: &[u8]) -> Result<PathBuf> {
+    let full = root.join(path);
+    if let Some(parent) = full.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&full, content)?;
+    Ok(PathBuf::from(path))
+}
+
+/// Populate root with files either side of [THRESHOLD], an executable and
+/// (where supported) a symlink.  Content is random so the objects are
+/// unique to this run and safe to delete afterwards.  Returns the paths
+/// created, relative to root.
+pub(crate) fn generate(root: &Path) -> Result<Vec<PathBuf>> {
+    let random = |len| std::iter::repeat_with(|| fastrand::u8(..)).take(len).collect::<Vec<u8>>();
+    let mut files = vec![
+        write(root, "small.txt", b"small file below the threshold\n")?,
+        write(root, "dir/large.bin", &random(4 * THRESHOLD))?,
+        write(root, "dir/nested/large2.bin", &random(THRESHOLD + 1))?,
+    ];
+
+    let exe = write(root, "run.sh", b"#!/bin/sh\necho selftest\n")?;
+    #[cfg(unix)]
+    std::fs::set_permissions(root.join(&exe), std::fs::Permissions::from_mode(0o755))?;
+    files.push(exe);
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink("../small.txt", root.join("dir/link"))?;
+        files.push(PathBuf::from("dir/link"));
+    }
+    Ok(files)
+}
+
+fn mismatch(path: &Path, what: &str) -> anyhow::Error {
+    Error::SelftestMismatch(format!("{}: {}", path.display(), what)).into()
+}
+
+/// Check each of files under actual matches the original under expected
+pub(crate) fn compare(expected: &Path, actual: &Path, files: &[PathBuf]) -> Result<()> {
+    for f in files {
+        let (e, a) = (expected.join(f), actual.join(f));
+        let em = std::fs::symlink_metadata(&e)?;
+        let am = std::fs::symlink_metadata(&a).map_err(|_| mismatch(f, "missing"))?;
+
+        if em.is_symlink() {
+            if !am.is_symlink() {
+                return Err(mismatch(f, "not a symlink"));
+            }
+            if std::fs::read_link(&e)? != std::fs::read_link(&a)? {
+                return Err(mismatch(f, "symlink target differs"));
+            }
+            continue;
+        }
+
+        if std::fs::read(&e)? != std::fs::read(&a)? {
+            return Err(mismatch(f, "content differs"));
+        }
+        #[cfg(unix)]
+        if em.permissions().mode() & 0o7777 != am.permissions().mode() & 0o7777 {
+            return Err(mismatch(f, "mode differs"));
+        }
+    }
+    Ok(())
+}
+
+/// Storage keys of the deduplicated objects for the fixture's large files
+async fn object_keys(root: &Path, files: &[PathBuf]) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for f in files {
+        let path = root.join(f);
+        let meta = std::fs::symlink_metadata(&path)?;
+        if !meta.is_file() || meta.len() <= THRESHOLD as u64 {
+            continue;
+        }
+        let hash = cache::read_hash(async_std::path::Path::new(&path), &Some(meta.len())).await?;
+        keys.push(ObjectKey::from_digest(&hash).storage_key());
+    }
+    Ok(keys)
+}
+
+fn stage<T>(name: &str, result: Result<T>) -> Result<T> {
+    match &result {
+        Ok(_) => println!("PASS {}", name),
+        Err(e) => println!("FAIL {}: {:#}", name, e),
+    }
+    result.with_context(|| format!("selftest failed at {}", name))
+}
+
+/// Upload with the fixture's parent as the working directory, so the entry
+/// holds relative paths
+async fn upload(storage: &Storage, cache_name: &str, base: &Path, options: &SelftestOptions) -> Result<()> {
+    let cwd = std::env::current_dir()?;
+    std::env::set_current_dir(base)?;
+    let upload_options = actions::UploadOptions {
+        recurse: true,
+        threshold: THRESHOLD,
+        max_in_flight: options.max_in_flight,
+        ..Default::default()
+    };
+    let result = actions::upload(storage.clone(), cache_name, &[PathBuf::from("fixture")], &upload_options).await;
+    std::env::set_current_dir(cwd)?;
+    result.map(drop)
+}
+
+async fn run(storage: &Storage, cache_name: &str, base: &Path, files: &[PathBuf],
+             options: &SelftestOptions) -> Result<()> {
+    let download_options = actions::DownloadOptions { max_in_flight: options.max_in_flight, ..Default::default() };
+    stage("upload", upload(storage, cache_name, base, options).await)?;
+    stage("download", actions::download(storage.clone(), cache_name, base.join("out"), &download_options).await)?;
+    stage("verify", compare(&base.join("fixture"), &base.join("out/fixture"), files))?;
+    stage("delete", actions::delete(storage.clone(), cache_name, false).await)
+}
+
+/// Remove everything this run may have created, returning what couldn't be
+async fn cleanup(storage: &Storage, cache_name: &str, base: &Path, objects: &[String]) -> Vec<String> {
+    let mut leftovers = Vec::new();
+
+    let location = Cache::location(cache_name);
+    let location = format!("{}/", location.to_string_lossy());
+    if let Err(e) = storage.recursive_delete(&location).await {
+        log::debug!("Cleanup of {} failed: {}", location, e);
+    }
+    match storage.list_objects(&location).await {
+        Ok(remaining) => leftovers.extend(remaining.into_iter().map(|o| o.key)),
+        Err(_) => leftovers.push(location),
+    }
+
+    for key in objects {
+        if let Err(e) = storage.delete(key).await {
+            log::debug!("Cleanup of {} failed: {}", key, e);
+        }
+        if !matches!(storage.head(key).await, Ok(None)) {
+            leftovers.push(key.clone());
+        }
+    }
+
+    if let Err(e) = std::fs::remove_dir_all(base) {
+        log::debug!("Cleanup of {} failed: {}", base.display(), e);
+    }
+    if base.exists() {
+        leftovers.push(base.display().to_string());
+    }
+    leftovers
+}
+
+/// Round trip a generated fixture through a throwaway cache, printing a
+/// pass/fail line per stage.  Cleanup is best-effort and happens whether or
+/// not the stages pass.
+pub async fn selftest(storage: Storage, options: &SelftestOptions) -> Result<()> {
+    let id = uuid::Uuid::new_v4();
+    let cache_name = format!("selftest-{}", id);
+    let base = std::env::temp_dir().join(format!("s3-cache-selftest-{}", id));
+
+    let files = stage("generate", generate(&base.join("fixture")));
+    let result = match &files {
+        Ok(files) => run(&storage, &cache_name, &base, files, options).await,
+        Err(_) => Ok(()),
+    };
+    let objects = match (&files, options.delete_objects) {
+        (Ok(files), true) => object_keys(&base.join("fixture"), files).await.unwrap_or_else(|e| {
+            log::warn!("Unable to determine uploaded objects: {:#}", e);
+            Vec::new()
+        }),
+        _ => Vec::new(),
+    };
+
+    let leftovers = cleanup(&storage, &cache_name, &base, &objects).await;
+    if leftovers.is_empty() {
+        println!("PASS cleanup");
+    } else {
+        println!("FAIL cleanup");
+        log::warn!("selftest left behind:\n  {}", leftovers.join("\n  "));
+    }
+
+    files?;
+    result?;
+    println!("selftest passed using cache '{}'", cache_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn fixture() -> (tempfile::TempDir, PathBuf, PathBuf, Vec<PathBuf>) {
+        let dir = tempfile::tempdir().unwrap();
+        let (expected, actual) = (dir.path().join("expected"), dir.path().join("actual"));
+        let files = generate(&expected).unwrap();
+        copy(&expected, &actual, &files);
+        (dir, expected, actual, files)
+    }
+
+    fn copy(from: &Path, to: &Path, files: &[PathBuf]) {
+        for f in files {
+            let dest = to.join(f);
+            std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+            let meta = std::fs::symlink_metadata(from.join(f)).unwrap();
+            #[cfg(unix)]
+            if meta.is_symlink() {
+                std::os::unix::fs::symlink(std::fs::read_link(from.join(f)).unwrap(), dest).unwrap();
+                continue;
+            }
+            std::fs::copy(from.join(f), &dest).unwrap();
+            std::fs::set_permissions(&dest, meta.permissions()).unwrap();
+        }
+    }
+
+    fn is_mismatch(r: Result<()>) -> bool {
+        matches!(r.unwrap_err().downcast_ref::<Error>(), Some(Error::SelftestMismatch(_)))
+    }
+
+    #[test]
+    fn fixture_covers_both_routes() {
+        let (_dir, expected, _, files) = fixture();
+        let sizes: Vec<u64> = files.iter()
+            .map(|f| std::fs::symlink_metadata(expected.join(f)).unwrap())
+            .filter(|m| m.is_file()).map(|m| m.len()).collect();
+        assert!(sizes.iter().any(|&s| s <= THRESHOLD as u64));
+        assert!(sizes.iter().any(|&s| s > THRESHOLD as u64));
+        #[cfg(unix)]
+        assert!(files.iter().any(|f| std::fs::symlink_metadata(expected.join(f)).unwrap().is_symlink()));
+    }
+
+    #[test]
+    fn identical_trees_match() {
+        let (_dir, expected, actual, files) = fixture();
+        compare(&expected, &actual, &files).unwrap();
+    }
+
+    #[test]
+    fn content_and_missing_files_differ() {
+        let (_dir, expected, actual, files) = fixture();
+        std::fs::write(actual.join("dir/large.bin"), b"changed").unwrap();
+        assert!(is_mismatch(compare(&expected, &actual, &files)));
+        std::fs::remove_file(actual.join("dir/large.bin")).unwrap();
+        assert!(is_mismatch(compare(&expected, &actual, &files)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mode_and_link_differ() {
+        let (_dir, expected, actual, files) = fixture();
+        std::fs::set_permissions(actual.join("run.sh"), std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(is_mismatch(compare(&expected, &actual, &files)));
+
+        let (_dir, expected, actual, files) = fixture();
+        std::fs::remove_file(actual.join("dir/link")).unwrap();
+        std::os::unix::fs::symlink("../run.sh", actual.join("dir/link")).unwrap();
+        assert!(is_mismatch(compare(&expected, &actual, &files)));
+    }
+
+    #[tokio::test]
+    async fn objects_for_large_files_only() {
+        let (_dir, expected, _, files) = fixture();
+        let keys = object_keys(&expected, &files).await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|k| k.starts_with("objects/") && k.ends_with("/bin")));
+    }
+}
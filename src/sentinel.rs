@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{actions::DownloadReport, cache::Cache, Result, Storage};
+
+/// Written in the outpath by download --sentinel, and never uploaded
+pub const FILE_NAME: &str = ".s3-cache.sentinel.json";
+
+/// Whether a download restored a cache, for build scripts to test
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sentinel {
+    pub cache: String,
+    pub hit: bool,
+    /// The name asked for, when cache is a fallback restored in its place
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_for: Option<String>,
+    /// When the entry restored was uploaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry_time: Option<DateTime<Utc>>,
+    pub files: usize,
+}
+
+impl Sentinel {
+    /// A restored cache, timed by when its entry was written
+    pub async fn hit(storage: &Storage, report: &DownloadReport) -> Result<Sentinel> {
+        let entry = Cache::entry_location(&report.cache);
+        let entry_time = storage.head(entry.to_str().expect("entry location is utf8")).await?
+            .and_then(|info| info.last_modified);
+        Ok(Sentinel {
+            cache: report.cache.clone(),
+            hit: true,
+            fallback_for: report.fallback_for.clone(),
+            entry_time,
+            files: report.files,
+        })
+    }
+
+    pub fn miss(cache_name: &str) -> Sentinel {
+        Sentinel { cache: cache_name.to_owned(), hit: false, fallback_for: None, entry_time: None, files: 0 }
+    }
+}
+
+/// Write value as JSON to path, replacing any earlier file in one step
+pub fn write(path: &Path, value: &impl Serialize) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&tmp, path).inspect_err(|_| { let _ = std::fs::remove_file(&tmp); })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn miss_is_written_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out").join(FILE_NAME);
+        write(&path, &Sentinel::miss("deps")).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains(r#""hit": false"#));
+        assert_eq!(serde_json::from_str::<Sentinel>(&text).unwrap(), Sentinel::miss("deps"));
+        assert_eq!(std::fs::read_dir(path.parent().unwrap()).unwrap().count(), 1, "temporary file left behind");
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Why upload left out a path it was given or walked
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    /// A local state or sentinel file of our own
+    Internal,
+    /// Matched --exclude
+    Excluded,
+    /// Not a regular file, directory or symlink: a fifo, socket or device
+    SpecialFile,
+    /// The path or symlink target isn't UTF-8, so can't be recorded
+    NonUtf8,
+    /// Dropped to fit --truncate-to-limits
+    OverLimit,
+    /// Unreadable by this process, left out with --keep-going
+    PermissionDenied,
+}
+
+/// Why download didn't restore a file recorded in the entry
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum DownloadSkipReason {
+    /// Not modified after --newer-than
+    NotNewer,
+    /// Didn't match --include
+    NotIncluded,
+    /// Missing or the wrong size in the bucket, left out with --keep-going
+    Damaged,
+    /// Already restored with the right content, per --local-state or
+    /// --if-changed
+    Unchanged,
+    /// A symlink that couldn't be created, left out with --symlinks=skip
+    Symlink,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SkipReason::Internal => "internal",
+            SkipReason::Excluded => "excluded",
+            SkipReason::SpecialFile => "special file",
+            SkipReason::NonUtf8 => "non-UTF-8 name",
+            SkipReason::OverLimit => "over limit",
+            SkipReason::PermissionDenied => "permission denied",
+        })
+    }
+}
+
+impl fmt::Display for DownloadSkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DownloadSkipReason::NotNewer => "not newer",
+            DownloadSkipReason::NotIncluded => "not included",
+            DownloadSkipReason::Damaged => "damaged",
+            DownloadSkipReason::Unchanged => "unchanged",
+            DownloadSkipReason::Symlink => "symlink not created",
+        })
+    }
+}
+
+/// Paths left out, grouped by why.  Every place a file is dropped records
+/// it here, so reports and summaries agree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Skips<R: Ord>(BTreeMap<R, Vec<String>>);
+
+impl<R: Ord> Default for Skips<R> {
+    fn default() -> Self {
+        Skips(BTreeMap::new())
+    }
+}
+
+impl<R: Copy + Ord + fmt::Display> Skips<R> {
+    pub fn add(&mut self, reason: R, path: impl Into<String>) {
+        let path = path.into();
+        log::debug!("Skipping {}: {}", path, reason);
+        self.0.entry(reason).or_default().push(path);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.values().map(Vec::len).sum()
+    }
+
+    /// Paths skipped for reason, in the order they were
+    pub fn paths(&self, reason: R) -> &[String] {
+        self.0.get(&reason).map_or(&[][..], Vec::as_slice)
+    }
+
+    /// How many were skipped for each reason
+    pub fn counts(&self) -> BTreeMap<R, usize> {
+        self.0.iter().map(|(reason, paths)| (*reason, paths.len())).collect()
+    }
+
+    /// eg "3 excluded, 1 special file"
+    pub fn summary(&self) -> String {
+        self.0.iter()
+            .map(|(reason, paths)| format!("{} {}", paths.len(), reason))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn skips_group_by_reason() {
+        let mut skips = Skips::default();
+        assert!(skips.is_empty());
+        skips.add(SkipReason::SpecialFile, "fifo");
+        skips.add(SkipReason::Excluded, "b.o");
+        skips.add(SkipReason::Excluded, "a.o");
+        assert_eq!(skips.len(), 3);
+        assert_eq!(skips.paths(SkipReason::Excluded), ["b.o", "a.o"]);
+        assert!(skips.paths(SkipReason::OverLimit).is_empty());
+        assert_eq!(skips.summary(), "2 excluded, 1 special file");
+        assert_eq!(skips.counts(), BTreeMap::from([(SkipReason::Excluded, 2), (SkipReason::SpecialFile, 1)]));
+        assert_eq!(serde_json::to_string(&skips).unwrap(), r#"{"excluded":["b.o","a.o"],"special-file":["fifo"]}"#);
+        assert_eq!(serde_json::from_str::<Skips<SkipReason>>(&serde_json::to_string(&skips).unwrap()).unwrap(), skips);
+    }
+}
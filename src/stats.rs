@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use chrono::{DateTime, Datelike, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Result, Storage, cache::{self, CacheKey}, s3::ObjectInfo, skip::SkipReason};
+
+/// Upper bound on records kept in one snapshot object, oldest are dropped first
+const MAX_RECORDS: usize = 10_000;
+
+/// One line of the `stats/` snapshot log, appended by each upload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadRecord {
+    pub time: DateTime<Utc>,
+    pub cache: String,
+    pub files: usize,
+    /// Logical size of everything in the entry
+    pub bytes: u64,
+    /// Portion of bytes routed to the deduplicated objects/ store
+    pub deduped_bytes: u64,
+    /// Paths left out to fit --truncate-to-limits
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dropped: Vec<String>,
+    /// Paths left out of the entry, counted by why
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub skipped: std::collections::BTreeMap<SkipReason, usize>,
+}
+
+/// Snapshots rotate monthly so no single object grows without bound
+pub(crate) fn snapshot_location(time: &DateTime<Utc>) -> String {
+    format!("stats/uploads-{}.jsonl", time.format("%Y-%m"))
+}
+
+/// Snapshot objects that may hold records between since and now
+pub(crate) fn snapshot_locations(since: &DateTime<Utc>, now: &DateTime<Utc>) -> Vec<String> {
+    let mut locations = Vec::new();
+    let (mut year, mut month) = (since.year(), since.month());
+    while (year, month) <= (now.year(), now.month()) {
+        locations.push(format!("stats/uploads-{:04}-{:02}.jsonl", year, month));
+        if month == 12 {
+            year += 1;
+            month = 1;
+        } else {
+            month += 1;
+        }
+    }
+    locations
+}
+
+/// Parse JSON lines, skipping those that are damaged (eg racing writers)
+pub(crate) fn parse_lines<T: DeserializeOwned>(v: &[u8]) -> Vec<T> {
+    String::from_utf8_lossy(v).lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|l| serde_json::from_str(l)
+                    .inspect_err(|e| log::debug!("Skipping bad stats record: {}", e))
+                    .ok())
+        .collect()
+}
+
+/// Append record to existing JSON lines, dropping the oldest beyond max
+pub(crate) fn append_line<T: Serialize + DeserializeOwned + Clone>(existing: &[u8], record: &T, max: usize) -> String {
+    let mut records = parse_lines::<T>(existing);
+    records.push(record.clone());
+    let skip = records.len().saturating_sub(max);
+
+    let mut out = String::new();
+    for r in &records[skip..] {
+        out.push_str(&serde_json::to_string(r).expect("stats records should be serialiseable"));
+        out.push('\n');
+    }
+    out
+}
+
+/// Parse snapshot content, skipping damaged lines
+pub(crate) fn parse_records(v: &[u8]) -> Vec<UploadRecord> {
+    parse_lines(v)
+}
+
+/// Append record to existing snapshot content, dropping the oldest beyond max
+pub(crate) fn append_record(existing: &[u8], record: &UploadRecord, max: usize) -> String {
+    append_line(existing, record, max)
+}
+
+/// Content of a JSON lines object, empty if there isn't one yet
+pub(crate) async fn read_snapshot(storage: &Storage, location: &str) -> Result<Vec<u8>> {
+    let mut vec = Vec::<u8>::new();
+    match storage.get_file(&mut vec, location).await {
+        Ok(()) => Ok(vec),
+        Err(e) if e.is_not_found() => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn try_record_upload(storage: &Storage, record: &UploadRecord) -> Result<()> {
+    let location = snapshot_location(&record.time);
+    let existing = read_snapshot(storage, &location).await?;
+    let content = append_record(&existing, record, MAX_RECORDS);
+    storage.put_file(&mut std::io::Cursor::new(content), &location).await?;
+    Ok(())
+}
+
+/// Best-effort append to the stats snapshot - failures are logged, never returned
+pub(crate) async fn record_upload(storage: &Storage, record: UploadRecord) {
+    if let Err(e) = try_record_upload(storage, &record).await {
+        log::info!("Unable to record upload statistics: {:#}", e);
+    }
+}
+
+async fn read_records(storage: &Storage, since: &DateTime<Utc>, now: &DateTime<Utc>) -> Result<Vec<UploadRecord>> {
+    let mut records = Vec::new();
+    for location in snapshot_locations(since, now) {
+        records.extend(parse_records(&read_snapshot(storage, &location).await?));
+    }
+    Ok(records)
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct CacheActivity {
+    pub name: String,
+    pub last_upload: Option<DateTime<Utc>>,
+    /// Uploads within the window
+    pub uploads: usize,
+    /// Logical bytes uploaded within the window
+    pub uploaded_bytes: u64,
+    /// Bytes stored under the cache itself (not deduplicated)
+    pub stored_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Totals {
+    pub count: usize,
+    pub bytes: u64,
+}
+
+impl Totals {
+    fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.bytes += size;
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    pub since: DateTime<Utc>,
+    pub generated: DateTime<Utc>,
+    pub caches: Vec<CacheActivity>,
+    pub objects: Totals,
+    pub cache_files: Totals,
+    /// Objects and cache files written within the window
+    pub growth: Totals,
+    pub uploads: usize,
+    pub uploaded_bytes: u64,
+    /// Bytes uploaded per byte of growth within the window
+    pub dedupe_ratio: Option<f64>,
+}
+
+impl Report {
+    /// Aggregate listings of objects/ and cache/ with the upload records
+    pub fn build(since: DateTime<Utc>, now: DateTime<Utc>,
+                 objects: &[ObjectInfo], cache_objects: &[ObjectInfo],
+                 records: &[UploadRecord]) -> Report {
+        let in_window = |t: &Option<DateTime<Utc>>| t.is_some_and(|t| t >= since);
+
+        let mut growth = Totals::default();
+        let mut object_totals = Totals::default();
+        for o in objects {
+            object_totals.add(o.size);
+            if in_window(&o.last_modified) {
+                growth.add(o.size);
+            }
+        }
+
+        let mut caches = std::collections::BTreeMap::<String, CacheActivity>::new();
+        let mut cache_files = Totals::default();
+        for o in cache_objects {
+            let Some((name, key)) = cache::parse_key(&o.key) else {
+                continue;
+            };
+            let activity = caches.entry(name.to_owned()).or_insert_with(|| CacheActivity {
+                name: name.to_owned(), ..Default::default()
+            });
+            match key {
+                CacheKey::Meta("entry") => activity.last_upload = o.last_modified,
+                CacheKey::Meta(_) => {},
+                CacheKey::File(_) => {
+                    activity.stored_bytes += o.size;
+                    cache_files.add(o.size);
+                    if in_window(&o.last_modified) {
+                        growth.add(o.size);
+                    }
+                },
+            }
+        }
+
+        let mut uploads = 0;
+        let mut uploaded_bytes = 0;
+        for r in records.iter().filter(|r| r.time >= since) {
+            uploads += 1;
+            uploaded_bytes += r.bytes;
+            // Deleted caches still count toward the totals, but not the per-cache table
+            if let Some(activity) = caches.get_mut(&r.cache) {
+                activity.uploads += 1;
+                activity.uploaded_bytes += r.bytes;
+            }
+        }
+
+        let dedupe_ratio = (growth.bytes > 0).then(|| uploaded_bytes as f64 / growth.bytes as f64);
+
+        Report {
+            since, generated: now,
+            caches: caches.into_values().collect(),
+            objects: object_totals,
+            cache_files, growth, uploads, uploaded_bytes, dedupe_ratio,
+        }
+    }
+
+    pub fn print_table(&self) {
+        println!("Report from {} to {}", self.since.to_rfc3339(), self.generated.to_rfc3339());
+        let len = self.caches.iter().map(|c| c.name.len()).max().unwrap_or(0).max(30);
+        println!("{:<len$} {:>25} {:>8} {:>14} {:>14}", "cache", "last upload", "uploads", "uploaded", "stored");
+        for c in &self.caches {
+            println!("{:<len$} {:>25} {:>8} {:>14} {:>14}", c.name,
+                     c.last_upload.map_or("-".into(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string()),
+                     c.uploads, c.uploaded_bytes, c.stored_bytes);
+        }
+        println!();
+        println!("objects:     {:>10} {:>16} bytes", self.objects.count, self.objects.bytes);
+        println!("cache files: {:>10} {:>16} bytes", self.cache_files.count, self.cache_files.bytes);
+        println!("growth:      {:>10} {:>16} bytes", self.growth.count, self.growth.bytes);
+        println!("uploads:     {:>10} {:>16} bytes", self.uploads, self.uploaded_bytes);
+        match self.dedupe_ratio {
+            Some(r) => println!("dedupe ratio: {:.2}", r),
+            None => println!("dedupe ratio: -"),
+        }
+    }
+}
+
+pub(crate) async fn report(storage: &Storage, since: DateTime<Utc>) -> Result<Report> {
+    let now = Utc::now();
+    let objects = storage.list_objects(crate::object::ROOT).await?;
+    let cache_objects = storage.list_objects("cache/").await?;
+    let records = read_records(storage, &since, &now).await?;
+    Ok(Report::build(since, now, &objects, &cache_objects, &records))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn record(t: &str, cache: &str, bytes: u64) -> UploadRecord {
+        UploadRecord { time: time(t), cache: cache.into(), files: 1, bytes, deduped_bytes: 0, dropped: Vec::new(), skipped: Default::default() }
+    }
+
+    fn object(key: &str, size: u64, t: &str) -> ObjectInfo {
+        ObjectInfo { key: key.into(), size, last_modified: Some(time(t)) }
+    }
+
+    #[test]
+    fn snapshot_append() {
+        let first = append_record(b"", &record("2025-01-01T00:00:00Z", "a", 1), 10);
+        let second = append_record(first.as_bytes(), &record("2025-01-02T00:00:00Z", "b", 2), 10);
+        let records = parse_records(second.as_bytes());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].cache, "a");
+        assert_eq!(records[1].cache, "b");
+    }
+
+    #[test]
+    fn snapshot_rotation_drops_oldest() {
+        let mut content = String::new();
+        for i in 0..5 {
+            content = append_record(content.as_bytes(), &record("2025-01-01T00:00:00Z", &i.to_string(), i), 3);
+        }
+        let caches: Vec<_> = parse_records(content.as_bytes()).into_iter().map(|r| r.cache).collect();
+        assert_eq!(caches, vec!["2", "3", "4"]);
+    }
+
+    #[test]
+    fn snapshot_skips_damaged_lines() {
+        let good = append_record(b"", &record("2025-01-01T00:00:00Z", "a", 1), 10);
+        let damaged = format!("{}{{\"time\": \"2025-\n", good);
+        assert_eq!(parse_records(damaged.as_bytes()).len(), 1);
+    }
+
+    #[test]
+    fn snapshot_months() {
+        assert_eq!(snapshot_location(&time("2025-03-04T00:00:00Z")), "stats/uploads-2025-03.jsonl");
+        assert_eq!(snapshot_locations(&time("2024-11-30T00:00:00Z"), &time("2025-02-01T00:00:00Z")),
+                   vec!["stats/uploads-2024-11.jsonl", "stats/uploads-2024-12.jsonl",
+                        "stats/uploads-2025-01.jsonl", "stats/uploads-2025-02.jsonl"]);
+    }
+
+    #[test]
+    fn report_aggregation() {
+        let since = time("2025-01-10T00:00:00Z");
+        let now = time("2025-02-01T00:00:00Z");
+        let objects = vec![
+            object("objects/aa/bin", 100, "2025-01-01T00:00:00Z"),
+            object("objects/bb/bin", 200, "2025-01-15T00:00:00Z"),
+        ];
+        let cache_objects = vec![
+            object("cache/one/entry", 50, "2025-01-20T00:00:00Z"),
+            object("cache/one/files/a.txt", 10, "2025-01-20T00:00:00Z"),
+            object("cache/two/entry", 50, "2025-01-02T00:00:00Z"),
+            object("cache/two/files/b.txt", 40, "2025-01-02T00:00:00Z"),
+        ];
+        let records = vec![
+            record("2025-01-02T00:00:00Z", "two", 1000),
+            record("2025-01-20T00:00:00Z", "one", 630),
+            record("2025-01-21T00:00:00Z", "one", 630),
+            record("2025-01-22T00:00:00Z", "deleted", 5),
+        ];
+        let r = Report::build(since, now, &objects, &cache_objects, &records);
+
+        assert_eq!(r.objects, Totals { count: 2, bytes: 300 });
+        assert_eq!(r.cache_files, Totals { count: 2, bytes: 50 });
+        assert_eq!(r.growth, Totals { count: 2, bytes: 210 });
+        assert_eq!(r.uploads, 3);
+        assert_eq!(r.uploaded_bytes, 1265);
+        assert_eq!(r.dedupe_ratio, Some(1265.0 / 210.0));
+
+        assert_eq!(r.caches.len(), 2);
+        assert_eq!(r.caches[0].name, "one");
+        assert_eq!(r.caches[0].uploads, 2);
+        assert_eq!(r.caches[0].uploaded_bytes, 1260);
+        assert_eq!(r.caches[0].stored_bytes, 10);
+        assert_eq!(r.caches[1].name, "two");
+        assert_eq!(r.caches[1].uploads, 0);
+        assert_eq!(r.caches[1].last_upload, Some(time("2025-01-02T00:00:00Z")));
+    }
+
+    #[test]
+    fn report_no_growth_has_no_ratio() {
+        let since = time("2025-01-10T00:00:00Z");
+        let r = Report::build(since, since, &[], &[], &[]);
+        assert_eq!(r.dedupe_ratio, None);
+    }
+}
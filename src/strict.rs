@@ -0,0 +1,21 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+/// Which of the usually warn-and-continue failures should abort instead
+#[derive(serde::Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Strictness {
+    /// Failure to apply file modes on download
+    pub permissions: bool,
+    /// Unexpected (non-error) HTTP status codes from S3
+    pub status: bool,
+    /// Failed deletes while deleting or expiring recursively
+    pub deletes: bool,
+    /// Deleting a cache that doesn't exist
+    pub missing_cache: bool,
+}
+
+impl Strictness {
+    pub fn all() -> Strictness {
+        Strictness { permissions: true, status: true, deletes: true, missing_cache: true }
+    }
+}
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Requests a [Storage](crate::Storage) has in flight before the provider
+/// throttles it
+pub const DEFAULT_MAX_REQUESTS: usize = 64;
+
+/// How requests to the provider were limited over a run
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleStats {
+    /// Requests the provider refused as too many
+    pub throttled: usize,
+    /// Requests allowed in flight at the end
+    pub concurrency: usize,
+    /// The fewest allowed at any point
+    pub lowest: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    max: usize,
+    in_flight: usize,
+    /// Successes since the limit last changed
+    successes: usize,
+    /// Bumped on each cut, so requests already in flight when the limit was
+    /// cut don't cut it again
+    epoch: u64,
+    stats: ThrottleStats,
+}
+
+/// Limits requests in flight, shared by every clone of a Storage.  The
+/// limit halves when the provider throttles a request, and grows by one
+/// for each limit's worth of successes, back up to the maximum.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    state: Mutex<State>,
+    released: tokio::sync::Notify,
+}
+
+/// A request in flight, released when dropped
+pub(crate) struct Slot<'a> {
+    throttle: &'a Throttle,
+    epoch: u64,
+}
+
+impl Throttle {
+    pub fn new(max: usize) -> Throttle {
+        let max = max.max(1);
+        Throttle {
+            state: Mutex::new(State {
+                limit: max, max, in_flight: 0, successes: 0, epoch: 0,
+                stats: ThrottleStats { throttled: 0, concurrency: max, lowest: max },
+            }),
+            released: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("throttle lock poisoned")
+    }
+
+    /// Wait for room for another request
+    pub async fn acquire(&self) -> Slot<'_> {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            {
+                let mut state = self.state();
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    return Slot { throttle: self, epoch: state.epoch };
+                }
+            }
+            released.await;
+        }
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        self.state().stats
+    }
+}
+
+impl Slot<'_> {
+    /// The provider refused the request as one too many
+    pub fn throttled(self) {
+        let mut state = self.throttle.state();
+        state.stats.throttled += 1;
+        if self.epoch != state.epoch {
+            return;
+        }
+        state.epoch += 1;
+        state.successes = 0;
+        let limit = (state.limit / 2).max(1);
+        if limit < state.limit {
+            log::warn!("Throttled by the provider, allowing {} requests in flight", limit);
+        }
+        state.limit = limit;
+        state.stats.concurrency = limit;
+        state.stats.lowest = state.stats.lowest.min(limit);
+    }
+
+    pub fn succeeded(self) {
+        let mut state = self.throttle.state();
+        state.successes += 1;
+        if state.successes >= state.limit && state.limit < state.max {
+            state.successes = 0;
+            state.limit += 1;
+            state.stats.concurrency = state.limit;
+            log::info!("Allowing {} requests in flight", state.limit);
+            self.throttle.released.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.throttle.state().in_flight -= 1;
+        self.throttle.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cuts_once_per_epoch_then_recovers() {
+        let throttle = Throttle::new(8);
+        let mut slots = Vec::new();
+        for _ in 0..8 {
+            slots.push(throttle.acquire().await);
+        }
+        // all in flight when the provider pushed back: one cut, not eight
+        for slot in slots {
+            slot.throttled();
+        }
+        assert_eq!(throttle.stats(), ThrottleStats { throttled: 8, concurrency: 4, lowest: 4 });
+
+        throttle.acquire().await.throttled();
+        assert_eq!(throttle.stats().concurrency, 2);
+
+        for _ in 0..(2 + 3 + 4 + 5 + 6 + 7) {
+            throttle.acquire().await.succeeded();
+        }
+        assert_eq!(throttle.stats(), ThrottleStats { throttled: 9, concurrency: 8, lowest: 2 });
+        throttle.acquire().await.succeeded();
+        assert_eq!(throttle.stats().concurrency, 8, "never above the maximum");
+    }
+
+    #[tokio::test]
+    async fn waits_for_room() {
+        let throttle = Throttle::new(1);
+        let held = throttle.acquire().await;
+        let waiting = tokio::time::timeout(std::time::Duration::from_millis(20), throttle.acquire()).await;
+        assert!(waiting.is_err(), "only one request is allowed");
+        drop(held);
+        tokio::time::timeout(std::time::Duration::from_secs(1), throttle.acquire()).await
+            .expect("room once released");
+    }
+}
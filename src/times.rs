@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+
+use crate::{Error, Result};
+
+/// Which file timestamps to record on upload
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PreserveTimes {
+    /// Don't record timestamps
+    #[default]
+    None,
+    /// Modification time only
+    Mtime,
+    /// Modification and creation (birth) time where the platform reports it
+    All,
+}
+
+/// Timestamps captured from, or to be restored onto, one file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FileTimes {
+    pub modified: Option<DateTime<Utc>>,
+    pub created: Option<DateTime<Utc>>,
+}
+
+/// Read the timestamps selected by preserve from meta
+pub fn capture(meta: &std::fs::Metadata, preserve: PreserveTimes) -> FileTimes {
+    let mut times = FileTimes::default();
+    if preserve == PreserveTimes::None {
+        return times;
+    }
+    times.modified = meta.modified()
+        .inspect_err(|e| log::debug!("No modification time available: {}", e))
+        .ok().map(DateTime::from);
+    if preserve == PreserveTimes::All {
+        // Linux needs statx and a filesystem that records it
+        times.created = meta.created()
+            .inspect_err(|e| log::debug!("No creation time available: {}", e))
+            .ok().map(DateTime::from);
+    }
+    times
+}
+
+/// Parse an RFC 3339 instant, or "@path" for the modification time of path
+pub fn parse_instant(s: &str) -> Result<DateTime<Utc>> {
+    let invalid = || Error::InvalidInstant(s.to_owned());
+    match s.strip_prefix('@') {
+        Some(path) => {
+            let modified = std::fs::metadata(path).and_then(|m| m.modified())
+                .with_context(invalid)?;
+            Ok(modified.into())
+        },
+        None => Ok(DateTime::parse_from_rfc3339(s).with_context(invalid)?.to_utc()),
+    }
+}
+
+#[cfg(windows)]
+fn set_created(times: std::fs::FileTimes, created: DateTime<Utc>) -> std::fs::FileTimes {
+    use std::os::windows::fs::FileTimesExt;
+    times.set_created(created.into())
+}
+
+#[cfg(target_os = "macos")]
+fn set_created(times: std::fs::FileTimes, created: DateTime<Utc>) -> std::fs::FileTimes {
+    use std::os::macos::fs::FileTimesExt;
+    times.set_created(created.into())
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn set_created(times: std::fs::FileTimes, _created: DateTime<Utc>) -> std::fs::FileTimes {
+    // eg Linux has no interface for setting birth time
+    log::debug!("Creation time can't be restored on this platform, skipping");
+    times
+}
+
+/// Apply whichever timestamps were recorded to an open file.  Failure only
+/// loses the timestamps, so it's warned about rather than returned.
+pub fn restore(file: &std::fs::File, path: &std::path::Path, times: &FileTimes) {
+    if times.modified.is_none() && times.created.is_none() {
+        return;
+    }
+    let mut t = std::fs::FileTimes::new();
+    if let Some(modified) = times.modified {
+        t = t.set_modified(modified.into());
+    }
+    if let Some(created) = times.created {
+        t = set_created(t, created);
+    }
+    if let Err(e) = file.set_times(t) {
+        log::warn!("Failed to set times on {}: {}", path.display(), e.kind());
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn capture_selection() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let meta = file.as_file().metadata().unwrap();
+        assert_eq!(capture(&meta, PreserveTimes::None), FileTimes::default());
+
+        let mtime = capture(&meta, PreserveTimes::Mtime);
+        assert!(mtime.modified.is_some());
+        assert!(mtime.created.is_none());
+
+        // created may legitimately be unavailable, but modified must be there
+        assert_eq!(capture(&meta, PreserveTimes::All).modified, mtime.modified);
+    }
+
+    #[test]
+    fn instants() {
+        assert_eq!(parse_instant("2020-01-02T03:04:05+01:00").unwrap(), time("2020-01-02T02:04:05Z"));
+        assert!(parse_instant("yesterday").is_err());
+        assert!(parse_instant("@/does/not/exist").is_err());
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let when = time("2021-06-07T08:09:10Z");
+        restore(file.as_file(), file.path(), &FileTimes { modified: Some(when), created: None });
+        assert_eq!(parse_instant(&format!("@{}", file.path().display())).unwrap(), when);
+    }
+
+    #[test]
+    fn restore_round_trip() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let times = FileTimes {
+            modified: Some(time("2020-01-02T03:04:05Z")),
+            created: Some(time("2019-01-02T03:04:05Z")),
+        };
+        restore(file.as_file(), file.path(), &times);
+
+        let restored = capture(&file.as_file().metadata().unwrap(), PreserveTimes::All);
+        assert_eq!(restored.modified, times.modified);
+        #[cfg(any(windows, target_os = "macos"))]
+        assert_eq!(restored.created, times.created);
+    }
+}
@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+/// Unicode form entry paths are recorded in.  macOS tools produce
+/// decomposed (NFD) names where most Linux tools produce composed (NFC)
+/// ones, so the same file can otherwise be cached under two paths.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    /// Record paths exactly as found
+    #[default]
+    Off,
+    /// Composed, as most Linux tools write
+    Nfc,
+    /// Decomposed, as macOS writes
+    Nfd,
+}
+
+impl Normalization {
+    pub fn is_off(&self) -> bool {
+        *self == Normalization::Off
+    }
+
+    pub fn apply<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        match self {
+            Normalization::Nfc if !is_nfc(s) => Cow::Owned(s.nfc().collect()),
+            Normalization::Nfd if !is_nfd(s) => Cow::Owned(s.nfd().collect()),
+            _ => Cow::Borrowed(s),
+        }
+    }
+}
+
+/// Entry paths that more than one local path normalised to, each with the
+/// local paths, from (entry, local) pairs
+pub fn collisions<'a>(paths: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<(&'a str, Vec<&'a str>)> {
+    let mut by_entry = BTreeMap::<&str, Vec<&str>>::new();
+    for (entry, local) in paths {
+        by_entry.entry(entry).or_default().push(local);
+    }
+    by_entry.into_iter()
+        .map(|(entry, mut locals)| {
+            // the same path given twice isn't a collision
+            locals.sort();
+            locals.dedup();
+            (entry, locals)
+        })
+        .filter(|(_, locals)| locals.len() > 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const COMPOSED: &str = "caf\u{e9}/r\u{e9}sum\u{e9}.txt";
+    const DECOMPOSED: &str = "cafe\u{301}/re\u{301}sume\u{301}.txt";
+
+    #[test]
+    fn forms() {
+        assert_eq!(Normalization::Nfc.apply(DECOMPOSED), COMPOSED);
+        assert_eq!(Normalization::Nfd.apply(COMPOSED), DECOMPOSED);
+        assert!(matches!(Normalization::Nfc.apply(COMPOSED), Cow::Borrowed(_)));
+        assert_eq!(Normalization::Off.apply(DECOMPOSED), DECOMPOSED);
+        assert_eq!(Normalization::Nfc.apply("plain/ascii"), "plain/ascii");
+    }
+
+    #[test]
+    fn serialised_lowercase() {
+        assert_eq!(serde_json::to_string(&Normalization::Nfc).unwrap(), "\"nfc\"");
+        assert_eq!(serde_json::from_str::<Normalization>("\"nfd\"").unwrap(), Normalization::Nfd);
+    }
+
+    #[test]
+    fn collisions_are_found() {
+        let nfc = Normalization::Nfc;
+        let locals = [COMPOSED, DECOMPOSED, "other.txt"];
+        let entries: Vec<_> = locals.iter().map(|l| nfc.apply(l)).collect();
+        let found = collisions(entries.iter().map(|e| e.as_ref()).zip(locals));
+        assert_eq!(found, vec![(COMPOSED, vec![DECOMPOSED, COMPOSED])]);
+
+        let distinct = collisions([("a", "a"), ("b", "b"), ("a", "a")]);
+        assert!(distinct.is_empty());
+    }
+}
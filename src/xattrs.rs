@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// (C) Copyright 2025 Greg Whiteley
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+use base64::Engine as _;
+
+use crate::Result;
+
+/// Attribute name to base64 encoded value
+pub type Xattrs = BTreeMap<String, String>;
+
+/// File capabilities and user attributes.  Other security.* and system.*
+/// attributes (eg SELinux labels, ACLs) belong to the host, not the file.
+pub fn wanted(name: &str) -> bool {
+    name == "security.capability" || name.starts_with("user.")
+}
+
+/// Read the attributes worth keeping from path, without following symlinks
+#[cfg(target_os = "linux")]
+pub fn capture(path: &Path) -> Result<Xattrs> {
+    let mut attrs = Xattrs::new();
+    for name in xattr::list(path)? {
+        let Some(name) = name.to_str().filter(|n| wanted(n)) else {
+            continue;
+        };
+        if let Some(value) = xattr::get(path, name)? {
+            attrs.insert(name.to_owned(), base64::engine::general_purpose::STANDARD.encode(value));
+        }
+    }
+    Ok(attrs)
+}
+
+/// Set attributes on path.  Failures are warned about per attribute, as
+/// setting security.* usually needs privileges the restore doesn't have.
+#[cfg(target_os = "linux")]
+pub fn restore(path: &Path, attrs: &Xattrs) {
+    for (name, value) in attrs {
+        let result = base64::engine::general_purpose::STANDARD.decode(value)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            .and_then(|value| xattr::set(path, name, &value));
+        if let Err(e) = result {
+            log::warn!("Failed to set {} on {}: {}", name, path.display(), e);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+static UNSUPPORTED: std::sync::Once = std::sync::Once::new();
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() {
+    UNSUPPORTED.call_once(|| log::warn!("Extended attributes are only supported on Linux, skipping them"));
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn capture(_path: &Path) -> Result<Xattrs> {
+    unsupported();
+    Ok(Xattrs::new())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restore(_path: &Path, _attrs: &Xattrs) {
+    unsupported();
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn selection() {
+        assert!(wanted("security.capability"));
+        assert!(wanted("user.origin"));
+        assert!(!wanted("security.selinux"));
+        assert!(!wanted("system.posix_acl_access"));
+        assert!(!wanted("trusted.overlay"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        std::fs::write(&from, "x").unwrap();
+        std::fs::write(&to, "x").unwrap();
+        if xattr::set(&from, "user.s3-cache-test", b"\x00value").is_err() {
+            eprintln!("filesystem doesn't support user xattrs, skipping");
+            return;
+        }
+
+        let attrs = capture(&from).unwrap();
+        assert_eq!(attrs.get("user.s3-cache-test").map(String::as_str), Some("AHZhbHVl"));
+        restore(&to, &attrs);
+        assert_eq!(xattr::get(&to, "user.s3-cache-test").unwrap().as_deref(), Some(&b"\x00value"[..]));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bad_values_dont_abort() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let attrs = Xattrs::from([("user.bad".to_owned(), "not base64!".to_owned())]);
+        restore(file.path(), &attrs);
+    }
+}
@@ -138,3 +138,836 @@ EOF
 
   $s3_cache delete --name="$cache_name"
 }
+
+@test "migrate to prefix" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt dir/text.txt
+  prefix="migrate-$(basename "${test_dir}")/"
+
+  $s3_cache migrate --to-prefix="$prefix"
+
+  # everything is already there, so a second run is all skips
+  run $s3_cache migrate --to-prefix="$prefix"
+  [ "$status" -eq 0 ]
+  echo "$output"
+  [[ "$output" == *" 0 copied"* ]]
+
+  $s3_cache migrate --to-prefix="$prefix" --delete-source
+  ! $s3_cache list | grep "$cache_name"
+
+  # overlapping the source layout is refused
+  ! $s3_cache migrate --to-prefix="cache/nested/"
+}
+
+@test "selftest" {
+  run $s3_cache selftest --delete-objects
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"PASS verify"* ]]
+  [[ "$output" == *"PASS cleanup"* ]]
+  [[ "$output" != *"FAIL"* ]]
+}
+
+@test "status against cache" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt dir/text.txt
+  $s3_cache status --threshold=0 --name="$cache_name" hello.sh text.txt dir/text.txt
+
+  echo changed >> text.txt
+  run $s3_cache --verbose status --threshold=0 --name="$cache_name" hello.sh text.txt dir/text.txt
+  echo "$output"
+  [ "$status" -eq 1 ]
+  [[ "$output" == *"  text.txt"* ]]
+}
+
+@test "plan then upload" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" --plan-out=plan.json --check-existing hello.sh text.txt
+  ! $s3_cache list | grep "$cache_name"
+  grep '"destination": "object"' plan.json
+
+  $s3_cache upload --name="$cache_name" --from-plan=plan.json
+  $s3_cache list --name="$cache_name" | grep text.txt
+
+  # stale plans are refused
+  echo changed >> text.txt
+  ! $s3_cache upload --name="$cache_name" --from-plan=plan.json
+}
+
+@test "absolute symlinks" {
+  prepare_basic_files
+
+  ln -s "$PWD/hello.sh" dir/hello.link
+
+  ! $s3_cache upload -r --absolute-symlinks=reject --name="$cache_name" hello.sh dir
+
+  $s3_cache upload -r --absolute-symlinks=rewrite --name="$cache_name" hello.sh dir
+  $s3_cache download --name="$cache_name" --outpath="out"
+  [ "$(readlink out/dir/hello.link)" = "../hello.sh" ]
+  cmp hello.sh out/dir/hello.link
+}
+
+@test "namespaces" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  prefix="ns-$(basename "${test_dir}")"
+  $s3_cache migrate --to-prefix="$prefix/"
+
+  run $s3_cache namespaces --json
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"\"name\": \"$prefix\""* ]]
+  [[ "$output" != *"\"name\": \"cache\""* ]]
+}
+
+@test "download with local state" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  $s3_cache download --local-state --name="$cache_name" --outpath="out"
+  test -f out/.s3-cache.state
+
+  run $s3_cache download --local-state --name="$cache_name" --outpath="out"
+  echo "$output"
+  [[ "$output" == *"Skipping 2 files already restored"* ]]
+  cmp text.txt out/text.txt
+
+  # the state isn't uploaded
+  $s3_cache upload -r --name="$cache_name" out
+  ! $s3_cache list --name="$cache_name" | grep s3-cache.state
+}
+
+@test "layout marker" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+
+  run $s3_cache info
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"layout version 1"* ]]
+  [[ "$output" == *"clock skew"* ]]
+  [[ "$output" != *"INCOMPATIBLE"* ]]
+}
+
+@test "expire with checkpoint" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  $s3_cache expire --checkpoint=checkpoint --time-budget=1h
+  test ! -e checkpoint
+  $s3_cache download --name="$cache_name" --outpath="out"
+  cmp text.txt out/text.txt
+}
+
+@test "purge orphans dry run" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  run $s3_cache purge-orphans --dry-run --grace=0s
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"Would delete"* ]]
+  $s3_cache download --name="$cache_name" --outpath="out"
+  cmp text.txt out/text.txt
+}
+
+@test "download several caches" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh
+  $s3_cache upload --name="$cache_name-2" text.txt
+  $s3_cache download --atomic --name="$cache_name" --name="$cache_name-2" --outpath="out"
+  cmp hello.sh out/hello.sh
+  cmp text.txt out/text.txt
+  [ -z "$(ls -A out | grep s3-cache-staging)" ]
+
+  mkdir -p out/.s3-cache-staging.1/0
+  echo partial > out/.s3-cache-staging.1/0/text.txt
+  $s3_cache download --recover --outpath="out"
+  [ ! -e out/.s3-cache-staging.1 ]
+  cmp text.txt out/text.txt
+
+  $s3_cache download --outpath-per-name --name="$cache_name" --name="$cache_name-2" --outpath="per"
+  cmp text.txt "per/$cache_name-2/text.txt"
+
+  $s3_cache upload --name="$cache_name-2" hello.sh
+  ! $s3_cache download --name="$cache_name" --name="$cache_name-2" --outpath="clash"
+  $s3_cache delete --name="$cache_name-2"
+}
+
+@test "verify cache objects" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  run $s3_cache verify --name="$cache_name"
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"2 of 2 objects present"* ]]
+}
+
+@test "download verify" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  $s3_cache download --verify --name="$cache_name" --outpath="out"
+  cmp text.txt out/text.txt
+}
+
+@test "expire unused keeps referenced objects" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  $s3_cache expire --unused --days=0
+  $s3_cache download --name="$cache_name" --outpath="out"
+  cmp text.txt out/text.txt
+}
+
+@test "access report" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache download --name="$cache_name" --outpath="out"
+  $s3_cache download --name="$cache_name" --outpath="out2"
+
+  run $s3_cache report --access --json
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"\"name\": \"$cache_name\""* ]]
+
+  $s3_cache expire --min-reads=2
+  $s3_cache download --name="$cache_name" --outpath="out3"
+}
+
+@test "result file" {
+  prepare_basic_files
+
+  $s3_cache upload --result-file=upload.json --name="$cache_name" hello.sh text.txt
+  cat upload.json
+  grep -q '"command": "upload"' upload.json
+  grep -q '"exit_code": 0' upload.json
+  grep -q '"files": 2' upload.json
+  grep -q '"error": null' upload.json
+
+  ! $s3_cache download --result-file=failed.json --name="missing-$cache_name" --outpath=out
+  cat failed.json
+  grep -q '"command": "download"' failed.json
+  grep -q '"exit_code": 1' failed.json
+  grep -q '"kind": "not_found"' failed.json
+  grep -q '"report": null' failed.json
+}
+
+@test "entry size limit" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  run $s3_cache download --max-entry-size=10 --name="$cache_name" --outpath="out"
+  echo "$output"
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"is corrupt: larger than 10 bytes"* ]]
+}
+
+@test "maintain once" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  $s3_cache maintain --once --expire-days=30 --gc --result-file=maintain.json
+  cat maintain.json
+  grep -q '"skipped": false' maintain.json
+  grep -q '"interrupted": false' maintain.json
+  $s3_cache download --name="$cache_name" --outpath="out"
+  cmp text.txt out/text.txt
+}
+
+@test "compressed put/get" {
+  prepare_basic_files
+  seq 1 100000 > big.txt
+
+  $s3_cache upload --threshold=0 --compress --compress-level=9 --name="$cache_name" big.txt text.txt
+  $s3_cache download --verify --name="$cache_name" --outpath="out"
+  cmp big.txt out/big.txt
+  cmp text.txt out/text.txt
+
+  run $s3_cache verify --name="$cache_name"
+  echo "$output"
+  [ "$status" -eq 0 ]
+}
+
+@test "upload limits" {
+  prepare_basic_files
+
+  run $s3_cache upload --max-files=2 --name="$cache_name" hello.sh text.txt dir/text.txt
+  echo "$output"
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"Largest: "* ]]
+  ! $s3_cache list | grep "$cache_name"
+
+  $s3_cache upload --max-files=2 --truncate-to-limits --priority-glob='dir/*' --result-file=upload.json --name="$cache_name" hello.sh text.txt dir/text.txt
+  cat upload.json
+  grep -q '"files": 2' upload.json
+  grep -q '"dropped"' upload.json
+  grep -q '"over-limit": 1' upload.json
+  $s3_cache list --name="$cache_name" | grep dir/text.txt
+}
+
+@test "download fallback" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" text.txt
+  run $s3_cache download --name="${cache_name}-missing" --outpath="out"
+  [ "$status" -ne 0 ]
+
+  $s3_cache download --name="${cache_name}-missing" --fallback=nothing- --fallback="$cache_name" --result-file=download.json --outpath="out"
+  cat download.json
+  grep -q "\"fallback_for\": \"${cache_name}-missing\"" download.json
+  cmp text.txt out/text.txt
+
+  $s3_cache download --name="$cache_name" --fallback=nothing- --result-file=exact.json --outpath="out"
+  ! grep -q fallback_for exact.json
+}
+
+@test "copy" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache upload --name="${cache_name}-copy" text.txt
+  run $s3_cache copy --from="$cache_name" --to="${cache_name}-copy"
+  echo "$output"
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"already exists"* ]]
+
+  $s3_cache copy --from="$cache_name" --to="${cache_name}-copy" --overwrite
+  $s3_cache delete --name="$cache_name"
+  $s3_cache download --name="${cache_name}-copy" --outpath="out"
+  $s3_cache delete --name="${cache_name}-copy"
+  cmp hello.sh out/hello.sh
+  cmp text.txt out/text.txt
+}
+
+@test "checksums" {
+  prepare_basic_files
+  seq 1 100000 > big.txt
+
+  $s3_cache upload --threshold=1000 --name="$cache_name" hello.sh text.txt dir/text.txt big.txt
+  sha256sum big.txt dir/text.txt hello.sh text.txt > expected
+
+  $s3_cache checksums --name="$cache_name" --output=SHA256SUMS
+  diff expected SHA256SUMS
+
+  $s3_cache download --write-checksums --name="$cache_name" --outpath="out"
+  diff expected out/SHA256SUMS
+  (cd out && sha256sum -c SHA256SUMS)
+}
+
+@test "rename" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache rename --from="$cache_name" --to="${cache_name}-renamed"
+  ! $s3_cache list | grep -x "$cache_name"
+  $s3_cache download --name="${cache_name}-renamed" --outpath="out"
+  $s3_cache delete --name="${cache_name}-renamed"
+  cmp hello.sh out/hello.sh
+  cmp text.txt out/text.txt
+}
+
+@test "download sentinel" {
+  prepare_basic_files
+
+  run $s3_cache download --name="$cache_name" --sentinel --outpath="out"
+  [ "$status" -ne 0 ]
+  [ ! -e out/.s3-cache.sentinel.json ]
+
+  $s3_cache download --name="$cache_name" --ok-if-missing --sentinel=miss.json --outpath="out"
+  cat miss.json
+  grep -q '"hit": false' miss.json
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache download --name="$cache_name" --ok-if-missing --sentinel --outpath="out"
+  cat out/.s3-cache.sentinel.json
+  grep -q '"hit": true' out/.s3-cache.sentinel.json
+  grep -q '"entry_time"' out/.s3-cache.sentinel.json
+
+  # a later upload of the restored tree leaves the sentinel out
+  (cd out && $s3_cache upload --recurse --name="$cache_name" .)
+  ! $s3_cache list --name="$cache_name" | grep sentinel
+}
+
+@test "stat" {
+  prepare_basic_files
+  seq 1 100000 > big.txt
+
+  $s3_cache upload --threshold=1000 --name="$cache_name" hello.sh text.txt big.txt
+  $s3_cache stat --name="$cache_name" | grep "files: *3"
+  $s3_cache stat --name="$cache_name" --json > stat.json
+  cat stat.json
+  grep -q '"file_count": 3' stat.json
+  grep -q '"deduped_object_count": 1' stat.json
+  grep -q '"inline_file_count": 2' stat.json
+}
+
+@test "list json" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt
+  $s3_cache list --name="$cache_name" | grep "2 files, .* bytes, 2 deduplicated"
+  $s3_cache list --name="$cache_name" --json > files.json
+  cat files.json
+  grep -q '"path": "hello.sh"' files.json
+  grep -q '"object"' files.json
+
+  $s3_cache list --json > caches.json
+  grep -A4 "\"name\": \"$cache_name\"" caches.json | grep -q '"file_count": 2'
+}
+
+@test "upload label shows in list" {
+  prepare_basic_files
+
+  $s3_cache upload --label=abc123 --name="$cache_name" hello.sh text.txt
+  $s3_cache list --json > caches.json
+  grep -A8 "\"name\": \"$cache_name\"" caches.json | grep -q '"label": "abc123"'
+  grep -A8 "\"name\": \"$cache_name\"" caches.json | grep -q '"writer_version"'
+}
+
+@test "upload exclude" {
+  prepare_basic_files
+  mkdir -p build/.fingerprint
+  echo object > build/main.o
+  echo hash > build/.fingerprint/hash
+  echo tool > build/tool
+
+  $s3_cache upload --recurse --exclude='**/*.o' --exclude='build/.fingerprint/**' --name="$cache_name" ./build text.txt
+  $s3_cache list --name="$cache_name" > listing
+  cat listing
+  grep -q build/tool listing
+  grep -q text.txt listing
+  ! grep -q -e main.o -e fingerprint listing
+}
+
+@test "ranged download" {
+  head -c 50000000 /dev/urandom > big.bin
+  echo small > small.txt
+
+  $s3_cache upload --threshold=1000 --name="$cache_name" big.bin small.txt
+  $s3_cache download --name="$cache_name" --ranged-threshold=1 --max-in-flight=4 --outpath="ranged"
+  $s3_cache download --name="$cache_name" --outpath="single"
+  cmp big.bin ranged/big.bin
+  cmp single/big.bin ranged/big.bin
+  cmp small.txt ranged/small.txt
+  ! ls -a ranged | grep s3-cache-part
+}
+
+@test "exists" {
+  prepare_basic_files
+
+  run $s3_cache exists --name="$cache_name"
+  echo "$output"
+  [ "$status" -eq 2 ]
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache exists --name="$cache_name"
+  $s3_cache --verbose exists --name="$cache_name" | grep "$cache_name: 2 files"
+
+  run $s3_cache --bucket=s3-cache-no-such-bucket exists --name="$cache_name"
+  echo "$output"
+  [ "$status" -eq 1 ]
+}
+
+@test "download include" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh text.txt dir/text.txt
+  $s3_cache download --name="$cache_name" --outpath=out --include='*.txt' --result-file=include.json
+  grep -q '"not-included": 1' include.json
+  cmp text.txt out/text.txt
+  cmp dir/text.txt out/dir/text.txt
+  [ ! -e out/hello.sh ]
+
+  $s3_cache download --name="$cache_name" --outpath=out2 --include='dir/*' --include=hello.sh
+  cmp hello.sh out2/hello.sh
+  cmp dir/text.txt out2/dir/text.txt
+  [ ! -e out2/text.txt ]
+}
+
+@test "download dry run" {
+  prepare_basic_files
+
+  $s3_cache upload --threshold=0 --name="$cache_name" hello.sh dir/text.txt
+  run $s3_cache download --dry-run --name="$cache_name" --outpath=out
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"Simulate restoring hello.sh"* ]]
+  [[ "$output" == *"dir/text.txt"*"deduplicated"* ]]
+  [ ! -e out ]
+}
+
+@test "download dry run names files it would overwrite" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  mkdir out
+  echo old > out/text.txt
+  run $s3_cache download --dry-run --name="$cache_name" --outpath=out
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"Simulate restoring text.txt ("*"bytes, with the cache), overwriting the existing file"* ]]
+  [[ "$output" != *"hello.sh ("*"overwriting"* ]]
+  [ "$(cat out/text.txt)" = old ]
+  [ ! -e out/hello.sh ]
+}
+
+@test "delete dry run" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache upload --name="${cache_name}-other" text.txt
+  run $s3_cache delete --dry-run --name="$cache_name"
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"Simulate deleting cache/$cache_name/entry ("*"Simulate deleting cache/$cache_name/files/hello.sh ("* ]]
+  [[ "$output" == *"Simulate deleting "*" keys, "*" bytes, of '$cache_name'"* ]]
+  [[ "$output" != *"${cache_name}-other/"* ]]
+  $s3_cache exists --name="$cache_name"
+
+  $s3_cache delete --name="$cache_name"
+  ! $s3_cache exists --name="$cache_name"
+  $s3_cache exists --name="${cache_name}-other"
+}
+
+@test "list pages" {
+  prepare_basic_files
+
+  for i in 1 2 3; do
+    $s3_cache upload --name="${cache_name}-page$i" text.txt
+  done
+  $s3_cache list --prefix="${cache_name}-page" > all.txt
+  [ "$(wc -l < all.txt)" -eq 3 ]
+
+  run $s3_cache list --prefix="${cache_name}-page" --limit=2
+  echo "$output"
+  [[ "$output" == *"--start-after=${cache_name}-page2"* ]]
+
+  $s3_cache list --prefix="${cache_name}-page" --limit=2 --start-after="${cache_name}-page2" --json > page.json
+  cat page.json
+  grep -q "\"name\": \"${cache_name}-page3\"" page.json
+  grep -q '"last_modified"' page.json
+  ! grep -q '"next"' page.json
+}
+
+@test "expire entries keeps young caches" {
+  prepare_basic_files
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache expire --entries --days=1 --result-file=expire.json
+  grep -q '"caches_deleted"' expire.json
+  $s3_cache exists --name="$cache_name"
+  $s3_cache expire --days=14 --cache-days=1
+  $s3_cache exists --name="$cache_name"
+}
+
+@test "upload hashes large files as it uploads them" {
+  head -c 3000000 /dev/urandom > big.bin
+  cp big.bin copy.bin
+  echo small > small.txt
+
+  $s3_cache upload --threshold=1000 --name="$cache_name" big.bin copy.bin small.txt
+  $s3_cache list --name="$cache_name" | grep "3 files, .* bytes, 2 deduplicated"
+  $s3_cache list --name="$cache_name" --json > files.json
+  [ "$(grep '"object": "' files.json | sort -u | wc -l)" -eq 1 ]
+  $s3_cache download --verify --name="$cache_name" --outpath=out
+  cmp big.bin out/big.bin
+  cmp big.bin out/copy.bin
+  cmp small.txt out/small.txt
+}
+
+@test "bucket prefix isolates caches" {
+  prepare_basic_files
+
+  $s3_cache --bucket-prefix="${cache_name}-team" upload --name="$cache_name" hello.sh text.txt
+  ! $s3_cache exists --name="$cache_name"
+  $s3_cache --bucket-prefix="${cache_name}-team" list | grep -x "$cache_name"
+  S3_CACHE_PREFIX="${cache_name}-team/" $s3_cache download --name="$cache_name" --outpath=out
+  cmp text.txt out/text.txt
+  S3_CACHE_PREFIX="${cache_name}-team" $s3_cache delete --name="$cache_name"
+  ! S3_CACHE_PREFIX="${cache_name}-team" $s3_cache exists --name="$cache_name"
+}
+
+@test "offline plans but doesn't reach the bucket" {
+  prepare_basic_files
+
+  $s3_cache --offline upload --plan-out=plan.json --name="$cache_name" hello.sh text.txt
+  grep -q '"path": "text.txt"' plan.json
+  run $s3_cache --offline download --name="$cache_name" --outpath=out
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"Offline, but"* ]]
+  run env S3_CACHE_OFFLINE=true $s3_cache list
+  [ "$status" -ne 0 ]
+}
+
+@test "support bundle holds no credentials" {
+  prepare_basic_files
+  $s3_cache upload --name="$cache_name" text.txt
+  $s3_cache --result-file=last.json list
+
+  $s3_cache support-bundle --output=bundle.tar.gz --last-result=last.json --redact-names
+  mkdir bundle
+  tar -xzf bundle.tar.gz -C bundle
+  ls bundle/s3-cache-support/manifest.json bundle/s3-cache-support/probes.json bundle/s3-cache-support/last-result.json
+  grep -q '"name": "put"' bundle/s3-cache-support/probes.json
+  ! grep -r -F "$cache_name" bundle
+
+  for var in AWS_ACCESS_KEY_ID AWS_SECRET_ACCESS_KEY; do
+    value=$(sed -n "s/^${var}=//p" .env 2>/dev/null | tr -d "\"'")
+    value=${value:-${!var}}
+    [ -n "$value" ]
+    ! grep -r -F -- "$value" bundle
+    ! zcat bundle.tar.gz | grep -a -F -- "$value"
+  done
+}
+
+@test "download one connection at a time" {
+  mkdir many
+  for i in $(seq 1 40); do echo "$i" > "many/$i.txt"; done
+  $s3_cache upload --name="$cache_name" --max-in-flight=8 many
+  $s3_cache download --name="$cache_name" --max-in-flight=1 --outpath="one"
+  diff -r many one/many
+}
+
+@test "download only what changed" {
+  mkdir tree
+  echo one > tree/one.txt
+  echo two > tree/two.txt
+  ln -s one.txt tree/link
+  $s3_cache upload --name="$cache_name" --threshold=0 tree
+  $s3_cache download --name="$cache_name" --outpath="out"
+
+  echo TWO > out/tree/two.txt
+  rm out/tree/link
+  echo file > out/tree/link
+  run $s3_cache download --name="$cache_name" --outpath="out" --if-changed
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"Downloaded 2 files"*"skipping 1 unchanged"* ]]
+  diff -r tree out/tree
+  [ "$(readlink out/tree/link)" = "one.txt" ]
+}
+
+@test "dups reports duplicate content" {
+  mkdir -p tree/a tree/b
+  head -c 100000 /dev/urandom > tree/a/big
+  cp tree/a/big tree/b/big
+  echo small > tree/a/small
+  echo small > tree/b/small
+  echo unique > tree/unique
+
+  run $s3_cache dups -r tree
+  echo "$output"
+  [ "$status" -eq 0 ]
+  [[ "$output" == *"4 of 5 files duplicated"*"hardlinking would save 100006 bytes"* ]]
+
+  $s3_cache dups -r --json tree > dups.json
+  grep -q '"savings": 100006' dups.json
+}
+
+@test "blake3 keyed upload round trips" {
+  prepare_basic_files
+  $s3_cache upload --name="$cache_name" --threshold=0 --hash-algorithm=blake3 text.txt dir/text.txt
+  $s3_cache download --name="$cache_name" --verify --outpath="out"
+  diff text.txt out/text.txt
+  diff dir/text.txt out/dir/text.txt
+  $s3_cache checksums --name="$cache_name" > sums
+  (cd out && sha256sum -c ../sums)
+}
+
+@test "sha256-merkle keyed upload round trips" {
+  head -c 3500000 /dev/urandom > big.bin
+  echo small > small.txt
+  $s3_cache upload --name="$cache_name" --threshold=0 --hash-algorithm=sha256-merkle-1048576 --hash-jobs=2 big.bin small.txt
+  $s3_cache download --name="$cache_name" --verify --outpath="out"
+  cmp big.bin out/big.bin
+  cmp small.txt out/small.txt
+}
+
+@test "unreadable files fail upload unless --keep-going" {
+  [ "$(id -u)" -ne 0 ] || skip "root reads everything"
+  mkdir tree
+  echo readable > tree/ok
+  echo secret > tree/secret
+  chmod 000 tree/secret
+  run $s3_cache upload --name="$cache_name" -r tree
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"Permission denied reading 'tree/secret'"* ]]
+  $s3_cache upload --name="$cache_name" -r --keep-going tree
+  $s3_cache download --name="$cache_name" --outpath="out"
+  diff tree/ok out/tree/ok
+  [ ! -e out/tree/secret ]
+}
+
+@test "empty directories are restored" {
+  mkdir -p tree/empty tree/nested/deeper tree/linkonly
+  ln -s ../text.txt tree/linkonly/link
+  echo content > tree/text.txt
+  $s3_cache upload --name="$cache_name" -r tree
+  $s3_cache download --name="$cache_name" --outpath="out"
+  [ -d out/tree/empty ]
+  [ -d out/tree/nested/deeper ]
+  [ "$(readlink out/tree/linkonly/link)" = "../text.txt" ]
+  diff tree/text.txt out/tree/text.txt
+}
+
+@test "output json for list and stat" {
+  prepare_basic_files
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache --output=json list --name="$cache_name" > files.json
+  grep -q '"path": "hello.sh"' files.json
+  $s3_cache --output=json stat --name="$cache_name" > stat.json
+  grep -q '"file_count": 2' stat.json
+  $s3_cache --output=human list --name="$cache_name" | grep "2 files"
+}
+
+@test "credentials from flags and profiles" {
+  run $s3_cache --profile=s3-cache-no-such-profile list
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"Unable to load credentials from profile 's3-cache-no-such-profile'"* ]]
+  [[ "$output" != *"Check AWS_ACCESS_KEY_ID"* ]]
+
+  run $s3_cache --access-key-id=s3-cache-wrong list
+  [ "$status" -ne 0 ]
+
+  run $s3_cache --access-key-id=s3-cache-wrong --secret-access-key=wrong list
+  [ "$status" -ne 0 ]
+}
+
+@test "progress reports files transferred" {
+  prepare_basic_files
+  $s3_cache --progress --verbose upload --name="$cache_name" hello.sh text.txt 2> upload.log
+  grep -q "Transferring hello.sh" upload.log
+  $s3_cache --progress --verbose download --name="$cache_name" --outpath="out" 2> download.log
+  grep -q "Finished text.txt" download.log
+  diff text.txt out/text.txt
+}
+
+@test "minio provider profile matches minio" {
+  prepare_basic_files
+  # strict: any status the profile doesn't expect fails
+  $s3_cache --provider=minio --strict-status upload --name="$cache_name" hello.sh text.txt
+  $s3_cache --provider=minio --strict-status download --name="$cache_name" --outpath="out"
+  diff text.txt out/text.txt
+  $s3_cache --provider=minio --strict-status delete --name="$cache_name"
+  ! $s3_cache exists --name="$cache_name"
+}
+
+@test "copy with delete-source and force" {
+  prepare_basic_files
+
+  run $s3_cache copy --from="${cache_name}-missing" --to="${cache_name}-copy"
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"not found"* ]]
+
+  $s3_cache upload --name="$cache_name" hello.sh text.txt
+  $s3_cache upload --name="${cache_name}-moved" text.txt
+  $s3_cache copy --from="$cache_name" --to="${cache_name}-moved" --force --delete-source
+  ! $s3_cache exists --name="$cache_name"
+  $s3_cache download --name="${cache_name}-moved" --outpath="out"
+  $s3_cache delete --name="${cache_name}-moved"
+  cmp hello.sh out/hello.sh
+}
+
+@test "export and import" {
+  prepare_basic_files
+  seq 1 100000 > big.txt
+
+  $s3_cache upload --threshold=1000 --name="$cache_name" hello.sh text.txt dir/text.txt big.txt
+  $s3_cache export --name="$cache_name" -o export.tar
+  tar -tf export.tar | grep -x ".s3cache_meta.json"
+  tar -tf export.tar | grep "dir/text.txt"
+
+  $s3_cache import --name="${cache_name}-imported" -i export.tar
+  $s3_cache download --name="${cache_name}-imported" --outpath="out"
+  $s3_cache delete --name="${cache_name}-imported"
+  cmp big.txt out/big.txt
+  cmp hello.sh out/hello.sh
+  cmp dir/text.txt out/dir/text.txt
+
+  run $s3_cache export --name="${cache_name}-missing" -o missing.tar
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"not found"* ]]
+}
+
+@test "keep-going uploads the rest but writes no entry" {
+  [ "$(id -u)" -ne 0 ] || skip "root reads everything"
+  mkdir tree
+  echo one > tree/one
+  echo two > tree/two
+  echo three > tree/three
+  $s3_cache upload --name="$cache_name" -r tree --plan-out=plan.json
+  # unreadable only once planned, so the upload itself fails
+  chmod 000 tree/two
+  run $s3_cache upload --name="$cache_name" --from-plan=plan.json --keep-going
+  echo "$output"
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"Failed to upload 1 files of '$cache_name'"*"tree/two"* ]]
+  ! $s3_cache exists --name="$cache_name"
+}
+
+@test "delete caches matching a name template" {
+  prepare_basic_files
+  export S3_CACHE_TEST_BRANCH="branch-$cache_name"
+  $s3_cache upload --name="deps-$S3_CACHE_TEST_BRANCH-one" text.txt
+  $s3_cache upload --name="deps-$S3_CACHE_TEST_BRANCH-two" text.txt
+  $s3_cache upload --name="deps-$S3_CACHE_TEST_BRANCH" text.txt
+
+  run $s3_cache delete --name-from='deps-{env:S3_CACHE_TEST_BRANCH}-*'
+  echo "$output"
+  [ "$status" -ne 0 ]
+  [[ "$output" == *"resolves to 'deps-$S3_CACHE_TEST_BRANCH-*', matching 2 caches"* ]]
+  [[ "$output" == *"use --yes"* ]]
+  $s3_cache exists --name="deps-$S3_CACHE_TEST_BRANCH-one"
+
+  $s3_cache delete --name-from='deps-{env:S3_CACHE_TEST_BRANCH}-*' --yes
+  ! $s3_cache exists --name="deps-$S3_CACHE_TEST_BRANCH-one"
+  ! $s3_cache exists --name="deps-$S3_CACHE_TEST_BRANCH-two"
+  $s3_cache exists --name="deps-$S3_CACHE_TEST_BRANCH"
+  $s3_cache delete --name-from='deps-{env:S3_CACHE_TEST_BRANCH}'
+  ! $s3_cache exists --name="deps-$S3_CACHE_TEST_BRANCH"
+}
+
+@test "files sharing an object are fetched once" {
+  prepare_basic_files
+  cp text.txt copy.txt
+  chmod 600 copy.txt
+  $s3_cache upload --threshold=0 --name="$cache_name" text.txt copy.txt
+
+  run $s3_cache --progress download --name="$cache_name" --outpath="out"
+  echo "$output"
+  [ "$status" -eq 0 ]
+  cmp text.txt out/text.txt
+  cmp text.txt out/copy.txt
+  [ "$(stat -c %a out/copy.txt)" = "600" ]
+
+  cp text.txt same.txt
+  $s3_cache upload --threshold=0 --name="$cache_name-linked" text.txt same.txt
+  $s3_cache download --hardlink --name="$cache_name-linked" --outpath="linked"
+  [ "$(stat -c %i linked/text.txt)" = "$(stat -c %i linked/same.txt)" ]
+  $s3_cache delete --name="$cache_name-linked"
+}
+
+@test "reuploading a cache replaces files stored with it" {
+  prepare_basic_files
+  echo "first" > changing.txt
+  $s3_cache upload --name="$cache_name" changing.txt
+  $s3_cache upload --compress --name="$cache_name-zst" changing.txt
+  echo "second, and longer" > changing.txt
+  $s3_cache upload --name="$cache_name" changing.txt
+  $s3_cache upload --compress --name="$cache_name-zst" changing.txt
+
+  $s3_cache download --name="$cache_name" --outpath="out"
+  cmp changing.txt out/changing.txt
+  $s3_cache download --name="$cache_name-zst" --outpath="zst"
+  cmp changing.txt zst/changing.txt
+  $s3_cache delete --name="$cache_name-zst"
+}